@@ -4,6 +4,7 @@ use bitcoin_hashes::{sha256d, Hash};
 
 use crate::{
     errors::RustifyError,
+    red::parametros_red,
     script::Script,
     serialized_block::SerializedBlock,
     txn::Txn,
@@ -15,6 +16,7 @@ const OP_DUP: u8 = 0x76;
 const OP_HASH160: u8 = 0xa9;
 const OP_EQUALVERIFY: u8 = 0x88;
 const OP_CHECKSIG: u8 = 0xac;
+const OP_EQUAL: u8 = 0x87;
 
 type TrxKey = (String, u32);
 type TrxHashMap<T> = HashMap<TrxKey, T>;
@@ -59,23 +61,36 @@ impl Account {
         }
     }
 
-    /// Obtiene el pubkeyHash del Bitcoin Address
+    /// Obtiene el pubkeyHash del Bitcoin Address (o el hash del script, si es P2SH)
     /// Usos: comparar con la pubkeyHash preexistentes
     /// en los outputs de las UTXOs
     pub fn decode_bitcoin_adress(&self) -> Result<Vec<u8>, RustifyError> {
-        let b58 = bs58::decode(self.public_address.clone()).into_vec()?;
-        let b58_checksum = &b58[b58.len() - 4..b58.len()];
-        let b58_hashversion = &b58[0..b58.len() - 4];
-        if b58_checksum != &sha256d::Hash::hash(b58_hashversion)[0..4] {
-            Err(RustifyError::ValidacionChecksumB58Invalida)
-        } else {
-            Ok(b58_hashversion[1..].to_vec())
+        Ok(self.decodificar_direccion()?.1)
+    }
+
+    /// Indica si la dirección es P2SH en la red seleccionada.
+    pub fn es_direccion_p2sh(&self) -> bool {
+        matches!(self.decodificar_direccion(), Ok((version, _)) if version == parametros_red().prefijo_p2sh)
+    }
+
+    /// Decodifica la dirección en base58check, verificando el checksum y que su version byte
+    /// corresponda a una dirección P2PKH o P2SH de la red seleccionada.
+    /// Devuelve el version byte y el hash de 20 bytes.
+    fn decodificar_direccion(&self) -> Result<(u8, Vec<u8>), RustifyError> {
+        let b58 = decodificar_base58check(&self.public_address)?;
+        if b58.len() != 21 {
+            return Err(RustifyError::ErrorConversionBitcoinAddress);
         }
+        let parametros = parametros_red();
+        if b58[0] != parametros.prefijo_p2pkh && b58[0] != parametros.prefijo_p2sh {
+            return Err(RustifyError::RedIncorrecta);
+        }
+        Ok((b58[0], b58[1..].to_vec()))
     }
 
     pub fn encode_bitcoin_adress(mut pubkey_hash: Vec<u8>) -> String {
         // Aca tengo b58_hashversion[1..]
-        let mut v = vec![0x6f];
+        let mut v = vec![parametros_red().prefijo_p2pkh];
         v.append(&mut pubkey_hash);
         let hashed = sha256d::Hash::hash(&(v.clone())).to_byte_array();
         let mut checksum = vec![];
@@ -113,11 +128,19 @@ impl Account {
 
     /// En base a la clave publica dada, genera la
     /// clave p2pkh a colocar en el TxOut de las Txn.
+    /// Si la dirección es P2SH, genera el script P2SH.
     pub fn obtain_pk_script(&self) -> Vec<u8> {
+        let mut pubkeyhash = self.decode_bitcoin_adress().unwrap_or_default();
         let mut pk_script = vec![];
+        if self.es_direccion_p2sh() {
+            pk_script.push(OP_HASH160);
+            pk_script.push(pubkeyhash.len().try_into().unwrap_or_default());
+            pk_script.append(&mut pubkeyhash);
+            pk_script.push(OP_EQUAL);
+            return pk_script;
+        }
         pk_script.push(OP_DUP);
         pk_script.push(OP_HASH160);
-        let mut pubkeyhash = self.decode_bitcoin_adress().unwrap_or_default();
         pk_script.push(pubkeyhash.len().try_into().unwrap_or_default());
         pk_script.append(&mut pubkeyhash);
         pk_script.push(OP_EQUALVERIFY);
@@ -127,13 +150,20 @@ impl Account {
     }
 
     /// Obtiene el formato Private Key Hexadecimal Format (64 characters [0-9A-F])
-    pub fn obtain_hex_privatekey(&self) -> String {
-        let wif = self.private_address.as_bytes();
-        let bs58 = bs58::decode(wif).into_vec().unwrap_or_default();
-
-        let bs58_str: String = bs58.iter().map(|byte| format!("{:02X}", byte)).collect();
-
-        bs58_str[2..bs58_str.len() - 10].to_string()
+    /// a partir de la clave en formato WIF, que tiene que corresponder a la red seleccionada.
+    pub fn obtain_hex_privatekey(&self) -> Result<String, RustifyError> {
+        let wif = decodificar_base58check(&self.private_address)
+            .map_err(|_| RustifyError::ErrorConversionSecretKey)?;
+        // Version byte, clave de 32 bytes y, si la clave pública es comprimida, el byte 0x01.
+        let clave = match wif.len() {
+            33 => &wif[1..],
+            34 if wif[33] == 0x01 => &wif[1..33],
+            _ => return Err(RustifyError::ErrorConversionSecretKey),
+        };
+        if wif[0] != parametros_red().prefijo_wif {
+            return Err(RustifyError::RedIncorrecta);
+        }
+        Ok(clave.iter().map(|byte| format!("{:02X}", byte)).collect())
     }
 
     /// En base a los atributos de transacciones pendientes
@@ -188,6 +218,32 @@ impl Account {
         }
     }
 
+    /// Revierte las confirmaciones de un bloque desconectado por una reorganización:
+    /// las transacciones enviadas y recibidas que estaban en el bloque vuelven a quedar pendientes.
+    pub fn revertir_bloque(&mut self, bloque: &SerializedBlock) {
+        let hash_bloque = SerializedBlock::obtain_blockhash(bloque.block_header.as_bytes());
+        let (revertidas, confirmadas): (Vec<TxnInfo>, Vec<TxnInfo>) = self
+            .sent_txn
+            .drain(..)
+            .partition(|txn_info| txn_info.bloque == hash_bloque);
+        self.sent_txn = confirmadas;
+        for mut txn_info in revertidas {
+            txn_info.txn_type = TxnType::Sending;
+            txn_info.bloque = '-'.to_string();
+            self.sending_txn.push(txn_info);
+        }
+
+        let (revertidas, confirmadas): (Vec<TxnInfo>, Vec<TxnInfo>) = self
+            .saved_received_txn
+            .drain(..)
+            .partition(|txn_info| bloque.txns.contains(&txn_info.txn));
+        self.saved_received_txn = confirmadas;
+        for mut txn_info in revertidas {
+            txn_info.txn_type = TxnType::Receiving;
+            self.receiving_txn.push(txn_info);
+        }
+    }
+
     /// Transforma el hashmap de utxos en vector de txn_info,
     /// mergeando con los datos historicos guardados por archivo
     pub fn obtain_utxo_info(&self) -> Vec<TxnInfo> {
@@ -281,12 +337,27 @@ pub fn is_p2pkh(raw_pk_script: &[u8], raw_pk_script_bytes: usize) -> bool {
         && raw_pk_script[raw_pk_script_bytes - 2] == OP_EQUALVERIFY
 }
 
+/// Decodifica una cadena en base58check y verifica su checksum.
+/// Devuelve el version byte seguido del contenido, sin el checksum.
+fn decodificar_base58check(cadena: &str) -> Result<Vec<u8>, RustifyError> {
+    let mut b58 = bs58::decode(cadena).into_vec()?;
+    if b58.len() < 5 {
+        return Err(RustifyError::ErrorConversionBitcoinAddress);
+    }
+    let b58_checksum = b58.split_off(b58.len() - 4);
+    if b58_checksum != sha256d::Hash::hash(&b58)[0..4] {
+        return Err(RustifyError::ValidacionChecksumB58Invalida);
+    }
+    Ok(b58)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        account::Account, compactsize::CompactSize, outpoint::OutPoint, txin::TxIn, txn::Txn,
-        txout::TxOut,
+        account::Account, compactsize::CompactSize, errors::RustifyError, outpoint::OutPoint,
+        red::parametros_red, txin::TxIn, txn::Txn, txout::TxOut,
     };
+    use bitcoin_hashes::{sha256d, Hash};
     use std::collections::HashMap;
 
     type TrxKey = (String, u32);
@@ -310,13 +381,46 @@ mod tests {
     #[test]
     fn obtain_hex_privatekey_test() {
         let emisor = Account::new_str("", "cRCLe18WvER3JYsfpGvNDncbsZhdecFwQmiVGBcRcC5EJLz7jRaG");
-        assert_eq!(emisor.obtain_hex_privatekey().len(), 64);
+        assert_eq!(emisor.obtain_hex_privatekey().unwrap().len(), 64);
         assert_eq!(
-            emisor.obtain_hex_privatekey(),
+            emisor.obtain_hex_privatekey().unwrap(),
             "6BD8798493D1734F8287847C11319299C21ECD6E962E37707BC5A1615A5A1C00"
         );
     }
 
+    /// Una clave WIF o una dirección de mainnet no se aceptan en testnet.
+    #[test]
+    fn test_claves_de_otra_red() {
+        let cuenta = Account::new_str(
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ",
+        );
+        assert_eq!(
+            cuenta.obtain_hex_privatekey(),
+            Err(RustifyError::RedIncorrecta)
+        );
+        assert_eq!(
+            cuenta.decode_bitcoin_adress(),
+            Err(RustifyError::RedIncorrecta)
+        );
+    }
+
+    /// Una dirección P2SH genera el script OP_HASH160 <hash> OP_EQUAL.
+    #[test]
+    fn test_pk_script_p2sh() {
+        let hash = vec![0x11; 20];
+        let mut version_y_hash = vec![parametros_red().prefijo_p2sh];
+        version_y_hash.extend(&hash);
+        let checksum = sha256d::Hash::hash(&version_y_hash)[0..4].to_vec();
+        version_y_hash.extend(checksum);
+        let cuenta = Account::new(bs58::encode(version_y_hash).into_string(), String::new());
+        assert!(cuenta.es_direccion_p2sh());
+        let mut esperado = vec![0xa9, 0x14];
+        esperado.extend(&hash);
+        esperado.push(0x87);
+        assert_eq!(cuenta.obtain_pk_script(), esperado);
+    }
+
     #[test]
     fn test_obtain_account_balance_() {
         let test_utxos = generar_utxos_for_test();
@@ -0,0 +1,176 @@
+use crate::compactsize::CompactSize;
+use crate::errors::RustifyError;
+use crate::event_loop::ConexionPeer;
+use crate::logger::{log, Action, Lvl};
+use crate::network_message::{escribir_mensaje, NetworkMessage};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc::Sender;
+
+pub const NETWORK_ADDRESS_SIZE: usize = 30;
+pub const MAX_DIRECCIONES_POR_ADDR: usize = 1000;
+
+/// Dirección de un nodo de la red, tal como viaja en el mensaje addr.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DireccionRed {
+    pub time: u32,
+    pub services: u64,
+    pub ip: [u8; 16],
+    pub port: u16,
+}
+
+impl DireccionRed {
+    pub fn new(addr: SocketAddr, services: u64, time: u32) -> DireccionRed {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        DireccionRed {
+            time,
+            services,
+            ip,
+            port: addr.port(),
+        }
+    }
+
+    /// Devuelve la dirección como SocketAddr. Las direcciones IPv4 mapeadas se devuelven como IPv4.
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = Ipv6Addr::from(self.ip);
+        match ip.to_ipv4_mapped() {
+            Some(ipv4) => SocketAddr::new(IpAddr::V4(ipv4), self.port),
+            None => SocketAddr::new(IpAddr::V6(ip), self.port),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+        bytes.extend_from_slice(&self.time.to_le_bytes());
+        bytes.extend_from_slice(&self.services.to_le_bytes());
+        bytes.extend_from_slice(&self.ip);
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DireccionRed, RustifyError> {
+        if bytes.len() < NETWORK_ADDRESS_SIZE {
+            return Err(RustifyError::ErrorParseoAddr);
+        }
+        Ok(DireccionRed {
+            time: u32::from_le_bytes(bytes[0..4].try_into()?),
+            services: u64::from_le_bytes(bytes[4..12].try_into()?),
+            ip: bytes[12..28].try_into()?,
+            port: u16::from_be_bytes(bytes[28..30].try_into()?),
+        })
+    }
+}
+
+/// Mensaje addr, con el que los nodos se comparten direcciones de otros nodos de la red.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrMessage {
+    pub count: CompactSize,
+    pub direcciones: Vec<DireccionRed>,
+}
+
+impl AddrMessage {
+    pub fn new(direcciones: Vec<DireccionRed>) -> AddrMessage {
+        AddrMessage {
+            count: CompactSize::new(direcciones.len() as u64),
+            direcciones,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.count.as_bytes();
+        for direccion in &self.direcciones {
+            bytes.append(&mut direccion.as_bytes());
+        }
+        bytes
+    }
+
+    /// Convierte la cadena de bytes recibida en un AddrMessage.
+    /// Devuelve error si el mensaje tiene más direcciones que las permitidas o está truncado.
+    pub fn from_bytes(bytes: &[u8]) -> Result<AddrMessage, RustifyError> {
+        let (cant_direcciones, mut index) =
+            CompactSize::leer(bytes, 0).map_err(|_| RustifyError::ErrorParseoAddr)?;
+        let count = CompactSize::new(cant_direcciones);
+        let cant_direcciones = cant_direcciones as usize;
+        if cant_direcciones > MAX_DIRECCIONES_POR_ADDR
+            || bytes.len() < index + cant_direcciones * NETWORK_ADDRESS_SIZE
+        {
+            return Err(RustifyError::ErrorParseoAddr);
+        }
+
+        let mut direcciones = vec![];
+        for _ in 0..cant_direcciones {
+            direcciones.push(DireccionRed::from_bytes(
+                &bytes[index..index + NETWORK_ADDRESS_SIZE],
+            )?);
+            index += NETWORK_ADDRESS_SIZE;
+        }
+        Ok(AddrMessage { count, direcciones })
+    }
+}
+
+/// Envía el mensaje getaddr, para que el nodo remoto nos responda con direcciones de otros nodos.
+pub fn getaddr(
+    socket: &mut ConexionPeer,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::GetAddr)?;
+    log(
+        Lvl::Info(Action::ADDRESSES),
+        "Enviado mensaje getaddr.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direccion_red_ipv4_mapeada() {
+        let addr: SocketAddr = "192.168.0.10:18333".parse().unwrap();
+        let direccion = DireccionRed::new(addr, 1, 1681160400);
+        assert_eq!(
+            direccion.ip,
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 168, 0, 10]
+        );
+        assert_eq!(direccion.socket_addr(), addr);
+    }
+
+    #[test]
+    fn test_addr_message_as_bytes_from_bytes() -> Result<(), RustifyError> {
+        let direcciones = vec![
+            DireccionRed::new("10.0.0.1:18333".parse().unwrap(), 0x0409, 1681160400),
+            DireccionRed::new("10.0.0.2:8333".parse().unwrap(), 0x01, 1681160500),
+        ];
+        let addr_message = AddrMessage::new(direcciones);
+        let bytes = addr_message.as_bytes();
+        assert_eq!(bytes.len(), 1 + 2 * NETWORK_ADDRESS_SIZE);
+        assert_eq!(AddrMessage::from_bytes(&bytes)?, addr_message);
+        Ok(())
+    }
+
+    #[test]
+    fn test_addr_message_truncado() {
+        let addr_message = AddrMessage::new(vec![DireccionRed::new(
+            "10.0.0.1:18333".parse().unwrap(),
+            0x01,
+            0,
+        )]);
+        let bytes = addr_message.as_bytes();
+        assert_eq!(
+            AddrMessage::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(RustifyError::ErrorParseoAddr)
+        );
+        // Vacío, o cortado dentro del compactsize de la cantidad de direcciones.
+        let truncados: [&[u8]; 3] = [&[], &[0xfd], &[0xfe, 0x01, 0x00]];
+        for truncado in truncados {
+            assert_eq!(
+                AddrMessage::from_bytes(truncado),
+                Err(RustifyError::ErrorParseoAddr)
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use crate::{
+    addr::AddrMessage,
+    addrv2::{AddrV2Message, DireccionNodo, DireccionRedV2},
+    ban_manager::penalizar_nodo,
+    config::Config,
+    errors::{obtener_mensaje_personalizado, RustifyError},
+    logger::{log_re_err, log_with_parameters, Action, Lvl},
+    red::parametros_red,
+};
+use chrono::Utc;
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    net::ToSocketAddrs,
+    path::Path,
+    sync::{mpsc::Sender, Arc, Mutex, OnceLock},
+};
+
+const MAX_DIRECCIONES_CONOCIDAS: usize = 2500;
+const MAX_FALLAS_CONSECUTIVAS: u32 = 10;
+const SEGUNDOS_ESPERA_TRAS_FALLA: i64 = 600;
+const SEGUNDOS_ANTIGUEDAD_MAXIMA: i64 = 60 * 60 * 24 * 30;
+
+static ADDRESS_MANAGER: OnceLock<AddressManager> = OnceLock::new();
+
+/// Dirección de un nodo conocido, junto con su historial de conexiones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DireccionConocida {
+    pub addr: DireccionNodo,
+    pub servicios: u64,
+    pub ultima_vez_visto: i64,
+    pub ultima_falla: i64,
+    pub fallas_consecutivas: u32,
+}
+
+impl DireccionConocida {
+    pub fn new(addr: DireccionNodo, servicios: u64, ultima_vez_visto: i64) -> DireccionConocida {
+        DireccionConocida {
+            addr,
+            servicios,
+            ultima_vez_visto,
+            ultima_falla: 0,
+            fallas_consecutivas: 0,
+        }
+    }
+
+    /// Convierte la dirección en una línea del archivo de direcciones:
+    /// "addr servicios ultima_vez_visto ultima_falla fallas_consecutivas"
+    fn as_linea(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.addr,
+            self.servicios,
+            self.ultima_vez_visto,
+            self.ultima_falla,
+            self.fallas_consecutivas
+        )
+    }
+
+    fn from_linea(linea: &str) -> Result<DireccionConocida, RustifyError> {
+        let partes: Vec<&str> = linea.split_whitespace().collect();
+        if partes.len() != 5 {
+            return Err(RustifyError::ErrorParseoAddr);
+        }
+        Ok(DireccionConocida {
+            addr: partes[0].parse()?,
+            servicios: partes[1].parse()?,
+            ultima_vez_visto: partes[2].parse()?,
+            ultima_falla: partes[3].parse()?,
+            fallas_consecutivas: partes[4].parse()?,
+        })
+    }
+
+    fn fallo_recientemente(&self, ahora: i64) -> bool {
+        self.fallas_consecutivas > 0 && ahora - self.ultima_falla < SEGUNDOS_ESPERA_TRAS_FALLA
+    }
+}
+
+/// Libreta de direcciones del nodo.
+/// Se inicializa con las direcciones del DNS seed (config.address) y las guardadas en disco (config.addresses_path),
+/// y aprende nuevas direcciones de los mensajes addr que envían los peers.
+/// Es utilizada por conectar para elegir a qué nodos conectarse.
+#[derive(Debug, Clone)]
+pub struct AddressManager {
+    direcciones: Arc<Mutex<HashMap<DireccionNodo, DireccionConocida>>>,
+    path: String,
+    logger_sender: Sender<String>,
+}
+
+impl AddressManager {
+    /// Constructor del AddressManager. Carga las direcciones guardadas en disco y resuelve el DNS seed.
+    /// Con un proxy configurado el DNS seed no se resuelve (ver agregar_semilla_sin_resolver).
+    /// Si el DNS seed no responde se continúa con las direcciones guardadas;
+    /// devuelve error solamente si no se conoce ninguna dirección.
+    pub fn build(
+        config: &Config,
+        logger_sender: &Sender<String>,
+    ) -> Result<AddressManager, RustifyError> {
+        let address_manager = AddressManager {
+            direcciones: Arc::new(Mutex::new(HashMap::new())),
+            path: config.addresses_path.clone(),
+            logger_sender: logger_sender.clone(),
+        };
+        address_manager.cargar_direcciones()?;
+
+        for semilla in obtener_semillas(config) {
+            if !config.proxy.is_empty() {
+                address_manager.agregar_semilla_sin_resolver(&semilla)?;
+                continue;
+            }
+            match semilla.to_socket_addrs() {
+                Ok(addrs) => {
+                    let mut direcciones = address_manager.direcciones.lock()?;
+                    for addr in addrs.map(DireccionNodo::Ip) {
+                        direcciones
+                            .entry(addr)
+                            .or_insert_with(|| DireccionConocida::new(addr, 0, 0));
+                    }
+                }
+                Err(e) => log_with_parameters(
+                    Lvl::Warning(Action::ADDRESSES),
+                    format!(
+                        "No se pudo resolver el DNS seed {}: {}. Se usarán las direcciones guardadas.",
+                        semilla, e
+                    ),
+                    logger_sender,
+                ),
+            }
+        }
+
+        if address_manager.cant_direcciones()? == 0 {
+            return Err(RustifyError::NoHayConexionesDisponibles);
+        }
+        Ok(address_manager)
+    }
+
+    /// Con un proxy configurado, el DNS seed no se resuelve localmente: la consulta saldría por fuera
+    /// del proxy (Tor). Solamente se agrega si ya es una dirección (IP, .onion o .i2p).
+    fn agregar_semilla_sin_resolver(&self, semilla: &str) -> Result<(), RustifyError> {
+        match semilla.parse::<DireccionNodo>() {
+            Ok(addr) => {
+                self.direcciones
+                    .lock()?
+                    .entry(addr)
+                    .or_insert_with(|| DireccionConocida::new(addr, 0, 0));
+            }
+            Err(_) => log_with_parameters(
+                Lvl::Warning(Action::ADDRESSES),
+                format!(
+                    "Con proxy configurado no se resuelve el DNS seed {}. Se usarán las direcciones guardadas.",
+                    semilla
+                ),
+                &self.logger_sender,
+            ),
+        }
+        Ok(())
+    }
+
+    /// Devuelve las direcciones a las que intentar conectarse, en orden de preferencia:
+    /// primero las que no fallaron recientemente, luego las que sí, y por último las excluidas
+    /// (por ejemplo, a las que ya se está conectado), por si no hubiera otras.
+    /// Se consideran tanto direcciones IPv4 como IPv6; las de redes sin IP (Tor, I2P)
+    /// solamente si se indica incluir_sin_ip (es decir, si hay un proxy configurado).
+    pub fn elegir_candidatas(
+        &self,
+        excluidas: &[DireccionNodo],
+        incluir_sin_ip: bool,
+    ) -> Result<Vec<DireccionNodo>, RustifyError> {
+        let ahora = Utc::now().timestamp();
+        let direcciones = self.direcciones.lock()?;
+        let mut sanas = vec![];
+        let mut con_fallas = vec![];
+        let mut repetidas = vec![];
+        for direccion in direcciones.values() {
+            let addr = direccion.addr;
+            if addr.socket_addr().is_none() && !incluir_sin_ip {
+                continue;
+            }
+            if excluidas.contains(&addr) {
+                repetidas.push(addr);
+            } else if direccion.fallo_recientemente(ahora) {
+                con_fallas.push(addr);
+            } else {
+                sanas.push(addr);
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        sanas.shuffle(&mut rng);
+        con_fallas.shuffle(&mut rng);
+        repetidas.shuffle(&mut rng);
+        sanas.append(&mut con_fallas);
+        sanas.append(&mut repetidas);
+        Ok(sanas)
+    }
+
+    /// Registra que la conexión con la dirección fue exitosa.
+    pub fn registrar_conexion(&self, addr: &DireccionNodo) -> Result<(), RustifyError> {
+        {
+            let mut direcciones = self.direcciones.lock()?;
+            let addr = *addr;
+            let direccion = direcciones
+                .entry(addr)
+                .or_insert_with(|| DireccionConocida::new(addr, 0, 0));
+            direccion.ultima_vez_visto = Utc::now().timestamp();
+            direccion.fallas_consecutivas = 0;
+        }
+        self.guardar_direcciones()
+    }
+
+    /// Registra que no se pudo conectar a la dirección.
+    /// Luego de MAX_FALLAS_CONSECUTIVAS fallas seguidas, la dirección se olvida.
+    pub fn registrar_falla(&self, addr: &DireccionNodo) -> Result<(), RustifyError> {
+        {
+            let mut direcciones = self.direcciones.lock()?;
+            if let Some(direccion) = direcciones.get_mut(addr) {
+                direccion.ultima_falla = Utc::now().timestamp();
+                direccion.fallas_consecutivas += 1;
+                if direccion.fallas_consecutivas >= MAX_FALLAS_CONSECUTIVAS {
+                    direcciones.remove(addr);
+                }
+            }
+        }
+        self.guardar_direcciones()
+    }
+
+    /// Agrega a la libreta las direcciones recibidas en un mensaje addr o addrv2.
+    /// Se ignoran las direcciones demasiado antiguas y las que exceden la capacidad máxima.
+    /// Devuelve la cantidad de direcciones nuevas.
+    pub fn agregar_direcciones(
+        &self,
+        direcciones_recibidas: &[DireccionRedV2],
+    ) -> Result<usize, RustifyError> {
+        let ahora = Utc::now().timestamp();
+        let mut nuevas = 0;
+        {
+            let mut direcciones = self.direcciones.lock()?;
+            for direccion_red in direcciones_recibidas {
+                let visto = (direccion_red.time as i64).min(ahora);
+                let addr = direccion_red.direccion;
+                let sin_puerto = addr.port() == 0 && !matches!(addr, DireccionNodo::I2p(..));
+                if ahora - visto > SEGUNDOS_ANTIGUEDAD_MAXIMA || sin_puerto {
+                    continue;
+                }
+                let cant_conocidas = direcciones.len();
+                match direcciones.get_mut(&addr) {
+                    Some(conocida) => {
+                        conocida.servicios = direccion_red.services;
+                        conocida.ultima_vez_visto = conocida.ultima_vez_visto.max(visto);
+                    }
+                    None if cant_conocidas < MAX_DIRECCIONES_CONOCIDAS => {
+                        direcciones.insert(
+                            addr,
+                            DireccionConocida::new(addr, direccion_red.services, visto),
+                        );
+                        nuevas += 1;
+                    }
+                    None => {}
+                }
+            }
+        }
+        self.guardar_direcciones()?;
+        Ok(nuevas)
+    }
+
+    /// Devuelve la cantidad de direcciones conocidas.
+    pub fn cant_direcciones(&self) -> Result<usize, RustifyError> {
+        Ok(self.direcciones.lock()?.len())
+    }
+
+    /// Devuelve el registro de la dirección, si es conocida.
+    pub fn obtener_direccion(
+        &self,
+        addr: &DireccionNodo,
+    ) -> Result<Option<DireccionConocida>, RustifyError> {
+        Ok(self.direcciones.lock()?.get(addr).cloned())
+    }
+
+    /// Carga las direcciones guardadas en el archivo de direcciones, si existe.
+    /// Las líneas inválidas se ignoran.
+    fn cargar_direcciones(&self) -> Result<(), RustifyError> {
+        if self.path.is_empty() || !Path::new(&self.path).exists() {
+            return Ok(());
+        }
+        let archivo = File::open(&self.path)?;
+        let mut direcciones = self.direcciones.lock()?;
+        for linea in BufReader::new(archivo).lines() {
+            if let Ok(direccion) = DireccionConocida::from_linea(&linea?) {
+                direcciones.insert(direccion.addr, direccion);
+            }
+        }
+        log_with_parameters(
+            Lvl::Info(Action::ADDRESSES),
+            format!(
+                "Se cargaron {} direcciones desde {}.",
+                direcciones.len(),
+                self.path
+            ),
+            &self.logger_sender,
+        );
+        Ok(())
+    }
+
+    /// Persiste las direcciones conocidas en el archivo de direcciones.
+    /// Si no se configuró un archivo, no se persiste nada.
+    fn guardar_direcciones(&self) -> Result<(), RustifyError> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let contenido: String = self
+            .direcciones
+            .lock()?
+            .values()
+            .map(|direccion| direccion.as_linea() + "\n")
+            .collect();
+        fs::write(&self.path, contenido)?;
+        Ok(())
+    }
+}
+
+/// Devuelve los DNS seeds a resolver: el configurado en config.address, o si no se configuró,
+/// los de la red seleccionada. Si no se indica el puerto, se usa el puerto por defecto de la red.
+fn obtener_semillas(config: &Config) -> Vec<String> {
+    let parametros = parametros_red();
+    if config.address.is_empty() {
+        return parametros
+            .dns_seeds
+            .iter()
+            .map(|seed| format!("{}:{}", seed, parametros.puerto_por_defecto))
+            .collect();
+    }
+    if config.address.contains(':') {
+        return vec![config.address.clone()];
+    }
+    vec![format!(
+        "{}:{}",
+        config.address, parametros.puerto_por_defecto
+    )]
+}
+
+/// Inicializa el AddressManager del nodo y lo registra para que lo utilicen
+/// conectar y el handleo de mensajes addr.
+pub fn iniciar_address_manager(
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<AddressManager, RustifyError> {
+    let address_manager = AddressManager::build(config, logger_sender)?;
+    Ok(ADDRESS_MANAGER.get_or_init(|| address_manager).clone())
+}
+
+/// Devuelve el AddressManager del nodo, si fue inicializado.
+pub fn obtener_address_manager() -> Option<&'static AddressManager> {
+    ADDRESS_MANAGER.get()
+}
+
+/// Procesa el mensaje addr recibido, agregando las direcciones al AddressManager del nodo.
+pub fn procesar_addr(addr_message: &AddrMessage, logger_sender: &Sender<String>) {
+    let direcciones: Vec<DireccionRedV2> = addr_message
+        .direcciones
+        .iter()
+        .cloned()
+        .map(DireccionRedV2::from)
+        .collect();
+    agregar_direcciones_recibidas(&direcciones, logger_sender);
+}
+
+/// Procesa el mensaje addrv2 (BIP155) recibido, agregando las direcciones al AddressManager del nodo.
+pub fn procesar_addrv2(addrv2_message: &AddrV2Message, logger_sender: &Sender<String>) {
+    agregar_direcciones_recibidas(&addrv2_message.direcciones, logger_sender);
+}
+
+/// Penaliza la dirección de un nodo que violó el protocolo, registrándola como una falla
+/// para que tenga menor prioridad al elegir a qué nodos conectarse,
+/// y sumándole puntos de mal comportamiento (que pueden terminar en un baneo).
+pub fn penalizar_direccion(
+    addr: &DireccionNodo,
+    error: &RustifyError,
+    logger_sender: &Sender<String>,
+) {
+    log_with_parameters(
+        Lvl::Warning(Action::ADDRESSES),
+        format!(
+            "El nodo {} violó el protocolo ({}). Se lo desconecta.",
+            addr,
+            obtener_mensaje_personalizado(error.clone())
+        ),
+        logger_sender,
+    );
+    if let Some(address_manager) = obtener_address_manager() {
+        if let Err(e) = address_manager.registrar_falla(addr) {
+            log_re_err(Action::ADDRESSES, e, logger_sender);
+        }
+    }
+    penalizar_nodo(addr, error, logger_sender);
+}
+
+fn agregar_direcciones_recibidas(direcciones: &[DireccionRedV2], logger_sender: &Sender<String>) {
+    if let Some(address_manager) = obtener_address_manager() {
+        match address_manager.agregar_direcciones(direcciones) {
+            Ok(nuevas) => log_with_parameters(
+                Lvl::Info(Action::ADDRESSES),
+                format!(
+                    "Recibidas {} direcciones, {} nuevas.",
+                    direcciones.len(),
+                    nuevas
+                ),
+                logger_sender,
+            ),
+            Err(e) => log_re_err(Action::ADDRESSES, e, logger_sender),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address_manager_test(path: &str) -> AddressManager {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        AddressManager {
+            direcciones: Arc::new(Mutex::new(HashMap::new())),
+            path: path.to_string(),
+            logger_sender,
+        }
+    }
+
+    fn direccion_test(addr: &str, time: u32) -> DireccionRedV2 {
+        DireccionRedV2 {
+            time,
+            services: 1,
+            direccion: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_agregar_direcciones_ignora_antiguas() -> Result<(), RustifyError> {
+        let address_manager = address_manager_test("");
+        let ahora = Utc::now().timestamp() as u32;
+        let direcciones = vec![
+            direccion_test("10.0.0.1:18333", ahora),
+            direccion_test("10.0.0.2:18333", 0),
+        ];
+        assert_eq!(address_manager.agregar_direcciones(&direcciones)?, 1);
+        assert_eq!(address_manager.cant_direcciones()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_elegir_candidatas_prioriza_sin_fallas() -> Result<(), RustifyError> {
+        let address_manager = address_manager_test("");
+        let ahora = Utc::now().timestamp() as u32;
+        let sana: DireccionNodo = "[2001:db8::1]:18333".parse()?;
+        let fallida: DireccionNodo = "10.0.0.2:18333".parse()?;
+        let excluida: DireccionNodo = "10.0.0.3:18333".parse()?;
+        let onion: DireccionNodo =
+            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:18333".parse()?;
+        address_manager.agregar_direcciones(&[
+            direccion_test(&fallida.to_string(), ahora),
+            direccion_test(&excluida.to_string(), ahora),
+            direccion_test(&sana.to_string(), ahora),
+            direccion_test(&onion.to_string(), ahora),
+        ])?;
+        address_manager.registrar_falla(&fallida)?;
+
+        let candidatas = address_manager.elegir_candidatas(&[excluida], false)?;
+        assert_eq!(candidatas, vec![sana, fallida, excluida]);
+
+        let candidatas_proxy = address_manager.elegir_candidatas(&[excluida], true)?;
+        assert_eq!(candidatas_proxy.len(), 4);
+        assert!(candidatas_proxy[..2].contains(&onion));
+        Ok(())
+    }
+
+    /// Test para chequear que con proxy no se resuelven localmente los DNS seeds,
+    /// pero sí se agregan las semillas que ya son direcciones.
+    #[test]
+    fn test_semillas_con_proxy_no_se_resuelven() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        let config = Config {
+            proxy: "127.0.0.1:9050".to_string(),
+            address: "localhost:18333".to_string(),
+            addresses_path: "".to_string(),
+            ..Config::new("./node.config").map_err(|_| RustifyError::NotFound)?
+        };
+        assert_eq!(
+            AddressManager::build(&config, &logger_sender).map(|_| ()),
+            Err(RustifyError::NoHayConexionesDisponibles)
+        );
+
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:18333";
+        let config = Config {
+            address: onion.to_string(),
+            ..config
+        };
+        let address_manager = AddressManager::build(&config, &logger_sender)?;
+        assert!(address_manager
+            .obtener_direccion(&onion.parse()?)?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_guardar_y_cargar_direcciones() -> Result<(), RustifyError> {
+        let path = std::env::temp_dir().join("rustify_test_direcciones.txt");
+        let path = path.to_str().unwrap_or_default();
+        let addr: DireccionNodo = "10.0.0.1:18333".parse()?;
+        let onion: DireccionNodo =
+            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:18333".parse()?;
+
+        let address_manager = address_manager_test(path);
+        address_manager.registrar_conexion(&addr)?;
+        address_manager.registrar_falla(&addr)?;
+        address_manager.agregar_direcciones(&[direccion_test(
+            &onion.to_string(),
+            Utc::now().timestamp() as u32,
+        )])?;
+
+        let cargado = address_manager_test(path);
+        cargado.cargar_direcciones()?;
+        fs::remove_file(path)?;
+
+        let direccion = cargado.obtener_direccion(&addr)?;
+        assert_eq!(direccion, address_manager.obtener_direccion(&addr)?);
+        assert_eq!(direccion.map(|d| d.fallas_consecutivas), Some(1));
+        assert!(cargado.obtener_direccion(&onion)?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use crate::addr::DireccionRed;
+use crate::compactsize::{leer_bytes, CompactSize};
+use crate::errors::RustifyError;
+use crate::event_loop::ConexionPeer;
+use crate::logger::{log, Action, Lvl};
+use crate::network_message::{escribir_mensaje, NetworkMessage};
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+
+pub const MAX_DIRECCIONES_POR_ADDRV2: usize = 1000;
+const MAX_LARGO_DIRECCION: usize = 512;
+
+// Identificadores de red definidos en BIP155.
+const RED_IPV4: u8 = 1;
+const RED_IPV6: u8 = 2;
+const RED_TORV3: u8 = 4;
+const RED_I2P: u8 = 5;
+const RED_CJDNS: u8 = 6;
+
+const TORV3_VERSION: u8 = 0x03;
+const ALFABETO_BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Dirección de un nodo de la red, en cualquiera de las redes soportadas por BIP155.
+/// Las direcciones que no son IP (Tor, I2P) solamente son alcanzables a través de un proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DireccionNodo {
+    Ip(SocketAddr),
+    TorV3([u8; 32], u16),
+    I2p([u8; 32], u16),
+    Cjdns(Ipv6Addr, u16),
+}
+
+impl DireccionNodo {
+    /// Devuelve la dirección IP del nodo, si es que tiene una.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            DireccionNodo::Ip(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            DireccionNodo::Ip(addr) => addr.port(),
+            DireccionNodo::TorV3(_, port)
+            | DireccionNodo::I2p(_, port)
+            | DireccionNodo::Cjdns(_, port) => *port,
+        }
+    }
+
+    /// Devuelve el nombre de host de la dirección (ip, .onion o .b32.i2p), sin el puerto.
+    pub fn host(&self) -> String {
+        match self {
+            DireccionNodo::Ip(addr) => addr.ip().to_string(),
+            DireccionNodo::TorV3(pubkey, _) => {
+                let mut bytes = pubkey.to_vec();
+                bytes.extend_from_slice(&checksum_torv3(pubkey));
+                bytes.push(TORV3_VERSION);
+                format!("{}.onion", codificar_base32(&bytes))
+            }
+            DireccionNodo::I2p(hash, _) => format!("{}.b32.i2p", codificar_base32(hash)),
+            DireccionNodo::Cjdns(ip, _) => ip.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DireccionNodo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DireccionNodo::Ip(addr) => write!(f, "{}", addr),
+            DireccionNodo::Cjdns(ip, port) => write!(f, "cjdns:[{}]:{}", ip, port),
+            _ => write!(f, "{}:{}", self.host(), self.port()),
+        }
+    }
+}
+
+impl FromStr for DireccionNodo {
+    type Err = RustifyError;
+
+    /// Parsea una dirección con el formato generado por Display.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(DireccionNodo::Ip(addr));
+        }
+        if let Some(resto) = s.strip_prefix("cjdns:") {
+            let addr: SocketAddr = resto.parse().map_err(|_| RustifyError::ErrorParseoAddr)?;
+            return match addr.ip() {
+                IpAddr::V6(ip) => Ok(DireccionNodo::Cjdns(ip, addr.port())),
+                IpAddr::V4(_) => Err(RustifyError::ErrorParseoAddr),
+            };
+        }
+
+        let (host, port) = s.rsplit_once(':').ok_or(RustifyError::ErrorParseoAddr)?;
+        let port: u16 = port.parse()?;
+        if let Some(onion) = host.strip_suffix(".onion") {
+            let bytes = decodificar_base32(onion)?;
+            if bytes.len() != 35 || bytes[34] != TORV3_VERSION {
+                return Err(RustifyError::ErrorParseoAddr);
+            }
+            let pubkey: [u8; 32] = bytes[..32].try_into()?;
+            if bytes[32..34] != checksum_torv3(&pubkey) {
+                return Err(RustifyError::ErrorParseoAddr);
+            }
+            return Ok(DireccionNodo::TorV3(pubkey, port));
+        }
+        if let Some(i2p) = host.strip_suffix(".b32.i2p") {
+            let hash: [u8; 32] = decodificar_base32(i2p)?.as_slice().try_into()?;
+            return Ok(DireccionNodo::I2p(hash, port));
+        }
+        Err(RustifyError::ErrorParseoAddr)
+    }
+}
+
+/// Dirección de un nodo, tal como viaja en el mensaje addrv2 (BIP155).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DireccionRedV2 {
+    pub time: u32,
+    pub services: u64,
+    pub direccion: DireccionNodo,
+}
+
+impl From<DireccionRed> for DireccionRedV2 {
+    fn from(direccion_red: DireccionRed) -> Self {
+        DireccionRedV2 {
+            time: direccion_red.time,
+            services: direccion_red.services,
+            direccion: DireccionNodo::Ip(direccion_red.socket_addr()),
+        }
+    }
+}
+
+impl DireccionRedV2 {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let (red, direccion): (u8, Vec<u8>) = match &self.direccion {
+            DireccionNodo::Ip(addr) => match addr.ip() {
+                IpAddr::V4(ip) => (RED_IPV4, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (RED_IPV6, ip.octets().to_vec()),
+            },
+            DireccionNodo::TorV3(pubkey, _) => (RED_TORV3, pubkey.to_vec()),
+            DireccionNodo::I2p(hash, _) => (RED_I2P, hash.to_vec()),
+            DireccionNodo::Cjdns(ip, _) => (RED_CJDNS, ip.octets().to_vec()),
+        };
+        let mut bytes: Vec<u8> = vec![];
+        bytes.extend_from_slice(&self.time.to_le_bytes());
+        bytes.append(&mut CompactSize::new(self.services).as_bytes());
+        bytes.push(red);
+        bytes.append(&mut CompactSize::new(direccion.len() as u64).as_bytes());
+        bytes.extend_from_slice(&direccion);
+        bytes.extend_from_slice(&self.direccion.port().to_be_bytes());
+        bytes
+    }
+
+    /// Parsea una dirección a partir de los bytes, devolviendo también la cantidad de bytes leídos.
+    /// Las direcciones de redes desconocidas o con largo inválido se devuelven como None,
+    /// para poder seguir leyendo el resto del mensaje (BIP155 indica ignorarlas).
+    /// Si los bytes están truncados devuelve MensajeMalformado.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Option<DireccionRedV2>, usize), RustifyError> {
+        let time = u32::from_le_bytes(leer_bytes(bytes, 0, 4)?.try_into()?);
+        let (services, mut index) = CompactSize::leer(bytes, 4)?;
+        let red = leer_bytes(bytes, index, 1)?[0];
+        index += 1;
+        let (largo_direccion, siguiente) = CompactSize::leer(bytes, index)?;
+        index = siguiente;
+        let largo_direccion = largo_direccion as usize;
+        if largo_direccion > MAX_LARGO_DIRECCION {
+            return Err(RustifyError::ErrorParseoAddr);
+        }
+        let direccion = leer_bytes(bytes, index, largo_direccion)?;
+        index += largo_direccion;
+        let port = u16::from_be_bytes(leer_bytes(bytes, index, 2)?.try_into()?);
+        index += 2;
+
+        let direccion = match (red, largo_direccion) {
+            (RED_IPV4, 4) => {
+                let ip: [u8; 4] = direccion.try_into()?;
+                Some(DireccionNodo::Ip(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::from(ip)),
+                    port,
+                )))
+            }
+            (RED_IPV6, 16) => {
+                let ip: [u8; 16] = direccion.try_into()?;
+                Some(DireccionNodo::Ip(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::from(ip)),
+                    port,
+                )))
+            }
+            (RED_TORV3, 32) => Some(DireccionNodo::TorV3(direccion.try_into()?, port)),
+            (RED_I2P, 32) => Some(DireccionNodo::I2p(direccion.try_into()?, port)),
+            (RED_CJDNS, 16) => {
+                let ip: [u8; 16] = direccion.try_into()?;
+                Some(DireccionNodo::Cjdns(Ipv6Addr::from(ip), port))
+            }
+            _ => None,
+        };
+        Ok((
+            direccion.map(|direccion| DireccionRedV2 {
+                time,
+                services,
+                direccion,
+            }),
+            index,
+        ))
+    }
+}
+
+/// Mensaje addrv2 (BIP155), con el que los nodos se comparten direcciones de cualquier red.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrV2Message {
+    pub direcciones: Vec<DireccionRedV2>,
+}
+
+impl AddrV2Message {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = CompactSize::new(self.direcciones.len() as u64).as_bytes();
+        for direccion in &self.direcciones {
+            bytes.append(&mut direccion.as_bytes());
+        }
+        bytes
+    }
+
+    /// Convierte la cadena de bytes recibida en un AddrV2Message.
+    /// Las direcciones de redes desconocidas se descartan.
+    pub fn from_bytes(bytes: &[u8]) -> Result<AddrV2Message, RustifyError> {
+        let (count, mut index) =
+            CompactSize::leer(bytes, 0).map_err(|_| RustifyError::ErrorParseoAddr)?;
+        if count as usize > MAX_DIRECCIONES_POR_ADDRV2 {
+            return Err(RustifyError::ErrorParseoAddr);
+        }
+        let mut direcciones = vec![];
+        for _ in 0..count {
+            let (direccion, largo) = DireccionRedV2::from_bytes(&bytes[index..])
+                .map_err(|_| RustifyError::ErrorParseoAddr)?;
+            index += largo;
+            if let Some(direccion) = direccion {
+                direcciones.push(direccion);
+            }
+        }
+        Ok(AddrV2Message { direcciones })
+    }
+}
+
+/// Envía el mensaje sendaddrv2, indicando que preferimos recibir direcciones en formato addrv2.
+/// Debe enviarse antes del verack.
+pub fn sendaddrv2(
+    socket: &mut ConexionPeer,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::SendAddrV2)?;
+    log(
+        Lvl::Info(Action::ADDRESSES),
+        "Enviado mensaje sendaddrv2.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Checksum de las direcciones onion v3: SHA3-256(".onion checksum" | pubkey | version)[..2]
+fn checksum_torv3(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([TORV3_VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+/// Codifica los bytes en base32 (RFC 4648) en minúsculas y sin padding.
+fn codificar_base32(bytes: &[u8]) -> String {
+    let mut resultado = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            resultado.push(ALFABETO_BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        resultado.push(ALFABETO_BASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    resultado
+}
+
+/// Decodifica un string en base32 (RFC 4648), en minúsculas y sin padding.
+fn decodificar_base32(texto: &str) -> Result<Vec<u8>, RustifyError> {
+    let mut resultado = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for caracter in texto.bytes() {
+        let valor = ALFABETO_BASE32
+            .iter()
+            .position(|c| *c == caracter.to_ascii_lowercase())
+            .ok_or(RustifyError::ErrorParseoAddr)?;
+        buffer = (buffer << 5) | valor as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            resultado.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(resultado)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direccion_onion_display_from_str() -> Result<(), RustifyError> {
+        // Dirección onion v3 del proyecto Tor.
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333";
+        let direccion: DireccionNodo = onion.parse()?;
+        assert!(matches!(direccion, DireccionNodo::TorV3(_, 8333)));
+        assert_eq!(direccion.to_string(), onion);
+        Ok(())
+    }
+
+    #[test]
+    fn test_direccion_onion_checksum_invalido() {
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wia.onion:8333";
+        assert!(onion.parse::<DireccionNodo>().is_err());
+    }
+
+    #[test]
+    fn test_addrv2_as_bytes_from_bytes() -> Result<(), RustifyError> {
+        let direcciones = vec![
+            DireccionRedV2 {
+                time: 1681160400,
+                services: 0x0409,
+                direccion: "10.0.0.1:18333".parse()?,
+            },
+            DireccionRedV2 {
+                time: 1681160400,
+                services: 0x01,
+                direccion: "[2001:db8::1]:18333".parse()?,
+            },
+            DireccionRedV2 {
+                time: 1681160400,
+                services: 0x01,
+                direccion: DireccionNodo::I2p([7; 32], 0),
+            },
+        ];
+        let addrv2 = AddrV2Message { direcciones };
+        assert_eq!(AddrV2Message::from_bytes(&addrv2.as_bytes())?, addrv2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_addrv2_ignora_redes_desconocidas() -> Result<(), RustifyError> {
+        // count 1, time, services 1, red 3 (TORV2 obsoleta), largo 10, dirección, puerto.
+        let mut bytes = vec![0x01, 0, 0, 0, 0, 0x01, 0x03, 0x0a];
+        bytes.extend_from_slice(&[0xab; 10]);
+        bytes.extend_from_slice(&8333u16.to_be_bytes());
+        let addrv2 = AddrV2Message::from_bytes(&bytes)?;
+        assert!(addrv2.direcciones.is_empty());
+        Ok(())
+    }
+
+    /// Test para chequear que los mensajes cortados en cualquier punto, incluso dentro de un compactsize,
+    /// devuelven error en lugar de entrar en pánico.
+    #[test]
+    fn test_addrv2_truncado() -> Result<(), RustifyError> {
+        let addrv2 = AddrV2Message {
+            direcciones: vec![DireccionRedV2 {
+                time: 1681160400,
+                services: 0x0409,
+                direccion: "10.0.0.1:18333".parse()?,
+            }],
+        };
+        let bytes = addrv2.as_bytes();
+        for largo in 0..bytes.len() {
+            assert_eq!(
+                AddrV2Message::from_bytes(&bytes[..largo]),
+                Err(RustifyError::ErrorParseoAddr)
+            );
+        }
+        let truncados: [&[u8]; 2] = [&[0xff], &[1, 0, 0, 0, 0, 0xff]];
+        for truncado in truncados {
+            assert_eq!(
+                AddrV2Message::from_bytes(truncado),
+                Err(RustifyError::ErrorParseoAddr)
+            );
+        }
+        Ok(())
+    }
+}
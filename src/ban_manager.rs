@@ -0,0 +1,313 @@
+use crate::{
+    addrv2::DireccionNodo,
+    config::Config,
+    errors::RustifyError,
+    logger::{log_re_err, log_with_parameters, Action, Lvl},
+};
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{mpsc::Sender, Arc, Mutex, OnceLock},
+};
+
+/// Puntaje de mal comportamiento a partir del cual se banea al nodo.
+pub const PUNTAJE_BAN: u32 = 100;
+
+static BAN_MANAGER: OnceLock<BanManager> = OnceLock::new();
+
+/// Lleva el puntaje de mal comportamiento de los nodos remotos y la lista de nodos baneados.
+/// Los nodos se identifican por su host (IP, .onion o .i2p), sin el puerto,
+/// para que un nodo baneado no pueda volver a conectarse desde otro puerto.
+/// La lista de baneados se persiste en disco en bans_path, con una línea "host hasta" por nodo.
+#[derive(Debug, Clone)]
+pub struct BanManager {
+    baneados: Arc<Mutex<HashMap<String, i64>>>,
+    puntajes: Arc<Mutex<HashMap<String, u32>>>,
+    path: String,
+    duracion_ban_secs: i64,
+    logger_sender: Sender<String>,
+}
+
+impl BanManager {
+    /// Constructor del BanManager. Carga los baneos vigentes guardados en disco.
+    pub fn build(
+        config: &Config,
+        logger_sender: &Sender<String>,
+    ) -> Result<BanManager, RustifyError> {
+        let ban_manager = BanManager {
+            baneados: Arc::new(Mutex::new(HashMap::new())),
+            puntajes: Arc::new(Mutex::new(HashMap::new())),
+            path: config.bans_path.clone(),
+            duracion_ban_secs: config.duracion_ban_secs,
+            logger_sender: logger_sender.clone(),
+        };
+        ban_manager.recargar()?;
+        Ok(ban_manager)
+    }
+
+    /// Indica si el host está baneado en este momento.
+    pub fn esta_baneado(&self, host: &str) -> Result<bool, RustifyError> {
+        let ahora = Utc::now().timestamp();
+        Ok(self
+            .baneados
+            .lock()?
+            .get(host)
+            .is_some_and(|hasta| *hasta > ahora))
+    }
+
+    /// Banea al host durante la cantidad de segundos indicada.
+    pub fn banear(&self, host: &str, duracion_secs: i64) -> Result<(), RustifyError> {
+        let hasta = Utc::now().timestamp() + duracion_secs;
+        self.baneados.lock()?.insert(host.to_string(), hasta);
+        self.puntajes.lock()?.remove(host);
+        log_with_parameters(
+            Lvl::Warning(Action::PEERS),
+            format!("Se baneó al nodo {} por {} segundos.", host, duracion_secs),
+            &self.logger_sender,
+        );
+        self.guardar_baneos()
+    }
+
+    /// Quita el baneo del host. Devuelve false si no estaba baneado.
+    pub fn desbanear(&self, host: &str) -> Result<bool, RustifyError> {
+        let estaba = self.baneados.lock()?.remove(host).is_some();
+        self.puntajes.lock()?.remove(host);
+        if estaba {
+            log_with_parameters(
+                Lvl::Info(Action::PEERS),
+                format!("Se quitó el baneo del nodo {}.", host),
+                &self.logger_sender,
+            );
+            self.guardar_baneos()?;
+        }
+        Ok(estaba)
+    }
+
+    /// Suma puntos de mal comportamiento al host. Al alcanzar PUNTAJE_BAN se lo banea
+    /// por la duración configurada. Devuelve true si el host quedó baneado.
+    pub fn penalizar(&self, host: &str, puntos: u32) -> Result<bool, RustifyError> {
+        let puntaje = {
+            let mut puntajes = self.puntajes.lock()?;
+            let puntaje = puntajes.entry(host.to_string()).or_insert(0);
+            *puntaje = puntaje.saturating_add(puntos);
+            *puntaje
+        };
+        log_with_parameters(
+            Lvl::Warning(Action::PEERS),
+            format!(
+                "El nodo {} suma {} puntos de mal comportamiento (total {}).",
+                host, puntos, puntaje
+            ),
+            &self.logger_sender,
+        );
+        if puntaje < PUNTAJE_BAN {
+            return Ok(false);
+        }
+        self.banear(host, self.duracion_ban_secs)?;
+        Ok(true)
+    }
+
+    /// Devuelve el puntaje de mal comportamiento acumulado por el host.
+    pub fn puntaje(&self, host: &str) -> Result<u32, RustifyError> {
+        Ok(self.puntajes.lock()?.get(host).copied().unwrap_or(0))
+    }
+
+    /// Devuelve los baneos vigentes, como pares (host, timestamp de fin del baneo).
+    pub fn baneados(&self) -> Result<Vec<(String, i64)>, RustifyError> {
+        let ahora = Utc::now().timestamp();
+        let mut baneados: Vec<(String, i64)> = self
+            .baneados
+            .lock()?
+            .iter()
+            .filter(|(_, hasta)| **hasta > ahora)
+            .map(|(host, hasta)| (host.clone(), *hasta))
+            .collect();
+        baneados.sort();
+        Ok(baneados)
+    }
+
+    /// Vuelve a leer la lista de baneados desde disco, para tomar los cambios hechos
+    /// manualmente (comandos ban y unban) mientras el nodo está corriendo.
+    pub fn recargar(&self) -> Result<(), RustifyError> {
+        if self.path.is_empty() || !Path::new(&self.path).exists() {
+            return Ok(());
+        }
+        let ahora = Utc::now().timestamp();
+        let mut baneados = HashMap::new();
+        for linea in fs::read_to_string(&self.path)?.lines() {
+            let partes: Vec<&str> = linea.split_whitespace().collect();
+            if partes.len() != 2 {
+                continue;
+            }
+            match partes[1].parse::<i64>() {
+                Ok(hasta) if hasta > ahora => {
+                    baneados.insert(partes[0].to_string(), hasta);
+                }
+                _ => {}
+            }
+        }
+        *self.baneados.lock()? = baneados;
+        Ok(())
+    }
+
+    fn guardar_baneos(&self) -> Result<(), RustifyError> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let contenido: String = self
+            .baneados()?
+            .iter()
+            .map(|(host, hasta)| format!("{} {}\n", host, hasta))
+            .collect();
+        fs::write(&self.path, contenido)?;
+        Ok(())
+    }
+}
+
+/// Inicializa el BanManager del nodo, para que pueda ser consultado al conectarse
+/// a nuevos nodos y al aceptar conexiones entrantes.
+pub fn iniciar_ban_manager(
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<BanManager, RustifyError> {
+    let ban_manager = BanManager::build(config, logger_sender)?;
+    Ok(BAN_MANAGER.get_or_init(|| ban_manager).clone())
+}
+
+/// Devuelve el BanManager del nodo, si fue inicializado.
+pub fn obtener_ban_manager() -> Option<&'static BanManager> {
+    BAN_MANAGER.get()
+}
+
+/// Indica si la dirección pertenece a un nodo baneado.
+pub fn direccion_baneada(addr: &DireccionNodo) -> bool {
+    match obtener_ban_manager() {
+        Some(ban_manager) => ban_manager.esta_baneado(&addr.host()).unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Suma al nodo los puntos de mal comportamiento que corresponden al error.
+/// Devuelve true si el nodo quedó baneado.
+pub fn penalizar_nodo(
+    addr: &DireccionNodo,
+    error: &RustifyError,
+    logger_sender: &Sender<String>,
+) -> bool {
+    let puntos = error.puntaje_mal_comportamiento();
+    match obtener_ban_manager() {
+        Some(ban_manager) if puntos > 0 => match ban_manager.penalizar(&addr.host(), puntos) {
+            Ok(baneado) => baneado,
+            Err(e) => {
+                log_re_err(Action::PEERS, e, logger_sender);
+                false
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Ejecuta un comando manual sobre la lista de baneados y devuelve el resultado a mostrar:
+/// - ban host [segundos]: banea al host (por defecto, durante duracion_ban_secs).
+/// - unban host: quita el baneo del host.
+/// - bans: lista los baneos vigentes.
+pub fn ejecutar_comando_ban(
+    comando: &[String],
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<String, RustifyError> {
+    let ban_manager = BanManager::build(config, logger_sender)?;
+    match comando {
+        [accion, host] if accion == "ban" => {
+            ban_manager.banear(host, config.duracion_ban_secs)?;
+            Ok(format!("{} baneado.", host))
+        }
+        [accion, host, segundos] if accion == "ban" => {
+            let segundos = segundos
+                .parse()
+                .map_err(|_| RustifyError::ComandoBanInvalido)?;
+            ban_manager.banear(host, segundos)?;
+            Ok(format!("{} baneado.", host))
+        }
+        [accion, host] if accion == "unban" => match ban_manager.desbanear(host)? {
+            true => Ok(format!("{} desbaneado.", host)),
+            false => Ok(format!("{} no estaba baneado.", host)),
+        },
+        [accion] if accion == "bans" => Ok(ban_manager
+            .baneados()?
+            .iter()
+            .map(|(host, hasta)| format!("{} {}", host, hasta))
+            .collect::<Vec<String>>()
+            .join("\n")),
+        _ => Err(RustifyError::ComandoBanInvalido),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban_manager_test(path: &str) -> BanManager {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        BanManager {
+            baneados: Arc::new(Mutex::new(HashMap::new())),
+            puntajes: Arc::new(Mutex::new(HashMap::new())),
+            path: path.to_string(),
+            duracion_ban_secs: 3600,
+            logger_sender,
+        }
+    }
+
+    #[test]
+    fn test_penalizar_hasta_banear() -> Result<(), RustifyError> {
+        let ban_manager = ban_manager_test("");
+        assert!(!ban_manager.penalizar("10.0.0.1", 60)?);
+        assert_eq!(ban_manager.puntaje("10.0.0.1")?, 60);
+        assert!(!ban_manager.esta_baneado("10.0.0.1")?);
+
+        assert!(ban_manager.penalizar("10.0.0.1", 40)?);
+        assert!(ban_manager.esta_baneado("10.0.0.1")?);
+        assert!(!ban_manager.esta_baneado("10.0.0.2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_banear_y_desbanear() -> Result<(), RustifyError> {
+        let ban_manager = ban_manager_test("");
+        ban_manager.banear("10.0.0.1", 3600)?;
+        assert!(ban_manager.esta_baneado("10.0.0.1")?);
+        assert!(ban_manager.desbanear("10.0.0.1")?);
+        assert!(!ban_manager.esta_baneado("10.0.0.1")?);
+        assert!(!ban_manager.desbanear("10.0.0.1")?);
+
+        // Un baneo ya vencido no cuenta.
+        ban_manager.banear("10.0.0.2", -1)?;
+        assert!(!ban_manager.esta_baneado("10.0.0.2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_persistencia_baneos() -> Result<(), RustifyError> {
+        let path = std::env::temp_dir().join("rustify_test_bans.txt");
+        let path = path.to_str().unwrap_or_default();
+        let ban_manager = ban_manager_test(path);
+        ban_manager.banear("10.0.0.1", 3600)?;
+        ban_manager.banear(
+            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion",
+            3600,
+        )?;
+
+        let ban_manager_cargado = ban_manager_test(path);
+        ban_manager_cargado.recargar()?;
+        assert_eq!(ban_manager_cargado.baneados()?, ban_manager.baneados()?);
+
+        ban_manager.desbanear("10.0.0.1")?;
+        ban_manager_cargado.recargar()?;
+        assert!(!ban_manager_cargado.esta_baneado("10.0.0.1")?);
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
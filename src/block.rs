@@ -1,37 +1,40 @@
 use crate::block_header::BlockHeader;
+use crate::block_validation::proof_of_inclusion;
+use crate::bloom::filtro_spv;
+use crate::compactsize::CompactSize;
 use crate::config::Config;
 use crate::errors::RustifyError;
+use crate::event_loop::ConexionPeer;
 use crate::inv::Inv;
-use crate::logger::{log, log_with_parameters, Action, Lvl};
-use crate::message_handler::handle_specific_message;
-use crate::message_header::MessageHeader;
-use crate::node::write_to_node;
+use crate::logger::{log, Action, Lvl};
+use crate::merkle_block::MSG_FILTERED_BLOCK;
+use crate::message_handler::esperar_respuesta;
+use crate::network_message::{escribir_mensaje, NetworkMessage};
 use crate::serialized_block::SerializedBlock;
+use crate::txn::Txn;
+use crate::utxo::{borrar_undo, es_archivo_bloque};
+use bitcoin_hashes::{sha256d, Hash};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 
 const MSG_BLOCK: u32 = 2;
 
-/// Revisa el vector de headers (que debe ser el que cumple la condición
-/// temporal de comienzo del tp) y realiza:
-/// 1) Envía el mensaje getdata con un pedido de bloque por archivo
-/// 2) Se queda esperando a recibir el bloque y lo guarda en un archivo especifico de bloque
-pub fn block_download(
-    socket: &mut TcpStream,
-    header: BlockHeader,
-    block_path: String,
-    cant_block_for_inv: u32,
-    sender: &Sender<String>,
+/// Pide con un único mensaje getdata los bloques de todos los headers indicados.
+/// Los bloques se reciben luego con recibir_bloque_pedido, en el orden en que el nodo los envíe.
+/// En modo SPV se piden bloques filtrados (merkleblock, BIP37).
+pub fn pedir_bloques(
+    socket: &mut ConexionPeer,
+    headers: &[BlockHeader],
 ) -> Result<(), RustifyError> {
     getdata(
         socket,
-        cant_block_for_inv,
-        vec![BlockHeader::as_bytes(&header).to_vec()],
-    )?;
-    receive_block_data(socket, block_path, sender)?;
-    Ok(())
+        headers
+            .iter()
+            .map(|header| header.as_bytes().to_vec())
+            .collect(),
+    )
 }
 
 /// Guarda un archivo por bloque en el directorio blocks.
@@ -54,6 +57,42 @@ pub fn guardar_bloque_memoria(
     Ok(())
 }
 
+/// Borra del directorio blocks el archivo del bloque con el header indicado, por ejemplo
+/// porque una reorganización lo desconectó de la cadena activa.
+/// También se borran sus datos de undo.
+/// Devuelve el bloque borrado, o None si no estaba descargado (quedaba antes de la fecha de corte).
+pub fn borrar_bloque_memoria(
+    header: &BlockHeader,
+    blocks_path: &str,
+) -> Result<Option<SerializedBlock>, RustifyError> {
+    let bloque = leer_bloque_guardado(header, blocks_path)?;
+    if bloque.is_some() {
+        fs::remove_file(ruta_bloque(header, blocks_path))?;
+    }
+    borrar_undo(header, blocks_path)?;
+    Ok(bloque)
+}
+
+/// Lee del directorio blocks el archivo del bloque con el header indicado, sin borrarlo.
+/// Devuelve None si no estaba descargado.
+pub fn leer_bloque_guardado(
+    header: &BlockHeader,
+    blocks_path: &str,
+) -> Result<Option<SerializedBlock>, RustifyError> {
+    match fs::read(ruta_bloque(header, blocks_path)) {
+        Ok(bytes_bloque) => Ok(Some(SerializedBlock::from_bytes(&bytes_bloque)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn ruta_bloque(header: &BlockHeader, blocks_path: &str) -> String {
+    format!(
+        "{}/{}.txt",
+        blocks_path,
+        SerializedBlock::obtain_blockhash(header.as_bytes())
+    )
+}
+
 /// Lee todos los archivos de bloques existentes en la carpeta blocks
 /// Si no encuentra la carpeta devuelve error
 /// Nota: Esta funcion toma como precondicion que todos los bloques
@@ -64,7 +103,7 @@ pub fn leer_bloque_memoria(config: &Config) -> Result<Vec<SerializedBlock>, Rust
     let mut vector_bloques: Vec<SerializedBlock> = vec![];
     let mut block: SerializedBlock;
 
-    for entry in fs::read_dir(&config.blocks_path)? {
+    for entry in fs::read_dir(&config.blocks_path)?.filter(es_archivo_bloque) {
         let entry = entry?;
         let mut archivo_bloque = File::options()
             .read(true)
@@ -81,39 +120,89 @@ pub fn leer_bloque_memoria(config: &Config) -> Result<Vec<SerializedBlock>, Rust
     Ok(vector_bloques)
 }
 
-/// Realiza una espera hasta obtener el mensaje block como respuesta al getdata
-/// Cuando ocurre esto, llama a la funcion de guardado de bloque
-fn receive_block_data(
-    socket: &mut TcpStream,
-    block_path: String,
+/// Realiza una espera hasta obtener un mensaje block como respuesta al getdata, como máximo durante el timeout.
+/// Los bloques pueden llegar en cualquier orden: se acepta cualquiera de los pedidos, siempre que su merkle root
+/// coincida con la del header. Cuando ocurre esto, llama a la funcion de guardado de bloque y devuelve el bloque.
+/// Si el nodo no envía el bloque a tiempo, no lo tiene o envía otro bloque, devuelve el error para que se pida a otro nodo.
+pub fn recibir_bloque_pedido(
+    socket: &mut ConexionPeer,
+    pedidos: &[BlockHeader],
+    block_path: &String,
+    timeout: Duration,
     logger_sender: &Sender<String>,
-) -> Result<(), RustifyError> {
-    match &handle_specific_message(socket, "block\0\0\0\0\0\0\0".to_string(), logger_sender) {
-        Ok(handled_bytes_headers_respuesta) => {
-            guardar_bloque_memoria(handled_bytes_headers_respuesta.to_vec(), &block_path)?;
-            log(
-                Lvl::Info(Action::INB),
-                "Se guardó bloque en disco",
-                logger_sender,
-            );
-        }
-        Err(e) => {
-            if e == &RustifyError::ElNodoNoEncuentraBloquePedido {
-                log(
-                    Lvl::Info(Action::INB),
-                    "Se prosigue con la descarga de otro bloque",
-                    logger_sender,
-                );
-            } else {
-                log_with_parameters(
-                    Lvl::Warning(Action::INB),
-                    format!("Se obtiene el error {:?} esperando a los bloques", e),
-                    logger_sender,
-                );
-            }
+) -> Result<SerializedBlock, RustifyError> {
+    if filtro_spv().is_some() {
+        return recibir_bloque_filtrado(socket, pedidos, block_path, timeout, logger_sender);
+    }
+    let bytes_bloque = match esperar_respuesta(socket, "block", timeout, logger_sender)? {
+        NetworkMessage::Block(bytes_bloque) => bytes_bloque,
+        _ => return Err(RustifyError::MensajeInesperado),
+    };
+    if !pedidos
+        .iter()
+        .any(|header| bytes_bloque.len() >= 80 && bytes_bloque[0..80] == header.as_bytes())
+    {
+        return Err(RustifyError::BloqueNoSolicitado);
+    }
+    let bloque = SerializedBlock::from_bytes(&bytes_bloque)?;
+    if !proof_of_inclusion(&bloque) {
+        return Err(RustifyError::BloqueInvalido);
+    }
+    guardar_bloque_memoria(bytes_bloque, block_path)?;
+    log(
+        Lvl::Info(Action::INB),
+        "Se guardó bloque en disco",
+        logger_sender,
+    );
+    Ok(bloque)
+}
+
+/// Recibe un bloque filtrado pedido en modo SPV: el mensaje merkleblock, seguido de un mensaje tx
+/// por cada transacción que coincide con el filtro, en el orden del bloque.
+/// Se guarda en disco un bloque con el header y solamente esas transacciones, que no cumple la proof of inclusion:
+/// su validez queda dada por el partial merkle tree, que se verifica contra la merkle root del header.
+fn recibir_bloque_filtrado(
+    socket: &mut ConexionPeer,
+    pedidos: &[BlockHeader],
+    block_path: &String,
+    timeout: Duration,
+    logger_sender: &Sender<String>,
+) -> Result<SerializedBlock, RustifyError> {
+    let merkleblock = match esperar_respuesta(socket, "merkleblock", timeout, logger_sender)? {
+        NetworkMessage::MerkleBlock(merkleblock) => merkleblock,
+        _ => return Err(RustifyError::MensajeInesperado),
+    };
+    if !pedidos
+        .iter()
+        .any(|header| header.as_bytes() == merkleblock.header.as_bytes())
+    {
+        return Err(RustifyError::BloqueNoSolicitado);
+    }
+    let txids = merkleblock.txids_incluidos()?;
+    let mut txns = vec![];
+    for txid in &txids {
+        let bytes_txn = match esperar_respuesta(socket, "tx", timeout, logger_sender)? {
+            NetworkMessage::Tx(bytes_txn) => bytes_txn,
+            _ => return Err(RustifyError::MensajeInesperado),
+        };
+        let (txn, _) = Txn::from_bytes(bytes_txn, 0)?;
+        if sha256d::Hash::hash(&txn.as_bytes()).to_byte_array() != *txid {
+            return Err(RustifyError::DatosNoSolicitados);
         }
+        txns.push(txn);
+    }
+    let bloque = SerializedBlock {
+        block_header: merkleblock.header,
+        txn_count: CompactSize::new(txns.len() as u64),
+        txns,
     };
-    Ok(())
+    guardar_bloque_memoria(bloque.as_bytes(), block_path)?;
+    log(
+        Lvl::Info(Action::INB),
+        "Se guardó bloque filtrado en disco",
+        logger_sender,
+    );
+    Ok(bloque)
 }
 
 /// Determina la cantidad de bloques a leer desde el header más reciente
@@ -137,30 +226,14 @@ pub fn obtener_headers_validos_fecha(
 }
 
 /// Envía el mensaje getdata, en base a uno o varios headers pasados por parametro
-fn getdata(
-    socket: &mut TcpStream,
-    cant_elem_en_inv: u32,
-    headers: Vec<Vec<u8>>,
-) -> Result<(), RustifyError> {
-    let cantidad_headers_fecha = cant_elem_en_inv as usize;
-    let cantidad_total_headers = headers.len();
-    let getdata_message = Inv::new(
-        cant_elem_en_inv,
-        MSG_BLOCK,
-        headers[cantidad_total_headers - cantidad_headers_fecha..cantidad_total_headers].to_vec(),
-    );
-
-    let getdata_message_bytes = getdata_message.as_bytes();
-
-    let getdata_message_header = MessageHeader::new("getdata".to_string(), &getdata_message_bytes);
-    let getdata_message_header_bytes = getdata_message_header.as_bytes();
+fn getdata(socket: &mut ConexionPeer, headers: Vec<Vec<u8>>) -> Result<(), RustifyError> {
+    let tipo = match filtro_spv() {
+        Some(_) => MSG_FILTERED_BLOCK,
+        None => MSG_BLOCK,
+    };
+    let getdata_message = Inv::new(headers.len() as u32, tipo, headers);
 
-    write_to_node(
-        socket,
-        &getdata_message_header_bytes,
-        &getdata_message_bytes,
-    )?;
-    Ok(())
+    escribir_mensaje(socket, &NetworkMessage::GetData(getdata_message))
 }
 
 #[cfg(test)]
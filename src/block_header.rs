@@ -1,35 +1,23 @@
+use crate::block_validation::validar_headers;
 use crate::config::Config;
 use crate::errors::RustifyError;
+use crate::event_loop::ConexionPeer;
 use crate::getheaders::{getheaders, getheaders_loop};
 use crate::gui_events::GuiEvent;
 use crate::logger::{log, log_with_parameters, Action, Lvl};
+use crate::red::parametros_red;
 use bitcoin_hashes::{sha256d, Hash};
 use std::fs::{self, File};
 use std::io::{prelude::*, BufReader};
-use std::net::TcpStream;
 use std::sync::mpsc::Sender;
 
-const TESTNET_GENESIS_HASH: [u8; 32] = [
-    0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3, 0xae,
-    0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00,
-];
 pub const NULL_HASH: [u8; 32] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-const TESNET_GENESIS_HEADER: [u8; 80] = [
-    0x01, 0x00, 0x00, 0x00, // version
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // previous_block_header_hash
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
-    // merkle_root_hash
-    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
-    0xda, 0xe5, 0x49, 0x4d, // time
-    0xff, 0xff, 0x00, 0x1d, // n_bits
-    0x1a, 0xa4, 0xae, 0x18, // nonce
-];
+/// Tamaño de un header serializado.
+pub const BLOCK_HEADER_SIZE: usize = 80;
 
 #[derive(Debug, Clone)]
 pub struct BlockHeader {
@@ -44,6 +32,9 @@ pub struct BlockHeader {
 impl BlockHeader {
     /// Realiza el parseo de una cadena de 80 bytes al tipo de dato BlockHeader
     pub fn from_bytes(bytes: &[u8]) -> Result<BlockHeader, RustifyError> {
+        if bytes.len() < BLOCK_HEADER_SIZE {
+            return Err(RustifyError::MensajeMalformado);
+        }
         let version = i32::from_le_bytes(bytes[0..4].try_into()?);
 
         let mut previous_block_header_hash = [0u8; 32];
@@ -97,7 +88,7 @@ impl BlockHeader {
 /// Carga los headers actuales desde el archivo a memoria, y descarga los nuevos headers.
 /// Devuelve toda la blockchain de headers completa, almacenada en memoria (vector de headers).
 pub fn actualizar_header_blockchain(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
     sender_gui: &gtk::glib::Sender<GuiEvent>,
@@ -122,12 +113,12 @@ pub fn actualizar_header_blockchain(
         );
         pagina_headers = getheaders(
             socket,
-            vec![TESTNET_GENESIS_HASH.to_vec()],
+            vec![parametros_red().genesis_hash().to_vec()],
             NULL_HASH.to_vec(),
             config,
             logger_sender,
         )?;
-        headers = vec![BlockHeader::from_bytes(&TESNET_GENESIS_HEADER)?];
+        headers = vec![BlockHeader::from_bytes(&parametros_red().genesis_header)?];
     } else {
         // Si el archivo tiene headers, tomo el ultimo.
         log(
@@ -180,12 +171,18 @@ pub fn actualizar_header_blockchain(
     Ok((headers, indice_ultimo_header))
 }
 
+/// Lee la cadena de headers guardada en el archivo indicado, empezando por el génesis.
+pub fn leer_headers(headers_path: &str) -> Result<Vec<BlockHeader>, RustifyError> {
+    Ok(cargar_headers_memoria(&File::open(headers_path)?)?.0)
+}
+
 /// Carga los headers guardados en disco (archivo) a memoria (Vec<Vec<u8>>).
 /// Traduce cada linea del archivo de hexa a vector en bytes decimales.
 /// Devuelve un vector con todos los headers que estaban guardados en el archivo.
 fn cargar_headers_memoria(archivo: &File) -> Result<(Vec<BlockHeader>, Vec<u8>), RustifyError> {
     let buf_reader = BufReader::new(archivo);
-    let mut headers: Vec<BlockHeader> = vec![BlockHeader::from_bytes(&TESNET_GENESIS_HEADER)?];
+    let mut headers: Vec<BlockHeader> =
+        vec![BlockHeader::from_bytes(&parametros_red().genesis_header)?];
     let mut ultima_linea = String::new();
     for linea in buf_reader.lines() {
         let linea_clonada = linea?.clone();
@@ -206,18 +203,49 @@ fn cargar_headers_memoria(archivo: &File) -> Result<(Vec<BlockHeader>, Vec<u8>),
     Ok((headers, ultimo_header))
 }
 
+/// Reescribe el archivo de headers con la cadena indicada, por ejemplo luego de descartar
+/// los headers desconectados en una reorganización. El génesis no se guarda en el archivo.
+pub fn reescribir_headers(headers_path: &str, headers: &[BlockHeader]) -> Result<(), RustifyError> {
+    let contenido: String = headers
+        .iter()
+        .skip(1)
+        .map(|header| {
+            let mut linea: String = header
+                .as_bytes()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            linea.push('\n');
+            linea
+        })
+        .collect();
+    fs::write(headers_path, contenido)?;
+    Ok(())
+}
+
 /// Guarda la pagina de headers descargada en disco y en memoria.
 /// Recibe el archivo donde se guardan los headers, y la pagina de headers descargada.
+/// Antes de guardar se valida la página completa contra la cadena (encadenamiento, dificultad y timestamps),
+/// así una página inválida no se guarda parcialmente.
+/// Devuelve error si algún header es inválido o no coincide con los checkpoints de la red.
 pub fn guardar_headers(
     archivo: &mut File,
     headers: &mut Vec<BlockHeader>,
-    pagina_headers: &Vec<Vec<u8>>,
+    pagina_headers: &[Vec<u8>],
 ) -> Result<(), RustifyError> {
-    for header in pagina_headers {
+    let nuevos = pagina_headers
+        .iter()
+        .map(|header| BlockHeader::from_bytes(header))
+        .collect::<Result<Vec<BlockHeader>, RustifyError>>()?;
+    validar_headers(headers, &nuevos)?;
+    for (i, header) in pagina_headers.iter().enumerate() {
+        parametros_red()
+            .verificar_checkpoint(headers.len() + i, &sha256d::Hash::hash(header)[..])?;
+    }
+    for (header, header_struct) in pagina_headers.iter().zip(nuevos) {
         // Recorro cada header (vector) y lo transformo a String en hexa.
         let header_bytes: String = header.iter().map(|b| format!("{:02x}", b) + "").collect();
         writeln!(archivo, "{}", header_bytes)?;
-        let header_struct = BlockHeader::from_bytes(header)?;
         headers.push(header_struct);
     }
     Ok(())
@@ -0,0 +1,351 @@
+use crate::block_header::BlockHeader;
+use crate::block_validation::trabajo_bloque;
+use crate::errors::RustifyError;
+use bitcoin_hashes::{sha256d, Hash};
+use std::collections::HashMap;
+
+/// Máxima profundidad (en bloques desde la punta) a la que se busca el punto de bifurcación de una rama.
+/// Las ramas que se separan antes se descartan.
+const PROFUNDIDAD_MAXIMA_BIFURCACION: usize = 2016;
+/// Cantidad de hashes consecutivos desde la punta con los que empieza el localizador, antes de espaciarlos.
+const HASHES_CONSECUTIVOS_LOCALIZADOR: usize = 10;
+
+/// Header conocido que no pertenece a la cadena activa, con su altura y el trabajo acumulado
+/// de la cadena que termina en él.
+#[derive(Debug, Clone)]
+pub struct EntradaIndice {
+    pub header: BlockHeader,
+    pub altura: usize,
+    pub trabajo_acumulado: u128,
+}
+
+/// Cambio de cadena activa que resulta de que una rama supere en trabajo acumulado a la punta actual.
+/// Los headers desde la altura de bifurcación se reemplazan: se desconectan los de la cadena activa
+/// y se conectan los de la rama (ambos en orden ascendente).
+#[derive(Debug, Clone)]
+pub struct Reorganizacion {
+    pub altura_bifurcacion: usize,
+    pub desconectados: Vec<BlockHeader>,
+    pub conectados: Vec<BlockHeader>,
+}
+
+/// Índice de bloques: árbol de los headers conocidos, que elige como cadena activa la de más trabajo acumulado.
+/// La cadena activa es el vector de headers que comparte el nodo, y acá se lleva solamente su trabajo acumulado;
+/// los headers de las ramas se guardan por hash, y cada rama se apoya en algún header de la cadena activa.
+#[derive(Debug, Clone)]
+pub struct IndiceBloques {
+    trabajo_punta: u128,
+    altura_punta: usize,
+    ramas: HashMap<[u8; 32], EntradaIndice>,
+}
+
+impl IndiceBloques {
+    /// Crea el índice a partir de la cadena activa, sin ramas.
+    pub fn new(cadena: &[BlockHeader]) -> IndiceBloques {
+        IndiceBloques {
+            trabajo_punta: cadena.iter().fold(0, |trabajo, header| {
+                trabajo.saturating_add(trabajo_bloque(header.n_bits))
+            }),
+            altura_punta: cadena.len().saturating_sub(1),
+            ramas: HashMap::new(),
+        }
+    }
+
+    /// Trabajo acumulado de la cadena activa.
+    pub fn trabajo_punta(&self) -> u128 {
+        self.trabajo_punta
+    }
+
+    /// Devuelve la entrada de un header de alguna rama, si se conoce.
+    pub fn entrada(&self, hash: &[u8; 32]) -> Option<&EntradaIndice> {
+        self.ramas.get(hash)
+    }
+
+    /// Registra un header que extiende la punta de la cadena activa, y descarta las ramas que quedan
+    /// demasiado lejos de la nueva punta.
+    pub fn extender(&mut self, header: &BlockHeader) {
+        self.trabajo_punta = self
+            .trabajo_punta
+            .saturating_add(trabajo_bloque(header.n_bits));
+        self.altura_punta += 1;
+        self.podar();
+    }
+
+    /// Registra que se quitaron de la punta de la cadena activa los headers indicados
+    /// (por ejemplo, porque alguno de sus bloques resultó inválido). No se guardan como rama.
+    pub fn retroceder(&mut self, quitados: &[BlockHeader]) {
+        for header in quitados {
+            self.trabajo_punta = self
+                .trabajo_punta
+                .saturating_sub(trabajo_bloque(header.n_bits));
+        }
+        self.altura_punta = self.altura_punta.saturating_sub(quitados.len());
+    }
+
+    /// Agrega a las ramas un header que no extiende la punta de la cadena activa.
+    /// El header previo tiene que estar en otra rama o en la cadena activa; si no, devuelve NotFound.
+    pub fn agregar_rama(
+        &mut self,
+        cadena: &[BlockHeader],
+        header: &BlockHeader,
+    ) -> Result<&EntradaIndice, RustifyError> {
+        let hash = hash_header(header);
+        let (altura_previo, trabajo_previo) = self
+            .ubicar(cadena, &header.previous_block_header_hash)
+            .ok_or(RustifyError::NotFound)?;
+        Ok(self.ramas.entry(hash).or_insert(EntradaIndice {
+            header: header.clone(),
+            altura: altura_previo + 1,
+            trabajo_acumulado: trabajo_previo.saturating_add(trabajo_bloque(header.n_bits)),
+        }))
+    }
+
+    /// Devuelve la rama que termina en el hash indicado: la altura del primer header que no está en la
+    /// cadena activa y los headers desde ahí, en orden ascendente. Si el hash es de la cadena activa,
+    /// la rama queda vacía. Devuelve None si el hash no se conoce.
+    pub fn rama(
+        &self,
+        cadena: &[BlockHeader],
+        hash: &[u8; 32],
+    ) -> Option<(usize, Vec<BlockHeader>)> {
+        let mut headers = vec![];
+        let mut actual = *hash;
+        while let Some(entrada) = self.ramas.get(&actual) {
+            headers.push(entrada.header.clone());
+            actual = entrada.header.previous_block_header_hash;
+        }
+        let altura_bifurcacion = altura_en_cadena(cadena, &actual)? + 1;
+        headers.reverse();
+        Some((altura_bifurcacion, headers))
+    }
+
+    /// Si la rama que termina en el hash indicado tiene más trabajo acumulado que la cadena activa,
+    /// la convierte en la cadena activa: los headers de la rama salen del índice y los desconectados
+    /// de la cadena activa pasan a ser una rama más. Devuelve los cambios que hay que aplicar a la cadena.
+    pub fn reorganizar(
+        &mut self,
+        cadena: &[BlockHeader],
+        hash: &[u8; 32],
+    ) -> Option<Reorganizacion> {
+        let trabajo_rama = self.ramas.get(hash)?.trabajo_acumulado;
+        if trabajo_rama <= self.trabajo_punta {
+            return None;
+        }
+        let (altura_bifurcacion, conectados) = self.rama(cadena, hash)?;
+        let desconectados = cadena[altura_bifurcacion..].to_vec();
+
+        let mut trabajo = self.trabajo_hasta(cadena, altura_bifurcacion - 1);
+        for (i, header) in desconectados.iter().enumerate() {
+            trabajo = trabajo.saturating_add(trabajo_bloque(header.n_bits));
+            self.ramas.insert(
+                hash_header(header),
+                EntradaIndice {
+                    header: header.clone(),
+                    altura: altura_bifurcacion + i,
+                    trabajo_acumulado: trabajo,
+                },
+            );
+        }
+        for header in &conectados {
+            self.ramas.remove(&hash_header(header));
+        }
+        self.trabajo_punta = trabajo_rama;
+        self.altura_punta = altura_bifurcacion + conectados.len() - 1;
+        self.podar();
+        Some(Reorganizacion {
+            altura_bifurcacion,
+            desconectados,
+            conectados,
+        })
+    }
+
+    /// Descarta las entradas que están más de PROFUNDIDAD_MAXIMA_BIFURCACION bloques por debajo de la punta.
+    fn podar(&mut self) {
+        let altura_minima = self
+            .altura_punta
+            .saturating_sub(PROFUNDIDAD_MAXIMA_BIFURCACION);
+        self.ramas
+            .retain(|_, entrada| entrada.altura >= altura_minima);
+    }
+
+    /// Altura y trabajo acumulado del header indicado, buscándolo en las ramas y cerca de la punta de la cadena activa.
+    fn ubicar(&self, cadena: &[BlockHeader], hash: &[u8; 32]) -> Option<(usize, u128)> {
+        if let Some(entrada) = self.ramas.get(hash) {
+            return Some((entrada.altura, entrada.trabajo_acumulado));
+        }
+        let altura = altura_en_cadena(cadena, hash)?;
+        Some((altura, self.trabajo_hasta(cadena, altura)))
+    }
+
+    /// Trabajo acumulado de la cadena activa hasta la altura indicada (inclusive).
+    /// Se obtiene restándole al de la punta el trabajo de los headers posteriores.
+    fn trabajo_hasta(&self, cadena: &[BlockHeader], altura: usize) -> u128 {
+        cadena[altura + 1..]
+            .iter()
+            .fold(self.trabajo_punta, |trabajo, header| {
+                trabajo.saturating_sub(trabajo_bloque(header.n_bits))
+            })
+    }
+}
+
+/// Busca la altura de un hash entre los últimos headers de la cadena activa.
+fn altura_en_cadena(cadena: &[BlockHeader], hash: &[u8; 32]) -> Option<usize> {
+    let desde = cadena.len().saturating_sub(PROFUNDIDAD_MAXIMA_BIFURCACION);
+    (desde..cadena.len())
+        .rev()
+        .find(|altura| hash_header(&cadena[*altura]) == *hash)
+}
+
+/// Arma el localizador de bloques para un getheaders: los hashes de la punta hacia atrás, primero consecutivos
+/// y luego cada vez más espaciados, terminando en el génesis. Así el nodo remoto encuentra el último
+/// header en común aunque la punta local sea de una rama que él no tiene.
+pub fn localizador(cadena: &[BlockHeader]) -> Vec<Vec<u8>> {
+    let mut hashes = vec![];
+    if cadena.is_empty() {
+        return hashes;
+    }
+    let mut altura = cadena.len() - 1;
+    let mut paso = 1;
+    loop {
+        hashes.push(hash_header(&cadena[altura]).to_vec());
+        if altura == 0 {
+            break;
+        }
+        if hashes.len() >= HASHES_CONSECUTIVOS_LOCALIZADOR {
+            paso *= 2;
+        }
+        altura = altura.saturating_sub(paso);
+    }
+    hashes
+}
+
+fn hash_header(header: &BlockHeader) -> [u8; 32] {
+    sha256d::Hash::hash(&header.as_bytes()).to_byte_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::red::parametros_red;
+
+    /// Cadena de prueba a partir del génesis de la red, con la dificultad indicada para los headers nuevos.
+    /// El nonce distingue a las ramas que salen del mismo header.
+    fn extender_cadena(
+        cadena: &[BlockHeader],
+        cantidad: usize,
+        n_bits: u32,
+        nonce: u32,
+    ) -> Vec<BlockHeader> {
+        let mut headers = cadena.to_vec();
+        for _ in 0..cantidad {
+            let previo = &headers[headers.len() - 1];
+            let mut header = previo.clone();
+            header.previous_block_header_hash = hash_header(previo);
+            header.time = previo.time + 600;
+            header.n_bits = n_bits;
+            header.nonce = nonce;
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn genesis() -> Result<Vec<BlockHeader>, RustifyError> {
+        Ok(vec![BlockHeader::from_bytes(
+            &parametros_red().genesis_header,
+        )?])
+    }
+
+    /// Test para chequear que una rama con menos trabajo se guarda sin reorganizar,
+    /// y que al superar el trabajo de la cadena activa se reorganiza desde el punto de bifurcación.
+    #[test]
+    fn test_reorganizar_con_mas_trabajo() -> Result<(), RustifyError> {
+        let cadena = extender_cadena(&genesis()?, 5, 0x1d00ffff, 0);
+        let mut indice = IndiceBloques::new(&cadena);
+
+        // La rama sale del header 3 y tiene la misma dificultad: con 2 headers empata, no reorganiza.
+        let rama = extender_cadena(&cadena[..4], 3, 0x1d00ffff, 1);
+        for header in &rama[4..6] {
+            indice.agregar_rama(&cadena, header)?;
+        }
+        assert!(indice
+            .reorganizar(&cadena, &hash_header(&rama[5]))
+            .is_none());
+
+        let entrada = indice.agregar_rama(&cadena, &rama[6])?.clone();
+        assert_eq!(entrada.altura, 6);
+        let reorganizacion = indice
+            .reorganizar(&cadena, &hash_header(&rama[6]))
+            .ok_or(RustifyError::NotFound)?;
+        assert_eq!(reorganizacion.altura_bifurcacion, 4);
+        assert_eq!(reorganizacion.desconectados.len(), 2);
+        assert_eq!(reorganizacion.conectados.len(), 3);
+        assert_eq!(indice.trabajo_punta(), entrada.trabajo_acumulado);
+
+        // Los desconectados quedan como rama, y los conectados ya no.
+        assert!(indice.entrada(&hash_header(&cadena[5])).is_some());
+        assert!(indice.entrada(&hash_header(&rama[6])).is_none());
+        Ok(())
+    }
+
+    /// Test para chequear que una rama más corta pero de mayor dificultad gana por trabajo acumulado.
+    #[test]
+    fn test_gana_la_rama_con_mas_trabajo_y_no_la_mas_larga() -> Result<(), RustifyError> {
+        let cadena = extender_cadena(&genesis()?, 4, 0x1d00ffff, 0);
+        let mut indice = IndiceBloques::new(&cadena);
+        let rama = extender_cadena(&cadena[..2], 1, 0x1c00ffff, 1);
+        indice.agregar_rama(&cadena, &rama[2])?;
+
+        let reorganizacion = indice
+            .reorganizar(&cadena, &hash_header(&rama[2]))
+            .ok_or(RustifyError::NotFound)?;
+        assert_eq!(reorganizacion.altura_bifurcacion, 2);
+        assert_eq!(reorganizacion.desconectados.len(), 3);
+        Ok(())
+    }
+
+    /// Test para chequear que las ramas que quedan a más de PROFUNDIDAD_MAXIMA_BIFURCACION bloques
+    /// de la punta se descartan al extender la cadena activa.
+    #[test]
+    fn test_descarta_ramas_profundas() -> Result<(), RustifyError> {
+        let cadena = extender_cadena(&genesis()?, 2, 0x1d00ffff, 0);
+        let mut indice = IndiceBloques::new(&cadena);
+        let rama = extender_cadena(&cadena[..2], 1, 0x1d00ffff, 1);
+        indice.agregar_rama(&cadena, &rama[2])?;
+
+        let cadena = extender_cadena(&cadena, PROFUNDIDAD_MAXIMA_BIFURCACION + 1, 0x1d00ffff, 0);
+        for header in &cadena[3..cadena.len() - 1] {
+            indice.extender(header);
+        }
+        assert!(indice.entrada(&hash_header(&rama[2])).is_some());
+        indice.extender(&cadena[cadena.len() - 1]);
+        assert!(indice.entrada(&hash_header(&rama[2])).is_none());
+        Ok(())
+    }
+
+    /// Test para chequear que no se agregan headers cuyo previo no se conoce.
+    #[test]
+    fn test_agregar_rama_sin_previo() -> Result<(), RustifyError> {
+        let cadena = extender_cadena(&genesis()?, 2, 0x1d00ffff, 0);
+        let mut indice = IndiceBloques::new(&cadena);
+        let mut huerfano = cadena[2].clone();
+        huerfano.previous_block_header_hash = [1; 32];
+        assert!(matches!(
+            indice.agregar_rama(&cadena, &huerfano),
+            Err(RustifyError::NotFound)
+        ));
+        Ok(())
+    }
+
+    /// Test para chequear que el localizador empieza en la punta, se espacia y termina en el génesis.
+    #[test]
+    fn test_localizador() -> Result<(), RustifyError> {
+        let cadena = extender_cadena(&genesis()?, 30, 0x1d00ffff, 0);
+        let hashes = localizador(&cadena);
+        assert_eq!(hashes[0], hash_header(&cadena[30]).to_vec());
+        assert_eq!(hashes[9], hash_header(&cadena[21]).to_vec());
+        assert_eq!(hashes[10], hash_header(&cadena[19]).to_vec());
+        assert_eq!(hashes[hashes.len() - 1], hash_header(&cadena[0]).to_vec());
+        assert!(hashes.len() < cadena.len());
+        Ok(())
+    }
+}
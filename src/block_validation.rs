@@ -1,25 +1,32 @@
 use crate::block_header::BlockHeader;
+use crate::errors::RustifyError;
+use crate::red::parametros_red;
 use crate::serialized_block::SerializedBlock;
 use bitcoin_hashes::{sha256d, Hash};
+use chrono::Utc;
 use std::cmp::Ordering;
 
 const LARGO_TARGET: usize = 32;
-const BYTES_IN_SIGNIFICAND: u8 = 3;
+/// Cada cuántos bloques se reajusta la dificultad.
+const INTERVALO_REAJUSTE: usize = 2016;
+/// Tiempo que deberían tardar los bloques de un intervalo de reajuste: dos semanas, en segundos.
+const TIEMPO_OBJETIVO_INTERVALO: u32 = 14 * 24 * 60 * 60;
+/// Tiempo esperado entre dos bloques, en segundos.
+const ESPACIADO_OBJETIVO: u32 = 10 * 60;
+/// Cantidad de bloques anteriores sobre los que se calcula el median time past.
+const CANT_BLOQUES_MEDIANA: usize = 11;
+/// Máximo que puede adelantarse el timestamp de un header respecto del reloj local, en segundos.
+const MAX_TIEMPO_FUTURO: i64 = 2 * 60 * 60;
 
 /// Dado el header de un bloque, se chequea que cumpla la proof of work.
 /// Se utiliza su campo n_bits y el hash del header del bloque.
-/// Para que cumpla, el hash tiene que ser menor al target.
-/// El target se calcula expandiendo el n_bits de 32 bits a un número de 256 bits en un [u8; 32].
+/// Para que cumpla, el hash tiene que ser menor al target, y el target no puede superar
+/// al máximo permitido por la red (pow_limit).
 pub fn proof_of_work(header_bloque: &BlockHeader) -> bool {
-    let n_bits = header_bloque.n_bits;
-    let exponente = (n_bits >> 24) as u8;
-    let mantisa = n_bits & 0x00ffffff;
-
-    let mut target = [0u8; 32];
-    let desplazamiento = (exponente - BYTES_IN_SIGNIFICAND) as usize;
-    let inicio_slice = LARGO_TARGET - desplazamiento - BYTES_IN_SIGNIFICAND as usize - 1;
-    let fin_slice = LARGO_TARGET - desplazamiento;
-    target[inicio_slice..fin_slice].copy_from_slice(&mantisa.to_be_bytes());
+    let target = target_desde_n_bits(header_bloque.n_bits);
+    if target > target_desde_n_bits(parametros_red().pow_limit_bits) {
+        return false;
+    }
 
     let mut hash = sha256d::Hash::hash(&header_bloque.as_bytes())
         .to_byte_array()
@@ -36,6 +43,177 @@ pub fn proof_of_work(header_bloque: &BlockHeader) -> bool {
     false
 }
 
+/// El target se calcula expandiendo el n_bits de 32 bits a un número de 256 bits en un [u8; 32] (big endian).
+/// El primer byte del n_bits es el exponente (largo en bytes del target) y los 3 restantes la mantisa.
+pub fn target_desde_n_bits(n_bits: u32) -> [u8; LARGO_TARGET] {
+    let exponente = (n_bits >> 24) as usize;
+    let mantisa = (n_bits & 0x007fffff).to_be_bytes();
+
+    let mut target = [0u8; LARGO_TARGET];
+    for (i, byte) in mantisa[1..].iter().enumerate() {
+        // Posición del byte de la mantisa dentro del target. Los que quedan fuera se descartan.
+        let posicion = (LARGO_TARGET + i).checked_sub(exponente);
+        if let Some(posicion) = posicion.filter(|p| *p < LARGO_TARGET) {
+            target[posicion] = *byte;
+        }
+    }
+    target
+}
+
+/// Codifica el target (big endian) en el formato compacto n_bits: exponente en el primer byte
+/// y los 3 bytes más significativos como mantisa. Como la mantisa no puede tener el bit de signo,
+/// si lo tiene se corre un byte y se incrementa el exponente.
+pub fn n_bits_desde_target(target: &[u8; LARGO_TARGET]) -> u32 {
+    let primer_byte = match target.iter().position(|byte| *byte != 0) {
+        Some(posicion) => posicion,
+        None => return 0,
+    };
+    let mut exponente = (LARGO_TARGET - primer_byte) as u32;
+    let mut mantisa_bytes = [0u8; 4];
+    for (i, byte) in target[primer_byte..].iter().take(3).enumerate() {
+        mantisa_bytes[i + 1] = *byte;
+    }
+    let mut mantisa = u32::from_be_bytes(mantisa_bytes);
+    if mantisa & 0x00800000 != 0 {
+        mantisa >>= 8;
+        exponente += 1;
+    }
+    mantisa | exponente << 24
+}
+
+/// Trabajo que representa un bloque con el n_bits indicado: la cantidad esperada de hashes para encontrarlo,
+/// 2^256 / target. Se calcula a partir del formato compacto, ya que el target es la mantisa corrida
+/// 8 * (exponente - 3) bits. Los targets absurdamente chicos se saturan en el máximo, y los que
+/// superan 2^256 no representan trabajo.
+pub fn trabajo_bloque(n_bits: u32) -> u128 {
+    let exponente = (n_bits >> 24) as i64;
+    let mantisa = (n_bits & 0x007fffff) as u128;
+    let desplazamiento = 256 - 8 * (exponente - 3);
+    if mantisa == 0 || desplazamiento < 0 {
+        return 0;
+    }
+    if desplazamiento >= 128 {
+        return u128::MAX;
+    }
+    (1u128 << desplazamiento) / mantisa
+}
+
+/// Calcula el n_bits que corresponde luego de un intervalo de reajuste de la dificultad.
+/// El target anterior se escala según lo que tardaron los bloques del intervalo respecto de las dos semanas
+/// esperadas, acotando ese tiempo a entre un cuarto y cuatro veces el objetivo. El resultado nunca supera al pow_limit.
+pub fn calcular_n_bits(n_bits_previo: u32, tiempo_inicio: u32, tiempo_fin: u32) -> u32 {
+    let tiempo_real = (tiempo_fin as i64 - tiempo_inicio as i64).clamp(
+        (TIEMPO_OBJETIVO_INTERVALO / 4) as i64,
+        (TIEMPO_OBJETIVO_INTERVALO * 4) as i64,
+    ) as u64;
+
+    // El target se opera en palabras de 32 bits (little endian), con una palabra extra para el desborde.
+    let target = target_desde_n_bits(n_bits_previo);
+    let mut palabras = [0u64; LARGO_TARGET / 4 + 1];
+    for (i, chunk) in target.rchunks(4).enumerate() {
+        palabras[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64;
+    }
+    let mut acarreo = 0;
+    for palabra in palabras.iter_mut() {
+        let producto = *palabra * tiempo_real + acarreo;
+        *palabra = producto & 0xffffffff;
+        acarreo = producto >> 32;
+    }
+    let mut resto = 0;
+    for palabra in palabras.iter_mut().rev() {
+        let dividendo = (resto << 32) | *palabra;
+        *palabra = dividendo / TIEMPO_OBJETIVO_INTERVALO as u64;
+        resto = dividendo % TIEMPO_OBJETIVO_INTERVALO as u64;
+    }
+
+    let pow_limit = target_desde_n_bits(parametros_red().pow_limit_bits);
+    if palabras[LARGO_TARGET / 4] != 0 {
+        return n_bits_desde_target(&pow_limit);
+    }
+    let mut nuevo_target = [0u8; LARGO_TARGET];
+    for (i, chunk) in nuevo_target.rchunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&(palabras[i] as u32).to_be_bytes());
+    }
+    n_bits_desde_target(&nuevo_target.min(pow_limit))
+}
+
+/// Devuelve el n_bits que debe tener el header de la altura indicada, según las reglas de dificultad de la red.
+/// Recibe el timestamp del header y una función que devuelve el header de cada altura anterior.
+/// En las redes que lo permiten (testnet), si pasaron más de 20 minutos desde el bloque anterior se acepta
+/// la dificultad mínima; si no, se usa la del último bloque que no fue de dificultad mínima.
+fn n_bits_esperado<'a>(
+    altura: usize,
+    tiempo: u32,
+    header_en: &impl Fn(usize) -> &'a BlockHeader,
+) -> u32 {
+    let parametros = parametros_red();
+    let previo = header_en(altura - 1);
+    if !altura.is_multiple_of(INTERVALO_REAJUSTE) {
+        if !parametros.permite_dificultad_minima {
+            return previo.n_bits;
+        }
+        if tiempo > previo.time.saturating_add(ESPACIADO_OBJETIVO * 2) {
+            return parametros.pow_limit_bits;
+        }
+        let mut altura_anterior = altura - 1;
+        while !altura_anterior.is_multiple_of(INTERVALO_REAJUSTE)
+            && header_en(altura_anterior).n_bits == parametros.pow_limit_bits
+        {
+            altura_anterior -= 1;
+        }
+        return header_en(altura_anterior).n_bits;
+    }
+    if parametros.sin_reajuste_dificultad {
+        return previo.n_bits;
+    }
+    let inicio = header_en(altura - INTERVALO_REAJUSTE);
+    calcular_n_bits(previo.n_bits, inicio.time, previo.time)
+}
+
+/// Mediana de los timestamps de los últimos 11 headers anteriores a la altura indicada (median time past).
+fn median_time_past<'a>(altura: usize, header_en: &impl Fn(usize) -> &'a BlockHeader) -> u32 {
+    let mut tiempos: Vec<u32> = (altura.saturating_sub(CANT_BLOQUES_MEDIANA)..altura)
+        .map(|altura_anterior| header_en(altura_anterior).time)
+        .collect();
+    tiempos.sort_unstable();
+    tiempos[tiempos.len() / 2]
+}
+
+/// Valida que los headers nuevos continúen la cadena recibida (que empieza en el génesis).
+/// Cada header debe apuntar al hash del anterior, tener el n_bits que corresponde según el reajuste
+/// de dificultad, cumplir la proof of work, tener un timestamp mayor al median time past
+/// y no estar más de dos horas en el futuro. Si alguno falla, se rechazan todos.
+pub fn validar_headers(cadena: &[BlockHeader], nuevos: &[BlockHeader]) -> Result<(), RustifyError> {
+    validar_headers_con_hora(cadena, nuevos, Utc::now().timestamp())
+}
+
+fn validar_headers_con_hora(
+    cadena: &[BlockHeader],
+    nuevos: &[BlockHeader],
+    ahora: i64,
+) -> Result<(), RustifyError> {
+    if cadena.is_empty() {
+        return Err(RustifyError::NotFound);
+    }
+    let header_en = |altura: usize| match altura.checked_sub(cadena.len()) {
+        Some(indice) => &nuevos[indice],
+        None => &cadena[altura],
+    };
+    for (i, header) in nuevos.iter().enumerate() {
+        let altura = cadena.len() + i;
+        let hash_previo = sha256d::Hash::hash(&header_en(altura - 1).as_bytes()).to_byte_array();
+        if header.previous_block_header_hash != hash_previo
+            || header.n_bits != n_bits_esperado(altura, header.time, &header_en)
+            || header.time <= median_time_past(altura, &header_en)
+            || header.time as i64 > ahora + MAX_TIEMPO_FUTURO
+            || !proof_of_work(header)
+        {
+            return Err(RustifyError::HeadersInvalidos);
+        }
+    }
+    Ok(())
+}
+
 /// Verifica la Proof of Inclusion del bloque recibido.
 /// Devuelve true si COINCIDE el hash de la raiz del merkle tree GENERADO con el original (guardado en el header del bloque).
 /// Devuelve false si no coinciden (el bloque es invalido y no se agrega a la blockchain).
@@ -206,6 +384,84 @@ pub fn generar_merkle_root_con_merkle_proof(merkle_proof: &[(Vec<u8>, &str)]) ->
     }
 }
 
+/// Valida el partial merkle tree de un mensaje merkleblock (BIP37) contra la merkle root del header.
+/// El árbol se recorre en profundidad: cada bit de flags indica si el nodo tiene debajo alguna transacción
+/// incluida (y entonces se desciende) o si se usa directamente el siguiente hash.
+/// Devuelve los txids de las transacciones incluidas, en el orden del bloque, o None si el árbol no es válido.
+pub fn validar_partial_merkle_tree(
+    merkle_root_hash: &[u8; 32],
+    cant_txns: u32,
+    hashes: &[[u8; 32]],
+    flags: &[u8],
+) -> Option<Vec<[u8; 32]>> {
+    let cant_txns = cant_txns as usize;
+    if cant_txns == 0 || hashes.len() > cant_txns || hashes.len() > flags.len() * 8 {
+        return None;
+    }
+    let mut altura = 0;
+    while ancho_nivel(cant_txns, altura) > 1 {
+        altura += 1;
+    }
+
+    let mut recorrido = RecorridoMerkle {
+        cant_txns,
+        hashes,
+        flags,
+        hashes_usados: 0,
+        bits_usados: 0,
+        incluidas: vec![],
+    };
+    let raiz = recorrido.recorrer(altura, 0)?;
+    let todos_usados =
+        recorrido.hashes_usados == hashes.len() && recorrido.bits_usados.div_ceil(8) == flags.len();
+    if !todos_usados || &raiz != merkle_root_hash {
+        return None;
+    }
+    Some(recorrido.incluidas)
+}
+
+/// Cantidad de nodos del merkle tree en el nivel indicado (0 son las hojas).
+fn ancho_nivel(cant_txns: usize, altura: u32) -> usize {
+    (cant_txns + (1 << altura) - 1) >> altura
+}
+
+struct RecorridoMerkle<'a> {
+    cant_txns: usize,
+    hashes: &'a [[u8; 32]],
+    flags: &'a [u8],
+    hashes_usados: usize,
+    bits_usados: usize,
+    incluidas: Vec<[u8; 32]>,
+}
+
+impl RecorridoMerkle<'_> {
+    fn recorrer(&mut self, altura: u32, posicion: usize) -> Option<[u8; 32]> {
+        let flag = self.flags.get(self.bits_usados / 8)? >> (self.bits_usados % 8) & 1 == 1;
+        self.bits_usados += 1;
+        if altura == 0 || !flag {
+            let hash = *self.hashes.get(self.hashes_usados)?;
+            self.hashes_usados += 1;
+            if altura == 0 && flag {
+                self.incluidas.push(hash);
+            }
+            return Some(hash);
+        }
+        let izquierdo = self.recorrer(altura - 1, posicion * 2)?;
+        let derecho = if posicion * 2 + 1 < ancho_nivel(self.cant_txns, altura - 1) {
+            let derecho = self.recorrer(altura - 1, posicion * 2 + 1)?;
+            // Dos hermanos iguales permitirían falsificar el árbol duplicando transacciones (CVE-2012-2459).
+            if derecho == izquierdo {
+                return None;
+            }
+            derecho
+        } else {
+            izquierdo
+        };
+        let concat = [izquierdo, derecho].concat();
+        Some(sha256d::Hash::hash(&concat).to_byte_array())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,6 +498,127 @@ mod tests {
         Ok(())
     }
 
+    /// Test para chequear la expansión del n_bits, incluyendo el target máximo de regtest (exponente 0x20).
+    #[test]
+    fn test_target_desde_n_bits() {
+        let mut target_testnet = [0u8; 32];
+        target_testnet[4..6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(target_desde_n_bits(0x1d00ffff), target_testnet);
+
+        let mut target_regtest = [0u8; 32];
+        target_regtest[0..3].copy_from_slice(&[0x7f, 0xff, 0xff]);
+        assert_eq!(target_desde_n_bits(0x207fffff), target_regtest);
+    }
+
+    /// Headers de prueba encadenados a partir del génesis de la red, con timestamps cada 10 minutos
+    /// y la dificultad indicada. Sirven para validar las reglas de contexto, no cumplen la proof of work.
+    fn cadena_de_prueba(cantidad: usize, n_bits: u32) -> Result<Vec<BlockHeader>, RustifyError> {
+        let mut cadena = vec![BlockHeader::from_bytes(&parametros_red().genesis_header)?];
+        for _ in 1..cantidad {
+            let previo = &cadena[cadena.len() - 1];
+            let mut header = previo.clone();
+            header.previous_block_header_hash =
+                sha256d::Hash::hash(&previo.as_bytes()).to_byte_array();
+            header.time = previo.time + ESPACIADO_OBJETIVO;
+            header.n_bits = n_bits;
+            cadena.push(header);
+        }
+        Ok(cadena)
+    }
+
+    /// Test para chequear que codificar un target en n_bits y volver a expandirlo no cambia el valor,
+    /// incluyendo el caso en que la mantisa tendría el bit de signo.
+    #[test]
+    fn test_n_bits_desde_target() {
+        for n_bits in [0x1d00ffff, 0x1b0404cb, 0x18013ce9, 0x207fffff, 0x02123400] {
+            assert_eq!(n_bits_desde_target(&target_desde_n_bits(n_bits)), n_bits);
+        }
+        let mut target = [0u8; 32];
+        target[4] = 0x80;
+        assert_eq!(n_bits_desde_target(&target), 0x1d008000);
+    }
+
+    /// Test del reajuste de dificultad con los casos de Bitcoin Core (pow_tests.cpp):
+    /// el del bloque 32256 de mainnet, el tope en el pow_limit y los dos límites del tiempo real.
+    #[test]
+    fn test_calcular_n_bits() {
+        assert_eq!(
+            calcular_n_bits(0x1d00ffff, 1261130161, 1262152739),
+            0x1d00d86a
+        );
+        assert_eq!(
+            calcular_n_bits(0x1d00ffff, 1231006505, 1233061996),
+            0x1d00ffff
+        );
+        assert_eq!(
+            calcular_n_bits(0x1c05a3f4, 1279008237, 1279297671),
+            0x1c0168fd
+        );
+        assert_eq!(
+            calcular_n_bits(0x1c387f6f, 1263163443, 1269211443),
+            0x1d00e1fd
+        );
+    }
+
+    /// Test del trabajo por bloque con los valores de Bitcoin Core: 0x100010001 con dificultad 1
+    /// y 2 con el target máximo de regtest.
+    #[test]
+    fn test_trabajo_bloque() {
+        assert_eq!(trabajo_bloque(0x1d00ffff), 0x100010001);
+        assert_eq!(trabajo_bloque(0x207fffff), 2);
+        assert_eq!(trabajo_bloque(0x247fffff), 0);
+        assert_eq!(trabajo_bloque(0xff7fffff), 0);
+        assert!(trabajo_bloque(0x1b0404cb) > trabajo_bloque(0x1d00ffff));
+    }
+
+    /// Test de la regla de testnet: luego de 20 minutos sin bloques se acepta la dificultad mínima,
+    /// y si no, se exige la del último bloque que no fue de dificultad mínima.
+    #[test]
+    fn test_n_bits_esperado_dificultad_minima() -> Result<(), RustifyError> {
+        let pow_limit = parametros_red().pow_limit_bits;
+        let mut cadena = cadena_de_prueba(5, 0x1c05a3f4)?;
+        cadena[4].n_bits = pow_limit;
+        let header_en = |altura: usize| &cadena[altura];
+        let previo = cadena[4].time;
+
+        assert_eq!(n_bits_esperado(5, previo + 1201, &header_en), pow_limit);
+        assert_eq!(n_bits_esperado(5, previo + 600, &header_en), 0x1c05a3f4);
+        Ok(())
+    }
+
+    /// Test para chequear que se rechazan los headers que no apuntan al anterior, los que tienen
+    /// un n_bits distinto del esperado, los que no superan el median time past y los del futuro.
+    #[test]
+    fn test_validar_headers_rechaza_contexto_invalido() -> Result<(), RustifyError> {
+        let cadena = cadena_de_prueba(20, parametros_red().pow_limit_bits)?;
+        let ahora = cadena[19].time as i64;
+        let siguiente = cadena_de_prueba(21, parametros_red().pow_limit_bits)?
+            .pop()
+            .ok_or(RustifyError::NotFound)?;
+
+        let mut desencadenado = siguiente.clone();
+        desencadenado.previous_block_header_hash = [0; 32];
+        let mut dificultad_incorrecta = siguiente.clone();
+        dificultad_incorrecta.n_bits = 0x1c05a3f4;
+        let mut anterior_a_la_mediana = siguiente.clone();
+        anterior_a_la_mediana.time = cadena[14].time;
+        let mut futuro = siguiente.clone();
+        futuro.time = (ahora + MAX_TIEMPO_FUTURO + 1) as u32;
+
+        for header in [
+            desencadenado,
+            dificultad_incorrecta,
+            anterior_a_la_mediana,
+            futuro,
+        ] {
+            assert!(matches!(
+                validar_headers_con_hora(&cadena, &[header], ahora),
+                Err(RustifyError::HeadersInvalidos)
+            ));
+        }
+        Ok(())
+    }
+
     /// Prueba que verifica la proof of inclusion del bloque 2.434.337 con 3 transacciones.
     #[test]
     fn test_proof_of_inclusion_datos_reales() {
@@ -335,6 +712,49 @@ mod tests {
         assert_eq!(merkle_tree[2][0], merkle_root_hash);
     }
 
+    /// Test que verifica el partial merkle tree de un merkleblock del bloque 2.434.337
+    /// que incluye solamente la segunda transacción.
+    #[test]
+    fn test_partial_merkle_tree() {
+        let merkle_root_hash: [u8; 32] = [
+            0x08, 0xcb, 0xea, 0xbc, 0x35, 0x30, 0xd4, 0x6f, 0xc2, 0xaa, 0xd5, 0x89, 0x96, 0xf9,
+            0x43, 0xce, 0x86, 0x6d, 0xe1, 0xbe, 0x62, 0x7c, 0x9c, 0x78, 0xd9, 0xbf, 0x8a, 0x5b,
+            0x20, 0xd8, 0xd6, 0x1e,
+        ];
+        let txn1: [u8; 32] = [
+            0x54, 0xb2, 0xd6, 0xb6, 0x71, 0xb7, 0xf8, 0x0f, 0xb4, 0xe0, 0x50, 0xc9, 0x93, 0x9f,
+            0x6a, 0xde, 0xc3, 0xc7, 0x73, 0x72, 0xf8, 0x59, 0x71, 0x05, 0x24, 0xbb, 0x3a, 0x41,
+            0x33, 0x97, 0xc1, 0xc6,
+        ];
+        let txn2: [u8; 32] = [
+            0x9f, 0xfc, 0xee, 0x1c, 0x31, 0xc3, 0xb2, 0x24, 0x55, 0xfe, 0xa2, 0x10, 0xa2, 0x62,
+            0xdf, 0xa4, 0x05, 0x67, 0xd8, 0x56, 0xa8, 0xbd, 0x8f, 0x35, 0x8f, 0xd9, 0x64, 0x5d,
+            0x7b, 0x71, 0x5f, 0x43,
+        ];
+        let txn3: [u8; 32] = [
+            0x75, 0x61, 0x1a, 0x4c, 0x06, 0xcd, 0xc6, 0x7f, 0x68, 0xbc, 0x50, 0x8f, 0x2f, 0x08,
+            0x8d, 0x42, 0x59, 0xc4, 0x03, 0x4b, 0xda, 0x07, 0x5d, 0xbc, 0x3a, 0x82, 0x9c, 0x32,
+            0x96, 0xd4, 0x49, 0xd0,
+        ];
+        let hash_txn3_duplicada = sha256d::Hash::hash(&[txn3, txn3].concat()).to_byte_array();
+        let hashes = [txn1, txn2, hash_txn3_duplicada];
+        // Recorrido: raíz (1), nodo izquierdo (1), txn1 (0), txn2 (1), nodo derecho (0).
+        let flags = [0b01011];
+
+        assert_eq!(
+            validar_partial_merkle_tree(&merkle_root_hash, 3, &hashes, &flags),
+            Some(vec![txn2])
+        );
+        assert_eq!(
+            validar_partial_merkle_tree(&merkle_root_hash, 5, &hashes, &flags),
+            None
+        );
+        assert_eq!(
+            validar_partial_merkle_tree(&merkle_root_hash, 3, &hashes[..2], &flags),
+            None
+        );
+    }
+
     /// Test que verifica que la merkle proof sea correcta.
     /// Se genera la merkle proof a partir de un bloque y una transacción de ese bloque.
     /// Luego se genera la merkle root a partir de la merkle proof y se comparar con
@@ -0,0 +1,275 @@
+use crate::{
+    account::obtain_pubkey_hash,
+    compactsize::CompactSize,
+    config::Config,
+    errors::RustifyError,
+    event_loop::ConexionPeer,
+    logger::{log, log_with_parameters, Action, Lvl},
+    network_message::{escribir_mensaje, NetworkMessage},
+    outpoint::obtain_txid_from_str,
+    utxo::obtain_utxo,
+    wallet::Wallet,
+};
+use rand::RngCore;
+use std::{
+    path::Path,
+    sync::{mpsc::Sender, OnceLock},
+};
+
+static FILTRO_SPV: OnceLock<FiltroBloom> = OnceLock::new();
+
+/// Tamaño máximo del filtro en bytes y cantidad máxima de funciones de hash (BIP37).
+pub const MAX_BYTES_FILTRO: usize = 36_000;
+const MAX_FUNCIONES_HASH: u32 = 50;
+const SEMILLA_FUNCION_HASH: u32 = 0xfba4c795;
+/// Probabilidad de falsos positivos del filtro de las wallets. Cuanto más alta, más privacidad
+/// (el nodo remoto no sabe cuáles de las transacciones enviadas son realmente nuestras) y más datos a descargar.
+const TASA_FALSOS_POSITIVOS: f64 = 0.0001;
+/// El nodo remoto agrega al filtro los outpoints de los outputs que coinciden con el filtro,
+/// para que también coincidan las transacciones que los gastan.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+
+/// Filtro bloom de BIP37, tal cual se envía en el mensaje filterload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiltroBloom {
+    pub filtro: Vec<u8>,
+    pub cant_funciones_hash: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl FiltroBloom {
+    /// Crea un filtro vacío dimensionado para la cantidad de elementos y la probabilidad de falsos positivos indicadas.
+    pub fn new(cant_elementos: usize, tasa_falsos_positivos: f64, tweak: u32, flags: u8) -> Self {
+        let cant_elementos = cant_elementos.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = -1.0 / (ln2 * ln2) * cant_elementos * tasa_falsos_positivos.ln();
+        let cant_bytes = ((bits as usize).min(MAX_BYTES_FILTRO * 8) / 8).max(1);
+        let cant_funciones_hash = ((cant_bytes * 8) as f64 / cant_elementos * ln2) as u32;
+        FiltroBloom {
+            filtro: vec![0; cant_bytes],
+            cant_funciones_hash: cant_funciones_hash.clamp(1, MAX_FUNCIONES_HASH),
+            tweak,
+            flags,
+        }
+    }
+
+    pub fn insertar(&mut self, dato: &[u8]) {
+        for i in 0..self.cant_funciones_hash {
+            let indice = self.indice_bit(i, dato);
+            self.filtro[indice >> 3] |= 1 << (indice & 7);
+        }
+    }
+
+    pub fn contiene(&self, dato: &[u8]) -> bool {
+        (0..self.cant_funciones_hash).all(|i| {
+            let indice = self.indice_bit(i, dato);
+            self.filtro[indice >> 3] & (1 << (indice & 7)) != 0
+        })
+    }
+
+    fn indice_bit(&self, nro_funcion: u32, dato: &[u8]) -> usize {
+        let semilla = nro_funcion
+            .wrapping_mul(SEMILLA_FUNCION_HASH)
+            .wrapping_add(self.tweak);
+        murmur3(dato, semilla) as usize % (self.filtro.len() * 8)
+    }
+
+    /// Serializa el filtro como payload del mensaje filterload.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = CompactSize::new(self.filtro.len() as u64).as_bytes();
+        bytes.extend_from_slice(&self.filtro);
+        bytes.extend_from_slice(&self.cant_funciones_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.tweak.to_le_bytes());
+        bytes.push(self.flags);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FiltroBloom, RustifyError> {
+        let largo = *bytes.first().ok_or(RustifyError::MensajeMalformado)?;
+        // El filtro tiene como máximo 36.000 bytes, por lo que el largo ocupa 1 o 3 bytes.
+        let (cant_bytes, inicio) = match largo {
+            0xfd => {
+                let largo = bytes.get(1..3).ok_or(RustifyError::MensajeMalformado)?;
+                (u16::from_le_bytes(largo.try_into()?) as usize, 3)
+            }
+            0xfe | 0xff => return Err(RustifyError::MensajeMalformado),
+            _ => (largo as usize, 1),
+        };
+        let fin = inicio + cant_bytes;
+        let (filtro, resto) = match (bytes.get(inicio..fin), bytes.get(fin..fin + 9)) {
+            (Some(filtro), Some(resto)) => (filtro, resto),
+            _ => return Err(RustifyError::MensajeMalformado),
+        };
+        if cant_bytes > MAX_BYTES_FILTRO {
+            return Err(RustifyError::MensajeMalformado);
+        }
+        Ok(FiltroBloom {
+            filtro: filtro.to_vec(),
+            cant_funciones_hash: u32::from_le_bytes(resto[0..4].try_into()?),
+            tweak: u32::from_le_bytes(resto[4..8].try_into()?),
+            flags: resto[8],
+        })
+    }
+}
+
+/// MurmurHash3 de 32 bits, la función de hash de los filtros bloom de BIP37.
+fn murmur3(datos: &[u8], semilla: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mezclar = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = semilla;
+    let bloques = datos.chunks_exact(4);
+    let resto = bloques.remainder();
+    for bloque in bloques {
+        let k = u32::from_le_bytes([bloque[0], bloque[1], bloque[2], bloque[3]]);
+        hash ^= mezclar(k);
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe6546b64);
+    }
+    if !resto.is_empty() {
+        let k = resto
+            .iter()
+            .enumerate()
+            .fold(0u32, |k, (i, byte)| k | (*byte as u32) << (8 * i));
+        hash ^= mezclar(k);
+    }
+
+    hash ^= datos.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Arma el filtro con los pubkey hashes de las wallets guardadas y los outpoints de sus UTXOs,
+/// para recibir solamente las transacciones que les pagan o que gastan sus fondos.
+/// Las wallets que se agreguen luego se incluyen en el filtro recién al reiniciar el nodo.
+pub fn filtro_wallets(
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<FiltroBloom, RustifyError> {
+    let wallets = Wallet::cargar_sin_utxos(config.wallets_path.clone(), logger_sender)?;
+    let mut pubkey_hashes = vec![];
+    for account in wallets.accounts.values() {
+        pubkey_hashes.push(account.decode_bitcoin_adress()?);
+    }
+
+    let mut outpoints = vec![];
+    if Path::new(&config.blocks_path).exists() {
+        for ((txid, indice), txn) in obtain_utxo(config, logger_sender)?.utxos {
+            let es_de_wallet = txn
+                .tx_out
+                .get(indice as usize)
+                .is_some_and(|output| pubkey_hashes.contains(&obtain_pubkey_hash(output)));
+            if es_de_wallet {
+                let mut outpoint = obtain_txid_from_str(&txid).to_vec();
+                outpoint.extend_from_slice(&indice.to_le_bytes());
+                outpoints.push(outpoint);
+            }
+        }
+    }
+
+    let mut filtro = FiltroBloom::new(
+        pubkey_hashes.len() + outpoints.len(),
+        TASA_FALSOS_POSITIVOS,
+        rand::thread_rng().next_u32(),
+        BLOOM_UPDATE_ALL,
+    );
+    for dato in pubkey_hashes.iter().chain(outpoints.iter()) {
+        filtro.insertar(dato);
+    }
+    log_with_parameters(
+        Lvl::Info(Action::WALLET),
+        format!(
+            "Filtro bloom armado con {} direcciones y {} UTXOs de las wallets.",
+            pubkey_hashes.len(),
+            outpoints.len()
+        ),
+        logger_sender,
+    );
+    Ok(filtro)
+}
+
+/// Establece el filtro de las wallets que se envía a cada nodo en modo SPV. Se puede establecer una única vez.
+pub fn establecer_filtro_spv(filtro: FiltroBloom) -> Result<(), RustifyError> {
+    FILTRO_SPV
+        .set(filtro)
+        .map_err(|_| RustifyError::FiltroSpvYaEstablecido)
+}
+
+/// Devuelve el filtro de las wallets si el nodo funciona en modo SPV (BIP37).
+pub fn filtro_spv() -> Option<&'static FiltroBloom> {
+    FILTRO_SPV.get()
+}
+
+/// Envía el mensaje filterload: a partir de ahí el nodo solamente anuncia las transacciones
+/// que coinciden con el filtro, y responde los pedidos de bloques filtrados.
+pub fn filterload(
+    socket: &mut ConexionPeer,
+    filtro: &FiltroBloom,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::FilterLoad(filtro.clone()))?;
+    log(
+        Lvl::Info(Action::NETWORK),
+        "Enviado mensaje filterload.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_desde_hexa(hexa: &str) -> Result<Vec<u8>, RustifyError> {
+        (0..hexa.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hexa[i..i + 2], 16).map_err(RustifyError::from))
+            .collect()
+    }
+
+    /// Vectores de prueba de MurmurHash3 de Bitcoin Core.
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3(&[], 0), 0);
+        assert_eq!(murmur3(&[], 0xfba4c795), 0x6a396f08);
+        assert_eq!(murmur3(&[0x00], 0), 0x514e28b7);
+        assert_eq!(murmur3(&[0xff], 0), 0xfd6cf10d);
+        assert_eq!(murmur3(&[0x00, 0x11, 0x22], 0), 0x8eb51c3d);
+        assert_eq!(murmur3(&[0x00, 0x11, 0x22, 0x33, 0x44], 0), 0xe2301fa8);
+    }
+
+    /// Filtro del test bloom_create_insert_serialize de Bitcoin Core.
+    #[test]
+    fn test_filtro_bloom_serializado() -> Result<(), RustifyError> {
+        let mut filtro = FiltroBloom::new(3, 0.01, 0, BLOOM_UPDATE_ALL);
+        filtro.insertar(&bytes_desde_hexa(
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+        )?);
+        filtro.insertar(&bytes_desde_hexa(
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+        )?);
+        filtro.insertar(&bytes_desde_hexa(
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        )?);
+
+        assert!(filtro.contiene(&bytes_desde_hexa(
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8"
+        )?));
+        assert!(!filtro.contiene(&bytes_desde_hexa(
+            "19108ad8ed9bb6274d3980bab5a85c048f0950c8"
+        )?));
+        assert_eq!(
+            filtro.as_bytes(),
+            bytes_desde_hexa("03614e9b050000000000000001")?
+        );
+        assert_eq!(FiltroBloom::from_bytes(&filtro.as_bytes())?, filtro);
+        Ok(())
+    }
+}
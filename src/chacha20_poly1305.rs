@@ -0,0 +1,390 @@
+use crate::errors::RustifyError;
+
+/// Largo del tag de autenticación de Poly1305.
+pub const LARGO_TAG: usize = 16;
+/// Cada cuántos mensajes (largos o paquetes) se renueva la clave de los cifradores de BIP324.
+const INTERVALO_RENOVACION: u64 = 224;
+/// "expand 32-byte k", en palabras little endian.
+const CONSTANTES_CHACHA20: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const MASCARA_26_BITS: u64 = 0x3ff_ffff;
+
+fn cuarto_de_ronda(estado: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    estado[a] = estado[a].wrapping_add(estado[b]);
+    estado[d] = (estado[d] ^ estado[a]).rotate_left(16);
+    estado[c] = estado[c].wrapping_add(estado[d]);
+    estado[b] = (estado[b] ^ estado[c]).rotate_left(12);
+    estado[a] = estado[a].wrapping_add(estado[b]);
+    estado[d] = (estado[d] ^ estado[a]).rotate_left(8);
+    estado[c] = estado[c].wrapping_add(estado[d]);
+    estado[b] = (estado[b] ^ estado[c]).rotate_left(7);
+}
+
+fn palabra(bytes: &[u8], inicio: usize) -> u32 {
+    let mut palabra = [0u8; 4];
+    palabra.copy_from_slice(&bytes[inicio..inicio + 4]);
+    u32::from_le_bytes(palabra)
+}
+
+/// Bloque de 64 bytes del keystream de ChaCha20 (RFC 8439).
+pub fn bloque_chacha20(clave: &[u8; 32], nonce: &[u8; 12], contador: u32) -> [u8; 64] {
+    let mut estado = [0u32; 16];
+    estado[..4].copy_from_slice(&CONSTANTES_CHACHA20);
+    for i in 0..8 {
+        estado[4 + i] = palabra(clave, 4 * i);
+    }
+    estado[12] = contador;
+    for i in 0..3 {
+        estado[13 + i] = palabra(nonce, 4 * i);
+    }
+
+    let mut mezcla = estado;
+    for _ in 0..10 {
+        cuarto_de_ronda(&mut mezcla, 0, 4, 8, 12);
+        cuarto_de_ronda(&mut mezcla, 1, 5, 9, 13);
+        cuarto_de_ronda(&mut mezcla, 2, 6, 10, 14);
+        cuarto_de_ronda(&mut mezcla, 3, 7, 11, 15);
+        cuarto_de_ronda(&mut mezcla, 0, 5, 10, 15);
+        cuarto_de_ronda(&mut mezcla, 1, 6, 11, 12);
+        cuarto_de_ronda(&mut mezcla, 2, 7, 8, 13);
+        cuarto_de_ronda(&mut mezcla, 3, 4, 9, 14);
+    }
+
+    let mut bloque = [0u8; 64];
+    for i in 0..16 {
+        let valor = mezcla[i].wrapping_add(estado[i]);
+        bloque[4 * i..4 * i + 4].copy_from_slice(&valor.to_le_bytes());
+    }
+    bloque
+}
+
+/// Cifra (o descifra, ya que es la misma operación) los datos con ChaCha20,
+/// usando el keystream a partir del bloque indicado.
+pub fn chacha20(clave: &[u8; 32], nonce: &[u8; 12], contador_inicial: u32, datos: &mut [u8]) {
+    for (i, fragmento) in datos.chunks_mut(64).enumerate() {
+        let bloque = bloque_chacha20(clave, nonce, contador_inicial.wrapping_add(i as u32));
+        for (byte, clave) in fragmento.iter_mut().zip(bloque.iter()) {
+            *byte ^= clave;
+        }
+    }
+}
+
+/// Tag de autenticación Poly1305 (RFC 8439) del mensaje, con la clave de un solo uso indicada.
+/// El acumulador se representa en 5 partes de 26 bits, para que los productos entren en u64.
+pub fn poly1305(clave: &[u8; 32], mensaje: &[u8]) -> [u8; LARGO_TAG] {
+    let r0 = palabra(clave, 0) as u64 & 0x3ff_ffff;
+    let r1 = (palabra(clave, 3) >> 2) as u64 & 0x3ff_ff03;
+    let r2 = (palabra(clave, 6) >> 4) as u64 & 0x3ff_c0ff;
+    let r3 = (palabra(clave, 9) >> 6) as u64 & 0x3f0_3fff;
+    let r4 = (palabra(clave, 12) >> 8) as u64 & 0x00f_ffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+
+    let mut h = [0u64; 5];
+    for fragmento in mensaje.chunks(16) {
+        let mut bloque = [0u8; 17];
+        bloque[..fragmento.len()].copy_from_slice(fragmento);
+        bloque[fragmento.len()] = 1;
+        let bit_alto = (bloque[16] as u64) << 24;
+
+        h[0] += palabra(&bloque, 0) as u64 & MASCARA_26_BITS;
+        h[1] += (palabra(&bloque, 3) >> 2) as u64 & MASCARA_26_BITS;
+        h[2] += (palabra(&bloque, 6) >> 4) as u64 & MASCARA_26_BITS;
+        h[3] += (palabra(&bloque, 9) >> 6) as u64 & MASCARA_26_BITS;
+        h[4] += (palabra(&bloque, 12) >> 8) as u64 | bit_alto;
+
+        let d = [
+            h[0] * r0 + h[1] * s4 + h[2] * s3 + h[3] * s2 + h[4] * s1,
+            h[0] * r1 + h[1] * r0 + h[2] * s4 + h[3] * s3 + h[4] * s2,
+            h[0] * r2 + h[1] * r1 + h[2] * r0 + h[3] * s4 + h[4] * s3,
+            h[0] * r3 + h[1] * r2 + h[2] * r1 + h[3] * r0 + h[4] * s4,
+            h[0] * r4 + h[1] * r3 + h[2] * r2 + h[3] * r1 + h[4] * r0,
+        ];
+        let mut acarreo = 0;
+        for i in 0..5 {
+            let valor = d[i] + acarreo;
+            h[i] = valor & MASCARA_26_BITS;
+            acarreo = valor >> 26;
+        }
+        h[0] += acarreo * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= MASCARA_26_BITS;
+    }
+
+    // Reducción completa módulo 2^130 - 5.
+    let mut acarreo = 0;
+    for _ in 0..2 {
+        for valor in h.iter_mut().skip(1) {
+            *valor += acarreo;
+            acarreo = *valor >> 26;
+            *valor &= MASCARA_26_BITS;
+        }
+        h[0] += acarreo * 5;
+        acarreo = h[0] >> 26;
+        h[0] &= MASCARA_26_BITS;
+    }
+    h[1] += acarreo;
+
+    // Si h + 5 - 2^130 no es negativo, h ya superaba el módulo.
+    let mut g = [0u64; 5];
+    let mut acarreo = 5;
+    for i in 0..5 {
+        let valor = h[i] + acarreo;
+        g[i] = valor & MASCARA_26_BITS;
+        acarreo = valor >> 26;
+    }
+    if acarreo > 0 {
+        h = g;
+    }
+
+    // El tag es (h + s) mod 2^128, por lo que los bits de h por encima de 128 se descartan.
+    let acumulador = h.iter().enumerate().fold(0u128, |acumulador, (i, valor)| {
+        acumulador | ((*valor as u128) << (26 * i))
+    });
+    let mut s = [0u8; 16];
+    s.copy_from_slice(&clave[16..]);
+    acumulador
+        .wrapping_add(u128::from_le_bytes(s))
+        .to_le_bytes()
+}
+
+/// Datos autenticados por el tag del AEAD: aad, texto cifrado (cada uno completado a múltiplo de 16)
+/// y los largos de ambos.
+fn tag_aead(clave: &[u8; 32], nonce: &[u8; 12], aad: &[u8], cifrado: &[u8]) -> [u8; LARGO_TAG] {
+    let bloque = bloque_chacha20(clave, nonce, 0);
+    let mut clave_poly1305 = [0u8; 32];
+    clave_poly1305.copy_from_slice(&bloque[..32]);
+
+    let relleno = |largo: usize| vec![0u8; (16 - largo % 16) % 16];
+    let datos = [
+        aad,
+        &relleno(aad.len()),
+        cifrado,
+        &relleno(cifrado.len()),
+        &(aad.len() as u64).to_le_bytes(),
+        &(cifrado.len() as u64).to_le_bytes(),
+    ]
+    .concat();
+    poly1305(&clave_poly1305, &datos)
+}
+
+/// Cifra el texto con ChaCha20-Poly1305 (RFC 8439). Devuelve el texto cifrado seguido del tag.
+pub fn cifrar_aead(clave: &[u8; 32], nonce: &[u8; 12], aad: &[u8], texto: &[u8]) -> Vec<u8> {
+    let mut cifrado = texto.to_vec();
+    chacha20(clave, nonce, 1, &mut cifrado);
+    let tag = tag_aead(clave, nonce, aad, &cifrado);
+    cifrado.extend_from_slice(&tag);
+    cifrado
+}
+
+/// Verifica el tag y descifra el texto cifrado con ChaCha20-Poly1305 (RFC 8439).
+/// Si el tag no corresponde, devuelve PaqueteCifradoInvalido.
+pub fn descifrar_aead(
+    clave: &[u8; 32],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    cifrado: &[u8],
+) -> Result<Vec<u8>, RustifyError> {
+    if cifrado.len() < LARGO_TAG {
+        return Err(RustifyError::PaqueteCifradoInvalido);
+    }
+    let (cifrado, tag) = cifrado.split_at(cifrado.len() - LARGO_TAG);
+    let esperado = tag_aead(clave, nonce, aad, cifrado);
+    // Comparación sin cortar en el primer byte distinto.
+    let diferencia = esperado
+        .iter()
+        .zip(tag.iter())
+        .fold(0, |acumulado, (a, b)| acumulado | (a ^ b));
+    if diferencia != 0 {
+        return Err(RustifyError::PaqueteCifradoInvalido);
+    }
+    let mut texto = cifrado.to_vec();
+    chacha20(clave, nonce, 1, &mut texto);
+    Ok(texto)
+}
+
+/// Nonce de 96 bits formado por un contador de 32 bits y otro de 64, en little endian.
+fn nonce(contador_bajo: u32, contador_alto: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&contador_bajo.to_le_bytes());
+    nonce[4..].copy_from_slice(&contador_alto.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20 que renueva su clave cada INTERVALO_RENOVACION mensajes (FSChaCha20 de BIP324),
+/// para tener forward secrecy. Se usa para cifrar el largo de los paquetes.
+#[derive(Debug, Clone)]
+pub struct FSChaCha20 {
+    clave: [u8; 32],
+    contador_mensajes: u64,
+    contador_bloques: u32,
+    keystream: Vec<u8>,
+}
+
+impl FSChaCha20 {
+    pub fn new(clave: [u8; 32]) -> FSChaCha20 {
+        FSChaCha20 {
+            clave,
+            contador_mensajes: 0,
+            contador_bloques: 0,
+            keystream: vec![],
+        }
+    }
+
+    /// Próximos bytes del keystream. Los mensajes no usan bloques enteros, así que el sobrante
+    /// se guarda para los mensajes siguientes.
+    fn keystream(&mut self, cantidad: usize) -> Vec<u8> {
+        while self.keystream.len() < cantidad {
+            let nonce = nonce(0, self.contador_mensajes / INTERVALO_RENOVACION);
+            let bloque = bloque_chacha20(&self.clave, &nonce, self.contador_bloques);
+            self.keystream.extend_from_slice(&bloque);
+            self.contador_bloques += 1;
+        }
+        self.keystream.drain(..cantidad).collect()
+    }
+
+    /// Cifra (o descifra) un mensaje.
+    pub fn cifrar(&mut self, mensaje: &mut [u8]) {
+        let keystream = self.keystream(mensaje.len());
+        for (byte, clave) in mensaje.iter_mut().zip(keystream) {
+            *byte ^= clave;
+        }
+        if (self.contador_mensajes + 1).is_multiple_of(INTERVALO_RENOVACION) {
+            let clave = self.keystream(32);
+            self.clave.copy_from_slice(&clave);
+            self.contador_bloques = 0;
+            self.keystream.clear();
+        }
+        self.contador_mensajes += 1;
+    }
+}
+
+/// ChaCha20-Poly1305 que renueva su clave cada INTERVALO_RENOVACION paquetes (FSChaCha20Poly1305 de BIP324).
+/// El nonce de cada paquete se deriva de la cantidad de paquetes ya procesados.
+#[derive(Debug, Clone)]
+pub struct FSChaCha20Poly1305 {
+    clave: [u8; 32],
+    contador_paquetes: u64,
+}
+
+impl FSChaCha20Poly1305 {
+    pub fn new(clave: [u8; 32]) -> FSChaCha20Poly1305 {
+        FSChaCha20Poly1305 {
+            clave,
+            contador_paquetes: 0,
+        }
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        nonce(
+            (self.contador_paquetes % INTERVALO_RENOVACION) as u32,
+            self.contador_paquetes / INTERVALO_RENOVACION,
+        )
+    }
+
+    fn avanzar(&mut self) {
+        if (self.contador_paquetes + 1).is_multiple_of(INTERVALO_RENOVACION) {
+            let nonce_renovacion = nonce(u32::MAX, self.contador_paquetes / INTERVALO_RENOVACION);
+            let clave = cifrar_aead(&self.clave, &nonce_renovacion, &[], &[0; 32]);
+            self.clave.copy_from_slice(&clave[..32]);
+        }
+        self.contador_paquetes += 1;
+    }
+
+    /// Cifra el paquete. Devuelve el texto cifrado seguido del tag.
+    pub fn cifrar(&mut self, aad: &[u8], texto: &[u8]) -> Vec<u8> {
+        let cifrado = cifrar_aead(&self.clave, &self.nonce(), aad, texto);
+        self.avanzar();
+        cifrado
+    }
+
+    /// Verifica y descifra el paquete.
+    pub fn descifrar(&mut self, aad: &[u8], cifrado: &[u8]) -> Result<Vec<u8>, RustifyError> {
+        let texto = descifrar_aead(&self.clave, &self.nonce(), aad, cifrado)?;
+        self.avanzar();
+        Ok(texto)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(texto: &str) -> Vec<u8> {
+        (0..texto.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&texto[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_bloque_chacha20_rfc8439() {
+        let mut clave = [0u8; 32];
+        clave.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let esperado = hex(concat!(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e",
+            "d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        ));
+        assert_eq!(bloque_chacha20(&clave, &nonce, 1).to_vec(), esperado);
+    }
+
+    #[test]
+    fn test_poly1305() {
+        let mut clave = [0u8; 32];
+        clave.copy_from_slice(&hex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b",
+        ));
+        assert_eq!(
+            poly1305(&clave, b"Cryptographic Forum Research Group").to_vec(),
+            hex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+        // Con todos los bits en 1 se ejercitan los acarreos y la reducción final.
+        assert_eq!(
+            poly1305(&[0xff; 32], &[0xff; 100]).to_vec(),
+            hex("b99c030d7ce939bb6607393e68656f22")
+        );
+    }
+
+    #[test]
+    fn test_aead_rfc8439() -> Result<(), RustifyError> {
+        let mut clave = [0u8; 32];
+        clave
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = 0x80 + i as u8);
+        let nonce = [7, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let texto = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut cifrado = cifrar_aead(&clave, &nonce, &aad, texto);
+        assert_eq!(
+            cifrado[texto.len()..].to_vec(),
+            hex("1ae10b594f09e26a7e902ecbd0600691")
+        );
+        assert_eq!(descifrar_aead(&clave, &nonce, &aad, &cifrado)?, texto);
+
+        cifrado[0] ^= 1;
+        assert_eq!(
+            descifrar_aead(&clave, &nonce, &aad, &cifrado).unwrap_err(),
+            RustifyError::PaqueteCifradoInvalido
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cifradores_renuevan_la_clave() -> Result<(), RustifyError> {
+        let mut emisor = (FSChaCha20::new([3; 32]), FSChaCha20Poly1305::new([5; 32]));
+        let mut receptor = (FSChaCha20::new([3; 32]), FSChaCha20Poly1305::new([5; 32]));
+        for i in 0..(2 * INTERVALO_RENOVACION + 3) {
+            let mut largo = (i as u32).to_le_bytes();
+            emisor.0.cifrar(&mut largo);
+            receptor.0.cifrar(&mut largo);
+            assert_eq!(u32::from_le_bytes(largo), i as u32);
+
+            let cifrado = emisor.1.cifrar(&[], &largo);
+            assert_eq!(receptor.1.descifrar(&[], &cifrado)?, largo);
+        }
+        assert_ne!(emisor.0.clave, [3; 32]);
+        assert_ne!(emisor.1.clave, [5; 32]);
+        Ok(())
+    }
+}
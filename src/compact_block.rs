@@ -0,0 +1,468 @@
+use crate::{
+    block_header::BlockHeader,
+    compactsize::{leer_bytes, CompactSize},
+    errors::RustifyError,
+    event_loop::ConexionPeer,
+    logger::{log, Action, Lvl},
+    network_message::{escribir_mensaje, NetworkMessage},
+    serialized_block::SerializedBlock,
+    txn::Txn,
+};
+use bitcoin_hashes::{sha256, sha256d, siphash24, Hash};
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+/// Versión de compact blocks soportada. La versión 1 identifica las transacciones por su txid
+/// (sin witness), que es como el nodo serializa las transacciones. La versión 2 (wtxids y transacciones
+/// con witness) no se soporta, por lo que con los nodos que solamente usan esa versión no se negocian
+/// bloques compactos y los bloques se descargan completos.
+pub const VERSION_COMPACT_BLOCKS: u64 = 1;
+/// Tipo de inventario para pedir un bloque compacto con getdata.
+pub const MSG_CMPCT_BLOCK: u32 = 4;
+
+const LARGO_SHORT_ID: usize = 6;
+const MASCARA_SHORT_ID: u64 = 0xffff_ffff_ffff;
+
+/// Mensaje sendcmpct: indica si el nodo quiere recibir los bloques nuevos directamente como
+/// cmpctblock (anunciar) o si los va a pedir luego de un inv o headers, y la versión soportada.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendCmpct {
+    pub anunciar: bool,
+    pub version: u64,
+}
+
+impl SendCmpct {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.anunciar as u8];
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SendCmpct, RustifyError> {
+        let anunciar = *leer_bytes(bytes, 0, 1)?.first().unwrap_or(&0) != 0;
+        let version = u64::from_le_bytes(leer_bytes(bytes, 1, 8)?.try_into()?);
+        Ok(SendCmpct { anunciar, version })
+    }
+}
+
+/// Bloque compacto (mensaje cmpctblock): el header, un nonce, los short IDs de las transacciones
+/// y las transacciones que el emisor envía completas (al menos la coinbase), con su posición en el bloque.
+#[derive(Debug, Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<(usize, Txn)>,
+}
+
+impl CompactBlock {
+    /// Arma el bloque compacto de un bloque completo, enviando completa solamente la coinbase.
+    pub fn desde_bloque(bloque: &SerializedBlock, nonce: u64) -> CompactBlock {
+        let (k0, k1) = claves_short_id(&bloque.block_header, nonce);
+        let mut prefilled = vec![];
+        let mut short_ids = vec![];
+        for (i, txn) in bloque.txns.iter().enumerate() {
+            if i == 0 {
+                prefilled.push((0, txn.clone()));
+            } else {
+                short_ids.push(short_id(k0, k1, &txid(txn)));
+            }
+        }
+        CompactBlock {
+            header: bloque.block_header.clone(),
+            nonce,
+            short_ids,
+            prefilled,
+        }
+    }
+
+    /// Cantidad de transacciones del bloque.
+    pub fn cant_txns(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Reconstruye el bloque con las transacciones que se tienen en memoria.
+    /// Las posiciones que no se pudieron completar quedan vacías, para pedirlas con getblocktxn.
+    /// Si dos transacciones de la memoria tienen el mismo short ID, no se usa ninguna de las dos.
+    pub fn reconstruir(&self, mempool: &[Txn]) -> Result<BloqueParcial, RustifyError> {
+        let cant_txns = self.cant_txns();
+        let mut txns: Vec<Option<Txn>> = vec![None; cant_txns];
+        for (indice, txn) in &self.prefilled {
+            match txns.get_mut(*indice) {
+                Some(posicion) => *posicion = Some(txn.clone()),
+                None => return Err(RustifyError::MensajeMalformado),
+            }
+        }
+
+        let (k0, k1) = claves_short_id(&self.header, self.nonce);
+        let mut candidatas: HashMap<u64, Option<&Txn>> = HashMap::new();
+        for txn in mempool {
+            candidatas
+                .entry(short_id(k0, k1, &txid(txn)))
+                .and_modify(|repetida| *repetida = None)
+                .or_insert(Some(txn));
+        }
+
+        let mut short_ids = self.short_ids.iter();
+        for posicion in txns.iter_mut().filter(|posicion| posicion.is_none()) {
+            if let Some(id) = short_ids.next() {
+                if let Some(Some(txn)) = candidatas.get(id) {
+                    *posicion = Some((*txn).clone());
+                }
+            }
+        }
+        Ok(BloqueParcial {
+            header: self.header.clone(),
+            txns,
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend(CompactSize::new(self.short_ids.len() as u64).as_bytes());
+        for id in &self.short_ids {
+            bytes.extend_from_slice(&id.to_le_bytes()[..LARGO_SHORT_ID]);
+        }
+        bytes.extend(CompactSize::new(self.prefilled.len() as u64).as_bytes());
+        // Las posiciones se codifican como la diferencia con la anterior, menos uno.
+        let mut siguiente = 0;
+        for (indice, txn) in &self.prefilled {
+            bytes.extend(CompactSize::new((indice - siguiente) as u64).as_bytes());
+            bytes.extend(txn.as_bytes());
+            siguiente = indice + 1;
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CompactBlock, RustifyError> {
+        let header = BlockHeader::from_bytes(leer_bytes(bytes, 0, 80)?)?;
+        let nonce = u64::from_le_bytes(leer_bytes(bytes, 80, 8)?.try_into()?);
+        let (cant_short_ids, mut index) = CompactSize::leer(bytes, 88)?;
+        let mut short_ids = vec![];
+        for _ in 0..cant_short_ids {
+            let mut id = [0u8; 8];
+            id[..LARGO_SHORT_ID].copy_from_slice(leer_bytes(bytes, index, LARGO_SHORT_ID)?);
+            short_ids.push(u64::from_le_bytes(id));
+            index += LARGO_SHORT_ID;
+        }
+        let cant_prefilled;
+        (cant_prefilled, index) = CompactSize::leer(bytes, index)?;
+        let mut prefilled = vec![];
+        let mut siguiente: u64 = 0;
+        for _ in 0..cant_prefilled {
+            let diferencia;
+            (diferencia, index) = CompactSize::leer(bytes, index)?;
+            let indice = siguiente
+                .checked_add(diferencia)
+                .ok_or(RustifyError::MensajeMalformado)?;
+            leer_bytes(bytes, index, 1)?;
+            let txn;
+            (txn, index) = Txn::from_bytes(bytes.to_vec(), index)?;
+            prefilled.push((indice as usize, txn));
+            siguiente = indice + 1;
+        }
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        })
+    }
+}
+
+/// Bloque reconstruido a partir de un cmpctblock, al que le pueden faltar transacciones.
+#[derive(Debug, Clone)]
+pub struct BloqueParcial {
+    pub header: BlockHeader,
+    pub txns: Vec<Option<Txn>>,
+}
+
+impl BloqueParcial {
+    /// Posiciones de las transacciones que faltan.
+    pub fn faltantes(&self) -> Vec<usize> {
+        self.txns
+            .iter()
+            .enumerate()
+            .filter(|(_, txn)| txn.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Completa las transacciones faltantes, en orden, con las recibidas en un blocktxn.
+    /// Devuelve el bloque completo.
+    pub fn completar(mut self, recibidas: Vec<Txn>) -> Result<SerializedBlock, RustifyError> {
+        let mut recibidas = recibidas.into_iter();
+        for posicion in self.txns.iter_mut().filter(|txn| txn.is_none()) {
+            *posicion = recibidas.next();
+        }
+        if recibidas.next().is_some() {
+            return Err(RustifyError::MensajeMalformado);
+        }
+        let txns: Vec<Txn> = self
+            .txns
+            .into_iter()
+            .collect::<Option<Vec<Txn>>>()
+            .ok_or(RustifyError::MensajeMalformado)?;
+        Ok(SerializedBlock {
+            block_header: self.header,
+            txn_count: CompactSize::new(txns.len() as u64),
+            txns,
+        })
+    }
+}
+
+/// Mensaje getblocktxn: pide las transacciones de un bloque según su posición.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetBlockTxn {
+    pub block_hash: [u8; 32],
+    pub indices: Vec<usize>,
+}
+
+impl GetBlockTxn {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.block_hash.to_vec();
+        bytes.extend(CompactSize::new(self.indices.len() as u64).as_bytes());
+        let mut siguiente = 0;
+        for indice in &self.indices {
+            bytes.extend(CompactSize::new((indice - siguiente) as u64).as_bytes());
+            siguiente = indice + 1;
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<GetBlockTxn, RustifyError> {
+        let block_hash: [u8; 32] = leer_bytes(bytes, 0, 32)?.try_into()?;
+        let (cant_indices, mut index) = CompactSize::leer(bytes, 32)?;
+        let mut indices = vec![];
+        let mut siguiente: u64 = 0;
+        for _ in 0..cant_indices {
+            let diferencia;
+            (diferencia, index) = CompactSize::leer(bytes, index)?;
+            let indice = siguiente
+                .checked_add(diferencia)
+                .ok_or(RustifyError::MensajeMalformado)?;
+            indices.push(indice as usize);
+            siguiente = indice + 1;
+        }
+        Ok(GetBlockTxn {
+            block_hash,
+            indices,
+        })
+    }
+}
+
+/// Mensaje blocktxn: las transacciones pedidas con getblocktxn, en el orden pedido.
+#[derive(Debug, Clone)]
+pub struct BlockTxn {
+    pub block_hash: [u8; 32],
+    pub txns: Vec<Txn>,
+}
+
+impl BlockTxn {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.block_hash.to_vec();
+        bytes.extend(CompactSize::new(self.txns.len() as u64).as_bytes());
+        for txn in &self.txns {
+            bytes.extend(txn.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockTxn, RustifyError> {
+        let block_hash: [u8; 32] = leer_bytes(bytes, 0, 32)?.try_into()?;
+        let (cant_txns, mut index) = CompactSize::leer(bytes, 32)?;
+        let mut txns = vec![];
+        for _ in 0..cant_txns {
+            leer_bytes(bytes, index, 1)?;
+            let txn;
+            (txn, index) = Txn::from_bytes(bytes.to_vec(), index)?;
+            txns.push(txn);
+        }
+        Ok(BlockTxn { block_hash, txns })
+    }
+}
+
+/// Envía el mensaje sendcmpct. Con anunciar en true se le pide al nodo que envíe los bloques
+/// nuevos directamente como cmpctblock (modo high-bandwidth de BIP152).
+pub fn sendcmpct(
+    socket: &mut ConexionPeer,
+    anunciar: bool,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    let mensaje = SendCmpct {
+        anunciar,
+        version: VERSION_COMPACT_BLOCKS,
+    };
+    escribir_mensaje(socket, &NetworkMessage::SendCmpct(mensaje))?;
+    log(
+        Lvl::Info(Action::NETWORK),
+        "Enviado mensaje sendcmpct.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Hash (en el orden interno) del bloque del header.
+pub fn hash_bloque(header: &BlockHeader) -> [u8; 32] {
+    sha256d::Hash::hash(&header.as_bytes()).to_byte_array()
+}
+
+/// Txid (en el orden interno) de la transacción.
+fn txid(txn: &Txn) -> [u8; 32] {
+    sha256d::Hash::hash(&txn.as_bytes()).to_byte_array()
+}
+
+/// Claves de SipHash para los short IDs: los primeros 16 bytes del SHA256 del header seguido del nonce.
+fn claves_short_id(header: &BlockHeader, nonce: u64) -> (u64, u64) {
+    let mut datos = header.as_bytes().to_vec();
+    datos.extend_from_slice(&nonce.to_le_bytes());
+    let hash = sha256::Hash::hash(&datos).to_byte_array();
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&hash[0..8]);
+    k1.copy_from_slice(&hash[8..16]);
+    (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+}
+
+/// Short ID de una transacción: los 6 bytes menos significativos del SipHash-2-4 de su txid.
+fn short_id(k0: u64, k1: u64, txid: &[u8; 32]) -> u64 {
+    siphash24::Hash::hash_to_u64_with_keys(k0, k1, txid) & MASCARA_SHORT_ID
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_validation::proof_of_inclusion;
+    use crate::red::{ParametrosRed, Red};
+
+    fn bytes_desde_hexa(hexa: &str) -> Result<Vec<u8>, RustifyError> {
+        (0..hexa.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hexa[i..i + 2], 16).map_err(RustifyError::from))
+            .collect()
+    }
+
+    /// Coinbase del bloque génesis de mainnet.
+    const COINBASE_GENESIS: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    /// Transacción con un input y un output P2PKH, distinta para cada valor.
+    fn txn_test(valor: i64) -> Result<Txn, RustifyError> {
+        let mut bytes = vec![1, 0, 0, 0, 1];
+        bytes.extend_from_slice(&[valor as u8; 32]);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 1, 0x51, 0xff, 0xff, 0xff, 0xff, 1]);
+        bytes.extend_from_slice(&valor.to_le_bytes());
+        bytes.extend_from_slice(&[0x19, 0x76, 0xa9, 0x14]);
+        bytes.extend_from_slice(&[valor as u8; 20]);
+        bytes.extend_from_slice(&[0x88, 0xac, 0, 0, 0, 0]);
+        let (txn, _) = Txn::from_bytes(bytes, 0)?;
+        Ok(txn)
+    }
+
+    fn bloque_test(cant_txns: i64) -> Result<SerializedBlock, RustifyError> {
+        let txns = (0..cant_txns)
+            .map(txn_test)
+            .collect::<Result<Vec<Txn>, _>>()?;
+        let header = BlockHeader::from_bytes(&[0u8; 80])?;
+        Ok(SerializedBlock {
+            block_header: header,
+            txn_count: CompactSize::new(txns.len() as u64),
+            txns,
+        })
+    }
+
+    #[test]
+    fn test_cmpctblock_ida_y_vuelta() -> Result<(), RustifyError> {
+        let bloque = bloque_test(4)?;
+        let compacto = CompactBlock::desde_bloque(&bloque, 0x1122334455667788);
+        let parseado = CompactBlock::from_bytes(&compacto.as_bytes())?;
+
+        assert_eq!(parseado.nonce, compacto.nonce);
+        assert_eq!(parseado.short_ids, compacto.short_ids);
+        assert_eq!(parseado.prefilled.len(), 1);
+        assert_eq!(parseado.prefilled[0].0, 0);
+        assert_eq!(parseado.cant_txns(), 4);
+        assert!(parseado.short_ids.iter().all(|id| *id <= MASCARA_SHORT_ID));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconstruir_bloque_con_transacciones_faltantes() -> Result<(), RustifyError> {
+        let bloque = bloque_test(4)?;
+        let compacto = CompactBlock::desde_bloque(&bloque, 7);
+
+        // En memoria se tienen la segunda y la cuarta transacción, y otra que no es del bloque.
+        let mempool = vec![
+            bloque.txns[3].clone(),
+            txn_test(100)?,
+            bloque.txns[1].clone(),
+        ];
+        let parcial = compacto.reconstruir(&mempool)?;
+        assert_eq!(parcial.faltantes(), vec![2]);
+
+        let completo = parcial.completar(vec![bloque.txns[2].clone()])?;
+        let txids: Vec<[u8; 32]> = completo.txns.iter().map(txid).collect();
+        let esperados: Vec<[u8; 32]> = bloque.txns.iter().map(txid).collect();
+        assert_eq!(txids, esperados);
+        assert_eq!(proof_of_inclusion(&completo), proof_of_inclusion(&bloque));
+        Ok(())
+    }
+
+    #[test]
+    fn test_getblocktxn_indices_diferenciales() -> Result<(), RustifyError> {
+        let pedido = GetBlockTxn {
+            block_hash: [3u8; 32],
+            indices: vec![1, 2, 5, 300],
+        };
+        let bytes = pedido.as_bytes();
+        // 1, 2 y 5 se codifican como 1, 0 y 2.
+        assert_eq!(bytes[33..36], [1, 0, 2]);
+        assert_eq!(GetBlockTxn::from_bytes(&bytes)?, pedido);
+        assert!(GetBlockTxn::from_bytes(&bytes[..34]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sendcmpct_ida_y_vuelta() -> Result<(), RustifyError> {
+        let mensaje = SendCmpct {
+            anunciar: true,
+            version: VERSION_COMPACT_BLOCKS,
+        };
+        assert_eq!(mensaje.as_bytes(), vec![1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(SendCmpct::from_bytes(&mensaje.as_bytes())?, mensaje);
+        Ok(())
+    }
+
+    /// Vector sobre el bloque génesis de mainnet, con nonce 0x0102030405060708. BIP152 no publica vectores:
+    /// el short ID y el cmpctblock esperados se calcularon con una implementación independiente de BIP152
+    /// (SipHash-2-4 verificado con el vector de su especificación). El cmpctblock tiene el header, el nonce,
+    /// ningún short ID y la coinbase completa en la posición 0, y reconstruye el bloque sin transacciones en memoria.
+    #[test]
+    fn test_vector_bloque_genesis() -> Result<(), RustifyError> {
+        let header = BlockHeader::from_bytes(&ParametrosRed::new(Red::Mainnet).genesis_header)?;
+        let (coinbase, _) = Txn::from_bytes(bytes_desde_hexa(COINBASE_GENESIS)?, 0)?;
+        assert_eq!(txid(&coinbase), header.merkle_root_hash);
+        let nonce = 0x0102030405060708;
+        let (k0, k1) = claves_short_id(&header, nonce);
+        assert_eq!(short_id(k0, k1, &txid(&coinbase)), 0xd3dad4322e88);
+
+        let bloque = SerializedBlock {
+            block_header: header.clone(),
+            txn_count: CompactSize::new(1),
+            txns: vec![coinbase],
+        };
+        let esperado = [
+            header.as_bytes().to_vec(),
+            bytes_desde_hexa("0807060504030201000100")?,
+            bytes_desde_hexa(COINBASE_GENESIS)?,
+        ]
+        .concat();
+        let compacto = CompactBlock::desde_bloque(&bloque, nonce);
+        assert_eq!(compacto.as_bytes(), esperado);
+
+        let reconstruido = CompactBlock::from_bytes(&esperado)?
+            .reconstruir(&[])?
+            .completar(vec![])?;
+        assert_eq!(reconstruido.as_bytes(), bloque.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,961 @@
+use crate::{
+    block::leer_bloque_guardado,
+    block_header::BlockHeader,
+    compactsize::{leer_bytes, CompactSize},
+    config::Config,
+    errors::RustifyError,
+    event_loop::ConexionPeer,
+    logger::{log_with_parameters, Action, Lvl},
+    message_handler::esperar_respuesta,
+    network_message::{escribir_mensaje, NetworkMessage},
+    serialized_block::SerializedBlock,
+    utxo::utxos_completas,
+    wallet::Wallet,
+};
+use bitcoin_hashes::{sha256d, siphash24, Hash};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{mpsc::Sender, Mutex},
+    time::Duration,
+};
+
+/// Tipo del filtro básico de BIP158, el único definido.
+pub const FILTRO_BASICO: u8 = 0;
+/// Parámetros del Golomb-coded set del filtro básico.
+const BITS_RESTO: u8 = 19;
+const INVERSA_FALSOS_POSITIVOS: u64 = 784_931;
+/// Máxima cantidad de headers de filtros por mensaje cfheaders y de filtros por pedido getcfilters (BIP157).
+pub const MAX_CFHEADERS_POR_MENSAJE: usize = 2000;
+pub const MAX_CFILTERS_POR_PEDIDO: usize = 1000;
+/// Cada cuántos bloques se envía un header de filtro en el mensaje cfcheckpt.
+pub const INTERVALO_CHECKPOINTS: usize = 1000;
+/// Primer byte de los scripts de outputs que no se pueden gastar (OP_RETURN), que no se incluyen en los filtros.
+const OP_RETURN: u8 = 0x6a;
+
+/// Hash de un bloque y header de su filtro, como se guardan en filtros_path una vez escaneado el filtro.
+pub type HeaderFiltroGuardado = ([u8; 32], [u8; 32]);
+
+/// Payload de los mensajes getcfilters y getcfheaders: los filtros (o sus headers) desde la altura inicial
+/// hasta el bloque de hash final, inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PedidoFiltros {
+    pub tipo_filtro: u8,
+    pub altura_inicial: u32,
+    pub hash_final: [u8; 32],
+}
+
+impl PedidoFiltros {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.tipo_filtro];
+        bytes.extend_from_slice(&self.altura_inicial.to_le_bytes());
+        bytes.extend_from_slice(&self.hash_final);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PedidoFiltros, RustifyError> {
+        Ok(PedidoFiltros {
+            tipo_filtro: *leer_bytes(bytes, 0, 1)?
+                .first()
+                .ok_or(RustifyError::MensajeMalformado)?,
+            altura_inicial: u32::from_le_bytes(leer_bytes(bytes, 1, 4)?.try_into()?),
+            hash_final: leer_bytes(bytes, 5, 32)?.try_into()?,
+        })
+    }
+}
+
+/// Mensaje cfheaders: los hashes de los filtros de un rango de bloques y el header del filtro anterior al rango,
+/// a partir del cual se encadenan los headers de cada filtro.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CFHeaders {
+    pub tipo_filtro: u8,
+    pub hash_final: [u8; 32],
+    pub header_filtro_previo: [u8; 32],
+    pub hashes_filtros: Vec<[u8; 32]>,
+}
+
+impl CFHeaders {
+    /// Encadena los hashes de los filtros a partir del header previo y devuelve el header de cada filtro.
+    pub fn headers_filtros(&self) -> Vec<[u8; 32]> {
+        let mut header_previo = self.header_filtro_previo;
+        self.hashes_filtros
+            .iter()
+            .map(|hash_filtro| {
+                header_previo = encadenar_header_filtro(hash_filtro, &header_previo);
+                header_previo
+            })
+            .collect()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.tipo_filtro];
+        bytes.extend_from_slice(&self.hash_final);
+        bytes.extend_from_slice(&self.header_filtro_previo);
+        bytes.extend(CompactSize::new(self.hashes_filtros.len() as u64).as_bytes());
+        for hash in &self.hashes_filtros {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CFHeaders, RustifyError> {
+        let tipo_filtro = *leer_bytes(bytes, 0, 1)?
+            .first()
+            .ok_or(RustifyError::MensajeMalformado)?;
+        let hash_final = leer_bytes(bytes, 1, 32)?.try_into()?;
+        let header_filtro_previo = leer_bytes(bytes, 33, 32)?.try_into()?;
+        let (cant_hashes, mut index) = CompactSize::leer(bytes, 65)?;
+        if cant_hashes as usize > MAX_CFHEADERS_POR_MENSAJE {
+            return Err(RustifyError::MensajeMalformado);
+        }
+        let mut hashes_filtros = vec![];
+        for _ in 0..cant_hashes {
+            hashes_filtros.push(leer_bytes(bytes, index, 32)?.try_into()?);
+            index += 32;
+        }
+        Ok(CFHeaders {
+            tipo_filtro,
+            hash_final,
+            header_filtro_previo,
+            hashes_filtros,
+        })
+    }
+}
+
+/// Payload del mensaje getcfcheckpt: los headers de los filtros cada INTERVALO_CHECKPOINTS bloques,
+/// hasta el bloque de hash final.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetCFCheckpt {
+    pub tipo_filtro: u8,
+    pub hash_final: [u8; 32],
+}
+
+impl GetCFCheckpt {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.tipo_filtro];
+        bytes.extend_from_slice(&self.hash_final);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<GetCFCheckpt, RustifyError> {
+        Ok(GetCFCheckpt {
+            tipo_filtro: *leer_bytes(bytes, 0, 1)?
+                .first()
+                .ok_or(RustifyError::MensajeMalformado)?,
+            hash_final: leer_bytes(bytes, 1, 32)?.try_into()?,
+        })
+    }
+}
+
+/// Mensaje cfcheckpt: respuesta a getcfcheckpt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CFCheckpt {
+    pub tipo_filtro: u8,
+    pub hash_final: [u8; 32],
+    pub headers_filtros: Vec<[u8; 32]>,
+}
+
+impl CFCheckpt {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.tipo_filtro];
+        bytes.extend_from_slice(&self.hash_final);
+        bytes.extend(CompactSize::new(self.headers_filtros.len() as u64).as_bytes());
+        for header in &self.headers_filtros {
+            bytes.extend_from_slice(header);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CFCheckpt, RustifyError> {
+        let tipo_filtro = *leer_bytes(bytes, 0, 1)?
+            .first()
+            .ok_or(RustifyError::MensajeMalformado)?;
+        let hash_final = leer_bytes(bytes, 1, 32)?.try_into()?;
+        let (cant_headers, mut index) = CompactSize::leer(bytes, 33)?;
+        let mut headers_filtros = vec![];
+        for _ in 0..cant_headers {
+            headers_filtros.push(leer_bytes(bytes, index, 32)?.try_into()?);
+            index += 32;
+        }
+        Ok(CFCheckpt {
+            tipo_filtro,
+            hash_final,
+            headers_filtros,
+        })
+    }
+}
+
+/// Mensaje cfilter: el filtro de un bloque.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CFilter {
+    pub tipo_filtro: u8,
+    pub block_hash: [u8; 32],
+    pub filtro: Vec<u8>,
+}
+
+impl CFilter {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.tipo_filtro];
+        bytes.extend_from_slice(&self.block_hash);
+        bytes.extend(CompactSize::new(self.filtro.len() as u64).as_bytes());
+        bytes.extend_from_slice(&self.filtro);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CFilter, RustifyError> {
+        let tipo_filtro = *leer_bytes(bytes, 0, 1)?
+            .first()
+            .ok_or(RustifyError::MensajeMalformado)?;
+        let block_hash = leer_bytes(bytes, 1, 32)?.try_into()?;
+        let (largo, index) = CompactSize::leer(bytes, 33)?;
+        let filtro = leer_bytes(bytes, index, largo as usize)?.to_vec();
+        Ok(CFilter {
+            tipo_filtro,
+            block_hash,
+            filtro,
+        })
+    }
+}
+
+/// Filtro básico de BIP158 decodificado: los elementos del bloque (scripts de los outputs y de los outputs gastados),
+/// hasheados con SipHash al rango [0, N * M) y ordenados.
+#[derive(Debug)]
+pub struct FiltroGcs {
+    valores: Vec<u64>,
+    claves: (u64, u64),
+    rango: u64,
+}
+
+impl FiltroGcs {
+    /// Decodifica el Golomb-coded set del filtro del bloque de hash indicado (en el orden de bytes interno).
+    pub fn from_bytes(block_hash: &[u8; 32], filtro: &[u8]) -> Result<FiltroGcs, RustifyError> {
+        let (cant_elementos, index) = CompactSize::leer(filtro, 0)?;
+        let mut lector = LectorBits::new(&filtro[index..]);
+        let mut valores = vec![];
+        let mut ultimo = 0u64;
+        for _ in 0..cant_elementos {
+            let mut cociente = 0u64;
+            while lector.leer_bits(1).ok_or(RustifyError::MensajeMalformado)? == 1 {
+                cociente += 1;
+            }
+            let resto = lector
+                .leer_bits(BITS_RESTO)
+                .ok_or(RustifyError::MensajeMalformado)?;
+            ultimo = ultimo.wrapping_add((cociente << BITS_RESTO) | resto);
+            valores.push(ultimo);
+        }
+        Ok(FiltroGcs {
+            valores,
+            claves: claves_siphash(block_hash),
+            rango: cant_elementos.wrapping_mul(INVERSA_FALSOS_POSITIVOS),
+        })
+    }
+
+    /// Indica si alguno de los scripts está en el filtro (con una probabilidad de falso positivo de 1/M por script).
+    pub fn coincide_alguno(&self, scripts: &[Vec<u8>]) -> bool {
+        scripts.iter().any(|script| {
+            let valor = hash_a_rango(script, self.claves, self.rango);
+            self.valores.binary_search(&valor).is_ok()
+        })
+    }
+}
+
+/// Arma el filtro básico de BIP158 con los elementos indicados, para el bloque de hash indicado.
+/// Los elementos se hashean al rango [0, N * M), se ordenan y se codifican las diferencias
+/// entre valores consecutivos con Golomb-Rice.
+pub fn construir_filtro(block_hash: &[u8; 32], elementos: &[Vec<u8>]) -> Vec<u8> {
+    let cant_elementos = elementos.len() as u64;
+    let claves = claves_siphash(block_hash);
+    let rango = cant_elementos * INVERSA_FALSOS_POSITIVOS;
+    let mut valores: Vec<u64> = elementos
+        .iter()
+        .map(|elemento| hash_a_rango(elemento, claves, rango))
+        .collect();
+    valores.sort_unstable();
+
+    let mut escritor = EscritorBits::default();
+    let mut ultimo = 0;
+    for valor in valores {
+        let diferencia = valor - ultimo;
+        for _ in 0..(diferencia >> BITS_RESTO) {
+            escritor.escribir_bits(1, 1);
+        }
+        escritor.escribir_bits(0, 1);
+        escritor.escribir_bits(diferencia, BITS_RESTO);
+        ultimo = valor;
+    }
+    let mut filtro = CompactSize::new(cant_elementos).as_bytes();
+    filtro.extend(escritor.bytes);
+    filtro
+}
+
+/// Elementos del filtro básico de un bloque: los scripts de sus outputs y los scripts de los outputs que gastan
+/// sus inputs, salvo los vacíos y los OP_RETURN. Estos últimos se buscan entre los outputs del mismo bloque y en
+/// los outputs no gastados de los bloques anteriores, que se actualizan con los del bloque.
+/// Si algún input gasta un output desconocido devuelve None, sin modificar los outputs no gastados:
+/// el filtro no se puede armar, ya que no coincidiría con el de la red.
+pub fn elementos_bloque(
+    bloque: &SerializedBlock,
+    scripts_no_gastados: &mut HashMap<([u8; 32], u32), Vec<u8>>,
+) -> Option<Vec<Vec<u8>>> {
+    let mut elementos = vec![];
+    let mut creados = HashMap::new();
+    let mut gastados = vec![];
+    for (i, txn) in bloque.txns.iter().enumerate() {
+        // La coinbase no gasta outputs.
+        if i > 0 {
+            for input in &txn.tx_in {
+                let outpoint = (
+                    input.previous_output.hash_previous_output_txid,
+                    input.previous_output.output_index,
+                );
+                let script = match creados.remove(&outpoint) {
+                    Some(script) => script,
+                    None => {
+                        gastados.push(outpoint);
+                        scripts_no_gastados.get(&outpoint)?.clone()
+                    }
+                };
+                elementos.push(script);
+            }
+        }
+        let txid = sha256d::Hash::hash(&txn.as_bytes()).to_byte_array();
+        for (indice, output) in txn.tx_out.iter().enumerate() {
+            if output.pk_script.first() != Some(&OP_RETURN) {
+                creados.insert((txid, indice as u32), output.pk_script.clone());
+                elementos.push(output.pk_script.clone());
+            }
+        }
+    }
+    for outpoint in &gastados {
+        scripts_no_gastados.remove(outpoint);
+    }
+    scripts_no_gastados.extend(creados);
+    elementos.retain(|script| !script.is_empty());
+    elementos.sort();
+    elementos.dedup();
+    Some(elementos)
+}
+
+/// Filtros básicos de los bloques de la cadena guardados en blocks_path, con sus headers, para servirlos
+/// a los clientes (BIP157). Los filtros incluyen los scripts de los outputs que gasta cada bloque, por lo que
+/// solamente se arman si se descargaron los bloques completos desde el génesis (ver utxos_completas):
+/// el índice empieza en el génesis y avanza mientras los bloques siguientes estén en disco.
+/// Se actualiza al conectarse bloques nuevos, y no al responder pedidos: como se guarda el hash de cada bloque
+/// indexado, un pedido se responde solamente si el bloque final sigue siendo el de esa altura en el índice.
+#[derive(Debug, Default)]
+pub struct IndiceFiltros {
+    filtros: Vec<Vec<u8>>,
+    headers_filtros: Vec<[u8; 32]>,
+    hashes_bloques: Vec<[u8; 32]>,
+    scripts_no_gastados: HashMap<([u8; 32], u32), Vec<u8>>,
+}
+
+impl IndiceFiltros {
+    /// Agrega al índice los filtros de los bloques en disco posteriores al último indexado.
+    /// Si el último bloque indexado ya no está en la cadena (hubo una reorganización), el índice se vuelve
+    /// a armar desde el génesis, ya que los outputs no gastados que guarda son los de la rama anterior.
+    /// La cadena se bloquea solamente para copiar los headers que faltan indexar, y no mientras se leen los bloques.
+    /// Devuelve la cantidad de filtros agregados.
+    pub fn actualizar(
+        &mut self,
+        headers: &Mutex<Vec<BlockHeader>>,
+        config: &Config,
+    ) -> Result<usize, RustifyError> {
+        if !utxos_completas(config) {
+            return Ok(0);
+        }
+        let pendientes = {
+            let headers_vec = headers.lock()?;
+            if let Some(ultimo) = self.hashes_bloques.last() {
+                let altura = self.hashes_bloques.len() - 1;
+                if headers_vec.get(altura).map(hash_header).as_ref() != Some(ultimo) {
+                    *self = IndiceFiltros::default();
+                }
+            }
+            headers_vec
+                .get(self.filtros.len()..)
+                .unwrap_or_default()
+                .to_vec()
+        };
+        let cant_previa = self.filtros.len();
+        for header in &pendientes {
+            let bloque = match leer_bloque_guardado(header, &config.blocks_path)? {
+                Some(bloque) => bloque,
+                None => break,
+            };
+            let elementos = match elementos_bloque(&bloque, &mut self.scripts_no_gastados) {
+                Some(elementos) => elementos,
+                None => break,
+            };
+            let block_hash = hash_header(header);
+            let filtro = construir_filtro(&block_hash, &elementos);
+            let hash_filtro = sha256d::Hash::hash(&filtro).to_byte_array();
+            let header_previo = self.headers_filtros.last().copied().unwrap_or([0; 32]);
+            self.headers_filtros
+                .push(encadenar_header_filtro(&hash_filtro, &header_previo));
+            self.filtros.push(filtro);
+            self.hashes_bloques.push(block_hash);
+        }
+        Ok(self.filtros.len() - cant_previa)
+    }
+
+    /// Indica si están indexados los filtros de todas las alturas del rango (inclusive).
+    pub fn cubre(&self, desde: usize, hasta: usize) -> bool {
+        desde <= hasta && hasta < self.filtros.len()
+    }
+
+    /// Hash del bloque indexado en la altura indicada.
+    pub fn hash_bloque(&self, altura: usize) -> Option<[u8; 32]> {
+        self.hashes_bloques.get(altura).copied()
+    }
+
+    /// Filtro del bloque de la altura indicada, si está indexado.
+    pub fn filtro(&self, altura: usize) -> Option<&[u8]> {
+        self.filtros.get(altura).map(|filtro| filtro.as_slice())
+    }
+
+    /// Header del filtro del bloque de la altura indicada, si está indexado.
+    pub fn header_filtro(&self, altura: usize) -> Option<[u8; 32]> {
+        self.headers_filtros.get(altura).copied()
+    }
+
+    /// Header del filtro anterior a la altura indicada: nulo para el génesis.
+    pub fn header_previo(&self, altura: usize) -> Option<[u8; 32]> {
+        match altura.checked_sub(1) {
+            Some(anterior) => self.header_filtro(anterior),
+            None => Some([0; 32]),
+        }
+    }
+}
+
+/// Escribe un stream de bits, del más significativo al menos significativo de cada byte.
+#[derive(Default)]
+struct EscritorBits {
+    bytes: Vec<u8>,
+    cant_bits: usize,
+}
+
+impl EscritorBits {
+    fn escribir_bits(&mut self, valor: u64, cant_bits: u8) {
+        for i in (0..cant_bits).rev() {
+            if self.cant_bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((valor >> i) & 1) as u8;
+            if let Some(ultimo) = self.bytes.last_mut() {
+                *ultimo |= bit << (7 - self.cant_bits % 8);
+            }
+            self.cant_bits += 1;
+        }
+    }
+}
+
+/// Lee un stream de bits, del más significativo al menos significativo de cada byte.
+struct LectorBits<'a> {
+    bytes: &'a [u8],
+    posicion: usize,
+}
+
+impl<'a> LectorBits<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        LectorBits { bytes, posicion: 0 }
+    }
+
+    fn leer_bits(&mut self, cant_bits: u8) -> Option<u64> {
+        let mut valor = 0u64;
+        for _ in 0..cant_bits {
+            let byte = self.bytes.get(self.posicion / 8)?;
+            let bit = (byte >> (7 - self.posicion % 8)) & 1;
+            valor = (valor << 1) | bit as u64;
+            self.posicion += 1;
+        }
+        Some(valor)
+    }
+}
+
+/// Claves de SipHash del filtro: los primeros 16 bytes del hash del bloque.
+fn claves_siphash(block_hash: &[u8; 32]) -> (u64, u64) {
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&block_hash[0..8]);
+    k1.copy_from_slice(&block_hash[8..16]);
+    (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+}
+
+/// Mapea el SipHash del elemento al rango [0, rango) multiplicando y tomando los 64 bits más significativos.
+fn hash_a_rango(elemento: &[u8], claves: (u64, u64), rango: u64) -> u64 {
+    let hash = siphash24::Hash::hash_to_u64_with_keys(claves.0, claves.1, elemento);
+    ((hash as u128 * rango as u128) >> 64) as u64
+}
+
+/// Header de un filtro: el hash del filtro concatenado con el header del filtro del bloque anterior.
+pub fn encadenar_header_filtro(hash_filtro: &[u8; 32], header_previo: &[u8; 32]) -> [u8; 32] {
+    sha256d::Hash::hash(&[hash_filtro.as_slice(), header_previo.as_slice()].concat())
+        .to_byte_array()
+}
+
+/// Recorre los filtros de toda la cadena de bloques, desde el último bloque ya escaneado, y devuelve los headers
+/// de los bloques cuyo filtro coincide con algún script de las wallets, junto con los headers de los filtros
+/// verificados y el hash de su bloque. Estos se deben guardar con guardar_headers_filtros una vez descargados los bloques.
+/// Los headers de los filtros se encadenan desde el genesis (cuyo header previo es nulo), y cada filtro recibido
+/// se verifica contra su hash en la cadena de headers de filtros.
+/// Como un nodo puede mentir sobre la cadena de headers de filtros (por ejemplo, para ocultar transacciones de
+/// las wallets), cada lote de headers se pide también al nodo de verificación; si no coinciden, falla sin
+/// escanear, ya que no se puede saber cuál de los dos es el correcto.
+pub fn escanear_filtros(
+    (socket, verificador): (&mut ConexionPeer, &mut ConexionPeer),
+    headers: &[BlockHeader],
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<(Vec<BlockHeader>, Vec<HeaderFiltroGuardado>), RustifyError> {
+    let wallets = Wallet::cargar_sin_utxos(config.wallets_path.clone(), logger_sender)?;
+    let scripts: Vec<Vec<u8>> = wallets
+        .accounts
+        .values()
+        .map(|account| account.obtain_pk_script())
+        .collect();
+    let headers_guardados = cargar_headers_filtros(config, headers)?;
+    let mut header_previo = headers_guardados.last().copied().unwrap_or([0; 32]);
+    let mut altura = headers_guardados.len();
+    let mut coincidencias = vec![];
+    let mut headers_filtros = vec![];
+    let timeout = Duration::from_secs(config.timeout_respuesta_secs);
+
+    while altura < headers.len() {
+        let fin = (altura + MAX_CFHEADERS_POR_MENSAJE).min(headers.len());
+        let cfheaders = pedir_cfheaders(
+            socket,
+            (altura, &headers[altura..fin]),
+            &header_previo,
+            timeout,
+            logger_sender,
+        )?;
+        match pedir_cfheaders(
+            verificador,
+            (altura, &headers[altura..fin]),
+            &header_previo,
+            timeout,
+            logger_sender,
+        ) {
+            Ok(verificados) if verificados.hashes_filtros == cfheaders.hashes_filtros => {}
+            Ok(_) | Err(RustifyError::FiltroCompactoInvalido) => {
+                log_with_parameters(
+                    Lvl::Error(Action::INB),
+                    format!(
+                        "Los nodos {} y {} enviaron distintos headers de filtros desde la altura {}",
+                        socket.addr(),
+                        verificador.addr(),
+                        altura
+                    ),
+                    logger_sender,
+                );
+                return Err(RustifyError::HeadersFiltrosEnDesacuerdo);
+            }
+            Err(e) => return Err(e),
+        }
+        let hashes_filtros = &cfheaders.hashes_filtros;
+        for inicio_lote in (altura..fin).step_by(MAX_CFILTERS_POR_PEDIDO) {
+            let fin_lote = (inicio_lote + MAX_CFILTERS_POR_PEDIDO).min(fin);
+            let pedido = PedidoFiltros {
+                tipo_filtro: FILTRO_BASICO,
+                altura_inicial: inicio_lote as u32,
+                hash_final: hash_header(&headers[fin_lote - 1]),
+            };
+            escribir_mensaje(socket, &NetworkMessage::GetCFilters(pedido))?;
+            for (header, hash_filtro) in headers[inicio_lote..fin_lote]
+                .iter()
+                .zip(&hashes_filtros[inicio_lote - altura..])
+            {
+                let cfilter = match esperar_respuesta(socket, "cfilter", timeout, logger_sender)? {
+                    NetworkMessage::CFilter(cfilter) => cfilter,
+                    _ => return Err(RustifyError::MensajeInesperado),
+                };
+                let block_hash = hash_header(header);
+                if cfilter.block_hash != block_hash || cfilter.tipo_filtro != FILTRO_BASICO {
+                    return Err(RustifyError::DatosNoSolicitados);
+                }
+                if sha256d::Hash::hash(&cfilter.filtro).to_byte_array() != *hash_filtro {
+                    return Err(RustifyError::FiltroCompactoInvalido);
+                }
+                if FiltroGcs::from_bytes(&block_hash, &cfilter.filtro)?.coincide_alguno(&scripts) {
+                    coincidencias.push(header.clone());
+                }
+            }
+        }
+        let nuevos = cfheaders.headers_filtros();
+        header_previo = *nuevos.last().ok_or(RustifyError::FiltroCompactoInvalido)?;
+        headers_filtros.extend(headers[altura..fin].iter().map(hash_header).zip(nuevos));
+        altura = fin;
+        log_with_parameters(
+            Lvl::Info(Action::INB),
+            format!(
+                "Filtros compactos escaneados hasta la altura {}: {} bloques con transacciones de las wallets.",
+                altura,
+                coincidencias.len()
+            ),
+            logger_sender,
+        );
+    }
+    Ok((coincidencias, headers_filtros))
+}
+
+/// Pide con getcfheaders los hashes de los filtros de los bloques indicados, que empiezan en la altura inicial,
+/// y verifica que sean uno por bloque y que se encadenen con el header del filtro anterior.
+fn pedir_cfheaders(
+    socket: &mut ConexionPeer,
+    (altura_inicial, bloques): (usize, &[BlockHeader]),
+    header_previo: &[u8; 32],
+    timeout: Duration,
+    logger_sender: &Sender<String>,
+) -> Result<CFHeaders, RustifyError> {
+    let hash_final = hash_header(bloques.last().ok_or(RustifyError::NotFound)?);
+    let pedido = PedidoFiltros {
+        tipo_filtro: FILTRO_BASICO,
+        altura_inicial: altura_inicial as u32,
+        hash_final,
+    };
+    escribir_mensaje(socket, &NetworkMessage::GetCFHeaders(pedido))?;
+    let cfheaders = match esperar_respuesta(socket, "cfheaders", timeout, logger_sender)? {
+        NetworkMessage::CFHeaders(cfheaders) => cfheaders,
+        _ => return Err(RustifyError::MensajeInesperado),
+    };
+    if cfheaders.hash_final != hash_final
+        || cfheaders.tipo_filtro != FILTRO_BASICO
+        || cfheaders.hashes_filtros.len() != bloques.len()
+    {
+        return Err(RustifyError::DatosNoSolicitados);
+    }
+    if cfheaders.header_filtro_previo != *header_previo {
+        return Err(RustifyError::FiltroCompactoInvalido);
+    }
+    Ok(cfheaders)
+}
+
+/// Lee los headers de los filtros ya verificados y escaneados (uno por línea, en hexa, desde el genesis,
+/// precedido por el hash de su bloque). Se conservan solamente los de los bloques que siguen en la cadena:
+/// si hubo una reorganización, el archivo se trunca hasta la bifurcación, para volver a escanear la nueva rama.
+/// Si no se configuró filtros_path o el archivo no existe, se escanea toda la cadena.
+fn cargar_headers_filtros(
+    config: &Config,
+    headers: &[BlockHeader],
+) -> Result<Vec<[u8; 32]>, RustifyError> {
+    if config.filtros_path.is_empty() || !Path::new(&config.filtros_path).exists() {
+        return Ok(vec![]);
+    }
+    let contenido = fs::read_to_string(&config.filtros_path)?;
+    let lineas: Vec<&str> = contenido.lines().collect();
+    let mut headers_filtros = vec![];
+    for (linea, header) in lineas.iter().zip(headers) {
+        let (block_hash, header_filtro) = match linea.split_once(' ') {
+            Some(campos) => campos,
+            None => break,
+        };
+        if leer_hexa(block_hash)? != hash_header(header) {
+            break;
+        }
+        headers_filtros.push(leer_hexa(header_filtro)?);
+    }
+    if headers_filtros.len() < lineas.len() {
+        let conservadas: String = lineas[..headers_filtros.len()]
+            .iter()
+            .map(|linea| format!("{}\n", linea))
+            .collect();
+        fs::write(&config.filtros_path, conservadas)?;
+    }
+    Ok(headers_filtros)
+}
+
+/// Agrega al archivo filtros_path los headers de los filtros escaneados, con el hash de su bloque,
+/// para no volver a escanearlos.
+pub fn guardar_headers_filtros(
+    config: &Config,
+    headers_filtros: &[HeaderFiltroGuardado],
+) -> Result<(), RustifyError> {
+    if config.filtros_path.is_empty() {
+        return Ok(());
+    }
+    let mut archivo = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.filtros_path)?;
+    for (block_hash, header) in headers_filtros {
+        writeln!(
+            archivo,
+            "{} {}",
+            escribir_hexa(block_hash),
+            escribir_hexa(header)
+        )?;
+    }
+    Ok(())
+}
+
+fn leer_hexa(texto: &str) -> Result<[u8; 32], RustifyError> {
+    let bytes = (0..texto.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(texto.get(i..i + 2).unwrap_or_default(), 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    Ok(bytes.as_slice().try_into()?)
+}
+
+fn escribir_hexa(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_header(header: &BlockHeader) -> [u8; 32] {
+    sha256d::Hash::hash(&header.as_bytes()).to_byte_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::guardar_bloque_memoria;
+    use crate::outpoint::OutPoint;
+    use crate::red::{parametros_red, ParametrosRed, Red};
+    use crate::txin::TxIn;
+    use crate::txn::Txn;
+    use crate::txout::TxOut;
+
+    /// Transacción que gasta los outpoints indicados y crea un output por cada script.
+    fn txn_test(gastados: &[([u8; 32], u32)], scripts: &[Vec<u8>]) -> Txn {
+        let tx_in: Vec<TxIn> = gastados
+            .iter()
+            .map(|(txid, indice)| TxIn {
+                previous_output: OutPoint {
+                    hash_previous_output_txid: *txid,
+                    output_index: *indice,
+                },
+                script_bytes: CompactSize::new(1),
+                signature_script: vec![0x51],
+                sequence: 0xffffffff,
+            })
+            .collect();
+        let tx_out: Vec<TxOut> = scripts
+            .iter()
+            .map(|script| TxOut {
+                value_amount_satoshis: 1000,
+                pk_script_bytes: CompactSize::new(script.len() as u64),
+                pk_script: script.clone(),
+            })
+            .collect();
+        Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(tx_in.len() as u64),
+            tx_in,
+            tx_out_count: CompactSize::new(tx_out.len() as u64),
+            tx_out,
+            tx_lock_time: 0,
+        }
+    }
+
+    fn bloque_test(header: &BlockHeader, txns: Vec<Txn>) -> SerializedBlock {
+        SerializedBlock {
+            block_header: header.clone(),
+            txn_count: CompactSize::new(txns.len() as u64),
+            txns,
+        }
+    }
+
+    fn txid(txn: &Txn) -> [u8; 32] {
+        sha256d::Hash::hash(&txn.as_bytes()).to_byte_array()
+    }
+
+    fn bytes_desde_hexa(hexa: &str) -> Result<Vec<u8>, RustifyError> {
+        (0..hexa.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hexa[i..i + 2], 16).map_err(RustifyError::from))
+            .collect()
+    }
+
+    /// Vector de prueba de BIP158 del bloque genesis de testnet: el filtro contiene solamente
+    /// el script del output de la coinbase.
+    #[test]
+    fn test_filtro_genesis_testnet() -> Result<(), RustifyError> {
+        let block_hash = ParametrosRed::new(Red::Testnet).genesis_hash();
+        let filtro = bytes_desde_hexa("019dfca8")?;
+        let script_coinbase = bytes_desde_hexa(
+            "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+        )?;
+        let gcs = FiltroGcs::from_bytes(&block_hash, &filtro)?;
+        assert!(gcs.coincide_alguno(&[script_coinbase]));
+        assert!(!gcs.coincide_alguno(&[vec![0x76, 0xa9, 0x14]]));
+
+        let hash_filtro = sha256d::Hash::hash(&filtro).to_byte_array();
+        let mut header = encadenar_header_filtro(&hash_filtro, &[0; 32]);
+        header.reverse();
+        assert_eq!(
+            header.to_vec(),
+            bytes_desde_hexa("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_construir_filtro() -> Result<(), RustifyError> {
+        let block_hash = ParametrosRed::new(Red::Testnet).genesis_hash();
+        let script_coinbase = bytes_desde_hexa(
+            "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+        )?;
+        assert_eq!(
+            construir_filtro(&block_hash, &[script_coinbase]),
+            bytes_desde_hexa("019dfca8")?
+        );
+        assert_eq!(construir_filtro(&block_hash, &[]), vec![0]);
+
+        let scripts: Vec<Vec<u8>> = (0..50u8).map(|i| vec![0x76, 0xa9, 0x14, i]).collect();
+        let gcs = FiltroGcs::from_bytes(&block_hash, &construir_filtro(&block_hash, &scripts))?;
+        assert!(scripts
+            .iter()
+            .all(|script| gcs.coincide_alguno(std::slice::from_ref(script))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfheaders_serializado() -> Result<(), RustifyError> {
+        let cfheaders = CFHeaders {
+            tipo_filtro: FILTRO_BASICO,
+            hash_final: [1; 32],
+            header_filtro_previo: [0; 32],
+            hashes_filtros: vec![[2; 32], [3; 32]],
+        };
+        assert_eq!(CFHeaders::from_bytes(&cfheaders.as_bytes())?, cfheaders);
+        let headers = cfheaders.headers_filtros();
+        assert_eq!(headers[0], encadenar_header_filtro(&[2; 32], &[0; 32]));
+        assert_eq!(headers[1], encadenar_header_filtro(&[3; 32], &headers[0]));
+        assert!(CFHeaders::from_bytes(&cfheaders.as_bytes()[..100]).is_err());
+        Ok(())
+    }
+
+    /// Los scripts gastados se toman de los bloques anteriores o del mismo bloque; si un input gasta
+    /// un output desconocido no se arma el filtro, y los outputs no gastados quedan como estaban.
+    #[test]
+    fn test_elementos_bloque_con_outputs_gastados() {
+        let header =
+            BlockHeader::from_bytes(&parametros_red().genesis_header).expect("header génesis");
+        let script_a = vec![0x51, 0x01];
+        let script_b = vec![0x51, 0x02];
+        let op_return = vec![OP_RETURN, 0x00];
+        let coinbase = txn_test(&[([0; 32], 0xffffffff)], &[script_a.clone(), vec![]]);
+        let mut no_gastados = HashMap::new();
+        let elementos = elementos_bloque(
+            &bloque_test(&header, vec![coinbase.clone()]),
+            &mut no_gastados,
+        );
+        assert_eq!(elementos, Some(vec![script_a.clone()]));
+        assert_eq!(no_gastados.len(), 2);
+
+        // Gasta el output de la coinbase anterior, el output vacío y uno creado en el mismo bloque.
+        let gasta_a = txn_test(
+            &[(txid(&coinbase), 0), (txid(&coinbase), 1)],
+            std::slice::from_ref(&script_b),
+        );
+        let gasta_b = txn_test(&[(txid(&gasta_a), 0)], &[op_return]);
+        let coinbase_2 = txn_test(&[([0; 32], 0xffffffff)], &[]);
+        let bloque = bloque_test(&header, vec![coinbase_2.clone(), gasta_a, gasta_b]);
+        assert_eq!(
+            elementos_bloque(&bloque, &mut no_gastados),
+            Some(vec![script_a, script_b])
+        );
+        assert!(no_gastados.is_empty());
+
+        let desconocido = txn_test(&[([7; 32], 0)], &[vec![0x51]]);
+        let bloque = bloque_test(&header, vec![coinbase_2.clone(), desconocido]);
+        let mut no_gastados = HashMap::from([((txid(&coinbase_2), 0), vec![0x52])]);
+        assert_eq!(elementos_bloque(&bloque, &mut no_gastados), None);
+        assert_eq!(no_gastados.len(), 1);
+    }
+
+    /// Los headers de filtros guardados se conservan mientras sus bloques sigan en la cadena:
+    /// después de una reorganización, el archivo se trunca hasta la bifurcación.
+    #[test]
+    fn test_headers_filtros_guardados_despues_de_reorganizacion() -> Result<(), RustifyError> {
+        let archivo = std::env::temp_dir().join("rustify_test_headers_filtros.txt");
+        let _ = fs::remove_file(&archivo);
+        let config = Config {
+            filtros_path: archivo.to_string_lossy().to_string(),
+            ..Config::new("./node.config").map_err(|_| RustifyError::NotFound)?
+        };
+        let genesis = BlockHeader::from_bytes(&parametros_red().genesis_header)?;
+        let mut siguiente = genesis.clone();
+        siguiente.previous_block_header_hash = hash_header(&genesis);
+        let mut alternativo = siguiente.clone();
+        alternativo.nonce += 1;
+
+        guardar_headers_filtros(
+            &config,
+            &[
+                (hash_header(&genesis), [1; 32]),
+                (hash_header(&siguiente), [2; 32]),
+            ],
+        )?;
+        assert_eq!(
+            cargar_headers_filtros(&config, &[genesis.clone(), siguiente])?,
+            vec![[1; 32], [2; 32]]
+        );
+        assert_eq!(
+            cargar_headers_filtros(&config, &[genesis.clone(), alternativo.clone()])?,
+            vec![[1; 32]]
+        );
+        guardar_headers_filtros(&config, &[(hash_header(&alternativo), [3; 32])])?;
+        assert_eq!(
+            cargar_headers_filtros(&config, &[genesis, alternativo])?,
+            vec![[1; 32], [3; 32]]
+        );
+        fs::remove_file(&archivo)?;
+        Ok(())
+    }
+
+    /// El índice se arma desde el génesis, solamente si se descargan los bloques completos desde él,
+    /// y se vuelve a armar si cambia la cadena.
+    #[test]
+    fn test_indice_filtros_desde_genesis() -> Result<(), RustifyError> {
+        let directorio = std::env::temp_dir().join("rustify_test_indice_filtros");
+        let _ = fs::remove_dir_all(&directorio);
+        let config = Config {
+            blocks_path: directorio.to_string_lossy().to_string(),
+            timestamp_bloque_inicial: 0,
+            modo_spv: false,
+            filtros_compactos: false,
+            ..Config::new("./node.config").map_err(|_| RustifyError::NotFound)?
+        };
+        let genesis = BlockHeader::from_bytes(&parametros_red().genesis_header)?;
+        let mut siguiente = genesis.clone();
+        siguiente.previous_block_header_hash = hash_header(&genesis);
+        let mut alternativo = siguiente.clone();
+        alternativo.nonce += 1;
+        for (i, header) in [&genesis, &siguiente, &alternativo].into_iter().enumerate() {
+            let coinbase = txn_test(&[([0; 32], 0xffffffff)], &[vec![0x51, i as u8]]);
+            guardar_bloque_memoria(
+                bloque_test(header, vec![coinbase]).as_bytes(),
+                &config.blocks_path,
+            )?;
+        }
+
+        let mut indice = IndiceFiltros::default();
+        let config_fecha = Config {
+            timestamp_bloque_inicial: genesis.time + 1,
+            ..config.clone()
+        };
+        let headers = Mutex::new(vec![genesis.clone(), siguiente.clone()]);
+        assert_eq!(indice.actualizar(&headers, &config_fecha)?, 0);
+        assert!(!indice.cubre(0, 0));
+
+        assert_eq!(indice.actualizar(&headers, &config)?, 2);
+        assert!(indice.cubre(0, 1));
+        assert_eq!(indice.header_previo(0), Some([0; 32]));
+        let filtro_siguiente = indice.filtro(1).map(|filtro| filtro.to_vec());
+
+        *headers.lock()? = vec![genesis, alternativo.clone()];
+        assert_eq!(indice.actualizar(&headers, &config)?, 2);
+        assert_eq!(indice.hash_bloque(1), Some(hash_header(&alternativo)));
+        assert!(indice.cubre(0, 1));
+        assert_ne!(
+            indice.filtro(1).map(|filtro| filtro.to_vec()),
+            filtro_siguiente
+        );
+        fs::remove_dir_all(&directorio)?;
+        Ok(())
+    }
+}
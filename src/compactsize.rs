@@ -1,3 +1,5 @@
+use crate::errors::RustifyError;
+
 #[derive(PartialEq, Debug, Default, Clone)]
 pub struct CompactSize {
     pub number: Vec<u8>,
@@ -38,6 +40,23 @@ impl CompactSize {
         )
     }
 
+    /// Lee el compactsize que empieza en el índice indicado, verificando que los bytes alcancen
+    /// (a diferencia de parse_from_byte_array, que se usa con datos ya validados).
+    /// Devuelve su valor y el índice siguiente.
+    pub fn leer(bytes: &[u8], index: usize) -> Result<(u64, usize), RustifyError> {
+        let primero = *bytes.get(index).ok_or(RustifyError::MensajeMalformado)?;
+        let largo = match primero {
+            0xfd => 3,
+            0xfe => 5,
+            0xff => 9,
+            _ => 1,
+        };
+        let compactsize = bytes
+            .get(index..index + largo)
+            .ok_or(RustifyError::MensajeMalformado)?;
+        Ok((Self::parse_to_u64(largo, compactsize), index + largo))
+    }
+
     ///Devuelve el valor en u64 contenido en el CompactSize
     pub fn value(&self) -> u64 {
         Self::parse_to_u64(self.number.len(), &self.number.clone())
@@ -59,9 +78,18 @@ impl CompactSize {
     }
 }
 
+/// Devuelve el slice de largo indicado a partir del índice, o MensajeMalformado si el mensaje
+/// está truncado. Acompaña a CompactSize::leer al parsear payloads recibidos de la red.
+pub fn leer_bytes(bytes: &[u8], index: usize, largo: usize) -> Result<&[u8], RustifyError> {
+    bytes
+        .get(index..index.saturating_add(largo))
+        .ok_or(RustifyError::MensajeMalformado)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::compactsize::CompactSize;
+    use crate::compactsize::{leer_bytes, CompactSize};
+    use crate::errors::RustifyError;
 
     #[test]
     fn compactsize_test() {
@@ -111,4 +139,28 @@ mod tests {
         assert_eq!(CompactSize::new(66).as_bytes(), [66]);
         assert_eq!(CompactSize::new(50000).as_bytes(), [0xfd, 0x50, 0xc3]);
     }
+
+    /// Las lecturas que exceden el largo de los bytes devuelven MensajeMalformado.
+    #[test]
+    fn leer_truncado_test() {
+        let bytes = [0xfd, 0x50, 0xc3, 0x07];
+        assert_eq!(CompactSize::leer(&bytes, 0), Ok((50000, 3)));
+        assert_eq!(
+            CompactSize::leer(&bytes[..2], 0),
+            Err(RustifyError::MensajeMalformado)
+        );
+        assert_eq!(
+            CompactSize::leer(&bytes, 4),
+            Err(RustifyError::MensajeMalformado)
+        );
+        assert_eq!(leer_bytes(&bytes, 3, 1), Ok(&bytes[3..]));
+        assert_eq!(
+            leer_bytes(&bytes, 3, 2),
+            Err(RustifyError::MensajeMalformado)
+        );
+        assert_eq!(
+            leer_bytes(&bytes, 1, usize::MAX),
+            Err(RustifyError::MensajeMalformado)
+        );
+    }
 }
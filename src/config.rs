@@ -1,3 +1,4 @@
+use crate::red::Red;
 use std::fs;
 
 #[derive(Debug, Clone)]
@@ -20,6 +21,20 @@ pub struct Config {
     pub cant_retries: usize,
     pub server_mode: bool,
     pub cant_max_txn_memoria: usize,
+    pub cant_peers: usize,
+    pub addresses_path: String,
+    pub proxy: String,
+    pub proxy_aislar_conexiones: bool,
+    pub network: Red,
+    pub bans_path: String,
+    pub duracion_ban_secs: i64,
+    pub timeout_respuesta_secs: u64,
+    pub ventana_descarga: usize,
+    pub fee_minima_relay: u64,
+    pub modo_spv: bool,
+    pub filtros_compactos: bool,
+    pub filtros_path: String,
+    pub transporte_v2: bool,
 }
 
 impl Config {
@@ -45,6 +60,20 @@ impl Config {
             cant_retries: 0,
             server_mode: true,
             cant_max_txn_memoria: 0,
+            cant_peers: 1,
+            addresses_path: "".to_string(),
+            proxy: "".to_string(),
+            proxy_aislar_conexiones: false,
+            network: Red::Testnet,
+            bans_path: "".to_string(),
+            duracion_ban_secs: 86400,
+            timeout_respuesta_secs: 60,
+            ventana_descarga: 1024,
+            fee_minima_relay: 1000,
+            modo_spv: false,
+            filtros_compactos: false,
+            filtros_path: "".to_string(),
+            transporte_v2: false,
         };
         for line in contents.lines() {
             let parts: Vec<&str> = line.split_whitespace().collect();
@@ -116,17 +145,83 @@ impl Config {
                         .parse()
                         .map_err(|e| format!("Error parsing cant_max_txn_memoria: {}", e))?
                 }
+                "cant_peers" => {
+                    config.cant_peers = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing cant_peers: {}", e))?
+                }
+                "addresses_path" => config.addresses_path = parts[1].to_string(),
+                "proxy" => config.proxy = parts[1].to_string(),
+                "network" => {
+                    config.network = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing network: {}", e))?
+                }
+                "proxy_aislar_conexiones" => {
+                    config.proxy_aislar_conexiones = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing proxy_aislar_conexiones: {}", e))?
+                }
+                "bans_path" => config.bans_path = parts[1].to_string(),
+                "duracion_ban_secs" => {
+                    config.duracion_ban_secs = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing duracion_ban_secs: {}", e))?
+                }
+                "timeout_respuesta_secs" => {
+                    config.timeout_respuesta_secs = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing timeout_respuesta_secs: {}", e))?
+                }
+                "ventana_descarga" => {
+                    config.ventana_descarga = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing ventana_descarga: {}", e))?
+                }
+                "fee_minima_relay" => {
+                    config.fee_minima_relay = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing fee_minima_relay: {}", e))?
+                }
+                "modo_spv" => {
+                    config.modo_spv = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing modo_spv: {}", e))?
+                }
+                "filtros_compactos" => {
+                    config.filtros_compactos = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing filtros_compactos: {}", e))?
+                }
+                "filtros_path" => config.filtros_path = parts[1].to_string(),
+                "transporte_v2" => {
+                    config.transporte_v2 = parts[1]
+                        .parse()
+                        .map_err(|e| format!("Error parsing transporte_v2: {}", e))?
+                }
                 _ => return Err(format!("Unknown config parameter: {}", parts[0])),
             }
         }
+        if config.modo_spv && config.server_mode {
+            return Err("modo_spv is incompatible with server_mode".to_string());
+        }
+        if config.filtros_compactos && (config.modo_spv || config.server_mode) {
+            return Err(
+                "filtros_compactos is incompatible with modo_spv and server_mode".to_string(),
+            );
+        }
         Ok(config)
     }
 
     /// Carga el archivo de configuración en una estructura Config.
     /// Esta estructura es pasada por parámetro donde se requiera un valor configurable.
+    /// Luego del archivo de configuración se puede indicar un comando de baneo (ver ban_manager).
     pub fn load_config(args: &[String]) -> Result<Config, String> {
-        if args.len() != 2 {
-            return Err("Usage: cargo run -- path/to/nodo.config".to_string());
+        if args.len() < 2 {
+            return Err(
+                "Usage: cargo run -- path/to/nodo.config [ban <host> [segundos] | unban <host> | bans]"
+                    .to_string(),
+            );
         }
 
         let config_file_path = &args[1];
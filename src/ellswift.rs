@@ -0,0 +1,342 @@
+use crate::errors::RustifyError;
+use k256::{FieldBytes, FieldElement};
+use rand::{random, Rng};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// Largo de una clave pública codificada con ElligatorSwift: los dos elementos (u, t) de 32 bytes.
+pub const LARGO_ELLSWIFT: usize = 64;
+/// Raíz cuadrada de -3 en el cuerpo de secp256k1 (la que se obtiene elevando a (p + 1) / 4).
+const RAIZ_MENOS_3: [u8; 32] = [
+    0x0a, 0x2d, 0x2b, 0xa9, 0x35, 0x07, 0xf1, 0xdf, 0x23, 0x37, 0x70, 0xc2, 0xa7, 0x97, 0x96, 0x2c,
+    0xc6, 0x1f, 0x6d, 0x15, 0xda, 0x14, 0xec, 0xd4, 0x7d, 0x8d, 0x27, 0xae, 0x1c, 0xd5, 0xf8, 0x52,
+];
+/// 2^256 - p: sumarlo módulo 2^256 equivale a restar p.
+const COMPLEMENTO_P: u64 = 0x1_0000_03d1;
+
+/// Elemento del cuerpo de secp256k1. Envuelve al de k256 normalizando el resultado de cada operación,
+/// para poder escribir las fórmulas de ElligatorSwift sin seguir la magnitud de cada valor.
+#[derive(Debug, Clone, Copy)]
+struct Elemento(FieldElement);
+
+impl Elemento {
+    fn new(valor: u64) -> Elemento {
+        Elemento(FieldElement::from_u64(valor))
+    }
+
+    /// Interpreta los 32 bytes como un entero big endian, reducido módulo p.
+    fn from_bytes(bytes: &[u8; 32]) -> Elemento {
+        if let Some(elemento) = FieldElement::from_bytes(FieldBytes::from_slice(bytes)).into() {
+            return Elemento(elemento);
+        }
+        // Es mayor o igual que p (y menor que 2p): se le resta p.
+        let mut reducido = *bytes;
+        let mut acarreo = COMPLEMENTO_P as u128;
+        for byte in reducido.iter_mut().rev() {
+            let suma = *byte as u128 + (acarreo & 0xff);
+            *byte = suma as u8;
+            acarreo = (acarreo >> 8) + (suma >> 8);
+        }
+        Elemento(
+            FieldElement::from_bytes(FieldBytes::from_slice(&reducido))
+                .unwrap_or(FieldElement::ZERO),
+        )
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        self.0.to_bytes().into()
+    }
+
+    fn es_cero(self) -> bool {
+        self.0.normalizes_to_zero().into()
+    }
+
+    /// Inverso multiplicativo. Como en la especificación, el inverso de 0 se toma como 0.
+    fn inverso(self) -> Elemento {
+        Elemento(self.0.invert().unwrap_or(FieldElement::ZERO).normalize())
+    }
+
+    fn raiz(self) -> Option<Elemento> {
+        Option::from(self.0.sqrt()).map(|raiz: FieldElement| Elemento(raiz.normalize()))
+    }
+
+    fn cuadrado(self) -> Elemento {
+        self * self
+    }
+
+    fn cubo(self) -> Elemento {
+        self * self * self
+    }
+
+    /// Indica si es la coordenada x de algún punto de la curva (x^3 + 7 tiene raíz cuadrada).
+    fn es_x_valida(self) -> bool {
+        (self.cubo() + Elemento::new(7)).raiz().is_some()
+    }
+}
+
+impl PartialEq for Elemento {
+    fn eq(&self, otro: &Elemento) -> bool {
+        (*self - *otro).es_cero()
+    }
+}
+
+impl Add for Elemento {
+    type Output = Elemento;
+
+    fn add(self, otro: Elemento) -> Elemento {
+        Elemento((self.0 + otro.0).normalize())
+    }
+}
+
+impl Sub for Elemento {
+    type Output = Elemento;
+
+    fn sub(self, otro: Elemento) -> Elemento {
+        self + -otro
+    }
+}
+
+impl Mul for Elemento {
+    type Output = Elemento;
+
+    fn mul(self, otro: Elemento) -> Elemento {
+        Elemento(self.0.mul(&otro.0).normalize())
+    }
+}
+
+impl Neg for Elemento {
+    type Output = Elemento;
+
+    fn neg(self) -> Elemento {
+        Elemento(self.0.negate(1).normalize())
+    }
+}
+
+/// Decodifica el par (u, t) a la coordenada x de un punto de la curva (XSwiftEC de BIP324).
+/// Cualquier par de elementos se decodifica a un punto válido.
+fn xswiftec(u: Elemento, t: Elemento) -> Elemento {
+    let raiz_menos_3 = Elemento::from_bytes(&RAIZ_MENOS_3);
+    let siete = Elemento::new(7);
+    let u = if u.es_cero() { Elemento::new(1) } else { u };
+    let mut t = if t.es_cero() { Elemento::new(1) } else { t };
+    if (u.cubo() + t.cuadrado() + siete).es_cero() {
+        t = t + t;
+    }
+    let x = (u.cubo() + siete - t.cuadrado()) * (t + t).inverso();
+    let y = (x + t) * (raiz_menos_3 * u).inverso();
+    let medio = Elemento::new(2).inverso();
+    let candidatos = [
+        u + Elemento::new(4) * y.cuadrado(),
+        (-x * y.inverso() - u) * medio,
+        (x * y.inverso() - u) * medio,
+    ];
+    candidatos
+        .into_iter()
+        .find(|candidato| candidato.es_x_valida())
+        .unwrap_or(candidatos[0])
+}
+
+/// Busca un t tal que xswiftec(u, t) = x (XSwiftECInv de BIP324). Hay hasta 8 soluciones,
+/// y el caso elige cuál devolver; si la del caso no existe, devuelve None.
+fn xswiftec_inv(x: Elemento, u: Elemento, caso: u8) -> Option<Elemento> {
+    let raiz_menos_3 = Elemento::from_bytes(&RAIZ_MENOS_3);
+    let siete = Elemento::new(7);
+    let medio = Elemento::new(2).inverso();
+    let (s, v) = if caso & 2 == 0 {
+        if (-x - u).es_x_valida() {
+            return None;
+        }
+        let s = -(u.cubo() + siete) * (u.cuadrado() + u * x + x.cuadrado()).inverso();
+        (s, x)
+    } else {
+        let s = x - u;
+        if s.es_cero() {
+            return None;
+        }
+        let r = (-s
+            * (Elemento::new(4) * (u.cubo() + siete) + Elemento::new(3) * s * u.cuadrado()))
+        .raiz()?;
+        if caso & 1 == 1 && r.es_cero() {
+            return None;
+        }
+        (s, (r * s.inverso() - u) * medio)
+    };
+    let w = s.raiz()?;
+    let uno = Elemento::new(1);
+    let t = match caso & 5 {
+        0 => -w * (u * (uno - raiz_menos_3) * medio + v),
+        1 => w * (u * (uno + raiz_menos_3) * medio + v),
+        4 => w * (u * (uno - raiz_menos_3) * medio + v),
+        _ => -w * (u * (uno + raiz_menos_3) * medio + v),
+    };
+    Some(t)
+}
+
+/// Codifica la coordenada x con ElligatorSwift, eligiendo u al azar hasta encontrar un t.
+/// El resultado es indistinguible de 64 bytes aleatorios.
+fn codificar(x: Elemento) -> [u8; LARGO_ELLSWIFT] {
+    let mut rng = rand::thread_rng();
+    loop {
+        let u = Elemento::from_bytes(&rng.gen());
+        let caso: u8 = rng.gen_range(0..8);
+        if u.es_cero() {
+            continue;
+        }
+        if let Some(t) = xswiftec_inv(x, u, caso) {
+            if xswiftec(u, t) == x {
+                let mut codificada = [0u8; LARGO_ELLSWIFT];
+                codificada[..32].copy_from_slice(&u.to_bytes());
+                codificada[32..].copy_from_slice(&t.to_bytes());
+                return codificada;
+            }
+        }
+    }
+}
+
+/// Genera un par de claves efímeras para el intercambio de claves de BIP324:
+/// la clave privada y la clave pública codificada con ElligatorSwift.
+pub fn crear_claves_ellswift() -> (SecretKey, [u8; LARGO_ELLSWIFT]) {
+    let secp = Secp256k1::new();
+    let clave_privada = loop {
+        if let Ok(clave) = SecretKey::from_slice(&random::<[u8; 32]>()) {
+            break clave;
+        }
+    };
+    let clave_publica = PublicKey::from_secret_key(&secp, &clave_privada).serialize();
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&clave_publica[1..]);
+    (clave_privada, codificar(Elemento::from_bytes(&x)))
+}
+
+/// ECDH con la clave pública remota codificada con ElligatorSwift.
+/// Devuelve la coordenada x del punto compartido.
+pub fn ecdh_ellswift(
+    clave_remota: &[u8; LARGO_ELLSWIFT],
+    clave_privada: &SecretKey,
+) -> Result<[u8; 32], RustifyError> {
+    let mut u = [0u8; 32];
+    let mut t = [0u8; 32];
+    u.copy_from_slice(&clave_remota[..32]);
+    t.copy_from_slice(&clave_remota[32..]);
+    let x = xswiftec(Elemento::from_bytes(&u), Elemento::from_bytes(&t));
+
+    // Cualquiera de los dos puntos con esa x sirve, porque solamente se usa la x del resultado.
+    let compartido = PublicKey::from_slice(&[&[0x02], &x.to_bytes()[..]].concat())
+        .and_then(|punto| punto.mul_tweak(&Secp256k1::new(), &Scalar::from(*clave_privada)))
+        .map_err(|_| RustifyError::HandshakeV2Invalido)?
+        .serialize();
+    let mut x_compartida = [0u8; 32];
+    x_compartida.copy_from_slice(&compartido[1..]);
+    Ok(x_compartida)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ecdh_ellswift_es_simetrico() -> Result<(), RustifyError> {
+        let (privada_a, publica_a) = crear_claves_ellswift();
+        let (privada_b, publica_b) = crear_claves_ellswift();
+        assert_eq!(
+            ecdh_ellswift(&publica_b, &privada_a)?,
+            ecdh_ellswift(&publica_a, &privada_b)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_codificar_y_decodificar_x() {
+        let secp = Secp256k1::new();
+        for _ in 0..8 {
+            let (privada, codificada) = crear_claves_ellswift();
+            let publica = PublicKey::from_secret_key(&secp, &privada).serialize();
+            let mut u = [0u8; 32];
+            let mut t = [0u8; 32];
+            u.copy_from_slice(&codificada[..32]);
+            t.copy_from_slice(&codificada[32..]);
+            let x = xswiftec(Elemento::from_bytes(&u), Elemento::from_bytes(&t));
+            assert_eq!(x.to_bytes(), publica[1..]);
+        }
+    }
+
+    #[test]
+    fn test_reduce_los_bytes_mayores_que_p() {
+        // p + 5 se reduce a 5.
+        let mut bytes = [0xff; 32];
+        bytes[27] = 0xfe;
+        bytes[28..].copy_from_slice(&[0xff, 0xff, 0xfc, 0x34]);
+        assert_eq!(Elemento::from_bytes(&bytes), Elemento::new(5));
+    }
+
+    fn hexa(texto: &str) -> Vec<u8> {
+        (0..texto.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&texto[i..i + 2], 16).unwrap_or_default())
+            .collect()
+    }
+
+    /// Vectores de ellswift_decode_test_vectors.csv de BIP324: la codificación (u, t) y la x que resulta
+    /// de decodificarla. Incluyen u o t iguales a 0 o mayores o iguales que p, y los casos en los que cada
+    /// uno de los tres candidatos de XSwiftEC es el primero válido.
+    const VECTORES_DECODIFICACION: [(&str, &str); 45] = [
+        ("00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000", "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"),
+        ("000000000000000000000000000000000000000000000000000000000000000001d3475bf7655b0fb2d852921035b2ef607f49069b97454e6795251062741771", "b5da00b73cd6560520e7c364086e7cd23a34bf60d0e707be9fc34d4cd5fdfa2c"),
+        ("000000000000000000000000000000000000000000000000000000000000000082277c4a71f9d22e66ece523f8fa08741a7c0912c66a69ce68514bfd3515b49f", "f482f2e241753ad0fb89150d8491dc1e34ff0b8acfbb442cfe999e2e5e6fd1d2"),
+        ("00000000000000000000000000000000000000000000000000000000000000008421cc930e77c9f514b6915c3dbe2a94c6d8f690b5b739864ba6789fb8a55dd0", "9f59c40275f5085a006f05dae77eb98c6fd0db1ab4a72ac47eae90a4fc9e57e0"),
+        ("0000000000000000000000000000000000000000000000000000000000000000bde70df51939b94c9c24979fa7dd04ebd9b3572da7802290438af2a681895441", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa9fffffd6b"),
+        ("0000000000000000000000000000000000000000000000000000000000000000d19c182d2759cd99824228d94799f8c6557c38a1c0d6779b9d4b729c6f1ccc42", "70720db7e238d04121f5b1afd8cc5ad9d18944c6bdc94881f502b7a3af3aecff"),
+        ("0000000000000000000000000000000000000000000000000000000000000000fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"),
+        ("0a2d2ba93507f1df233770c2a797962cc61f6d15da14ecd47d8d27ae1cd5f8530000000000000000000000000000000000000000000000000000000000000000", "532167c11200b08c0e84a354e74dcc40f8b25f4fe686e30869526366278a0688"),
+        ("0a2d2ba93507f1df233770c2a797962cc61f6d15da14ecd47d8d27ae1cd5f853fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "532167c11200b08c0e84a354e74dcc40f8b25f4fe686e30869526366278a0688"),
+        ("0ffde9ca81d751e9cdaffc1a50779245320b28996dbaf32f822f20117c22fbd6c74d99efceaa550f1ad1c0f43f46e7ff1ee3bd0162b7bf55f2965da9c3450646", "74e880b3ffd18fe3cddf7902522551ddf97fa4a35a3cfda8197f947081a57b8f"),
+        ("0ffde9ca81d751e9cdaffc1a50779245320b28996dbaf32f822f20117c22fbd6ffffffffffffffffffffffffffffffffffffffffffffffffffffffff156ca896", "377b643fce2271f64e5c8101566107c1be4980745091783804f654781ac9217c"),
+        ("123658444f32be8f02ea2034afa7ef4bbe8adc918ceb49b12773b625f490b368ffffffffffffffffffffffffffffffffffffffffffffffffffffffff8dc5fe11", "ed16d65cf3a9538fcb2c139f1ecbc143ee14827120cbc2659e667256800b8142"),
+        ("146f92464d15d36e35382bd3ca5b0f976c95cb08acdcf2d5b3570617990839d7ffffffffffffffffffffffffffffffffffffffffffffffffffffffff3145e93b", "0d5cd840427f941f65193079ab8e2e83024ef2ee7ca558d88879ffd879fb6657"),
+        ("15fdf5cf09c90759add2272d574d2bb5fe1429f9f3c14c65e3194bf61b82aa73ffffffffffffffffffffffffffffffffffffffffffffffffffffffff04cfd906", "16d0e43946aec93f62d57eb8cde68951af136cf4b307938dd1447411e07bffe1"),
+        ("1f67edf779a8a649d6def60035f2fa22d022dd359079a1a144073d84f19b92d50000000000000000000000000000000000000000000000000000000000000000", "025661f9aba9d15c3118456bbe980e3e1b8ba2e047c737a4eb48a040bb566f6c"),
+        ("1f67edf779a8a649d6def60035f2fa22d022dd359079a1a144073d84f19b92d5fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "025661f9aba9d15c3118456bbe980e3e1b8ba2e047c737a4eb48a040bb566f6c"),
+        ("1fe1e5ef3fceb5c135ab7741333ce5a6e80d68167653f6b2b24bcbcfaaaff507fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "98bec3b2a351fa96cfd191c1778351931b9e9ba9ad1149f6d9eadca80981b801"),
+        ("4056a34a210eec7892e8820675c860099f857b26aad85470ee6d3cf1304a9dcf375e70374271f20b13c9986ed7d3c17799698cfc435dbed3a9f34b38c823c2b4", "868aac2003b29dbcad1a3e803855e078a89d16543ac64392d122417298cec76e"),
+        ("4197ec3723c654cfdd32ab075506648b2ff5070362d01a4fff14b336b78f963fffffffffffffffffffffffffffffffffffffffffffffffffffffffffb3ab1e95", "ba5a6314502a8952b8f456e085928105f665377a8ce27726a5b0eb7ec1ac0286"),
+        ("47eb3e208fedcdf8234c9421e9cd9a7ae873bfbdbc393723d1ba1e1e6a8e6b24ffffffffffffffffffffffffffffffffffffffffffffffffffffffff7cd12cb1", "d192d52007e541c9807006ed0468df77fd214af0a795fe119359666fdcf08f7c"),
+        ("5eb9696a2336fe2c3c666b02c755db4c0cfd62825c7b589a7b7bb442e141c1d693413f0052d49e64abec6d5831d66c43612830a17df1fe4383db896468100221", "ef6e1da6d6c7627e80f7a7234cb08a022c1ee1cf29e4d0f9642ae924cef9eb38"),
+        ("7bf96b7b6da15d3476a2b195934b690a3a3de3e8ab8474856863b0de3af90b0e0000000000000000000000000000000000000000000000000000000000000000", "50851dfc9f418c314a437295b24feeea27af3d0cd2308348fda6e21c463e46ff"),
+        ("7bf96b7b6da15d3476a2b195934b690a3a3de3e8ab8474856863b0de3af90b0efffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "50851dfc9f418c314a437295b24feeea27af3d0cd2308348fda6e21c463e46ff"),
+        ("851b1ca94549371c4f1f7187321d39bf51c6b7fb61f7cbf027c9da62021b7a65fc54c96837fb22b362eda63ec52ec83d81bedd160c11b22d965d9f4a6d64d251", "3e731051e12d33237eb324f2aa5b16bb868eb49a1aa1fadc19b6e8761b5a5f7b"),
+        ("943c2f775108b737fe65a9531e19f2fc2a197f5603e3a2881d1d83e4008f91250000000000000000000000000000000000000000000000000000000000000000", "311c61f0ab2f32b7b1f0223fa72f0a78752b8146e46107f8876dd9c4f92b2942"),
+        ("943c2f775108b737fe65a9531e19f2fc2a197f5603e3a2881d1d83e4008f9125fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "311c61f0ab2f32b7b1f0223fa72f0a78752b8146e46107f8876dd9c4f92b2942"),
+        ("a0f18492183e61e8063e573606591421b06bc3513631578a73a39c1c3306239f2f32904f0d2a33ecca8a5451705bb537d3bf44e071226025cdbfd249fe0f7ad6", "97a09cf1a2eae7c494df3c6f8a9445bfb8c09d60832f9b0b9d5eabe25fbd14b9"),
+        ("a1ed0a0bd79d8a23cfe4ec5fef5ba5cccfd844e4ff5cb4b0f2e71627341f1c5b17c499249e0ac08d5d11ea1c2c8ca7001616559a7994eadec9ca10fb4b8516dc", "65a89640744192cdac64b2d21ddf989cdac7500725b645bef8e2200ae39691f2"),
+        ("ba94594a432721aa3580b84c161d0d134bc354b690404d7cd4ec57c16d3fbe98ffffffffffffffffffffffffffffffffffffffffffffffffffffffffea507dd7", "5e0d76564aae92cb347e01a62afd389a9aa401c76c8dd227543dc9cd0efe685a"),
+        ("bcaf7219f2f6fbf55fe5e062dce0e48c18f68103f10b8198e974c184750e1be3932016cbf69c4471bd1f656c6a107f1973de4af7086db897277060e25677f19a", "2d97f96cac882dfe73dc44db6ce0f1d31d6241358dd5d74eb3d3b50003d24c2b"),
+        ("bcaf7219f2f6fbf55fe5e062dce0e48c18f68103f10b8198e974c184750e1be3ffffffffffffffffffffffffffffffffffffffffffffffffffffffff6507d09a", "e7008afe6e8cbd5055df120bd748757c686dadb41cce75e4addcc5e02ec02b44"),
+        ("c5981bae27fd84401c72a155e5707fbb811b2b620645d1028ea270cbe0ee225d4b62aa4dca6506c1acdbecc0552569b4b21436a5692e25d90d3bc2eb7ce24078", "948b40e7181713bc018ec1702d3d054d15746c59a7020730dd13ecf985a010d7"),
+        ("c894ce48bfec433014b931a6ad4226d7dbd8eaa7b6e3faa8d0ef94052bcf8cff336eeb3919e2b4efb746c7f71bbca7e9383230fbbc48ffafe77e8bcc69542471", "f1c91acdc2525330f9b53158434a4d43a1c547cff29f15506f5da4eb4fe8fa5a"),
+        ("cbb0deab125754f1fdb2038b0434ed9cb3fb53ab735391129994a535d925f6730000000000000000000000000000000000000000000000000000000000000000", "872d81ed8831d9998b67cb7105243edbf86c10edfebb786c110b02d07b2e67cd"),
+        ("d917b786dac35670c330c9c5ae5971dfb495c8ae523ed97ee2420117b171f41effffffffffffffffffffffffffffffffffffffffffffffffffffffff2001f6f6", "e45b71e110b831f2bdad8651994526e58393fde4328b1ec04d59897142584691"),
+        ("e28bd8f5929b467eb70e04332374ffb7e7180218ad16eaa46b7161aa679eb4260000000000000000000000000000000000000000000000000000000000000000", "66b8c980a75c72e598d383a35a62879f844242ad1e73ff12edaa59f4e58632b5"),
+        ("e28bd8f5929b467eb70e04332374ffb7e7180218ad16eaa46b7161aa679eb426fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "66b8c980a75c72e598d383a35a62879f844242ad1e73ff12edaa59f4e58632b5"),
+        ("e7ee5814c1706bf8a89396a9b032bc014c2cac9c121127dbf6c99278f8bb53d1dfd04dbcda8e352466b6fcd5f2dea3e17d5e133115886eda20db8a12b54de71b", "e842c6e3529b234270a5e97744edc34a04d7ba94e44b6d2523c9cf0195730a50"),
+        ("f292e46825f9225ad23dc057c1d91c4f57fcb1386f29ef10481cb1d22518593fffffffffffffffffffffffffffffffffffffffffffffffffffffffff7011c989", "3cea2c53b8b0170166ac7da67194694adacc84d56389225e330134dab85a4d55"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f01d3475bf7655b0fb2d852921035b2ef607f49069b97454e6795251062741771", "b5da00b73cd6560520e7c364086e7cd23a34bf60d0e707be9fc34d4cd5fdfa2c"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f4218f20ae6c646b363db68605822fb14264ca8d2587fdd6fbc750d587e76a7ee", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa9fffffd6b"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f82277c4a71f9d22e66ece523f8fa08741a7c0912c66a69ce68514bfd3515b49f", "f482f2e241753ad0fb89150d8491dc1e34ff0b8acfbb442cfe999e2e5e6fd1d2"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f8421cc930e77c9f514b6915c3dbe2a94c6d8f690b5b739864ba6789fb8a55dd0", "9f59c40275f5085a006f05dae77eb98c6fd0db1ab4a72ac47eae90a4fc9e57e0"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2fd19c182d2759cd99824228d94799f8c6557c38a1c0d6779b9d4b729c6f1ccc42", "70720db7e238d04121f5b1afd8cc5ad9d18944c6bdc94881f502b7a3af3aecff"),
+        ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2ffffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f", "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"),
+    ];
+
+    #[test]
+    fn test_vectores_decodificacion_bip324() {
+        for (codificada, x) in VECTORES_DECODIFICACION {
+            let bytes = hexa(codificada);
+            let mut u = [0u8; 32];
+            let mut t = [0u8; 32];
+            u.copy_from_slice(&bytes[..32]);
+            t.copy_from_slice(&bytes[32..]);
+            let decodificada = xswiftec(Elemento::from_bytes(&u), Elemento::from_bytes(&t));
+            assert_eq!(decodificada.to_bytes().to_vec(), hexa(x), "{}", codificada);
+        }
+    }
+}
@@ -1,16 +1,22 @@
 use std::collections::HashMap;
-use std::net::TcpStream;
 use std::string::FromUtf8Error;
 use std::sync::mpsc::SendError;
-use std::sync::{mpsc::Receiver, MutexGuard};
+use std::sync::{MutexGuard, WaitTimeoutResult};
 
+use crate::address_manager::DireccionConocida;
+use crate::addrv2::DireccionNodo;
 use crate::block_header::BlockHeader;
+use crate::compact_filter::IndiceFiltros;
+use crate::event_loop::{ConexionPeer, EstadoCola};
 use crate::gui_events::GuiEvent;
 use crate::inv::Inv;
+use crate::peer_manager::Peer;
+use crate::threadpool::EstadoDescargas;
 use crate::txn::Txn;
+use crate::utxo::SetUtxos;
 use crate::wallet_events::WalletEvent;
 
-type TrxServer = Vec<(String, Txn)>;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum RustifyError {
@@ -44,6 +50,46 @@ pub enum RustifyError {
     NoSeEncontroTransaccionPedidaPorCliente,
     ElNodoNoEncuentraBloquePedido,
     ElNodoNoEncuentraTransaccionPedida,
+    CantPeers,
+    NoHayPeersDisponibles,
+    ErrorParseoAddr,
+    ErrorProxy,
+    CheckpointInvalido,
+    RedYaEstablecida,
+    MensajeMalformado,
+    MensajeInesperado,
+    MagicInvalido,
+    ChecksumInvalido,
+    PayloadExcedido,
+    BloqueInvalido,
+    DatosNoSolicitados,
+    ComandoBanInvalido,
+    ColaMensajesLlena,
+    TimeoutRespuesta,
+    DescargaDeBloquesIncompleta,
+    BloqueNoSolicitado,
+    HeadersInvalidos,
+    NodoSinFiltrosBloom,
+    FiltroSpvYaEstablecido,
+    NodoSinFiltrosCompactos,
+    FiltroCompactoInvalido,
+    HeadersFiltrosEnDesacuerdo,
+    HandshakeV2Invalido,
+    PaqueteCifradoInvalido,
+    CoinbaseInvalida,
+    AlturaCoinbaseInvalida,
+    BloqueExcedePeso,
+    BloqueExcedeSigops,
+    InputInexistente,
+    DobleGasto,
+    MontosInvalidos,
+    RecompensaCoinbaseExcedida,
+    CoinbaseInmadura,
+    ScriptInvalido,
+    FirmaInvalida,
+    LockTimeNoCumplido,
+    ScriptNoEstandar,
+    RedIncorrecta,
 }
 
 impl From<std::io::Error> for RustifyError {
@@ -51,6 +97,7 @@ impl From<std::io::Error> for RustifyError {
         match value.kind() {
             std::io::ErrorKind::NotFound => RustifyError::NotFound,
             std::io::ErrorKind::UnexpectedEof => RustifyError::EofInesperado,
+            std::io::ErrorKind::BrokenPipe => RustifyError::PipeRoto,
             _ => {
                 let now = chrono::Local::now();
                 let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
@@ -77,12 +124,6 @@ impl From<std::str::Utf8Error> for RustifyError {
     }
 }
 
-impl From<std::sync::mpsc::SendError<BlockHeader>> for RustifyError {
-    fn from(_value: std::sync::mpsc::SendError<BlockHeader>) -> Self {
-        RustifyError::ChannelSend
-    }
-}
-
 impl From<std::sync::mpsc::SendError<WalletEvent>> for RustifyError {
     fn from(_value: std::sync::mpsc::SendError<WalletEvent>) -> Self {
         RustifyError::EnvioEventoWallet
@@ -107,14 +148,14 @@ impl From<std::num::ParseIntError> for RustifyError {
     }
 }
 
-impl From<std::sync::PoisonError<MutexGuard<'_, Receiver<BlockHeader>>>> for RustifyError {
-    fn from(_value: std::sync::PoisonError<MutexGuard<'_, Receiver<BlockHeader>>>) -> Self {
+impl From<std::sync::PoisonError<MutexGuard<'_, EstadoDescargas>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, EstadoDescargas>>) -> Self {
         RustifyError::MutexPoisonError
     }
 }
 
-impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<String, TcpStream>>>> for RustifyError {
-    fn from(_value: std::sync::PoisonError<MutexGuard<'_, HashMap<String, TcpStream>>>) -> Self {
+impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<String, ConexionPeer>>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, HashMap<String, ConexionPeer>>>) -> Self {
         RustifyError::MutexPoisonError
     }
 }
@@ -167,11 +208,110 @@ impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<Vec<u8>, usize>>>> for R
     }
 }
 
+impl From<std::sync::PoisonError<MutexGuard<'_, Vec<Peer>>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, Vec<Peer>>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<DireccionNodo, DireccionConocida>>>>
+    for RustifyError
+{
+    fn from(
+        _value: std::sync::PoisonError<MutexGuard<'_, HashMap<DireccionNodo, DireccionConocida>>>,
+    ) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<String, i64>>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, HashMap<String, i64>>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<String, u64>>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, HashMap<String, u64>>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, IndiceFiltros>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, IndiceFiltros>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, HashMap<String, u32>>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, HashMap<String, u32>>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, SetUtxos>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, SetUtxos>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<MutexGuard<'_, EstadoCola>>> for RustifyError {
+    fn from(_value: std::sync::PoisonError<MutexGuard<'_, EstadoCola>>) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
+impl From<std::sync::PoisonError<(MutexGuard<'_, EstadoCola>, WaitTimeoutResult)>>
+    for RustifyError
+{
+    fn from(
+        _value: std::sync::PoisonError<(MutexGuard<'_, EstadoCola>, WaitTimeoutResult)>,
+    ) -> Self {
+        RustifyError::MutexPoisonError
+    }
+}
+
 /// Catchea los errores, si los hay, en funciones que no retornan nada en su Ok()
 pub fn catch(action: RustifyError) {
     println!("FATAL ERROR: {}", obtener_mensaje_personalizado(action));
 }
 
+impl RustifyError {
+    /// Puntos de mal comportamiento que suma el nodo remoto que provocó el error.
+    /// Los errores que no son culpa del nodo remoto (conexión caída, timeouts, etc) no suman.
+    pub fn puntaje_mal_comportamiento(&self) -> u32 {
+        match self {
+            RustifyError::MagicInvalido
+            | RustifyError::PayloadExcedido
+            | RustifyError::BloqueInvalido
+            | RustifyError::HeadersInvalidos
+            | RustifyError::FiltroCompactoInvalido
+            | RustifyError::HandshakeV2Invalido
+            | RustifyError::PaqueteCifradoInvalido
+            | RustifyError::CoinbaseInvalida
+            | RustifyError::AlturaCoinbaseInvalida
+            | RustifyError::BloqueExcedePeso
+            | RustifyError::BloqueExcedeSigops
+            | RustifyError::InputInexistente
+            | RustifyError::DobleGasto
+            | RustifyError::MontosInvalidos
+            | RustifyError::RecompensaCoinbaseExcedida
+            | RustifyError::CoinbaseInmadura
+            | RustifyError::ScriptInvalido
+            | RustifyError::FirmaInvalida
+            | RustifyError::LockTimeNoCumplido => 100,
+            RustifyError::MensajeMalformado | RustifyError::DatosNoSolicitados => 20,
+            RustifyError::ChecksumInvalido => 10,
+            _ => 0,
+        }
+    }
+
+    /// Indica si el error se debe a que el nodo remoto violó el protocolo
+    /// (en cuyo caso se lo desconecta y penaliza), y no a una falla de la conexión.
+    pub fn es_violacion_protocolo(&self) -> bool {
+        self.puntaje_mal_comportamiento() > 0
+    }
+}
+
 /// Matchea los tipos de errores con un mensaje personalizado a mostrar en pantalla
 pub fn obtener_mensaje_personalizado(tipo: RustifyError) -> String {
     let mensaje = match tipo {
@@ -211,6 +351,84 @@ pub fn obtener_mensaje_personalizado(tipo: RustifyError) -> String {
         RustifyError::NoSeEncontroTransaccionPedidaPorCliente => "No se encontró la transaccion solicitada por el nodo cliente",
         RustifyError::ElNodoNoEncuentraTransaccionPedida => "El nodo no tiene la transaccion solicitada",
         RustifyError::EnvioInvNotificar => "Error al enviar inv desde el listener al servidor",
+        RustifyError::CantPeers => "Cantidad de peers invalida, debe ser mayor a 0",
+        RustifyError::NoHayPeersDisponibles => "No hay peers sanos disponibles para realizar el pedido",
+        RustifyError::ErrorParseoAddr => "No se pudo parsear la dirección recibida en el mensaje addr",
+        RustifyError::ErrorProxy => "El proxy SOCKS5 rechazó la conexión o respondió de forma inválida",
+        RustifyError::CheckpointInvalido => "El header recibido no coincide con el checkpoint de la red",
+        RustifyError::RedYaEstablecida => "Ya se había seleccionado otra red para el nodo",
+        RustifyError::MensajeMalformado => "El mensaje recibido no respeta el formato del protocolo",
+        RustifyError::MensajeInesperado => "Se recibió un mensaje distinto al esperado",
+        RustifyError::MagicInvalido => "El mensaje recibido tiene un magic de otra red",
+        RustifyError::ChecksumInvalido => "El checksum del mensaje recibido no coincide con su payload",
+        RustifyError::PayloadExcedido => "El mensaje recibido supera el tamaño máximo permitido para su comando",
+        RustifyError::BloqueInvalido => "El bloque recibido no cumple la proof of work o la proof of inclusion",
+        RustifyError::DatosNoSolicitados => "El nodo envió datos que no fueron solicitados",
+        RustifyError::ComandoBanInvalido => "Comando inválido. Uso: ban <host> [segundos] | unban <host> | bans",
+        RustifyError::ColaMensajesLlena => "El nodo envió más mensajes de los que se llegan a procesar",
+        RustifyError::TimeoutRespuesta => "El nodo no respondió el pedido dentro del tiempo límite",
+        RustifyError::DescargaDeBloquesIncompleta => {
+            "No se pudieron descargar todos los bloques pedidos"
+        }
+        RustifyError::BloqueNoSolicitado => "El nodo envió un bloque distinto del pedido",
+        RustifyError::HeadersInvalidos => {
+            "Los headers anunciados no se encadenan o no cumplen la proof of work"
+        }
+        RustifyError::NodoSinFiltrosBloom => {
+            "El nodo no soporta filtros bloom (BIP37), necesarios en modo SPV"
+        }
+        RustifyError::FiltroSpvYaEstablecido => "Ya se había establecido el filtro de las wallets",
+        RustifyError::NodoSinFiltrosCompactos => {
+            "El nodo no sirve filtros compactos de bloques (BIP157)"
+        }
+        RustifyError::FiltroCompactoInvalido => {
+            "El filtro compacto recibido no coincide con la cadena de headers de filtros"
+        }
+        RustifyError::HeadersFiltrosEnDesacuerdo => {
+            "Dos nodos enviaron distintas cadenas de headers de filtros compactos"
+        }
+        RustifyError::HandshakeV2Invalido => {
+            "El nodo no completó correctamente el handshake del transporte cifrado (BIP324)"
+        }
+        RustifyError::PaqueteCifradoInvalido => {
+            "No se pudo autenticar un paquete del transporte cifrado (BIP324)"
+        }
+        RustifyError::CoinbaseInvalida => {
+            "La primera transacción del bloque no es una coinbase, o hay más de una coinbase"
+        }
+        RustifyError::AlturaCoinbaseInvalida => {
+            "La coinbase del bloque no empieza con la altura del bloque (BIP34)"
+        }
+        RustifyError::BloqueExcedePeso => "El bloque supera el peso máximo permitido",
+        RustifyError::BloqueExcedeSigops => {
+            "El bloque supera la cantidad máxima de operaciones de firma"
+        }
+        RustifyError::InputInexistente => {
+            "Una transacción del bloque gasta un output que no existe o ya fue gastado"
+        }
+        RustifyError::DobleGasto => "Dos inputs del bloque gastan el mismo output",
+        RustifyError::MontosInvalidos => {
+            "Una transacción del bloque tiene montos inválidos o gasta más de lo que recibe"
+        }
+        RustifyError::RecompensaCoinbaseExcedida => {
+            "La coinbase cobra más que el subsidio más las fees del bloque"
+        }
+        RustifyError::CoinbaseInmadura => {
+            "Una transacción del bloque gasta un output de coinbase que todavía no maduró"
+        }
+        RustifyError::ScriptInvalido => {
+            "El script de un input no se pudo ejecutar o no terminó en verdadero"
+        }
+        RustifyError::FirmaInvalida => "La firma de un input no es válida",
+        RustifyError::LockTimeNoCumplido => {
+            "Un input no cumple el locktime que exige su script (CLTV/CSV)"
+        }
+        RustifyError::ScriptNoEstandar => {
+            "El script de un input no cumple las reglas de estandaridad"
+        }
+        RustifyError::RedIncorrecta => {
+            "La dirección o clave no corresponde a la red seleccionada"
+        }
     };
     mensaje.to_string()
 }
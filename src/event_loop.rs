@@ -0,0 +1,1036 @@
+use crate::{
+    addrv2::DireccionNodo,
+    errors::{obtener_mensaje_personalizado, RustifyError},
+    logger::{log, log_with_parameters, Action, Lvl},
+    message_header::MESSAGE_HEADER_SIZE,
+    network_message::{decodificar_mensaje, NetworkMessage, MAX_PAYLOAD_SIZE},
+    transporte_v2::{es_transporte_v1, TransporteV2, MAX_PAQUETE},
+};
+use mio::{
+    net::{TcpListener as MioTcpListener, TcpStream as MioTcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, OnceLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const TOKEN_WAKER: Token = Token(usize::MAX);
+const CAPACIDAD_EVENTOS: usize = 1024;
+const TAMANIO_LECTURA: usize = 64 * 1024;
+/// Cantidad máxima de bytes que se leen de una conexión por cada vez que se la atiende, para no
+/// desatender al resto de las conexiones si un nodo envía datos sin parar. Lo que quede en el socket
+/// se lee en la siguiente vuelta del event loop.
+const MAX_LECTURA_POR_EVENTO: usize = 4 * TAMANIO_LECTURA;
+/// Cantidad máxima de bytes recibidos que pueden quedar sin decodificar: un único mensaje incompleto
+/// del tamaño máximo, con su header (v1) o como paquete cifrado (v2).
+const MAX_BYTES_ENTRADA: usize = if MESSAGE_HEADER_SIZE + MAX_PAYLOAD_SIZE > MAX_PAQUETE {
+    MESSAGE_HEADER_SIZE + MAX_PAYLOAD_SIZE
+} else {
+    MAX_PAQUETE
+};
+/// Cantidad máxima de bytes de mensajes recibidos y todavía no consumidos por conexión.
+/// Alcanza para todos los bloques que se piden a un nodo de una vez, aunque tengan el tamaño máximo.
+/// Si un nodo la supera, se lo desconecta en lugar de descartar sus mensajes.
+pub const MAX_BYTES_EN_COLA: usize = 16 * MAX_PAYLOAD_SIZE;
+
+static EVENT_LOOP: OnceLock<EventLoopHandle> = OnceLock::new();
+
+/// Cola de mensajes recibidos de una conexión, en el orden en que llegaron.
+/// El event loop la llena y el thread que esté usando la conexión la consume.
+#[derive(Debug, Default)]
+struct ColaMensajes {
+    estado: Mutex<EstadoCola>,
+    disponible: Condvar,
+}
+
+/// Mensajes pendientes de la cola (con los bytes que ocupaban al recibirlos) y su total,
+/// y el error con el que se cerró la conexión (si se cerró).
+/// También indica si ya se completó el handshake del transporte cifrado, para quien lo esté esperando.
+#[derive(Debug, Default)]
+pub struct EstadoCola {
+    mensajes: VecDeque<(NetworkMessage, usize)>,
+    bytes: usize,
+    cierre: Option<RustifyError>,
+    cifrada: bool,
+}
+
+impl EstadoCola {
+    fn sacar(&mut self) -> Option<NetworkMessage> {
+        let (mensaje, tamanio) = self.mensajes.pop_front()?;
+        self.bytes -= tamanio;
+        Some(mensaje)
+    }
+}
+
+impl ColaMensajes {
+    /// Encola el mensaje recibido, que ocupaba la cantidad de bytes indicada.
+    /// Devuelve ColaMensajesLlena si los mensajes sin consumir superan MAX_BYTES_EN_COLA.
+    fn agregar(&self, mensaje: NetworkMessage, tamanio: usize) -> Result<(), RustifyError> {
+        let mut estado = self.estado.lock()?;
+        if estado.bytes + tamanio > MAX_BYTES_EN_COLA {
+            return Err(RustifyError::ColaMensajesLlena);
+        }
+        estado.bytes += tamanio;
+        estado.mensajes.push_back((mensaje, tamanio));
+        self.disponible.notify_all();
+        Ok(())
+    }
+
+    fn cerrar(&self, error: RustifyError) -> Result<(), RustifyError> {
+        let mut estado = self.estado.lock()?;
+        if estado.cierre.is_none() {
+            estado.cierre = Some(error);
+        }
+        self.disponible.notify_all();
+        Ok(())
+    }
+
+    fn marcar_cifrada(&self) -> Result<(), RustifyError> {
+        self.estado.lock()?.cifrada = true;
+        self.disponible.notify_all();
+        Ok(())
+    }
+}
+
+/// Conexión con un nodo remoto, administrada por el event loop.
+/// Los mensajes recibidos se encolan sin perderse hasta que alguien los consuma,
+/// y los mensajes enviados (con escribir_mensaje, ya que implementa Write) se escriben
+/// en el socket cuando está listo, sin bloquear al que envía.
+/// Se puede clonar: todos los clones comparten la misma conexión y la misma cola.
+/// Al igual que un TcpStream, la conexión se cierra cuando se descarta el último clon.
+#[derive(Debug, Clone)]
+pub struct ConexionPeer {
+    compartida: Arc<ConexionCompartida>,
+}
+
+#[derive(Debug)]
+struct ConexionCompartida {
+    id: usize,
+    addr: DireccionNodo,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    cola: Arc<ColaMensajes>,
+    event_loop: EventLoopHandle,
+}
+
+impl Drop for ConexionCompartida {
+    fn drop(&mut self) {
+        self.event_loop.enviar_comando(Comando::Cerrar(self.id));
+    }
+}
+
+impl ConexionPeer {
+    /// Identificador de la conexión dentro del event loop.
+    pub fn id(&self) -> usize {
+        self.compartida.id
+    }
+
+    /// Dirección del nodo remoto (puede ser .onion o .i2p si la conexión pasa por un proxy).
+    pub fn addr(&self) -> DireccionNodo {
+        self.compartida.addr
+    }
+
+    /// Dirección del otro extremo del socket (el proxy, si se usa uno).
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.compartida.peer_addr
+    }
+
+    /// Dirección local del socket.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.compartida.local_addr
+    }
+
+    /// Espera el próximo mensaje recibido de la conexión.
+    /// Una vez consumidos los mensajes pendientes, si la conexión se cerró devuelve el error del cierre.
+    pub fn recibir(&self) -> Result<NetworkMessage, RustifyError> {
+        let cola = &self.compartida.cola;
+        let mut estado = cola.estado.lock()?;
+        loop {
+            if let Some(mensaje) = estado.sacar() {
+                return Ok(mensaje);
+            }
+            if let Some(error) = &estado.cierre {
+                return Err(error.clone());
+            }
+            estado = cola.disponible.wait(estado)?;
+        }
+    }
+
+    /// Igual que recibir, pero si no llega ningún mensaje antes del límite devuelve TimeoutRespuesta.
+    pub fn recibir_hasta(&self, limite: Instant) -> Result<NetworkMessage, RustifyError> {
+        let cola = &self.compartida.cola;
+        let mut estado = cola.estado.lock()?;
+        loop {
+            if let Some(mensaje) = estado.sacar() {
+                return Ok(mensaje);
+            }
+            if let Some(error) = &estado.cierre {
+                return Err(error.clone());
+            }
+            let restante = limite.saturating_duration_since(Instant::now());
+            if restante.is_zero() {
+                return Err(RustifyError::TimeoutRespuesta);
+            }
+            estado = cola.disponible.wait_timeout(estado, restante)?.0;
+        }
+    }
+
+    /// Devuelve mensajes ya recibidos al principio de la cola, en el mismo orden,
+    /// para que los consuma el próximo que lea de la conexión.
+    pub fn devolver(&self, mensajes: Vec<NetworkMessage>) -> Result<(), RustifyError> {
+        if mensajes.is_empty() {
+            return Ok(());
+        }
+        let cola = &self.compartida.cola;
+        let mut estado = cola.estado.lock()?;
+        for mensaje in mensajes.into_iter().rev() {
+            let tamanio = MESSAGE_HEADER_SIZE + mensaje.payload().len();
+            estado.bytes += tamanio;
+            estado.mensajes.push_front((mensaje, tamanio));
+        }
+        cola.disponible.notify_all();
+        Ok(())
+    }
+
+    /// Devuelve el próximo mensaje recibido si hay alguno, sin bloquearse.
+    pub fn try_recibir(&self) -> Result<Option<NetworkMessage>, RustifyError> {
+        let mut estado = self.compartida.cola.estado.lock()?;
+        match estado.sacar() {
+            Some(mensaje) => Ok(Some(mensaje)),
+            None => match &estado.cierre {
+                Some(error) => Err(error.clone()),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Espera a que se complete el handshake del transporte cifrado v2 (BIP324).
+    /// Si antes se cierra la conexión devuelve el error del cierre, y si se alcanza el límite, TimeoutRespuesta.
+    pub fn esperar_cifrado(&self, limite: Instant) -> Result<(), RustifyError> {
+        let cola = &self.compartida.cola;
+        let mut estado = cola.estado.lock()?;
+        loop {
+            if estado.cifrada {
+                return Ok(());
+            }
+            if let Some(error) = &estado.cierre {
+                return Err(error.clone());
+            }
+            let restante = limite.saturating_duration_since(Instant::now());
+            if restante.is_zero() {
+                return Err(RustifyError::TimeoutRespuesta);
+            }
+            estado = cola.disponible.wait_timeout(estado, restante)?.0;
+        }
+    }
+
+    /// Indica si la conexión usa el transporte cifrado v2 (BIP324) y ya completó su handshake.
+    pub fn cifrada(&self) -> bool {
+        match self.compartida.cola.estado.lock() {
+            Ok(estado) => estado.cifrada,
+            Err(_) => false,
+        }
+    }
+
+    /// Indica si la conexión ya se cerró (aunque puedan quedar mensajes sin consumir).
+    pub fn cerrada(&self) -> bool {
+        match self.compartida.cola.estado.lock() {
+            Ok(estado) => estado.cierre.is_some(),
+            Err(_) => true,
+        }
+    }
+
+    /// Cierra la conexión sin esperar a que se descarten todos los clones.
+    /// Los que estén esperando mensajes reciben PipeRoto.
+    pub fn cerrar(&self) {
+        self.compartida
+            .event_loop
+            .enviar_comando(Comando::Cerrar(self.id()));
+    }
+}
+
+impl Write for ConexionPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let comando = Comando::Enviar(self.id(), buf.to_vec());
+        if self.cerrada() || !self.compartida.event_loop.enviar_comando(comando) {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pedidos que los demás threads le hacen al event loop.
+#[derive(Debug)]
+enum Comando {
+    /// El último campo indica si se usa el transporte v2.
+    Registrar(MioTcpStream, usize, DireccionNodo, Arc<ColaMensajes>, bool),
+    Escuchar(MioTcpListener, Sender<ConexionPeer>, bool),
+    Enviar(usize, Vec<u8>),
+    Cerrar(usize),
+}
+
+/// Handle para comunicarse con el event loop desde cualquier thread.
+#[derive(Debug, Clone)]
+pub struct EventLoopHandle {
+    comandos: Sender<Comando>,
+    waker: Arc<Waker>,
+    proximo_id: Arc<AtomicUsize>,
+}
+
+impl EventLoopHandle {
+    /// Registra en el event loop un socket ya conectado (y con el proxy ya negociado, si se usa uno).
+    /// A partir de este momento el socket solamente se usa a través de la ConexionPeer devuelta.
+    pub fn registrar(
+        &self,
+        socket: TcpStream,
+        addr: DireccionNodo,
+    ) -> Result<ConexionPeer, RustifyError> {
+        self.registrar_con_transporte(socket, addr, false)
+    }
+
+    /// Igual que registrar, pero la conexión usa el transporte cifrado v2 (BIP324) y se empieza su handshake.
+    /// Los mensajes que se envíen antes de que termine se cifran y envían al terminar.
+    /// Si el nodo remoto no soporta v2 cierra la conexión, y hay que volver a conectarse con registrar.
+    pub fn registrar_v2(
+        &self,
+        socket: TcpStream,
+        addr: DireccionNodo,
+    ) -> Result<ConexionPeer, RustifyError> {
+        self.registrar_con_transporte(socket, addr, true)
+    }
+
+    fn registrar_con_transporte(
+        &self,
+        socket: TcpStream,
+        addr: DireccionNodo,
+        transporte_v2: bool,
+    ) -> Result<ConexionPeer, RustifyError> {
+        let conexion = self.nueva_conexion(addr, socket.peer_addr()?, socket.local_addr()?);
+        socket.set_nonblocking(true)?;
+        let comando = Comando::Registrar(
+            MioTcpStream::from_std(socket),
+            conexion.id(),
+            addr,
+            conexion.compartida.cola.clone(),
+            transporte_v2,
+        );
+        if !self.enviar_comando(comando) {
+            return Err(RustifyError::PipeRoto);
+        }
+        Ok(conexion)
+    }
+
+    /// Acepta conexiones entrantes en el listener. Cada vez que un cliente se conecta,
+    /// y cada vez que llegan mensajes suyos o se desconecta, se envía su ConexionPeer por el channel.
+    /// El que recibe los avisos debe conservar la ConexionPeer mientras quiera mantener la conexión.
+    pub fn escuchar(
+        &self,
+        listener: TcpListener,
+        avisos: Sender<ConexionPeer>,
+    ) -> Result<(), RustifyError> {
+        self.escuchar_con_transporte(listener, avisos, false)
+    }
+
+    /// Igual que escuchar, pero los clientes pueden usar el transporte cifrado v2 (BIP324) o el v1:
+    /// se detecta cuál usa cada uno según los primeros bytes que envía.
+    pub fn escuchar_v2(
+        &self,
+        listener: TcpListener,
+        avisos: Sender<ConexionPeer>,
+    ) -> Result<(), RustifyError> {
+        self.escuchar_con_transporte(listener, avisos, true)
+    }
+
+    fn escuchar_con_transporte(
+        &self,
+        listener: TcpListener,
+        avisos: Sender<ConexionPeer>,
+        transporte_v2: bool,
+    ) -> Result<(), RustifyError> {
+        listener.set_nonblocking(true)?;
+        if !self.enviar_comando(Comando::Escuchar(
+            MioTcpListener::from_std(listener),
+            avisos,
+            transporte_v2,
+        )) {
+            return Err(RustifyError::PipeRoto);
+        }
+        Ok(())
+    }
+
+    fn nueva_conexion(
+        &self,
+        addr: DireccionNodo,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> ConexionPeer {
+        ConexionPeer {
+            compartida: Arc::new(ConexionCompartida {
+                id: self.proximo_id.fetch_add(1, Ordering::SeqCst),
+                addr,
+                peer_addr,
+                local_addr,
+                cola: Arc::new(ColaMensajes::default()),
+                event_loop: self.clone(),
+            }),
+        }
+    }
+
+    /// Envía el comando y despierta al event loop. Devuelve false si el event loop ya no corre.
+    fn enviar_comando(&self, comando: Comando) -> bool {
+        self.comandos.send(comando).is_ok() && self.waker.wake().is_ok()
+    }
+}
+
+/// Devuelve el handle del event loop del nodo. La primera vez lo crea y lo pone a correr
+/// en su propio thread, que es el único que lee y escribe en los sockets.
+pub fn event_loop(logger_sender: &Sender<String>) -> Result<EventLoopHandle, RustifyError> {
+    if let Some(handle) = EVENT_LOOP.get() {
+        return Ok(handle.clone());
+    }
+    let (handle, event_loop) = EventLoop::build(logger_sender)?;
+    let handle_global = EVENT_LOOP.get_or_init(|| handle.clone());
+    // Si otro thread lo inicializó primero, se descarta el event loop creado acá.
+    if Arc::ptr_eq(&handle_global.waker, &handle.waker) {
+        thread::spawn(move || event_loop.ejecutar());
+    }
+    Ok(handle_global.clone())
+}
+
+/// Transporte de una conexión: cómo se codifican los mensajes en el socket.
+enum Transporte {
+    V1,
+    /// Conexión entrante que acepta ambos transportes, hasta que lleguen los bytes suficientes para
+    /// saber cuál usa el cliente. Guarda lo que se envíe mientras tanto.
+    Detectando(Vec<u8>),
+    V2(Box<TransporteV2>),
+}
+
+/// Conexión tal como la ve el event loop: el socket y sus buffers de entrada y salida.
+/// Para las conexiones entrantes se guarda además a quién avisar de sus mensajes, con una
+/// referencia débil a la ConexionPeer para no impedir que se cierre al descartarla.
+struct Conexion {
+    socket: MioTcpStream,
+    addr: DireccionNodo,
+    cola: Arc<ColaMensajes>,
+    entrada: Vec<u8>,
+    salida: VecDeque<u8>,
+    esperando_escritura: bool,
+    avisos: Option<(Sender<ConexionPeer>, Weak<ConexionCompartida>)>,
+    transporte: Transporte,
+}
+
+impl Conexion {
+    /// Agrega los mensajes (serializados como en v1) a la salida, cifrados si la conexión usa el transporte v2.
+    fn enviar(&mut self, bytes: &[u8]) -> Result<(), RustifyError> {
+        match &mut self.transporte {
+            Transporte::V1 => self.salida.extend(bytes),
+            Transporte::Detectando(pendientes) => pendientes.extend_from_slice(bytes),
+            Transporte::V2(transporte) => transporte.enviar(bytes, &mut self.salida)?,
+        }
+        Ok(())
+    }
+
+    /// Decodifica el próximo mensaje completo de la entrada según el transporte de la conexión.
+    /// Devuelve None si todavía no llegó completo.
+    fn decodificar(&mut self) -> Result<Option<NetworkMessage>, RustifyError> {
+        loop {
+            match &mut self.transporte {
+                Transporte::V1 => {
+                    let Some((mensaje, consumidos)) = decodificar_mensaje(&self.entrada)? else {
+                        return Ok(None);
+                    };
+                    self.entrada.drain(..consumidos);
+                    return Ok(Some(mensaje));
+                }
+                Transporte::V2(transporte) => {
+                    return transporte.recibir(&mut self.entrada, &mut self.salida)
+                }
+                Transporte::Detectando(pendientes) => match es_transporte_v1(&self.entrada) {
+                    None => return Ok(None),
+                    Some(true) => {
+                        self.salida.extend(pendientes.drain(..));
+                        self.transporte = Transporte::V1;
+                    }
+                    Some(false) => {
+                        let pendientes = std::mem::take(pendientes);
+                        let mut transporte = TransporteV2::new(false, &mut self.salida);
+                        transporte.enviar(&pendientes, &mut self.salida)?;
+                        self.transporte = Transporte::V2(Box::new(transporte));
+                    }
+                },
+            }
+        }
+    }
+
+    fn cifrada(&self) -> bool {
+        matches!(&self.transporte, Transporte::V2(transporte) if transporte.establecido())
+    }
+
+    fn avisar(&self) {
+        if let Some((avisos, compartida)) = &self.avisos {
+            if let Some(compartida) = compartida.upgrade() {
+                avisos.send(ConexionPeer { compartida }).unwrap_or(());
+            }
+        }
+    }
+}
+
+/// Event loop basado en readiness (mio): un único thread atiende todos los sockets del nodo,
+/// sin bloquearse en ninguno, por lo que la cantidad de conexiones no depende de la de threads.
+struct EventLoop {
+    poll: Poll,
+    comandos: Receiver<Comando>,
+    handle: EventLoopHandle,
+    conexiones: HashMap<Token, Conexion>,
+    listeners: HashMap<Token, (MioTcpListener, Sender<ConexionPeer>, bool)>,
+    /// Conexiones a las que les quedaron bytes por leer al alcanzar MAX_LECTURA_POR_EVENTO.
+    /// Como mio avisa solamente cuando llegan datos nuevos, se vuelven a leer sin esperar otro evento.
+    lecturas_pendientes: VecDeque<Token>,
+    logger_sender: Sender<String>,
+}
+
+impl EventLoop {
+    fn build(logger_sender: &Sender<String>) -> Result<(EventLoopHandle, EventLoop), RustifyError> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), TOKEN_WAKER)?);
+        let (comandos_sender, comandos) = mpsc::channel();
+        let handle = EventLoopHandle {
+            comandos: comandos_sender,
+            waker,
+            proximo_id: Arc::new(AtomicUsize::new(0)),
+        };
+        let event_loop = EventLoop {
+            poll,
+            comandos,
+            handle: handle.clone(),
+            conexiones: HashMap::new(),
+            listeners: HashMap::new(),
+            lecturas_pendientes: VecDeque::new(),
+            logger_sender: logger_sender.clone(),
+        };
+        Ok((handle, event_loop))
+    }
+
+    fn ejecutar(mut self) {
+        log(
+            Lvl::Info(Action::NETWORK),
+            "Event loop iniciado.",
+            &self.logger_sender,
+        );
+        let mut eventos = Events::with_capacity(CAPACIDAD_EVENTOS);
+        loop {
+            let espera = match self.lecturas_pendientes.is_empty() {
+                true => None,
+                false => Some(Duration::ZERO),
+            };
+            if let Err(e) = self.poll.poll(&mut eventos, espera) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                log_with_parameters(
+                    Lvl::Error(Action::NETWORK),
+                    format!("El event loop se detuvo por el error {:?}.", e.kind()),
+                    &self.logger_sender,
+                );
+                return;
+            }
+            for evento in eventos.iter() {
+                let token = evento.token();
+                if token == TOKEN_WAKER {
+                    self.procesar_comandos();
+                } else if self.listeners.contains_key(&token) {
+                    self.aceptar(token);
+                } else {
+                    if evento.is_writable() {
+                        self.escribir(token);
+                    }
+                    if evento.is_readable() || evento.is_read_closed() || evento.is_error() {
+                        self.leer(token);
+                    }
+                }
+            }
+            for _ in 0..self.lecturas_pendientes.len() {
+                if let Some(token) = self.lecturas_pendientes.pop_front() {
+                    self.leer(token);
+                }
+            }
+        }
+    }
+
+    fn procesar_comandos(&mut self) {
+        while let Ok(comando) = self.comandos.try_recv() {
+            match comando {
+                Comando::Registrar(socket, id, addr, cola, transporte_v2) => {
+                    self.agregar_conexion(socket, id, addr, cola, None, transporte_v2)
+                }
+                Comando::Escuchar(mut listener, avisos, transporte_v2) => {
+                    let token = Token(self.handle.proximo_id.fetch_add(1, Ordering::SeqCst));
+                    match self
+                        .poll
+                        .registry()
+                        .register(&mut listener, token, Interest::READABLE)
+                    {
+                        Ok(()) => {
+                            self.listeners
+                                .insert(token, (listener, avisos, transporte_v2));
+                        }
+                        Err(e) => log_with_parameters(
+                            Lvl::Error(Action::NETWORK),
+                            format!("No se pudo registrar el listener: {:?}.", e.kind()),
+                            &self.logger_sender,
+                        ),
+                    }
+                }
+                Comando::Enviar(id, bytes) => {
+                    if let Some(conexion) = self.conexiones.get_mut(&Token(id)) {
+                        match conexion.enviar(&bytes) {
+                            Ok(()) => self.escribir(Token(id)),
+                            Err(e) => self.cerrar(Token(id), e),
+                        }
+                    }
+                }
+                Comando::Cerrar(id) => self.cerrar(Token(id), RustifyError::PipeRoto),
+            }
+        }
+    }
+
+    /// Registra el socket en el poll y agrega la conexión. Con el transporte v2, las conexiones salientes
+    /// empiezan enseguida el handshake, y las entrantes esperan a saber qué transporte usa el cliente.
+    fn agregar_conexion(
+        &mut self,
+        mut socket: MioTcpStream,
+        id: usize,
+        addr: DireccionNodo,
+        cola: Arc<ColaMensajes>,
+        avisos: Option<(Sender<ConexionPeer>, Weak<ConexionCompartida>)>,
+        transporte_v2: bool,
+    ) {
+        let token = Token(id);
+        if let Err(e) = self
+            .poll
+            .registry()
+            .register(&mut socket, token, Interest::READABLE)
+        {
+            cola.cerrar(e.into()).unwrap_or(());
+            return;
+        }
+        let mut salida = VecDeque::new();
+        let transporte = match (transporte_v2, avisos.is_some()) {
+            (false, _) => Transporte::V1,
+            (true, false) => Transporte::V2(Box::new(TransporteV2::new(true, &mut salida))),
+            (true, true) => Transporte::Detectando(vec![]),
+        };
+        self.conexiones.insert(
+            token,
+            Conexion {
+                socket,
+                addr,
+                cola,
+                entrada: vec![],
+                salida,
+                esperando_escritura: false,
+                avisos,
+                transporte,
+            },
+        );
+        self.escribir(token);
+    }
+
+    /// Acepta todas las conexiones entrantes pendientes del listener,
+    /// y le envía cada nueva conexión al dueño del listener.
+    fn aceptar(&mut self, token: Token) {
+        loop {
+            let (aceptado, avisos, transporte_v2) = match self.listeners.get(&token) {
+                Some((listener, avisos, transporte_v2)) => {
+                    (listener.accept(), avisos.clone(), *transporte_v2)
+                }
+                None => return,
+            };
+            match aceptado {
+                Ok((socket, addr)) => {
+                    let local_addr = socket.local_addr().unwrap_or(addr);
+                    let conexion =
+                        self.handle
+                            .nueva_conexion(DireccionNodo::Ip(addr), addr, local_addr);
+                    let compartida = Arc::downgrade(&conexion.compartida);
+                    self.agregar_conexion(
+                        socket,
+                        conexion.id(),
+                        conexion.addr(),
+                        conexion.compartida.cola.clone(),
+                        Some((avisos.clone(), compartida)),
+                        transporte_v2,
+                    );
+                    avisos.send(conexion).unwrap_or(());
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log_with_parameters(
+                        Lvl::Error(Action::NETWORK),
+                        format!("No se pudo aceptar la conexión: {:?}.", e.kind()),
+                        &self.logger_sender,
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Lee lo disponible en el socket, hasta MAX_LECTURA_POR_EVENTO bytes, y encola los mensajes completos
+    /// a medida que llegan. Si queda algo por leer, la conexión se vuelve a atender en la siguiente vuelta.
+    /// Si el nodo remoto viola el protocolo o cierra la conexión, se la cierra.
+    fn leer(&mut self, token: Token) {
+        let Some(conexion) = self.conexiones.get_mut(&token) else {
+            return;
+        };
+        let mut buffer = [0u8; TAMANIO_LECTURA];
+        let mut cierre = None;
+        let mut recibidos = false;
+        let mut leidos_total = 0;
+        loop {
+            if leidos_total >= MAX_LECTURA_POR_EVENTO {
+                if !self.lecturas_pendientes.contains(&token) {
+                    self.lecturas_pendientes.push_back(token);
+                }
+                break;
+            }
+            match conexion.socket.read(&mut buffer) {
+                Ok(0) => {
+                    cierre = Some(RustifyError::PipeRoto);
+                    break;
+                }
+                Ok(leidos) => {
+                    leidos_total += leidos;
+                    conexion.entrada.extend_from_slice(&buffer[..leidos]);
+                    if let Err(e) = Self::decodificar_entrada(conexion, &mut recibidos) {
+                        cierre = Some(e);
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    cierre = Some(e.into());
+                    break;
+                }
+            }
+        }
+
+        if recibidos {
+            conexion.avisar();
+        }
+        // El transporte v2 puede tener que responder durante el handshake.
+        let responder = !conexion.salida.is_empty();
+        match cierre {
+            Some(error) => self.cerrar(token, error),
+            None if responder => self.escribir(token),
+            None => {}
+        }
+    }
+
+    /// Encola los mensajes completos de la entrada de la conexión, e indica en recibidos si hubo alguno.
+    /// Si lo que queda sin decodificar supera MAX_BYTES_ENTRADA, el nodo remoto no está enviando
+    /// mensajes válidos y se devuelve PayloadExcedido.
+    fn decodificar_entrada(
+        conexion: &mut Conexion,
+        recibidos: &mut bool,
+    ) -> Result<(), RustifyError> {
+        let mut cifrada = conexion.cifrada();
+        loop {
+            let pendientes = conexion.entrada.len();
+            let decodificado = conexion.decodificar();
+            let consumidos = pendientes.saturating_sub(conexion.entrada.len());
+            // El fin del handshake se marca antes de encolar los mensajes que llegaron junto con él.
+            if !cifrada && conexion.cifrada() {
+                conexion.cola.marcar_cifrada().unwrap_or(());
+                cifrada = true;
+            }
+            match decodificado? {
+                Some(mensaje) => {
+                    conexion.cola.agregar(mensaje, consumidos)?;
+                    *recibidos = true;
+                }
+                None => break,
+            }
+        }
+        if conexion.entrada.len() > MAX_BYTES_ENTRADA {
+            return Err(RustifyError::PayloadExcedido);
+        }
+        Ok(())
+    }
+
+    /// Escribe en el socket todo lo pendiente que acepte sin bloquearse.
+    /// Si queda algo sin escribir, se espera a que el socket vuelva a estar listo.
+    fn escribir(&mut self, token: Token) {
+        let Some(conexion) = self.conexiones.get_mut(&token) else {
+            return;
+        };
+        while !conexion.salida.is_empty() {
+            let (pendiente, _) = conexion.salida.as_slices();
+            match conexion.socket.write(pendiente) {
+                Ok(0) => return self.cerrar(token, RustifyError::PipeRoto),
+                Ok(escritos) => {
+                    conexion.salida.drain(..escritos);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return self.cerrar(token, e.into()),
+            }
+        }
+
+        let esperar_escritura = !conexion.salida.is_empty();
+        if esperar_escritura != conexion.esperando_escritura {
+            let interes = match esperar_escritura {
+                true => Interest::READABLE | Interest::WRITABLE,
+                false => Interest::READABLE,
+            };
+            if let Err(e) = self
+                .poll
+                .registry()
+                .reregister(&mut conexion.socket, token, interes)
+            {
+                return self.cerrar(token, e.into());
+            }
+            conexion.esperando_escritura = esperar_escritura;
+        }
+    }
+
+    /// Cierra la conexión y le avisa a quien la esté usando, con el error que provocó el cierre.
+    fn cerrar(&mut self, token: Token, error: RustifyError) {
+        let Some(mut conexion) = self.conexiones.remove(&token) else {
+            return;
+        };
+        self.poll
+            .registry()
+            .deregister(&mut conexion.socket)
+            .unwrap_or(());
+        conexion.socket.shutdown(Shutdown::Both).unwrap_or(());
+        if error != RustifyError::PipeRoto {
+            log_with_parameters(
+                Lvl::Warning(Action::NETWORK),
+                format!(
+                    "Se cierra la conexión con {}: {}",
+                    conexion.addr,
+                    obtener_mensaje_personalizado(error.clone())
+                ),
+                &self.logger_sender,
+            );
+        }
+        conexion.cola.cerrar(error).unwrap_or(());
+        conexion.avisar();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network_message::{escribir_mensaje, leer_mensaje};
+
+    fn conexion_local() -> Result<(ConexionPeer, TcpStream), RustifyError> {
+        let (logger_sender, _receiver) = mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let socket = TcpStream::connect(listener.local_addr()?)?;
+        let (remoto, addr) = listener.accept()?;
+        let conexion = event_loop(&logger_sender)?.registrar(socket, DireccionNodo::Ip(addr))?;
+        Ok((conexion, remoto))
+    }
+
+    #[test]
+    fn test_encola_todos_los_mensajes_recibidos() -> Result<(), RustifyError> {
+        let (conexion, mut remoto) = conexion_local()?;
+        // Se envían varios mensajes juntos: ninguno se pierde y se reciben en orden.
+        let bytes = [
+            NetworkMessage::Ping(1).as_bytes(),
+            NetworkMessage::Inv(crate::inv::Inv::new(1, 1, vec![vec![7; 32]])).as_bytes(),
+            NetworkMessage::Ping(2).as_bytes(),
+        ]
+        .concat();
+        remoto.write_all(&bytes)?;
+
+        assert!(matches!(conexion.recibir()?, NetworkMessage::Ping(1)));
+        assert!(matches!(conexion.recibir()?, NetworkMessage::Inv(_)));
+        assert!(matches!(conexion.recibir()?, NetworkMessage::Ping(2)));
+
+        drop(remoto);
+        assert_eq!(conexion.recibir().unwrap_err(), RustifyError::PipeRoto);
+        assert!(conexion.cerrada());
+        Ok(())
+    }
+
+    /// Los mensajes que no se llegan a leer en una única vez (MAX_LECTURA_POR_EVENTO) se leen en
+    /// las vueltas siguientes del event loop, aunque no lleguen datos nuevos.
+    #[test]
+    fn test_lee_en_partes_lo_que_supera_la_lectura_por_evento() -> Result<(), RustifyError> {
+        let (conexion, mut remoto) = conexion_local()?;
+        let ping = NetworkMessage::Ping(3).as_bytes();
+        let cant_pings = 2 * MAX_LECTURA_POR_EVENTO / ping.len();
+        remoto.write_all(&ping.repeat(cant_pings))?;
+        for _ in 0..cant_pings {
+            assert!(matches!(conexion.recibir()?, NetworkMessage::Ping(3)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_envia_mensajes_sin_bloquear() -> Result<(), RustifyError> {
+        let (mut conexion, mut remoto) = conexion_local()?;
+        escribir_mensaje(&mut conexion, &NetworkMessage::Pong(5))?;
+        assert!(matches!(
+            leer_mensaje(&mut remoto)?,
+            NetworkMessage::Pong(5)
+        ));
+
+        conexion.cerrar();
+        assert_eq!(conexion.recibir().unwrap_err(), RustifyError::PipeRoto);
+        Ok(())
+    }
+
+    #[test]
+    fn test_descartar_la_conexion_la_cierra() -> Result<(), RustifyError> {
+        let (conexion, mut remoto) = conexion_local()?;
+        let clon = conexion.clone();
+        drop(conexion);
+        escribir_mensaje(&mut clon.clone(), &NetworkMessage::Verack)?;
+        assert!(matches!(leer_mensaje(&mut remoto)?, NetworkMessage::Verack));
+
+        drop(clon);
+        let mut buffer = [0u8; 1];
+        assert_eq!(remoto.read(&mut buffer)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_escuchar_avisa_las_conexiones_entrantes() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (avisos_sender, avisos) = mpsc::channel();
+        event_loop(&logger_sender)?.escuchar(listener, avisos_sender)?;
+
+        let mut cliente = TcpStream::connect(addr)?;
+        let nueva = avisos.recv().map_err(|_| RustifyError::PipeRoto)?;
+        escribir_mensaje(&mut cliente, &NetworkMessage::Ping(3))?;
+        let aviso = avisos.recv().map_err(|_| RustifyError::PipeRoto)?;
+        assert_eq!(aviso.id(), nueva.id());
+        assert!(matches!(
+            aviso.try_recibir()?,
+            Some(NetworkMessage::Ping(3))
+        ));
+        assert!(aviso.try_recibir()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transporte_v2_entre_conexiones() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = mpsc::channel();
+        let event_loop = event_loop(&logger_sender)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (avisos_sender, avisos) = mpsc::channel();
+        event_loop.escuchar_v2(listener, avisos_sender)?;
+
+        let mut cliente =
+            event_loop.registrar_v2(TcpStream::connect(addr)?, DireccionNodo::Ip(addr))?;
+        // Se envía antes de terminar el handshake: se cifra y envía al terminar.
+        escribir_mensaje(&mut cliente, &NetworkMessage::Ping(4))?;
+        let mut servidor = avisos.recv().map_err(|_| RustifyError::PipeRoto)?;
+        assert!(matches!(servidor.recibir()?, NetworkMessage::Ping(4)));
+        assert!(servidor.cifrada());
+
+        escribir_mensaje(&mut servidor, &NetworkMessage::Pong(4))?;
+        cliente.esperar_cifrado(Instant::now() + std::time::Duration::from_secs(5))?;
+        assert!(matches!(cliente.recibir()?, NetworkMessage::Pong(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escuchar_v2_acepta_clientes_v1() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (avisos_sender, avisos) = mpsc::channel();
+        event_loop(&logger_sender)?.escuchar_v2(listener, avisos_sender)?;
+
+        let mut cliente = TcpStream::connect(addr)?;
+        let config =
+            crate::config::Config::new("./node.config").map_err(|_| RustifyError::NotFound)?;
+        let version = crate::version::VersionMessage::new(addr, addr, &config);
+        escribir_mensaje(&mut cliente, &NetworkMessage::Version(version))?;
+        let mut servidor = avisos.recv().map_err(|_| RustifyError::PipeRoto)?;
+        assert!(matches!(servidor.recibir()?, NetworkMessage::Version(_)));
+        assert!(!servidor.cifrada());
+
+        escribir_mensaje(&mut servidor, &NetworkMessage::Verack)?;
+        assert!(matches!(
+            leer_mensaje(&mut cliente)?,
+            NetworkMessage::Verack
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cierra_la_conexion_ante_una_violacion() -> Result<(), RustifyError> {
+        let (conexion, mut remoto) = conexion_local()?;
+        let mut bytes = NetworkMessage::Verack.as_bytes();
+        bytes[0] ^= 0xff;
+        remoto.write_all(&bytes)?;
+        assert_eq!(conexion.recibir().unwrap_err(), RustifyError::MagicInvalido);
+        Ok(())
+    }
+
+    /// Un payload truncado pero con checksum válido cierra solamente esa conexión, sin detener el event loop.
+    #[test]
+    fn test_payload_truncado_desconecta_sin_panic() -> Result<(), RustifyError> {
+        let (conexion, mut remoto) = conexion_local()?;
+        let truncado = NetworkMessage::Desconocido {
+            comando: "headers".to_string(),
+            payload: vec![0x01, 0x00],
+        };
+        remoto.write_all(&truncado.as_bytes())?;
+        assert_eq!(
+            conexion.recibir().unwrap_err(),
+            RustifyError::MensajeMalformado
+        );
+
+        let (otra, mut otro_remoto) = conexion_local()?;
+        otro_remoto.write_all(&NetworkMessage::Ping(9).as_bytes())?;
+        assert!(matches!(otra.recibir()?, NetworkMessage::Ping(9)));
+        Ok(())
+    }
+
+    /// El límite de la cola se mide en bytes: un único mensaje grande la puede llenar,
+    /// y al consumirlo se libera su espacio.
+    #[test]
+    fn test_limite_de_bytes_en_cola() -> Result<(), RustifyError> {
+        let cola = ColaMensajes::default();
+        cola.agregar(NetworkMessage::Block(vec![]), MAX_BYTES_EN_COLA - 8)?;
+        cola.agregar(NetworkMessage::Ping(1), 8)?;
+        assert_eq!(
+            cola.agregar(NetworkMessage::Ping(2), 1).unwrap_err(),
+            RustifyError::ColaMensajesLlena
+        );
+
+        assert!(matches!(
+            cola.estado.lock()?.sacar(),
+            Some(NetworkMessage::Block(_))
+        ));
+        cola.agregar(NetworkMessage::Ping(2), 1)?;
+        assert_eq!(cola.estado.lock()?.bytes, 9);
+        Ok(())
+    }
+}
@@ -1,14 +1,15 @@
 use crate::block_header::{guardar_headers, BlockHeader, NULL_HASH};
-use crate::compactsize::CompactSize;
+use crate::compactsize::{leer_bytes, CompactSize};
 use crate::config::Config;
 use crate::errors::RustifyError;
-use crate::message_handler::handle_specific_message;
-use crate::message_header::MessageHeader;
-use crate::node::write_to_node;
+use crate::event_loop::ConexionPeer;
+use crate::logger::{log, Action, Lvl};
+use crate::message_handler::esperar_respuesta;
+use crate::network_message::{escribir_mensaje, NetworkMessage};
 use bitcoin_hashes::{sha256d, Hash};
 use std::fs::File;
-use std::net::TcpStream;
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 const HASH_LENGTH: usize = 32;
 
@@ -48,20 +49,17 @@ impl GetHeadersMessage {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<GetHeadersMessage, RustifyError> {
-        let version = u32::from_le_bytes(bytes[0..4].try_into()?);
-        let (hash_count, index) = CompactSize::parse_from_byte_array(&bytes[4..14]);
+        let version = u32::from_le_bytes(leer_bytes(bytes, 0, 4)?.try_into()?);
+        let (cant_hashes, mut index) = CompactSize::leer(bytes, 4)?;
+        let hash_count = CompactSize::new(cant_hashes);
 
         let mut starting_hashes = vec![];
-        let mut start_index = 4 + index;
-        for _i in 0..hash_count.value() {
-            let mut starting_hash = vec![0; HASH_LENGTH];
-            starting_hash.copy_from_slice(&bytes[start_index..start_index + HASH_LENGTH]);
-            starting_hashes.push(starting_hash);
-            start_index += HASH_LENGTH;
+        for _i in 0..cant_hashes {
+            starting_hashes.push(leer_bytes(bytes, index, HASH_LENGTH)?.to_vec());
+            index += HASH_LENGTH;
         }
 
-        let mut stopping_hash = vec![0; HASH_LENGTH];
-        stopping_hash.copy_from_slice(&bytes[start_index..start_index + HASH_LENGTH]);
+        let stopping_hash = leer_bytes(bytes, index, HASH_LENGTH)?.to_vec();
 
         Ok(GetHeadersMessage {
             version,
@@ -76,7 +74,7 @@ impl GetHeadersMessage {
 /// Recibe el socket al nodo conectado, el archivo de headers, el vector de headers, y la pagina actual de headers descargada.
 /// Actualiza el vector de headers y el archivo de headers. Los deja con toda la blockchain descargada.
 pub fn getheaders_loop(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     headers_archivo: &mut File,
     headers: &mut Vec<BlockHeader>,
     mut pagina_headers: Vec<Vec<u8>>,
@@ -104,36 +102,90 @@ pub fn getheaders_loop(
 /// Mensaje GETHEADERS.
 /// Devuelve todos los headers posteriores al starting_hash, y previos al stopping_hash.
 /// Si stopping_hash es el vector nulo, se devuelven todos los headers posteriores que se encuentren o un máximo de 2000 (lo que ocurra primero).
+/// Los mensajes headers que no continúan desde el starting_hash son anuncios de bloques nuevos (sendheaders):
+/// no se toman como respuesta, y se devuelven a la cola de la conexión para que los procese el listener.
 pub fn getheaders(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     starting_hash: Vec<Vec<u8>>,
     stopping_hash: Vec<u8>,
     config: &Config,
     sender: &Sender<String>,
 ) -> Result<Vec<Vec<u8>>, RustifyError> {
-    let getheaders_message = GetHeadersMessage::new(starting_hash, stopping_hash, config);
-    let getheaders_message_bytes = getheaders_message.as_bytes();
-
-    let getheaders_message_header =
-        MessageHeader::new("getheaders".to_string(), &getheaders_message_bytes);
-    let getheaders_message_header_bytes = getheaders_message_header.as_bytes();
-
-    write_to_node(
-        socket,
-        &getheaders_message_header_bytes,
-        &getheaders_message_bytes,
-    )?;
-
-    let bytes_getheaders_respuesta =
-        handle_specific_message(socket, "headers\0\0\0\0\0".to_string(), sender)?;
-
-    // Proceso el hash_count (tipo compactsize) para luego hacer el slice y removerlo del mensaje.
-    let hashcount_compactsize = CompactSize::parse_from_byte_array(&bytes_getheaders_respuesta).1;
-    // Corto cada 81 bytes y en el map remuevo el ultimo byte de c/u (remuevo el Transaction count (0x00))
-    let headers: Vec<Vec<u8>> = bytes_getheaders_respuesta[hashcount_compactsize..]
-        .chunks(81)
-        .map(|x| x[0..x.len() - 1].to_vec())
-        .collect();
-
-    Ok(headers)
+    let getheaders_message = GetHeadersMessage::new(starting_hash.clone(), stopping_hash, config);
+    escribir_mensaje(socket, &NetworkMessage::GetHeaders(getheaders_message))?;
+
+    let limite = Instant::now() + Duration::from_secs(config.timeout_respuesta_secs);
+    let mut anuncios = vec![];
+    let respuesta = loop {
+        let restante = limite.saturating_duration_since(Instant::now());
+        match esperar_respuesta(socket, "headers", restante, sender) {
+            Ok(NetworkMessage::Headers(headers))
+                if responde_a_locator(&headers, &starting_hash) =>
+            {
+                break Ok(headers)
+            }
+            Ok(anuncio @ NetworkMessage::Headers(_)) => anuncios.push(anuncio),
+            Ok(_) => break Err(RustifyError::MensajeInesperado),
+            Err(e) => break Err(e),
+        }
+    };
+    socket.devolver(anuncios)?;
+
+    Ok(respuesta?
+        .iter()
+        .map(|header| header.as_bytes().to_vec())
+        .collect())
+}
+
+/// Indica si los headers recibidos son la respuesta a un getheaders con los starting hashes indicados:
+/// la respuesta está vacía o su primer header continúa alguno de esos hashes.
+fn responde_a_locator(headers: &[BlockHeader], starting_hashes: &[Vec<u8>]) -> bool {
+    match headers.first() {
+        Some(primero) => starting_hashes
+            .iter()
+            .any(|hash| hash[..] == primero.previous_block_header_hash),
+        None => true,
+    }
+}
+
+/// Mensaje SENDHEADERS (BIP130).
+/// Le indica al nodo que anuncie los bloques nuevos directamente con un mensaje headers, en lugar de un inv.
+pub fn sendheaders(
+    socket: &mut ConexionPeer,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::SendHeaders)?;
+    log(
+        Lvl::Info(Action::NETWORK),
+        "Enviado mensaje sendheaders.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_con_previo(previo: [u8; 32]) -> Result<BlockHeader, RustifyError> {
+        let mut bytes = [0u8; 80];
+        bytes[4..36].copy_from_slice(&previo);
+        BlockHeader::from_bytes(&bytes)
+    }
+
+    #[test]
+    fn test_responde_a_locator() -> Result<(), RustifyError> {
+        let locator = vec![vec![1u8; 32], vec![2u8; 32]];
+        assert!(responde_a_locator(&[], &locator));
+        assert!(responde_a_locator(
+            &[header_con_previo([2u8; 32])?],
+            &locator
+        ));
+        // Un anuncio de un bloque nuevo continúa la punta de la cadena del nodo, no el locator.
+        assert!(!responde_a_locator(
+            &[header_con_previo([3u8; 32])?],
+            &locator
+        ));
+        Ok(())
+    }
 }
@@ -13,6 +13,7 @@ pub enum GuiEvent {
     ActualizarLabelEstado(String),
     OcultarEstado,
     CargarBloques(Vec<BlockHeader>, u32),
+    QuitarBloques(usize),
     ActualizarWallet(Account),
     IniciarWallets(Vec<String>),
 }
@@ -79,6 +80,17 @@ pub fn actualizar_gui(
                     indice += 1;
                 }
             }
+            // Quita de la pestaña "Blocks" los últimos bloques, desconectados por una reorganización de la cadena.
+            GuiEvent::QuitarBloques(cantidad) => {
+                for _ in 0..cantidad {
+                    match list_store_blocks.iter_first() {
+                        Some(iter) => {
+                            list_store_blocks.remove(&iter);
+                        }
+                        None => break,
+                    }
+                }
+            }
             // Actualiza balance y transacciones de la wallet activa. Esto ocurre cada vez que se selecciona una wallet, o se recibe o envia dinero.
             GuiEvent::ActualizarWallet(wallet) => {
                 list_store_transactions.clear();
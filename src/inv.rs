@@ -1,7 +1,10 @@
-use crate::compactsize::CompactSize;
+use crate::compactsize::{leer_bytes, CompactSize};
 use crate::errors::RustifyError;
 use bitcoin_hashes::{sha256d, Hash};
 
+/// Tipo de inventario (4 bytes) seguido del hash (32 bytes).
+pub const INVENTORY_SIZE: usize = 36;
+
 #[derive(Debug, Clone)]
 pub struct Inv {
     pub count: CompactSize,
@@ -31,6 +34,20 @@ impl Inv {
         vector_inventarios
     }
 
+    /// Devuelve un Inv con solamente los inventarios del tipo indicado (por ejemplo, solo bloques).
+    pub fn filtrar_por_tipo(&self, tipo: u32) -> Inv {
+        let inventories: Vec<Vec<u8>> = self
+            .inventories
+            .iter()
+            .filter(|inventory| inventory.len() >= 4 && inventory[0..4] == tipo.to_le_bytes())
+            .cloned()
+            .collect();
+        Inv {
+            count: CompactSize::new(inventories.len() as u64),
+            inventories,
+        }
+    }
+
     /// Convierte el mensaje en una cadena de bytes
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut vec_inv: Vec<u8> = vec![];
@@ -44,11 +61,13 @@ impl Inv {
 
     /// Convierte la cadena de bytes recibida en un struct Inv
     pub fn from_bytes(bytes: &[u8]) -> Result<Inv, RustifyError> {
-        let (count, count_bytes) = CompactSize::parse_from_byte_array(bytes);
-        let inventories: Vec<Vec<u8>> = bytes[count_bytes..]
-            .chunks(bytes[count_bytes..].len() / count.value() as usize)
-            .map(|s| s.into())
-            .collect();
+        let (cant_inventarios, mut index) = CompactSize::leer(bytes, 0)?;
+        let count = CompactSize::new(cant_inventarios);
+        let mut inventories = vec![];
+        for _ in 0..cant_inventarios {
+            inventories.push(leer_bytes(bytes, index, INVENTORY_SIZE)?.to_vec());
+            index += INVENTORY_SIZE;
+        }
         Ok(Inv { count, inventories })
     }
 }
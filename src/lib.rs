@@ -1,26 +1,45 @@
 pub mod account;
+pub mod addr;
+pub mod address_manager;
+pub mod addrv2;
+pub mod ban_manager;
 pub mod block;
 pub mod block_header;
+pub mod block_index;
 pub mod block_validation;
+pub mod bloom;
+pub mod chacha20_poly1305;
+pub mod compact_block;
+pub mod compact_filter;
 pub mod compactsize;
 pub mod config;
+pub mod ellswift;
 pub mod errors;
+pub mod event_loop;
 pub mod getheaders;
 pub mod gui;
 pub mod gui_events;
 pub mod inv;
 pub mod locktime;
 pub mod logger;
+pub mod merkle_block;
 pub mod message_handler;
 pub mod message_header;
+pub mod network_message;
 pub mod node;
 pub mod outpoint;
+pub mod peer_manager;
+pub mod proxy;
+pub mod red;
 pub mod script;
+pub mod script_interpreter;
 pub mod serialized_block;
 pub mod server;
 pub mod server_messages;
 pub mod server_notification;
 pub mod threadpool;
+pub mod transporte_v2;
+pub mod tx_validation;
 pub mod txin;
 pub mod txn;
 pub mod txn_info;
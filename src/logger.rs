@@ -30,6 +30,8 @@ pub enum Action {
     SERVER,
     NETWORK,
     LISTENER,
+    PEERS,
+    ADDRESSES,
 }
 
 /// Logger que registra mensajes en un archivo o los imprime por pantalla, dependiendo de la configuración.
@@ -156,6 +158,8 @@ fn log_action(action: Action) -> String {
         Action::SERVER => "SERVER",
         Action::NETWORK => "NETWORK",
         Action::LISTENER => "LISTENER",
+        Action::PEERS => "PEERS",
+        Action::ADDRESSES => "ADDRESSES",
     }
     .to_string()
 }
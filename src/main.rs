@@ -1,34 +1,37 @@
 use gtk::glib;
 use rustify_11::block_header::BlockHeader;
+use rustify_11::block_index::IndiceBloques;
 use rustify_11::inv::Inv;
 use rustify_11::txn::Txn;
-use std::collections::HashMap;
-use std::net::TcpStream;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+use rustify_11::address_manager::iniciar_address_manager;
+use rustify_11::ban_manager::{ejecutar_comando_ban, iniciar_ban_manager};
+use rustify_11::bloom::{establecer_filtro_spv, filtro_wallets};
 use rustify_11::config::Config;
 use rustify_11::errors::{catch, RustifyError};
 use rustify_11::gui::iniciar_gui;
 use rustify_11::gui_events::GuiEvent;
-use rustify_11::logger::initialize_logger;
-use rustify_11::node::{conectar, handshake, initial_block_download, recibir_nuevos_bloques_txs};
+use rustify_11::logger::{initialize_logger, log_re_err, Action};
+use rustify_11::node::{initial_block_download, recibir_nuevos_bloques_txs};
+use rustify_11::peer_manager::PeerManager;
+use rustify_11::red::establecer_red;
 use rustify_11::server::iniciar_server;
-use rustify_11::utxo::obtain_utxo;
+use rustify_11::utxo::{obtain_utxo, SetUtxos};
 use rustify_11::wallet_events::{iniciar_wallet, WalletEvent};
 
-//Tipo de dato de Hashmap de transacción
-type TrxKey = (String, u32);
-type TrxHashMap<T> = HashMap<TrxKey, T>;
-type TrxServer = Vec<(String, Txn)>;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
 type OkInicioNodo = (
-    TrxHashMap<Txn>,
-    TcpStream,
+    Arc<Mutex<SetUtxos>>,
+    PeerManager,
     Arc<Mutex<Vec<BlockHeader>>>,
     Arc<Mutex<TrxServer>>,
 );
+/// Espera antes de volver a intentar tomar un peer para escuchar bloques y transacciones.
+const ESPERA_PEER_LISTENER_SECS: u64 = 5;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
@@ -40,8 +43,25 @@ fn main() {
         }
     };
 
+    if let Err(e) = establecer_red(config.network) {
+        catch(e);
+        std::process::exit(1);
+    }
+
     let logger_sender = initialize_logger(&config);
 
+    // Comandos manuales sobre la lista de nodos baneados (ban, unban, bans).
+    if args.len() > 2 {
+        match ejecutar_comando_ban(&args[2..], &config, &logger_sender) {
+            Ok(resultado) => println!("{}", resultado),
+            Err(e) => {
+                catch(e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let (sender_wallet, recv_wallet) = std::sync::mpsc::channel();
     let (sender_gui, recv_gui) = glib::MainContext::channel(glib::source::Priority::DEFAULT);
 
@@ -49,14 +69,14 @@ fn main() {
 
     iniciar_gui(recv_gui, sender_wallet.clone(), &config);
 
-    let (utxos_init, mut socket, headers, txn_memory_client) = match iniciar_nodo(
+    let (utxos_init, peer_manager, headers, txn_memory_client) = match iniciar_nodo(
         &config,
         &logger_sender,
         sender_gui.clone(),
         sender_wallet,
         sender_notif,
     ) {
-        Ok((u, s, h, n)) => (u, s, h, n),
+        Ok((u, p, h, n)) => (u, p, h, n),
         Err(e) => {
             catch(e);
             std::process::exit(1);
@@ -74,7 +94,7 @@ fn main() {
     }
 
     iniciar_wallet(
-        &mut socket,
+        &peer_manager,
         &config,
         &logger_sender,
         utxos_init,
@@ -86,6 +106,7 @@ fn main() {
 /// Inicializa un nodo Bitcoin de tipo light.
 /// Se conecta a otros nodos, realiza un handshake, y descarga headers y bloques.
 /// Queda a la espera de nuevos bloques para validar (y descargar si es valido).
+/// En modo SPV, antes de conectarse arma el filtro bloom con las wallets guardadas.
 pub fn iniciar_nodo(
     config: &Config,
     logger_sender: &Sender<String>,
@@ -93,51 +114,78 @@ pub fn iniciar_nodo(
     sender_wallet: Sender<WalletEvent>,
     sender_notif: Sender<Inv>,
 ) -> Result<OkInicioNodo, RustifyError> {
-    let mut socket = conectar(config, logger_sender)?;
-    handshake(&mut socket, config, logger_sender)?;
+    iniciar_address_manager(config, logger_sender)?;
+    iniciar_ban_manager(config, logger_sender)?;
+    if config.modo_spv {
+        establecer_filtro_spv(filtro_wallets(config, logger_sender)?)?;
+    }
+    let peer_manager = PeerManager::build(config, logger_sender)?;
+    peer_manager.iniciar_mantenimiento();
     thread::sleep(Duration::from_millis(1000)); // Para que se llegue a ver el "Connecting to peers..." en la GUI.
 
-    let headers = initial_block_download(&mut socket, config, logger_sender, &sender_gui)?;
+    let headers = peer_manager.ejecutar_en_peer(|socket| {
+        initial_block_download(socket, config, logger_sender, &sender_gui)
+    })?;
 
     sender_gui.send(GuiEvent::CargarBloques(
         headers[config.height_bloque_inicial..].to_owned(),
         config.height_bloque_inicial as u32,
     ))?;
 
+    // Las UTXOs se obtienen antes de escuchar bloques nuevos, que se validan y se conectan a ellas.
+    sender_gui.send(GuiEvent::ActualizarLabelEstado(
+        "Obtaining UTXOs...".to_string(),
+    ))?;
+    let utxos = Arc::new(Mutex::new(obtain_utxo(config, logger_sender)?));
+    let utxos_listener = utxos.clone();
+
+    let mut indice = IndiceBloques::new(&headers);
     let headers_ref = Arc::new(Mutex::new(headers)); // Usamos Arc Mutex para compartir el vector de headers entre threads.
     let mut headers_block_broadcasting = headers_ref.clone();
 
     let txn_memory_server: Arc<Mutex<TrxServer>> = Arc::new(Mutex::new(vec![]));
     let txn_memory_client = txn_memory_server.clone();
 
-    let mut socket_clone = socket.try_clone()?;
+    let peer_manager_listener = peer_manager.clone();
     let config_clone = config.clone();
     let logger_sender_clone = logger_sender.clone();
     let sender_gui_clone = sender_gui.clone();
     thread::spawn(move || -> Result<(), RustifyError> {
-        recibir_nuevos_bloques_txs(
-            &mut socket_clone,
-            &mut headers_block_broadcasting,
-            txn_memory_server,
-            &config_clone,
-            (
-                &logger_sender_clone,
-                &sender_gui_clone,
-                &sender_wallet,
-                &sender_notif,
-            ),
-        )?;
-        Ok(())
+        // Si el peer que escucha los nuevos bloques se cae, se continúa escuchando con otro.
+        loop {
+            let (id, mut socket) = match peer_manager_listener.tomar_peer() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    log_re_err(Action::LISTENER, e, &logger_sender_clone);
+                    thread::sleep(Duration::from_secs(ESPERA_PEER_LISTENER_SECS));
+                    continue;
+                }
+            };
+            match recibir_nuevos_bloques_txs(
+                &mut socket,
+                &mut headers_block_broadcasting,
+                &mut indice,
+                &utxos_listener,
+                txn_memory_server.clone(),
+                &config_clone,
+                (
+                    &logger_sender_clone,
+                    &sender_gui_clone,
+                    &sender_wallet,
+                    &sender_notif,
+                ),
+            ) {
+                Ok(()) => peer_manager_listener.liberar_peer(id)?,
+                Err(e) => {
+                    log_re_err(Action::LISTENER, e.clone(), &logger_sender_clone);
+                    peer_manager_listener.reportar_error(id, &e)?;
+                }
+            }
+        }
     });
 
-    sender_gui.send(GuiEvent::ActualizarLabelEstado(
-        "Obtaining UTXOs...".to_string(),
-    ))?;
-
-    let utxos = obtain_utxo(config, logger_sender)?;
-
     sender_gui.send(GuiEvent::ActualizarLabelEstado("Up to date.".to_string()))?;
     sender_gui.send(GuiEvent::OcultarEstado)?;
 
-    Ok((utxos, socket, headers_ref, txn_memory_client))
+    Ok((utxos, peer_manager, headers_ref, txn_memory_client))
 }
@@ -0,0 +1,65 @@
+use crate::{
+    block_header::BlockHeader,
+    block_validation::validar_partial_merkle_tree,
+    compactsize::{leer_bytes, CompactSize},
+    errors::RustifyError,
+};
+
+/// Tipo de inventario para pedir un bloque filtrado (merkleblock) con getdata.
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+
+/// Mensaje merkleblock (BIP37): el header del bloque y un partial merkle tree con los txids
+/// de las transacciones que coinciden con el filtro cargado. Las transacciones se reciben
+/// a continuación, como mensajes tx.
+#[derive(Debug, Clone)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub cant_txns: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<u8>,
+}
+
+impl MerkleBlock {
+    /// Valida el partial merkle tree contra la merkle root del header y devuelve los txids incluidos,
+    /// en el orden del bloque. Si el árbol no es válido devuelve BloqueInvalido.
+    pub fn txids_incluidos(&self) -> Result<Vec<[u8; 32]>, RustifyError> {
+        validar_partial_merkle_tree(
+            &self.header.merkle_root_hash,
+            self.cant_txns,
+            &self.hashes,
+            &self.flags,
+        )
+        .ok_or(RustifyError::BloqueInvalido)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.cant_txns.to_le_bytes());
+        bytes.extend(CompactSize::new(self.hashes.len() as u64).as_bytes());
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash);
+        }
+        bytes.extend(CompactSize::new(self.flags.len() as u64).as_bytes());
+        bytes.extend_from_slice(&self.flags);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MerkleBlock, RustifyError> {
+        let header = BlockHeader::from_bytes(leer_bytes(bytes, 0, 80)?)?;
+        let cant_txns = u32::from_le_bytes(leer_bytes(bytes, 80, 4)?.try_into()?);
+        let (cant_hashes, mut index) = CompactSize::leer(bytes, 84)?;
+        let mut hashes = vec![];
+        for _ in 0..cant_hashes {
+            hashes.push(leer_bytes(bytes, index, 32)?.try_into()?);
+            index += 32;
+        }
+        let (cant_flags, index) = CompactSize::leer(bytes, index)?;
+        let flags = leer_bytes(bytes, index, cant_flags as usize)?.to_vec();
+        Ok(MerkleBlock {
+            header,
+            cant_txns,
+            hashes,
+            flags,
+        })
+    }
+}
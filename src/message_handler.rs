@@ -1,78 +1,131 @@
-use std::{net::TcpStream, sync::mpsc::Sender};
+use std::{
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    address_manager::{procesar_addr, procesar_addrv2},
     errors::RustifyError,
+    event_loop::ConexionPeer,
     logger::{log_with_parameters, Action, Lvl},
-    message_header::MessageHeader,
-    node::{pong, read_from_node},
+    network_message::NetworkMessage,
+    node::pong,
 };
 
-const MESSAGE_HEADER_SIZE: usize = 24;
-
 /// Recibe un comando especifico a buscar, ejemplo: busca los blocks e itera los demas mensajes que llegan
-/// hasta encontrarlo, utilizando la funcion handle_message.
+/// hasta encontrarlo. Mientras tanto, los mensajes que no son la respuesta buscada no se pierden:
+/// los que se pueden atender en el momento (ping, addr) se handlean con handle_message, y los que
+/// debe procesar otra parte del nodo (inv, tx) se guardan y se devuelven a la cola de la conexión.
+/// Espera sin límite de tiempo; para esperar la respuesta a un pedido usar esperar_respuesta.
 /// Devuelve el mensaje que se estaba buscando.
 pub fn handle_specific_message(
-    socket: &mut TcpStream,
-    comando_esperado: String,
+    socket: &mut ConexionPeer,
+    comando_esperado: &str,
     logger_sender: &Sender<String>,
-) -> Result<Vec<u8>, RustifyError> {
-    let mut bytes_header_respuesta = read_from_node(socket, MESSAGE_HEADER_SIZE)?;
-    let mut message_header_respuesta = MessageHeader::from_bytes(&bytes_header_respuesta)?;
-    let mut bytes_message_respuesta =
-        read_from_node(socket, message_header_respuesta.payload_size as usize)?;
-    let mut comando = String::from_utf8((message_header_respuesta.command_name).to_vec())?;
-    log_with_parameters(
-        Lvl::Info(Action::NETWORK),
-        format!("Recibido mensaje {}.", comando),
-        logger_sender,
-    );
+) -> Result<NetworkMessage, RustifyError> {
+    despachar_hasta(socket, &[comando_esperado], None, logger_sender)
+}
 
-    while comando != comando_esperado {
-        handle_message(
-            &comando,
-            &bytes_message_respuesta,
-            socket,
-            logger_sender,
-            &comando_esperado,
-        )?;
-        bytes_header_respuesta = read_from_node(socket, MESSAGE_HEADER_SIZE)?;
-        message_header_respuesta = MessageHeader::from_bytes(&bytes_header_respuesta)?;
-        bytes_message_respuesta =
-            read_from_node(socket, message_header_respuesta.payload_size as usize)?;
-        comando = String::from_utf8(message_header_respuesta.command_name.to_vec())?;
+/// Igual que handle_specific_message, pero devuelve el primero que llegue de cualquiera
+/// de los comandos indicados (por ejemplo, anuncios de bloques por inv o por headers).
+pub fn handle_specific_messages(
+    socket: &mut ConexionPeer,
+    comandos_esperados: &[&str],
+    logger_sender: &Sender<String>,
+) -> Result<NetworkMessage, RustifyError> {
+    despachar_hasta(socket, comandos_esperados, None, logger_sender)
+}
+
+/// Igual que handle_specific_message, pero si el mensaje esperado no llega dentro del timeout
+/// devuelve TimeoutRespuesta, en lugar de esperar para siempre a un nodo que no responde.
+pub fn esperar_respuesta(
+    socket: &mut ConexionPeer,
+    comando_esperado: &str,
+    timeout: Duration,
+    logger_sender: &Sender<String>,
+) -> Result<NetworkMessage, RustifyError> {
+    let limite = Instant::now() + timeout;
+    despachar_hasta(socket, &[comando_esperado], Some(limite), logger_sender)
+}
+
+fn despachar_hasta(
+    socket: &mut ConexionPeer,
+    comandos_esperados: &[&str],
+    limite: Option<Instant>,
+    logger_sender: &Sender<String>,
+) -> Result<NetworkMessage, RustifyError> {
+    let mut postergados = vec![];
+    let resultado = loop {
+        let recibido = match limite {
+            Some(limite) => socket.recibir_hasta(limite),
+            None => socket.recibir(),
+        };
+        let mensaje = match recibido {
+            Ok(mensaje) => mensaje,
+            Err(e) => break Err(e),
+        };
         log_with_parameters(
             Lvl::Info(Action::NETWORK),
-            format!("Recibido mensaje {}.", comando),
+            format!("Recibido mensaje {}.", mensaje.comando()),
             logger_sender,
         );
-    }
+        if comandos_esperados.contains(&mensaje.comando()) {
+            break Ok(mensaje);
+        }
+        if debe_postergarse(&mensaje) {
+            postergados.push(mensaje);
+            continue;
+        }
+        let comando_esperado = comandos_esperados.first().copied().unwrap_or_default();
+        if let Err(e) = handle_message(&mensaje, socket, logger_sender, comando_esperado) {
+            break Err(e);
+        }
+    };
+    socket.devolver(postergados)?;
+    resultado
+}
 
-    Ok(bytes_message_respuesta)
+/// Mensajes que no son la respuesta a un pedido en curso, pero que debe procesar
+/// quien lea luego de la conexión (por ejemplo, el listener de nuevos bloques y transacciones).
+fn debe_postergarse(mensaje: &NetworkMessage) -> bool {
+    matches!(
+        mensaje,
+        NetworkMessage::Inv(_)
+            | NetworkMessage::Tx(_)
+            | NetworkMessage::Headers(_)
+            | NetworkMessage::CmpctBlock(_)
+    )
 }
 
 /// Handleo de mensajes, en esta primer version solo responde el PING con el PONG.
 /// No devuelve nada, solo responde y trabaja lo necesario.
 pub fn handle_message(
-    comando: &str,
-    bytes_mensaje_respuesta: &[u8],
-    socket: &mut TcpStream,
+    mensaje: &NetworkMessage,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
     comando_esperado: &str,
 ) -> Result<(), RustifyError> {
-    match comando {
-        "ping\0\0\0\0\0\0\0\0" => {
-            pong(bytes_mensaje_respuesta, socket, logger_sender)?;
+    match mensaje {
+        NetworkMessage::Ping(nonce) => {
+            pong(*nonce, socket, logger_sender)?;
         }
-        "tx\0\0\0\0\0\0\0\0\0\0" => {}
-        "block\0\0\0\0\0\0\0" => {}
-        "notfound\0\0\0\0" => {
+        NetworkMessage::Addr(addr) => {
+            procesar_addr(addr, logger_sender);
+        }
+        NetworkMessage::AddrV2(addrv2) => {
+            procesar_addrv2(addrv2, logger_sender);
+        }
+        // El nodo solamente descarga bloques que pidió explícitamente. Los headers pueden
+        // llegar sin pedirlos, como anuncio de bloques nuevos (sendheaders, BIP130), al igual
+        // que los bloques compactos (sendcmpct, BIP152).
+        NetworkMessage::Block(_) | NetworkMessage::BlockTxn(_) => {
+            return Err(RustifyError::DatosNoSolicitados);
+        }
+        NetworkMessage::NotFound(_) => {
             log_notfound_result(socket, comando_esperado, logger_sender);
             match comando_esperado {
-                "block\0\0\0\0\0\0\0" => return Err(RustifyError::ElNodoNoEncuentraBloquePedido),
-                "tx\0\0\0\0\0\0\0\0\0\0" => {
-                    return Err(RustifyError::ElNodoNoEncuentraTransaccionPedida)
-                }
+                "block" => return Err(RustifyError::ElNodoNoEncuentraBloquePedido),
+                "tx" => return Err(RustifyError::ElNodoNoEncuentraTransaccionPedida),
                 _ => {}
             };
         }
@@ -85,30 +138,86 @@ pub fn handle_message(
 /// Genera logs en base al tipo de resultado que se estaba esperando
 /// (si era un bloque o una transaccion)
 fn log_notfound_result(
-    socket: &mut TcpStream,
+    socket: &ConexionPeer,
     comando_esperado: &str,
     logger_sender: &Sender<String>,
 ) {
-    let peer_ip = socket.peer_addr();
     let notfound_str = match comando_esperado {
-        "block\0\0\0\0\0\0\0" => "el bloque",
-        "tx\0\0\0\0\0\0\0\0\0\0" => "la transaccion",
+        "block" => "el bloque",
+        "tx" => "la transaccion",
         _ => "lo",
     };
-    if let Ok(ip) = peer_ip {
-        log_with_parameters(
-            Lvl::Warning(Action::NETWORK),
-            format!(
-                "El nodo con IP {:?} no pudo obtener {} que se solicitó!",
-                ip, notfound_str
-            ),
-            logger_sender,
-        );
-    } else {
-        log_with_parameters(
-            Lvl::Warning(Action::NETWORK),
-            format!("El nodo no pudo obtener {} que se solicitó!", notfound_str),
-            logger_sender,
+    log_with_parameters(
+        Lvl::Warning(Action::NETWORK),
+        format!(
+            "El nodo {} no pudo obtener {} que se solicitó!",
+            socket.addr(),
+            notfound_str
+        ),
+        logger_sender,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addrv2::DireccionNodo,
+        event_loop::event_loop,
+        inv::Inv,
+        network_message::{escribir_mensaje, leer_mensaje},
+    };
+    use std::net::{TcpListener, TcpStream};
+
+    fn conexion_local(
+        logger_sender: &Sender<String>,
+    ) -> Result<(ConexionPeer, TcpStream), RustifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let socket = TcpStream::connect(listener.local_addr()?)?;
+        let (remoto, addr) = listener.accept()?;
+        let conexion = event_loop(logger_sender)?.registrar(socket, DireccionNodo::Ip(addr))?;
+        Ok((conexion, remoto))
+    }
+
+    #[test]
+    fn test_los_mensajes_no_esperados_no_se_pierden() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        let (mut conexion, mut remoto) = conexion_local(&logger_sender)?;
+        escribir_mensaje(
+            &mut remoto,
+            &NetworkMessage::Inv(Inv::new(1, 1, vec![vec![1; 32]])),
+        )?;
+        escribir_mensaje(&mut remoto, &NetworkMessage::Ping(9))?;
+        escribir_mensaje(&mut remoto, &NetworkMessage::Block(vec![0; 10]))?;
+
+        let timeout = Duration::from_secs(5);
+        let bloque = esperar_respuesta(&mut conexion, "block", timeout, &logger_sender)?;
+        assert!(matches!(bloque, NetworkMessage::Block(_)));
+        // El ping se contestó en el momento, y el inv quedó para el próximo que lea.
+        assert!(matches!(
+            leer_mensaje(&mut remoto)?,
+            NetworkMessage::Pong(9)
+        ));
+        let inv = handle_specific_message(&mut conexion, "inv", &logger_sender)?;
+        assert!(matches!(inv, NetworkMessage::Inv(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_esperar_respuesta_con_timeout() -> Result<(), RustifyError> {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        let (mut conexion, mut remoto) = conexion_local(&logger_sender)?;
+        escribir_mensaje(&mut remoto, &NetworkMessage::Tx(vec![1, 2, 3]))?;
+
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            esperar_respuesta(&mut conexion, "headers", timeout, &logger_sender).unwrap_err(),
+            RustifyError::TimeoutRespuesta
         );
+        assert!(matches!(
+            conexion.try_recibir()?,
+            Some(NetworkMessage::Tx(_))
+        ));
+        Ok(())
     }
 }
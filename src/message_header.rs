@@ -1,8 +1,8 @@
 use crate::errors::RustifyError;
+use crate::red::parametros_red;
 use bitcoin_hashes::{sha256d, Hash};
 
 pub const MESSAGE_HEADER_SIZE: usize = 24;
-const TESTNET_START_STRING: [u8; 4] = [0x0B, 0x11, 0x09, 0x07];
 
 #[derive(Debug, Clone)]
 pub struct MessageHeader {
@@ -15,7 +15,7 @@ pub struct MessageHeader {
 impl MessageHeader {
     pub fn new(command: String, payload: &[u8]) -> MessageHeader {
         MessageHeader {
-            start_string: TESTNET_START_STRING,
+            start_string: parametros_red().magic,
             command_name: MessageHeader::procesar_comando(command),
             payload_size: payload.len() as u32,
             checksum: MessageHeader::procesar_payload(payload),
@@ -45,6 +45,17 @@ impl MessageHeader {
         bytes
     }
 
+    /// Verifica que el checksum del header corresponda al payload recibido.
+    pub fn checksum_valido(&self, payload: &[u8]) -> bool {
+        MessageHeader::procesar_payload(payload) == self.checksum
+    }
+
+    /// Devuelve el nombre del comando sin el padding de '\0'.
+    pub fn comando(&self) -> Result<String, RustifyError> {
+        let comando = String::from_utf8(self.command_name.to_vec())?;
+        Ok(comando.trim_end_matches('\0').to_string())
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<MessageHeader, RustifyError> {
         let mut start_string = [0; 4];
         start_string.copy_from_slice(&bytes[0..4]);
@@ -0,0 +1,505 @@
+use crate::addr::{AddrMessage, MAX_DIRECCIONES_POR_ADDR, NETWORK_ADDRESS_SIZE};
+use crate::addrv2::AddrV2Message;
+use crate::block_header::{BlockHeader, BLOCK_HEADER_SIZE};
+use crate::bloom::{FiltroBloom, MAX_BYTES_FILTRO};
+use crate::compact_block::{GetBlockTxn, SendCmpct};
+use crate::compact_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, PedidoFiltros, MAX_CFHEADERS_POR_MENSAJE,
+};
+use crate::compactsize::{leer_bytes, CompactSize};
+use crate::errors::RustifyError;
+use crate::getheaders::GetHeadersMessage;
+use crate::inv::{Inv, INVENTORY_SIZE};
+use crate::merkle_block::MerkleBlock;
+use crate::message_header::{MessageHeader, MESSAGE_HEADER_SIZE};
+use crate::red::parametros_red;
+use crate::version::VersionMessage;
+use std::io::{Read, Write};
+
+const MAX_COMPACTSIZE: usize = 9;
+/// Tamaño máximo de payload para cualquier mensaje (4 MB, igual que Bitcoin Core).
+pub const MAX_PAYLOAD_SIZE: usize = 4_000_000;
+const MAX_HEADERS_POR_MENSAJE: usize = 2000;
+const MAX_INVENTARIOS_POR_MENSAJE: usize = 50_000;
+const MAX_HASHES_LOCATOR: usize = 101;
+const MAX_USER_AGENT: usize = 256;
+/// Version message sin el user agent: 85 bytes fijos, start_height y relay.
+const VERSION_SIN_USER_AGENT: usize = 90;
+/// time, services, network id, largo de la dirección, dirección (máximo 512 bytes) y puerto.
+const MAX_DIRECCION_ADDRV2: usize = 4 + MAX_COMPACTSIZE + 1 + MAX_COMPACTSIZE + 512 + 2;
+
+/// Mensajes del protocolo P2P soportados por el nodo.
+/// Para soportar un mensaje nuevo alcanza con agregar la variante, su comando,
+/// su serialización y su parseo en este archivo.
+#[derive(Debug)]
+pub enum NetworkMessage {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    GetAddr,
+    Addr(AddrMessage),
+    SendAddrV2,
+    AddrV2(AddrV2Message),
+    SendHeaders,
+    /// Fee mínima, en satoshis por kilobyte, de las transacciones que se quieren recibir (BIP133).
+    FeeFilter(u64),
+    GetHeaders(GetHeadersMessage),
+    Headers(Vec<BlockHeader>),
+    Inv(Inv),
+    GetData(Inv),
+    NotFound(Inv),
+    /// Bloque serializado, tal cual se recibe y se guarda en disco.
+    Block(Vec<u8>),
+    /// Transacción serializada. El txid se calcula sobre estos mismos bytes.
+    Tx(Vec<u8>),
+    SendCmpct(SendCmpct),
+    /// Bloque compacto serializado. Como con block, lo parsea quien lo procesa.
+    CmpctBlock(Vec<u8>),
+    GetBlockTxn(GetBlockTxn),
+    /// Transacciones de un bloque pedidas con getblocktxn, serializadas.
+    BlockTxn(Vec<u8>),
+    FilterLoad(FiltroBloom),
+    MerkleBlock(MerkleBlock),
+    GetCFilters(PedidoFiltros),
+    CFilter(CFilter),
+    GetCFHeaders(PedidoFiltros),
+    CFHeaders(CFHeaders),
+    GetCFCheckpt(GetCFCheckpt),
+    CFCheckpt(CFCheckpt),
+    /// Mensaje con un comando que el nodo no conoce. Se conserva el payload sin parsear.
+    Desconocido {
+        comando: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl NetworkMessage {
+    /// Devuelve el nombre del comando del mensaje, sin el padding de '\0'.
+    pub fn comando(&self) -> &str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::Verack => "verack",
+            NetworkMessage::Ping(_) => "ping",
+            NetworkMessage::Pong(_) => "pong",
+            NetworkMessage::GetAddr => "getaddr",
+            NetworkMessage::Addr(_) => "addr",
+            NetworkMessage::SendAddrV2 => "sendaddrv2",
+            NetworkMessage::AddrV2(_) => "addrv2",
+            NetworkMessage::SendHeaders => "sendheaders",
+            NetworkMessage::FeeFilter(_) => "feefilter",
+            NetworkMessage::GetHeaders(_) => "getheaders",
+            NetworkMessage::Headers(_) => "headers",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::NotFound(_) => "notfound",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::SendCmpct(_) => "sendcmpct",
+            NetworkMessage::CmpctBlock(_) => "cmpctblock",
+            NetworkMessage::GetBlockTxn(_) => "getblocktxn",
+            NetworkMessage::BlockTxn(_) => "blocktxn",
+            NetworkMessage::FilterLoad(_) => "filterload",
+            NetworkMessage::MerkleBlock(_) => "merkleblock",
+            NetworkMessage::GetCFilters(_) => "getcfilters",
+            NetworkMessage::CFilter(_) => "cfilter",
+            NetworkMessage::GetCFHeaders(_) => "getcfheaders",
+            NetworkMessage::CFHeaders(_) => "cfheaders",
+            NetworkMessage::GetCFCheckpt(_) => "getcfcheckpt",
+            NetworkMessage::CFCheckpt(_) => "cfcheckpt",
+            NetworkMessage::Desconocido { comando, .. } => comando,
+        }
+    }
+
+    /// Serializa el payload del mensaje (sin el header).
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            NetworkMessage::Version(version) => version.as_bytes(),
+            NetworkMessage::Verack
+            | NetworkMessage::GetAddr
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::SendHeaders => {
+                vec![]
+            }
+            NetworkMessage::Ping(nonce) | NetworkMessage::Pong(nonce) => {
+                nonce.to_le_bytes().to_vec()
+            }
+            NetworkMessage::FeeFilter(fee) => fee.to_le_bytes().to_vec(),
+            NetworkMessage::Addr(addr) => addr.as_bytes(),
+            NetworkMessage::AddrV2(addrv2) => addrv2.as_bytes(),
+            NetworkMessage::GetHeaders(getheaders) => getheaders.as_bytes(),
+            NetworkMessage::Headers(headers) => {
+                let mut bytes = CompactSize::new(headers.len() as u64).as_bytes();
+                for header in headers {
+                    bytes.extend_from_slice(&header.as_bytes());
+                    bytes.push(0x00); // Transaction count, siempre 0 en el mensaje headers.
+                }
+                bytes
+            }
+            NetworkMessage::Inv(inv)
+            | NetworkMessage::GetData(inv)
+            | NetworkMessage::NotFound(inv) => inv.as_bytes(),
+            NetworkMessage::Block(bytes)
+            | NetworkMessage::Tx(bytes)
+            | NetworkMessage::CmpctBlock(bytes)
+            | NetworkMessage::BlockTxn(bytes) => bytes.clone(),
+            NetworkMessage::SendCmpct(sendcmpct) => sendcmpct.as_bytes(),
+            NetworkMessage::GetBlockTxn(getblocktxn) => getblocktxn.as_bytes(),
+            NetworkMessage::FilterLoad(filtro) => filtro.as_bytes(),
+            NetworkMessage::MerkleBlock(merkleblock) => merkleblock.as_bytes(),
+            NetworkMessage::GetCFilters(pedido) | NetworkMessage::GetCFHeaders(pedido) => {
+                pedido.as_bytes()
+            }
+            NetworkMessage::CFilter(cfilter) => cfilter.as_bytes(),
+            NetworkMessage::CFHeaders(cfheaders) => cfheaders.as_bytes(),
+            NetworkMessage::GetCFCheckpt(getcfcheckpt) => getcfcheckpt.as_bytes(),
+            NetworkMessage::CFCheckpt(cfcheckpt) => cfcheckpt.as_bytes(),
+            NetworkMessage::Desconocido { payload, .. } => payload.clone(),
+        }
+    }
+
+    /// Parsea el payload recibido según el comando indicado en el header.
+    /// Cualquier error de parseo se devuelve como MensajeMalformado: es una violación del protocolo.
+    pub fn from_payload(comando: &str, payload: &[u8]) -> Result<NetworkMessage, RustifyError> {
+        Self::parsear_comando(comando, payload).map_err(|_| RustifyError::MensajeMalformado)
+    }
+
+    fn parsear_comando(comando: &str, payload: &[u8]) -> Result<NetworkMessage, RustifyError> {
+        let mensaje = match comando {
+            "version" => NetworkMessage::Version(VersionMessage::from_bytes(payload)?),
+            "verack" => NetworkMessage::Verack,
+            "ping" => NetworkMessage::Ping(leer_u64(payload)?),
+            "pong" => NetworkMessage::Pong(leer_u64(payload)?),
+            "getaddr" => NetworkMessage::GetAddr,
+            "addr" => NetworkMessage::Addr(AddrMessage::from_bytes(payload)?),
+            "sendaddrv2" => NetworkMessage::SendAddrV2,
+            "addrv2" => NetworkMessage::AddrV2(AddrV2Message::from_bytes(payload)?),
+            "sendheaders" => NetworkMessage::SendHeaders,
+            "feefilter" => NetworkMessage::FeeFilter(leer_u64(payload)?),
+            "getheaders" => NetworkMessage::GetHeaders(GetHeadersMessage::from_bytes(payload)?),
+            "headers" => NetworkMessage::Headers(parsear_headers(payload)?),
+            "inv" => NetworkMessage::Inv(Inv::from_bytes(payload)?),
+            "getdata" => NetworkMessage::GetData(Inv::from_bytes(payload)?),
+            "notfound" => NetworkMessage::NotFound(Inv::from_bytes(payload)?),
+            "block" => NetworkMessage::Block(payload.to_vec()),
+            "tx" => NetworkMessage::Tx(payload.to_vec()),
+            "sendcmpct" => NetworkMessage::SendCmpct(SendCmpct::from_bytes(payload)?),
+            "cmpctblock" => NetworkMessage::CmpctBlock(payload.to_vec()),
+            "getblocktxn" => NetworkMessage::GetBlockTxn(GetBlockTxn::from_bytes(payload)?),
+            "blocktxn" => NetworkMessage::BlockTxn(payload.to_vec()),
+            "filterload" => NetworkMessage::FilterLoad(FiltroBloom::from_bytes(payload)?),
+            "merkleblock" => NetworkMessage::MerkleBlock(MerkleBlock::from_bytes(payload)?),
+            "getcfilters" => NetworkMessage::GetCFilters(PedidoFiltros::from_bytes(payload)?),
+            "cfilter" => NetworkMessage::CFilter(CFilter::from_bytes(payload)?),
+            "getcfheaders" => NetworkMessage::GetCFHeaders(PedidoFiltros::from_bytes(payload)?),
+            "cfheaders" => NetworkMessage::CFHeaders(CFHeaders::from_bytes(payload)?),
+            "getcfcheckpt" => NetworkMessage::GetCFCheckpt(GetCFCheckpt::from_bytes(payload)?),
+            "cfcheckpt" => NetworkMessage::CFCheckpt(CFCheckpt::from_bytes(payload)?),
+            _ => NetworkMessage::Desconocido {
+                comando: comando.to_string(),
+                payload: payload.to_vec(),
+            },
+        };
+        Ok(mensaje)
+    }
+
+    /// Serializa el mensaje completo: header (magic, comando, largo y checksum) seguido del payload.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let payload = self.payload();
+        let header = MessageHeader::new(self.comando().to_string(), &payload);
+        [header.as_bytes().to_vec(), payload].concat()
+    }
+}
+
+/// Lee del stream un mensaje completo (header y payload) y lo parsea.
+pub fn leer_mensaje<R: Read>(lector: &mut R) -> Result<NetworkMessage, RustifyError> {
+    let mut bytes_header = [0u8; MESSAGE_HEADER_SIZE];
+    lector.read_exact(&mut bytes_header)?;
+    let header = MessageHeader::from_bytes(&bytes_header)?;
+    let comando = validar_header(&header)?;
+
+    let mut payload = vec![0u8; header.payload_size as usize];
+    lector.read_exact(&mut payload)?;
+    parsear_payload(&header, &comando, &payload)
+}
+
+/// Intenta decodificar un mensaje completo desde el comienzo del buffer, con las mismas
+/// validaciones que leer_mensaje. Se usa con sockets no bloqueantes, donde los bytes llegan de a partes.
+/// Devuelve el mensaje y la cantidad de bytes que ocupaba, o None si todavía no llegó completo.
+pub fn decodificar_mensaje(buffer: &[u8]) -> Result<Option<(NetworkMessage, usize)>, RustifyError> {
+    if buffer.len() < MESSAGE_HEADER_SIZE {
+        return Ok(None);
+    }
+    let header = MessageHeader::from_bytes(&buffer[..MESSAGE_HEADER_SIZE])?;
+    let comando = validar_header(&header)?;
+    let largo_total = MESSAGE_HEADER_SIZE + header.payload_size as usize;
+    if buffer.len() < largo_total {
+        return Ok(None);
+    }
+    let mensaje = parsear_payload(&header, &comando, &buffer[MESSAGE_HEADER_SIZE..largo_total])?;
+    Ok(Some((mensaje, largo_total)))
+}
+
+/// Valida el magic de la red y el largo del payload, antes de reservar memoria para el payload.
+/// Devuelve el comando del mensaje.
+fn validar_header(header: &MessageHeader) -> Result<String, RustifyError> {
+    if header.start_string != parametros_red().magic {
+        return Err(RustifyError::MagicInvalido);
+    }
+    let comando = header.comando()?;
+    if header.payload_size as usize > tamanio_maximo_payload(&comando) {
+        return Err(RustifyError::PayloadExcedido);
+    }
+    Ok(comando)
+}
+
+fn parsear_payload(
+    header: &MessageHeader,
+    comando: &str,
+    payload: &[u8],
+) -> Result<NetworkMessage, RustifyError> {
+    if !header.checksum_valido(payload) {
+        return Err(RustifyError::ChecksumInvalido);
+    }
+    NetworkMessage::from_payload(comando, payload)
+}
+
+/// Tamaño máximo que puede tener el payload de cada comando.
+/// Los comandos desconocidos (o sin límite propio, como block y tx) se limitan a MAX_PAYLOAD_SIZE.
+pub fn tamanio_maximo_payload(comando: &str) -> usize {
+    match comando {
+        "verack" | "getaddr" | "sendaddrv2" | "sendheaders" => 0,
+        "ping" | "pong" | "feefilter" => 8,
+        "sendcmpct" => 9,
+        "getcfilters" | "getcfheaders" => 37,
+        "getcfcheckpt" => 33,
+        "cfheaders" => 65 + MAX_COMPACTSIZE + MAX_CFHEADERS_POR_MENSAJE * 32,
+        "filterload" => MAX_COMPACTSIZE + MAX_BYTES_FILTRO + 9,
+        "version" => VERSION_SIN_USER_AGENT + MAX_COMPACTSIZE + MAX_USER_AGENT,
+        "addr" => MAX_COMPACTSIZE + MAX_DIRECCIONES_POR_ADDR * NETWORK_ADDRESS_SIZE,
+        "addrv2" => MAX_COMPACTSIZE + MAX_DIRECCIONES_POR_ADDR * MAX_DIRECCION_ADDRV2,
+        "getheaders" => 4 + MAX_COMPACTSIZE + (MAX_HASHES_LOCATOR + 1) * 32,
+        "headers" => MAX_COMPACTSIZE + MAX_HEADERS_POR_MENSAJE * (BLOCK_HEADER_SIZE + 1),
+        "inv" | "getdata" | "notfound" => {
+            MAX_COMPACTSIZE + MAX_INVENTARIOS_POR_MENSAJE * INVENTORY_SIZE
+        }
+        _ => MAX_PAYLOAD_SIZE,
+    }
+}
+
+/// Escribe el mensaje completo en el stream.
+pub fn escribir_mensaje<W: Write>(
+    escritor: &mut W,
+    mensaje: &NetworkMessage,
+) -> Result<(), RustifyError> {
+    escritor.write_all(&mensaje.as_bytes())?;
+    escritor.flush()?;
+    Ok(())
+}
+
+/// Lee el entero de 8 bytes (little endian) de los mensajes ping, pong y feefilter.
+fn leer_u64(payload: &[u8]) -> Result<u64, RustifyError> {
+    Ok(u64::from_le_bytes(leer_bytes(payload, 0, 8)?.try_into()?))
+}
+
+/// Parsea el payload del mensaje headers: cantidad de headers (compactsize), y por cada uno
+/// los 80 bytes del header seguidos del transaction count.
+fn parsear_headers(payload: &[u8]) -> Result<Vec<BlockHeader>, RustifyError> {
+    let (cant_headers, mut index) = CompactSize::leer(payload, 0)?;
+    let mut headers = vec![];
+    for _ in 0..cant_headers {
+        let bytes_header = leer_bytes(payload, index, BLOCK_HEADER_SIZE + 1)?;
+        headers.push(BlockHeader::from_bytes(&bytes_header[..BLOCK_HEADER_SIZE])?);
+        index += BLOCK_HEADER_SIZE + 1;
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escribir_y_leer_ping() -> Result<(), RustifyError> {
+        let mut buffer = vec![];
+        escribir_mensaje(&mut buffer, &NetworkMessage::Ping(0x1122334455667788))?;
+        assert_eq!(&buffer[4..16], b"ping\0\0\0\0\0\0\0\0");
+
+        let mensaje = leer_mensaje(&mut buffer.as_slice())?;
+        assert!(matches!(mensaje, NetworkMessage::Ping(0x1122334455667788)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_headers_ida_y_vuelta() -> Result<(), RustifyError> {
+        let genesis = BlockHeader::from_bytes(&parametros_red().genesis_header)?;
+        let mensaje = NetworkMessage::Headers(vec![genesis.clone(), genesis]);
+        let bytes = mensaje.as_bytes();
+        assert_eq!(
+            bytes.len(),
+            MESSAGE_HEADER_SIZE + 1 + 2 * (BLOCK_HEADER_SIZE + 1)
+        );
+
+        match leer_mensaje(&mut bytes.as_slice())? {
+            NetworkMessage::Headers(headers) => {
+                assert_eq!(headers.len(), 2);
+                assert_eq!(headers[1].as_bytes(), parametros_red().genesis_header);
+            }
+            otro => panic!("Se esperaba headers, se obtuvo {}", otro.comando()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_comando_desconocido() -> Result<(), RustifyError> {
+        let mensaje = NetworkMessage::from_payload("wtxidrelay", &[])?;
+        assert_eq!(mensaje.comando(), "wtxidrelay");
+        assert!(mensaje.payload().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_leer_mensaje_con_magic_invalido() {
+        let mut bytes = NetworkMessage::Verack.as_bytes();
+        bytes[0] ^= 0xff;
+        assert_eq!(
+            leer_mensaje(&mut bytes.as_slice()).unwrap_err(),
+            RustifyError::MagicInvalido
+        );
+    }
+
+    #[test]
+    fn test_leer_mensaje_con_checksum_invalido() {
+        let mut bytes = NetworkMessage::Ping(7).as_bytes();
+        let ultimo = bytes.len() - 1;
+        bytes[ultimo] ^= 0x01;
+        assert_eq!(
+            leer_mensaje(&mut bytes.as_slice()).unwrap_err(),
+            RustifyError::ChecksumInvalido
+        );
+    }
+
+    #[test]
+    fn test_leer_mensaje_con_payload_excedido() {
+        // Solo se envía el header: el payload declarado no se llega a reservar ni leer.
+        let mut header = MessageHeader::new("block".to_string(), &[]);
+        header.payload_size = u32::MAX;
+        assert_eq!(
+            leer_mensaje(&mut header.as_bytes().as_slice()).unwrap_err(),
+            RustifyError::PayloadExcedido
+        );
+
+        let mut header = MessageHeader::new("headers".to_string(), &[]);
+        header.payload_size = (tamanio_maximo_payload("headers") + 1) as u32;
+        assert_eq!(
+            leer_mensaje(&mut header.as_bytes().as_slice()).unwrap_err(),
+            RustifyError::PayloadExcedido
+        );
+    }
+
+    #[test]
+    fn test_decodificar_mensaje_por_partes() -> Result<(), RustifyError> {
+        let bytes = [
+            NetworkMessage::Ping(1).as_bytes(),
+            NetworkMessage::Verack.as_bytes(),
+        ]
+        .concat();
+        assert!(decodificar_mensaje(&bytes[..10])?.is_none());
+        assert!(decodificar_mensaje(&bytes[..MESSAGE_HEADER_SIZE + 4])?.is_none());
+
+        let (mensaje, consumidos) = match decodificar_mensaje(&bytes)? {
+            Some(decodificado) => decodificado,
+            None => panic!("El ping ya llegó completo"),
+        };
+        assert!(matches!(mensaje, NetworkMessage::Ping(1)));
+        assert_eq!(consumidos, MESSAGE_HEADER_SIZE + 8);
+        assert!(matches!(
+            decodificar_mensaje(&bytes[consumidos..])?,
+            Some((NetworkMessage::Verack, MESSAGE_HEADER_SIZE))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_headers_truncado() {
+        assert_eq!(
+            NetworkMessage::from_payload("headers", &[0x01, 0x00]).unwrap_err(),
+            RustifyError::MensajeMalformado
+        );
+    }
+
+    /// Payloads válidos de cada comando que se parsea al recibirlo.
+    fn payloads_validos() -> Vec<(&'static str, Vec<u8>)> {
+        let hash = vec![0x11; 32];
+        let genesis = parametros_red().genesis_header.to_vec();
+        vec![
+            (
+                "version",
+                [vec![0; 80], vec![3], b"/a/".to_vec(), vec![0; 4], vec![1]].concat(),
+            ),
+            ("ping", vec![7; 8]),
+            ("pong", vec![7; 8]),
+            ("feefilter", vec![7; 8]),
+            ("addr", [vec![1], vec![0; NETWORK_ADDRESS_SIZE]].concat()),
+            (
+                "addrv2",
+                [
+                    vec![1],
+                    vec![0; 4],
+                    vec![1, 1, 4],
+                    vec![10, 0, 0, 1],
+                    vec![0; 2],
+                ]
+                .concat(),
+            ),
+            (
+                "getheaders",
+                [vec![0; 4], vec![1], hash.clone(), hash.clone()].concat(),
+            ),
+            ("headers", [vec![1], genesis.clone(), vec![0]].concat()),
+            ("inv", [vec![1], vec![0; INVENTORY_SIZE]].concat()),
+            ("getdata", [vec![1], vec![0; INVENTORY_SIZE]].concat()),
+            ("notfound", [vec![1], vec![0; INVENTORY_SIZE]].concat()),
+            ("sendcmpct", vec![1; 9]),
+            ("getblocktxn", [hash.clone(), vec![1, 0]].concat()),
+            ("filterload", [vec![1, 0xff], vec![0; 9]].concat()),
+            (
+                "merkleblock",
+                [genesis, vec![1, 0, 0, 0, 1], hash.clone(), vec![1, 1]].concat(),
+            ),
+            ("getcfilters", vec![0; 37]),
+            ("getcfheaders", vec![0; 37]),
+            ("cfilter", [vec![0], hash.clone(), vec![1, 0xab]].concat()),
+            (
+                "cfheaders",
+                [vec![0], hash.clone(), hash.clone(), vec![1], hash.clone()].concat(),
+            ),
+            ("getcfcheckpt", [vec![0], hash.clone()].concat()),
+            ("cfcheckpt", [vec![0], hash.clone(), vec![1], hash].concat()),
+        ]
+    }
+
+    /// Ningún payload truncado hace fallar el parseo con un panic: se devuelve MensajeMalformado.
+    #[test]
+    fn test_payloads_truncados() -> Result<(), RustifyError> {
+        for (comando, payload) in payloads_validos() {
+            NetworkMessage::from_payload(comando, &payload)?;
+            // En version el campo relay es opcional.
+            let largo_minimo = if comando == "version" {
+                payload.len() - 1
+            } else {
+                payload.len()
+            };
+            for largo in 0..largo_minimo {
+                assert_eq!(
+                    NetworkMessage::from_payload(comando, &payload[..largo]).unwrap_err(),
+                    RustifyError::MensajeMalformado,
+                    "{} truncado a {} bytes",
+                    comando,
+                    largo
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,84 +1,206 @@
-use crate::block::{guardar_bloque_memoria, obtener_headers_validos_fecha};
-use crate::block_header::{actualizar_header_blockchain, BlockHeader};
-use crate::block_validation::{proof_of_inclusion, proof_of_work};
+use crate::address_manager::{obtener_address_manager, AddressManager};
+use crate::addrv2::{sendaddrv2, DireccionNodo};
+use crate::ban_manager::direccion_baneada;
+use crate::block::{
+    borrar_bloque_memoria, guardar_bloque_memoria, leer_bloque_guardado,
+    obtener_headers_validos_fecha, pedir_bloques, recibir_bloque_pedido,
+};
+use crate::block_header::{
+    actualizar_header_blockchain, guardar_headers, reescribir_headers, BlockHeader, NULL_HASH,
+};
+use crate::block_index::{localizador, IndiceBloques, Reorganizacion};
+use crate::block_validation::{proof_of_inclusion, validar_headers};
+use crate::bloom::{filterload, filtro_spv};
+use crate::compact_block::{hash_bloque, sendcmpct, BlockTxn, CompactBlock, GetBlockTxn};
+use crate::compact_filter::{escanear_filtros, guardar_headers_filtros};
 use crate::config::Config;
-use crate::errors::RustifyError;
+use crate::errors::{obtener_mensaje_personalizado, RustifyError};
+use crate::event_loop::{event_loop, ConexionPeer};
+use crate::getheaders::{sendheaders, GetHeadersMessage};
 use crate::gui_events::GuiEvent;
 use crate::inv::Inv;
-use crate::logger::{log, log_err, log_re_err, log_with_parameters, Action, Lvl};
-use crate::message_handler::handle_specific_message;
-use crate::message_header::MessageHeader;
+use crate::logger::{log, log_re_err, log_with_parameters, Action, Lvl};
+use crate::message_handler::{esperar_respuesta, handle_specific_messages};
+use crate::network_message::{escribir_mensaje, NetworkMessage};
+use crate::proxy::{conectar_socks5, CredencialesProxy};
+use crate::red::parametros_red;
 use crate::serialized_block::SerializedBlock;
-use crate::server_notification::add_txn_in_memory;
+use crate::server_notification::{add_txn_in_memory, fee_minima_mempool};
 use crate::threadpool::ThreadPool;
+use crate::tx_validation::{validar_transacciones_bloque, verificar_scripts_txn};
 use crate::txn::Txn;
+use crate::utxo::{desconectar_bloques, update_utxo, utxos_completas, SetUtxos};
 use crate::version::{verack, version};
 use crate::wallet_events::WalletEvent;
-use rand::prelude::*;
+use bitcoin_hashes::{sha256d, Hash};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::{TcpStream, ToSocketAddrs};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const MSG_TX: usize = 1;
 const MSG_BLOCK: usize = 2;
-type TrxServer = Vec<(String, Txn)>;
+const MAX_HEADERS_POR_MENSAJE: usize = 2000;
+// Cantidad de headers de la punta de la cadena contra los que se comparan los headers anunciados.
+const CANT_HEADERS_RECIENTES: usize = 10;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
+/// Estado de la cadena que actualiza el listener: los headers de la cadena activa, el índice de bloques
+/// y el set de UTXOs, que comparte con la wallet.
+type Cadena<'a> = (
+    &'a mut Arc<Mutex<Vec<BlockHeader>>>,
+    &'a mut IndiceBloques,
+    &'a Arc<Mutex<SetUtxos>>,
+);
 
 /// Conecta el nodo a otro nodo del DNS de Bitcoin Testnet.
-/// Devuelve el TcpStream con la conexión establecida.
+/// Devuelve la conexión establecida.
 pub fn conectar(
     config: &Config,
     logger_sender: &Sender<String>,
-) -> Result<TcpStream, RustifyError> {
-    let server: Vec<_> = config.address.to_socket_addrs()?.collect();
-
-    for _ in 0..config.cant_retries {
-        let mut random_ip = server
-            .choose(&mut rand::thread_rng())
-            .expect("ERROR: Error al obtener una direccion IP.");
-        while !random_ip.is_ipv4() {
-            random_ip = server
-                .choose(&mut rand::thread_rng())
-                .expect("ERROR: Error al obtener una direccion IP.");
-        }
-        match TcpStream::connect_timeout(random_ip, Duration::new(config.timeout_secs, 0)) {
+) -> Result<ConexionPeer, RustifyError> {
+    conectar_excluyendo(config, logger_sender, &[])
+}
+
+/// Conecta el nodo a otro nodo de la red, evitando las direcciones excluidas
+/// (por ejemplo, las de los peers a los que ya se está conectado).
+/// Las direcciones se eligen del AddressManager del nodo, que registra el resultado de cada intento.
+/// Nunca se conecta a nodos baneados.
+/// Si hay un proxy configurado, la conexión se establece a través de él.
+/// La conexión establecida se registra en el event loop del nodo, que es quien lee y escribe el socket.
+/// Si está habilitado el transporte v2 (BIP324), se intenta primero la conexión cifrada.
+pub fn conectar_excluyendo(
+    config: &Config,
+    logger_sender: &Sender<String>,
+    excluidas: &[DireccionNodo],
+) -> Result<ConexionPeer, RustifyError> {
+    let address_manager = match obtener_address_manager() {
+        Some(address_manager) => address_manager.clone(),
+        None => AddressManager::build(config, logger_sender)?,
+    };
+    // Las direcciones sin IP (.onion, .i2p) solamente son alcanzables a través del proxy.
+    let candidatas: Vec<DireccionNodo> = address_manager
+        .elegir_candidatas(excluidas, !config.proxy.is_empty())?
+        .into_iter()
+        .filter(|direccion| !direccion_baneada(direccion))
+        .collect();
+
+    for direccion in candidatas.iter().cycle().take(config.cant_retries) {
+        match abrir_conexion(config, direccion) {
             Ok(conexion) => {
                 log_with_parameters(
                     Lvl::Info(Action::CONNECT),
-                    format!("Se realizo la conexion con la IP: {}", random_ip),
+                    format!("Se realizo la conexion con la IP: {}", direccion),
                     logger_sender,
                 );
-                return Ok(conexion);
+                address_manager.registrar_conexion(direccion)?;
+                return registrar_conexion(config, conexion, direccion, logger_sender);
             }
             Err(e) => {
                 log_with_parameters(
                     Lvl::Error(Action::CONNECT),
-                    format!("La ip {} da el error {}", random_ip, e),
+                    format!(
+                        "La ip {} da el error {}",
+                        direccion,
+                        obtener_mensaje_personalizado(e)
+                    ),
                     logger_sender,
                 );
-                log_err(Action::CONNECT, e, logger_sender)
+                address_manager.registrar_falla(direccion)?;
             }
         };
     }
     Err(RustifyError::NoHayConexionesDisponibles)
 }
 
+/// Registra la conexión en el event loop. Con el transporte v2 habilitado, espera a que se complete
+/// su handshake; si el nodo no lo soporta (cierra la conexión o no responde a tiempo),
+/// se vuelve a conectar y usa el transporte v1.
+fn registrar_conexion(
+    config: &Config,
+    socket: TcpStream,
+    direccion: &DireccionNodo,
+    logger_sender: &Sender<String>,
+) -> Result<ConexionPeer, RustifyError> {
+    let event_loop = event_loop(logger_sender)?;
+    if !config.transporte_v2 {
+        return event_loop.registrar(socket, *direccion);
+    }
+    let conexion = event_loop.registrar_v2(socket, *direccion)?;
+    let limite = Instant::now() + Duration::new(config.timeout_secs, 0);
+    match conexion.esperar_cifrado(limite) {
+        Ok(()) => {
+            log_with_parameters(
+                Lvl::Info(Action::CONNECT),
+                format!("La conexion con {} usa el transporte cifrado v2", direccion),
+                logger_sender,
+            );
+            Ok(conexion)
+        }
+        Err(e) => {
+            conexion.cerrar();
+            log_with_parameters(
+                Lvl::Warning(Action::CONNECT),
+                format!(
+                    "No se pudo usar el transporte v2 con {} ({}), se reconecta con v1",
+                    direccion,
+                    obtener_mensaje_personalizado(e)
+                ),
+                logger_sender,
+            );
+            event_loop.registrar(abrir_conexion(config, direccion)?, *direccion)
+        }
+    }
+}
+
+/// Abre la conexión TCP con el nodo, directamente o a través del proxy SOCKS5 configurado.
+/// Si se configuró proxy_aislar_conexiones, cada conexión usa credenciales aleatorias,
+/// para que el proxy (Tor) la aísle en un circuito propio.
+fn abrir_conexion(config: &Config, destino: &DireccionNodo) -> Result<TcpStream, RustifyError> {
+    let timeout = Duration::new(config.timeout_secs, 0);
+    if config.proxy.is_empty() {
+        let addr = destino
+            .socket_addr()
+            .ok_or(RustifyError::NoHayConexionesDisponibles)?;
+        return Ok(TcpStream::connect_timeout(&addr, timeout)?);
+    }
+
+    let proxy = config
+        .proxy
+        .to_socket_addrs()?
+        .next()
+        .ok_or(RustifyError::ErrorProxy)?;
+    let credenciales = match config.proxy_aislar_conexiones {
+        true => Some(CredencialesProxy::aleatorias()),
+        false => None,
+    };
+    conectar_socks5(&proxy, destino, credenciales.as_ref(), timeout)
+}
+
 /// Hace el handshake con el nodo conectado, para terminar de establecer la conexión.
 /// Envía y recibe los mensajes version y verack.
+/// Antes del verack envía sendaddrv2, para recibir direcciones en formato addrv2 (BIP155).
+/// Luego pide que los bloques nuevos se anuncien con headers (BIP130) o directamente como bloques compactos (BIP152).
+/// En modo SPV, en lugar de pedir bloques compactos, carga el filtro bloom de las wallets (BIP37).
 pub fn handshake(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
     version(socket, config, logger_sender)?;
-    verack(socket, logger_sender)?;
+    sendaddrv2(socket, logger_sender)?;
+    verack(socket, config, logger_sender)?;
     log(
         Lvl::Info(Action::CONNECT),
         "Se realizó el handshake con el nodo. Conexión establecida",
         logger_sender,
     );
+    sendheaders(socket, logger_sender)?;
+    match filtro_spv() {
+        Some(filtro) => filterload(socket, filtro, logger_sender)?,
+        None => sendcmpct(socket, true, logger_sender)?,
+    }
 
     Ok(())
 }
@@ -87,8 +209,11 @@ pub fn handshake(
 /// 1) Chequear si tengo la cadena de BLOQUES completa.
 /// 2) Si 1) es NO, chequear si tengo la cadena de HEADERS completa.
 /// 3) Si 2) es NO, se descargan los headers restantes con el mensaje getheaders.
+///
+/// Con filtros_compactos, en lugar de los bloques posteriores a la fecha inicial se descargan los bloques
+/// de toda la cadena cuyo filtro compacto (BIP158) coincide con las wallets.
 pub fn initial_block_download(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
     sender_gui: &gtk::glib::Sender<GuiEvent>,
@@ -96,8 +221,22 @@ pub fn initial_block_download(
     let (headers, indice_ultimo_header) =
         actualizar_header_blockchain(socket, config, logger_sender, sender_gui)?; // Vector con todos los headers en memoria.
 
-    let headers_validos_fecha =
-        obtener_headers_validos_fecha(config, &headers, indice_ultimo_header);
+    let (headers_validos_fecha, headers_filtros) = if config.filtros_compactos {
+        sender_gui.send(GuiEvent::ActualizarLabelEstado(
+            "Scanning compact filters...".to_string(),
+        ))?;
+        // Los headers de los filtros se verifican contra los de un segundo nodo.
+        let mut verificador = conectar_excluyendo(config, logger_sender, &[socket.addr()])?;
+        let resultado = handshake(&mut verificador, config, logger_sender).and_then(|_| {
+            escanear_filtros((socket, &mut verificador), &headers, config, logger_sender)
+        });
+        verificador.cerrar();
+        resultado?
+    } else {
+        let headers_validos_fecha =
+            obtener_headers_validos_fecha(config, &headers, indice_ultimo_header);
+        (headers_validos_fecha, vec![])
+    };
     let cant_bloques_a_descargar = headers_validos_fecha.len();
 
     log_with_parameters(
@@ -115,6 +254,7 @@ pub fn initial_block_download(
 
     if cant_bloques_a_descargar == 0 {
         // Si no hay bloques a descargar, no hace falta inicializar la threadpool.
+        guardar_headers_filtros(config, &headers_filtros)?;
         return Ok(headers);
     }
 
@@ -133,6 +273,8 @@ pub fn initial_block_download(
     );
 
     threads.download_blocks(headers_validos_fecha, logger_sender)?;
+    // Los filtros se marcan como escaneados recién cuando se descargaron sus bloques.
+    guardar_headers_filtros(config, &headers_filtros)?;
 
     log(
         Lvl::Info(Action::INB),
@@ -145,10 +287,19 @@ pub fn initial_block_download(
 
 /// El nodo queda a la espera de nuevos bloques y transacciones enviados por el nodo remoto para su validación.
 /// Se reciben mensajes inv y se filtran aquellos que son de tipo bloque o de tipo transacción.
+/// Los nodos que respetan sendheaders anuncian los bloques nuevos con mensajes headers, que se procesan directamente,
+/// y los que respetan sendcmpct los envían como bloques compactos, que se reconstruyen con las transacciones en memoria.
+/// Con feefilter (BIP133) se le indica al nodo la fee mínima de las transacciones que se quieren recibir,
+/// y se actualiza cada vez que cambia la fee mínima de la memoria.
+/// En modo SPV los bloques anunciados con inv se piden a partir de sus headers, para recibirlos filtrados.
+/// El índice de bloques registra las ramas conocidas, para reorganizar la cadena si alguna supera en trabajo a la activa.
+/// Los bloques que se agregan a la cadena se validan contra el set de UTXOs, y se conectan a él antes de enviarlos a la wallet.
 pub fn recibir_nuevos_bloques_txs(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     headers: &mut Arc<Mutex<Vec<BlockHeader>>>,
-    mut txn_memory_server: Arc<Mutex<Vec<(String, Txn)>>>,
+    indice: &mut IndiceBloques,
+    utxos: &Arc<Mutex<SetUtxos>>,
+    mut txn_memory_server: Arc<Mutex<TrxServer>>,
     config: &Config,
     tupla_senders: (
         &Sender<String>,
@@ -158,50 +309,80 @@ pub fn recibir_nuevos_bloques_txs(
     ),
 ) -> Result<(), RustifyError> {
     let (logger_sender, sender_gui, sender_wallet, sender_notif) = tupla_senders;
-    let mut bytes_respuesta: Vec<u8>;
     let mut headers_archivo = OpenOptions::new()
         .read(true)
         .write(true)
         .append(true)
         .open(config.headers_path.clone())?;
-    let blocks_path = config.blocks_path.clone();
     log(
         Lvl::Info(Action::LISTENER),
         "Ha iniciado el proceso que recibe notificaciones de bloques y transacciones",
         logger_sender,
     );
+    let mut fee_filtro = fee_minima_mempool(&txn_memory_server, config)?;
+    feefilter(socket, fee_filtro, logger_sender)?;
     loop {
-        // Filtro si el mensaje recibido es "inv".
-        bytes_respuesta = match handle_specific_message(
-            socket,
-            "inv\0\0\0\0\0\0\0\0\0".to_string(),
-            logger_sender,
-        ) {
-            Ok(b) => b,
-            Err(e) => {
-                if e == RustifyError::ElNodoNoEncuentraBloquePedido
-                    || e == RustifyError::ElNodoNoEncuentraTransaccionPedida
-                {
+        // Filtro si el mensaje recibido es "inv", "headers" (anuncio de bloques nuevos, BIP130)
+        // o "cmpctblock" (bloque nuevo compacto, BIP152).
+        let mensajes_esperados = ["inv", "headers", "cmpctblock"];
+        let inv_recibido =
+            match handle_specific_messages(socket, &mensajes_esperados, logger_sender) {
+                Ok(NetworkMessage::Inv(inv)) => inv,
+                Ok(NetworkMessage::Headers(anunciados)) => {
+                    let nuevos = recibir_headers_anunciados(
+                        socket,
+                        (headers, indice, utxos),
+                        anunciados,
+                        &mut headers_archivo,
+                        config,
+                        (logger_sender, sender_gui, sender_wallet),
+                    )?;
+                    notificar_bloques_servidor(&nuevos, config, sender_notif, logger_sender);
                     continue;
-                } else {
-                    log_re_err(Action::LISTENER, e.clone(), logger_sender);
-                    return Err(e);
                 }
-            }
-        };
+                Ok(NetworkMessage::CmpctBlock(bytes)) => {
+                    let nuevo = recibir_bloque_compacto(
+                        socket,
+                        (headers, indice, utxos),
+                        &bytes,
+                        &txn_memory_server,
+                        &mut headers_archivo,
+                        config,
+                        (logger_sender, sender_gui, sender_wallet),
+                    )?;
+                    notificar_bloques_servidor(&nuevo, config, sender_notif, logger_sender);
+                    continue;
+                }
+                Ok(_) => return Err(RustifyError::MensajeInesperado),
+                Err(e) => {
+                    if e == RustifyError::ElNodoNoEncuentraBloquePedido
+                        || e == RustifyError::ElNodoNoEncuentraTransaccionPedida
+                    {
+                        continue;
+                    } else {
+                        log_re_err(Action::LISTENER, e.clone(), logger_sender);
+                        return Err(e);
+                    }
+                }
+            };
 
-        let inv_recibido = Inv::from_bytes(&bytes_respuesta)?;
-        let tipo_inv = inv_recibido.inventories[0][0] as usize;
-        let tupla_senders = (sender_gui, sender_wallet);
+        let tipo_inv = match inv_recibido.inventories.first().and_then(|i| i.first()) {
+            Some(tipo) => *tipo as usize,
+            None => return Err(RustifyError::MensajeMalformado),
+        };
+        let tupla_senders = (logger_sender, sender_gui, sender_wallet);
         // Filtro los inv recibidos.
-        if tipo_inv == MSG_BLOCK {
+        if tipo_inv == MSG_BLOCK && filtro_spv().is_some() {
+            // En modo SPV los bloques anunciados se piden filtrados, a partir de sus headers.
+            pedir_headers_faltantes(socket, &headers.lock()?, config)?;
+            continue;
+        } else if tipo_inv == MSG_BLOCK {
             recibir_bloque(
                 socket,
-                headers,
-                logger_sender,
+                (headers, indice, utxos),
                 &mut headers_archivo,
-                &blocks_path,
-                bytes_respuesta,
+                config,
+                &inv_recibido.filtrar_por_tipo(MSG_BLOCK as u32),
                 tupla_senders,
             )?;
         } else if tipo_inv == MSG_TX {
@@ -209,10 +390,15 @@ pub fn recibir_nuevos_bloques_txs(
                 socket,
                 config,
                 logger_sender,
-                &inv_recibido,
+                &inv_recibido.filtrar_por_tipo(MSG_TX as u32),
                 sender_wallet,
-                &mut txn_memory_server,
+                (&mut txn_memory_server, utxos),
             )?;
+            let fee_minima = fee_minima_mempool(&txn_memory_server, config)?;
+            if fee_minima != fee_filtro {
+                fee_filtro = fee_minima;
+                feefilter(socket, fee_filtro, logger_sender)?;
+            }
         } else {
             log(
                 Lvl::Info(Action::NETWORK),
@@ -221,48 +407,91 @@ pub fn recibir_nuevos_bloques_txs(
             );
             continue;
         }
-        if config.server_mode {
-            match sender_notif.send(inv_recibido) {
-                Ok(_) => log(
-                    Lvl::Info(Action::LISTENER),
-                    "Se envía inv por el channel del servidor",
-                    logger_sender,
-                ),
-                Err(e) => log_re_err(Action::LISTENER, e.into(), logger_sender),
-            };
-        }
+        notificar_servidor(inv_recibido, config, sender_notif, logger_sender);
+    }
+}
+
+/// Envía el inv por el channel del servidor, para que lo reenvíe a sus clientes.
+fn notificar_servidor(
+    inv: Inv,
+    config: &Config,
+    sender_notif: &Sender<Inv>,
+    logger_sender: &Sender<String>,
+) {
+    if !config.server_mode {
+        return;
+    }
+    match sender_notif.send(inv) {
+        Ok(_) => log(
+            Lvl::Info(Action::LISTENER),
+            "Se envía inv por el channel del servidor",
+            logger_sender,
+        ),
+        Err(e) => log_re_err(Action::LISTENER, e.into(), logger_sender),
+    };
+}
+
+/// Notifica al servidor los bloques nuevos recibidos por un anuncio de headers o un bloque compacto.
+fn notificar_bloques_servidor(
+    nuevos: &[BlockHeader],
+    config: &Config,
+    sender_notif: &Sender<Inv>,
+    logger_sender: &Sender<String>,
+) {
+    if nuevos.is_empty() {
+        return;
     }
+    let headers_bytes = nuevos
+        .iter()
+        .map(|header| header.as_bytes().to_vec())
+        .collect();
+    let inv = Inv::new(nuevos.len() as u32, MSG_BLOCK as u32, headers_bytes);
+    notificar_servidor(inv, config, sender_notif, logger_sender);
 }
 
-/// Envia la transacción recibida como mensaje Inv, a la wallet, parseandola a txid
+/// Envia la transacción recibida como mensaje Inv, a la wallet, parseandola a txid.
+/// Las transacciones cuyos scripts no verifican contra los outputs conocidos se descartan.
 fn recibir_transaccion(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
     inv_txn: &Inv,
     sender_wallet: &Sender<WalletEvent>,
-    txn_memory_server: &mut Arc<Mutex<TrxServer>>,
+    (txn_memory_server, utxos): (&mut Arc<Mutex<TrxServer>>, &Arc<Mutex<SetUtxos>>),
 ) -> Result<(), RustifyError> {
     log(
         Lvl::Info(Action::NETWORK),
         "Inv de tipo transaccion.",
         logger_sender,
     );
-    let cant_inv = send_inv("getdata".to_owned(), socket, inv_txn)? as usize;
+    escribir_mensaje(socket, &NetworkMessage::GetData(inv_txn.clone()))?;
+    let timeout = Duration::from_secs(config.timeout_respuesta_secs);
+    let cant_inv = inv_txn.count.value() as usize;
     for _ in 0..cant_inv {
-        let bytes_respuesta = match handle_specific_message(
-            socket,
-            "tx\0\0\0\0\0\0\0\0\0\0".to_string(),
-            logger_sender,
-        ) {
-            Ok(b) => b,
-            Err(_) => continue,
+        let bytes_respuesta = match esperar_respuesta(socket, "tx", timeout, logger_sender) {
+            Ok(NetworkMessage::Tx(bytes)) => bytes,
+            Err(e) if e.es_violacion_protocolo() => return Err(e),
+            _ => continue,
         };
         let txid_str = Txn::obtain_tx_id(bytes_respuesta.clone());
         let (transaccion, _) = Txn::from_bytes(bytes_respuesta.to_vec(), 0)?;
+        if let Err(e) =
+            verificar_scripts_txn(&transaccion, &*utxos.lock()?, &txn_memory_server.lock()?)
+        {
+            log_with_parameters(
+                Lvl::Info(Action::NETWORK),
+                format!(
+                    "Se descarta la transaccion {}: {}",
+                    txid_str,
+                    obtener_mensaje_personalizado(e)
+                ),
+                logger_sender,
+            );
+            continue;
+        }
         if config.server_mode {
             add_txn_in_memory(
-                txn_memory_server,
+                (txn_memory_server, utxos),
                 &transaccion,
                 &txid_str,
                 config,
@@ -277,16 +506,21 @@ fn recibir_transaccion(
 
 /// Se recibe el bloque enviando un mensaje getdata y recibiendo un mensaje block, a partir del inv.
 /// Si el bloque recibido es válido, se agrega a la blockchain local.
+/// Si no continúa la punta de la cadena, se piden los headers que faltan: pueden ser de otra rama.
 fn recibir_bloque(
-    socket: &mut TcpStream,
-    headers: &mut Arc<Mutex<Vec<BlockHeader>>>,
-    logger_sender: &Sender<String>,
+    socket: &mut ConexionPeer,
+    cadena: Cadena,
     headers_archivo: &mut File,
-    blocks_path: &String,
-    mut bytes_respuesta: Vec<u8>,
-    tupla_senders: (&gtk::glib::Sender<GuiEvent>, &Sender<WalletEvent>),
+    config: &Config,
+    inv_bloque: &Inv,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
 ) -> Result<(), RustifyError> {
-    let (sender_gui, sender_wallet) = tupla_senders;
+    let (headers, indice, utxos) = cadena;
+    let logger_sender = tupla_senders.0;
 
     log(
         Lvl::Info(Action::WALLET),
@@ -294,97 +528,755 @@ fn recibir_bloque(
         logger_sender,
     );
     // Reenvio el inv recibido con un mensaje "getdata", ya que quiero recibir el bloque completo.
-    let response_message_header = MessageHeader::new("getdata".to_string(), &bytes_respuesta);
-    let response_message_header_bytes = response_message_header.as_bytes();
-    write_to_node(socket, &response_message_header_bytes, &bytes_respuesta)?;
+    escribir_mensaje(socket, &NetworkMessage::GetData(inv_bloque.clone()))?;
     log(
         Lvl::Info(Action::WALLET),
         "Enviado mensaje getdata.",
         logger_sender,
     );
 
-    // Recibo el bloque completo mediante un mensaje "block", durante initial block download
-    // En este handleo es posible perder mensajes inv de transacciones, que se descartan.
-    bytes_respuesta =
-        handle_specific_message(socket, "block\0\0\0\0\0\0\0".to_string(), logger_sender)?;
+    // Recibo cada bloque completo mediante un mensaje "block". Los inv de transacciones
+    // que lleguen mientras tanto quedan encolados, y se procesan al volver a esperar inv.
+    let timeout = Duration::from_secs(config.timeout_respuesta_secs);
+    for _ in 0..inv_bloque.count.value() {
+        let bytes_respuesta = match esperar_respuesta(socket, "block", timeout, logger_sender)? {
+            NetworkMessage::Block(bytes) => bytes,
+            _ => return Err(RustifyError::MensajeInesperado),
+        };
+
+        log(
+            Lvl::Info(Action::WALLET),
+            "Recibido mensaje block.",
+            logger_sender,
+        );
+        let agregado = validar_bloque(
+            (headers, indice, utxos),
+            headers_archivo,
+            config,
+            bytes_respuesta,
+            tupla_senders,
+        )?;
+        if !agregado {
+            pedir_headers_faltantes(socket, &headers.lock()?, config)?;
+        }
+    }
+    Ok(())
+}
+
+/// Procesa los headers con los que el nodo anuncia bloques nuevos (sendheaders, BIP130).
+/// Si continúan la cadena local, se validan y se agregan a la cadena de headers, y luego se piden
+/// los bloques en un único getdata. Si continúan otra rama conocida, se procesan con recibir_headers_rama,
+/// que reorganiza la cadena si la rama pasa a tener más trabajo acumulado. Si no continúan ningún header
+/// conocido (por ejemplo, porque se perdió algún anuncio), se piden los que faltan con un getheaders;
+/// la respuesta llega como otro mensaje headers y se procesa de la misma forma.
+/// Los bloques pedidos se conectan en el orden de la cadena con conectar_bloque_descargado.
+/// Si hubo una reorganización y algún bloque de la nueva rama no se pudo conectar, se vuelve a la cadena anterior.
+/// Devuelve los headers que se agregaron a la cadena activa.
+fn recibir_headers_anunciados(
+    socket: &mut ConexionPeer,
+    cadena: Cadena,
+    anunciados: Vec<BlockHeader>,
+    headers_archivo: &mut File,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<Vec<BlockHeader>, RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let (logger_sender, sender_gui, _) = tupla_senders;
+    let cant_anunciados = anunciados.len();
+    let (nuevos, reorganizacion) = {
+        let mut headers_vec = headers.lock()?;
+        let mut nuevos: Vec<BlockHeader> = anunciados
+            .into_iter()
+            .filter(|header| {
+                !header_reciente(&headers_vec, header)
+                    && indice.entrada(&hash_bloque(header)).is_none()
+            })
+            .collect();
+        let hash_punta = match headers_vec.last() {
+            Some(punta) => hash_bloque(punta),
+            None => return Err(RustifyError::NotFound),
+        };
+        let primero = match nuevos.first() {
+            None => return Ok(vec![]),
+            Some(primero) => primero.clone(),
+        };
+        // Si se apoyan en un header de la cadena activa, se descartan los que ya están en ella
+        // (por ejemplo, en la respuesta a un getheaders que encontró un header en común antes de la punta).
+        if let Some((altura, rama)) = indice.rama(&headers_vec, &primero.previous_block_header_hash)
+        {
+            if rama.is_empty() {
+                let repetidos = nuevos
+                    .iter()
+                    .zip(&headers_vec[altura..])
+                    .take_while(|(nuevo, header)| nuevo.as_bytes() == header.as_bytes())
+                    .count();
+                nuevos.drain(..repetidos);
+            }
+        }
+
+        match nuevos.first() {
+            None => return Ok(vec![]),
+            Some(primero) if primero.previous_block_header_hash == hash_punta => {
+                let altura = headers_vec.len() as u32;
+                let pagina: Vec<Vec<u8>> = nuevos
+                    .iter()
+                    .map(|header| header.as_bytes().to_vec())
+                    .collect();
+                guardar_headers(headers_archivo, &mut headers_vec, &pagina)?;
+                for header in &nuevos {
+                    indice.extender(header);
+                }
+                sender_gui.send(GuiEvent::CargarBloques(nuevos.clone(), altura))?;
+                log_with_parameters(
+                    Lvl::Info(Action::LISTENER),
+                    format!(
+                        "Se agregaron {} headers anunciados a la cadena.",
+                        nuevos.len()
+                    ),
+                    logger_sender,
+                );
+                (nuevos, None)
+            }
+            Some(_) => {
+                match recibir_headers_rama(
+                    socket,
+                    (&mut headers_vec, indice, utxos),
+                    nuevos,
+                    config,
+                    tupla_senders,
+                )? {
+                    Some(reorganizacion) => {
+                        (reorganizacion.conectados.clone(), Some(reorganizacion))
+                    }
+                    None => return Ok(vec![]),
+                }
+            }
+        }
+    };
+
+    let mut conectados = 0;
+    let descarga = descargar_bloques(
+        socket,
+        (headers, indice, utxos),
+        &nuevos,
+        config,
+        tupla_senders,
+        &mut conectados,
+    );
+    if let Some(reorganizacion) = reorganizacion {
+        match descarga {
+            Ok(()) => finalizar_reorganizacion(&reorganizacion, config)?,
+            Err(e) => {
+                revertir_reorganizacion(
+                    (headers, indice, utxos),
+                    &reorganizacion,
+                    conectados,
+                    config,
+                    tupla_senders,
+                )?;
+                return Err(e);
+            }
+        }
+    }
+    descarga?;
+
+    // Una respuesta completa a un getheaders indica que todavía quedan headers por pedir.
+    if cant_anunciados == MAX_HEADERS_POR_MENSAJE {
+        pedir_headers_faltantes(socket, &headers.lock()?, config)?;
+    }
+    Ok(nuevos)
+}
+
+/// Pide los bloques de los headers nuevos, que ya están en la punta de la cadena activa, en un único getdata,
+/// y los conecta en el orden de la cadena con conectar_bloque_descargado a medida que llegan.
+/// En conectados se lleva la cantidad de bloques conectados, para poder revertirlos si la descarga falla.
+fn descargar_bloques(
+    socket: &mut ConexionPeer,
+    cadena: Cadena,
+    nuevos: &[BlockHeader],
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+    conectados: &mut usize,
+) -> Result<(), RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let logger_sender = tupla_senders.0;
+    pedir_bloques(socket, nuevos)?;
+    let altura_primero = headers.lock()?.len() - nuevos.len();
+    let timeout = Duration::from_secs(config.timeout_respuesta_secs);
+    let mut recibidos: Vec<Option<SerializedBlock>> = nuevos.iter().map(|_| None).collect();
+    for _ in 0..nuevos.len() {
+        let bloque =
+            recibir_bloque_pedido(socket, nuevos, &config.blocks_path, timeout, logger_sender)?;
+        if let Some(posicion) = nuevos
+            .iter()
+            .position(|header| header.as_bytes() == bloque.block_header.as_bytes())
+        {
+            recibidos[posicion] = Some(bloque);
+        }
+        // Los bloques pueden llegar en cualquier orden, pero se conectan en el orden de la cadena.
+        while let Some(bloque) = recibidos.get_mut(*conectados).and_then(Option::take) {
+            conectar_bloque_descargado(
+                (headers, indice, utxos),
+                altura_primero + *conectados,
+                bloque,
+                config,
+                tupla_senders,
+            )?;
+            *conectados += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Conecta al set de UTXOs un bloque descargado cuyo header ya está en la cadena activa, en la altura indicada,
+/// y lo envía a la wallet. Salvo en modo SPV, en el que los bloques llegan filtrados, antes se validan
+/// sus transacciones: si el bloque es inválido, se descartan su header y los posteriores (de la cadena,
+/// del índice, de disco y de la interfaz) y se devuelve el motivo del rechazo, para penalizar al nodo que lo envió.
+fn conectar_bloque_descargado(
+    cadena: Cadena,
+    altura: usize,
+    bloque: SerializedBlock,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<(), RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let (logger_sender, sender_gui, sender_wallet) = tupla_senders;
+    let validacion = match filtro_spv() {
+        Some(_) => Ok(()),
+        None => validar_transacciones_bloque(
+            &bloque,
+            altura as u32,
+            &*utxos.lock()?,
+            utxos_completas(config),
+        ),
+    };
+    if let Err(e) = validacion {
+        log_with_parameters(
+            Lvl::Warning(Action::POWPOI),
+            format!(
+                "El bloque de la altura {} fue rechazado: {}. Se descarta junto con los posteriores.",
+                altura,
+                obtener_mensaje_personalizado(e.clone())
+            ),
+            logger_sender,
+        );
+        let mut headers_vec = headers.lock()?;
+        let quitados = headers_vec.split_off(altura);
+        reescribir_headers(&config.headers_path, &headers_vec)?;
+        indice.retroceder(&quitados);
+        for header in &quitados {
+            borrar_bloque_memoria(header, &config.blocks_path)?;
+        }
+        sender_gui.send(GuiEvent::QuitarBloques(quitados.len()))?;
+        return Err(e);
+    }
+    update_utxo(
+        &mut *utxos.lock()?,
+        logger_sender,
+        &bloque,
+        altura as u32,
+        &config.blocks_path,
+    )?;
+    sender_wallet.send(WalletEvent::RecibirBloque(bloque))?;
+    Ok(())
+}
+
+/// Procesa headers que no continúan la punta de la cadena local.
+/// Si se apoyan en un header conocido (de la cadena activa o de otra rama), se validan en el contexto
+/// de su rama y se agregan al índice de bloques. Si con ellos la rama supera en trabajo acumulado
+/// a la cadena activa, se reorganiza la cadena hacia esa rama. Si no se conoce el header en el que
+/// se apoyan, se piden los que faltan con un getheaders.
+/// Devuelve la reorganización aplicada, si la hubo: sus headers conectados quedan en la cadena activa
+/// y hay que descargar sus bloques.
+fn recibir_headers_rama(
+    socket: &mut ConexionPeer,
+    cadena: (
+        &mut Vec<BlockHeader>,
+        &mut IndiceBloques,
+        &Arc<Mutex<SetUtxos>>,
+    ),
+    nuevos: Vec<BlockHeader>,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<Option<Reorganizacion>, RustifyError> {
+    let (headers_vec, indice, utxos) = cadena;
+    let logger_sender = tupla_senders.0;
+    let (altura_bifurcacion, rama) =
+        match indice.rama(headers_vec, &nuevos[0].previous_block_header_hash) {
+            Some(rama) => rama,
+            None => {
+                log(
+                    Lvl::Info(Action::LISTENER),
+                    "Los headers anunciados no continúan la cadena local. Se piden los que faltan.",
+                    logger_sender,
+                );
+                pedir_headers_faltantes(socket, headers_vec, config)?;
+                return Ok(None);
+            }
+        };
+
+    // La rama completa se valida desde la bifurcación, con la cadena activa hasta ese punto como contexto.
+    let rama_completa = [rama, nuevos.clone()].concat();
+    validar_headers(&headers_vec[..altura_bifurcacion], &rama_completa)?;
+    for (i, header) in rama_completa.iter().enumerate() {
+        parametros_red().verificar_checkpoint(
+            altura_bifurcacion + i,
+            &sha256d::Hash::hash(&header.as_bytes())[..],
+        )?;
+    }
+    for header in &nuevos {
+        indice.agregar_rama(headers_vec, header)?;
+    }
+    log_with_parameters(
+        Lvl::Info(Action::LISTENER),
+        format!(
+            "Se agregaron {} headers de una rama que se separa en la altura {}.",
+            nuevos.len(),
+            altura_bifurcacion
+        ),
+        logger_sender,
+    );
+
+    let hash_ultimo = hash_bloque(&nuevos[nuevos.len() - 1]);
+    match indice.reorganizar(headers_vec, &hash_ultimo) {
+        Some(reorganizacion) => {
+            aplicar_reorganizacion(headers_vec, &reorganizacion, utxos, config, tupla_senders)?;
+            Ok(Some(reorganizacion))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Aplica una reorganización a la cadena activa: reemplaza los headers desconectados (en memoria y en el archivo)
+/// por los de la nueva rama, que ya fueron validados. Los bloques desconectados se desconectan del set de UTXOs
+/// con sus datos de undo, se envían a la wallet, para que revierta sus confirmaciones, y se quitan de la interfaz.
+/// Los archivos de los bloques desconectados y sus datos de undo quedan en disco hasta que se conecta
+/// la nueva rama (ver finalizar_reorganizacion), para poder volver a la cadena anterior si falla.
+fn aplicar_reorganizacion(
+    headers_vec: &mut Vec<BlockHeader>,
+    reorganizacion: &Reorganizacion,
+    utxos: &Arc<Mutex<SetUtxos>>,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<(), RustifyError> {
+    let (logger_sender, sender_gui, sender_wallet) = tupla_senders;
+    let Reorganizacion {
+        altura_bifurcacion,
+        desconectados,
+        conectados,
+    } = reorganizacion;
+    log_with_parameters(
+        Lvl::Warning(Action::LISTENER),
+        format!(
+            "Reorganización de la cadena en la altura {}: se desconectan {} bloques y se conectan {}.",
+            altura_bifurcacion,
+            desconectados.len(),
+            conectados.len()
+        ),
+        logger_sender,
+    );
+
+    headers_vec.truncate(*altura_bifurcacion);
+    headers_vec.extend(conectados.iter().cloned());
+    reescribir_headers(&config.headers_path, headers_vec)?;
+
+    let mut bloques_desconectados = vec![];
+    for header in desconectados.iter().rev() {
+        if let Some(bloque) = leer_bloque_guardado(header, &config.blocks_path)? {
+            bloques_desconectados.push(bloque);
+        }
+    }
+    desconectar_bloques(
+        &mut *utxos.lock()?,
+        &bloques_desconectados,
+        headers_vec,
+        config,
+        logger_sender,
+    )?;
+    if !bloques_desconectados.is_empty() {
+        sender_wallet.send(WalletEvent::DesconectarBloques(bloques_desconectados))?;
+    }
+    sender_gui.send(GuiEvent::QuitarBloques(desconectados.len()))?;
+    sender_gui.send(GuiEvent::CargarBloques(
+        conectados.clone(),
+        *altura_bifurcacion as u32,
+    ))?;
+    Ok(())
+}
+
+/// Termina una reorganización cuyos bloques nuevos se conectaron todos: borra de disco los bloques
+/// desconectados y sus datos de undo.
+fn finalizar_reorganizacion(
+    reorganizacion: &Reorganizacion,
+    config: &Config,
+) -> Result<(), RustifyError> {
+    for header in &reorganizacion.desconectados {
+        borrar_bloque_memoria(header, &config.blocks_path)?;
+    }
+    Ok(())
+}
+
+/// Vuelve a la cadena anterior a una reorganización cuya nueva rama no se pudo conectar completa
+/// (por un bloque inválido, o porque falló la descarga). Si lo que queda de la nueva rama en la cadena activa
+/// sigue teniendo más trabajo acumulado que la cadena anterior, se mantiene y solamente se finaliza la reorganización.
+/// Si no, se desconectan del set de UTXOs los primeros bloques conectados de la nueva rama, se borran sus archivos
+/// y se vuelven a conectar los bloques de la cadena anterior, que quedaron en disco con sus datos de undo.
+fn revertir_reorganizacion(
+    cadena: Cadena,
+    reorganizacion: &Reorganizacion,
+    conectados: usize,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<(), RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let (logger_sender, sender_gui, sender_wallet) = tupla_senders;
+    let altura_bifurcacion = reorganizacion.altura_bifurcacion;
+    let hash_punta_anterior = match reorganizacion.desconectados.last() {
+        Some(punta) => hash_bloque(punta),
+        None => return Ok(()),
+    };
+    let mut headers_vec = headers.lock()?;
+    let vuelta = match indice.reorganizar(&headers_vec, &hash_punta_anterior) {
+        Some(vuelta) => vuelta,
+        None => return finalizar_reorganizacion(reorganizacion, config),
+    };
+    log_with_parameters(
+        Lvl::Warning(Action::LISTENER),
+        format!(
+            "No se pudo conectar la nueva rama desde la altura {}. Se vuelve a la cadena anterior.",
+            altura_bifurcacion
+        ),
+        logger_sender,
+    );
 
+    headers_vec.truncate(altura_bifurcacion);
+    reescribir_headers(&config.headers_path, &headers_vec)?;
+    let mut bloques_desconectados = vec![];
+    for header in vuelta.desconectados.iter().take(conectados).rev() {
+        if let Some(bloque) = leer_bloque_guardado(header, &config.blocks_path)? {
+            bloques_desconectados.push(bloque);
+        }
+    }
+    desconectar_bloques(
+        &mut *utxos.lock()?,
+        &bloques_desconectados,
+        &headers_vec,
+        config,
+        logger_sender,
+    )?;
+    for header in &vuelta.desconectados {
+        borrar_bloque_memoria(header, &config.blocks_path)?;
+    }
+    if !bloques_desconectados.is_empty() {
+        sender_wallet.send(WalletEvent::DesconectarBloques(bloques_desconectados))?;
+    }
+
+    headers_vec.extend(vuelta.conectados.iter().cloned());
+    reescribir_headers(&config.headers_path, &headers_vec)?;
+    for (i, header) in vuelta.conectados.iter().enumerate() {
+        if let Some(bloque) = leer_bloque_guardado(header, &config.blocks_path)? {
+            update_utxo(
+                &mut *utxos.lock()?,
+                logger_sender,
+                &bloque,
+                (altura_bifurcacion + i) as u32,
+                &config.blocks_path,
+            )?;
+            sender_wallet.send(WalletEvent::RecibirBloque(bloque))?;
+        }
+    }
+    sender_gui.send(GuiEvent::QuitarBloques(vuelta.desconectados.len()))?;
+    sender_gui.send(GuiEvent::CargarBloques(
+        vuelta.conectados,
+        altura_bifurcacion as u32,
+    ))?;
+    Ok(())
+}
+
+/// Procesa un bloque nuevo recibido como bloque compacto (BIP152).
+/// Si continúa la cadena local y su header es válido, se reconstruye con las transacciones en memoria
+/// y se piden con getblocktxn las que falten. Si no continúa la cadena, se piden los headers que faltan,
+/// como con los headers anunciados. Si el bloque reconstruido no cumple la proof of inclusion
+/// (por ejemplo, por una colisión de short IDs), se pide el bloque completo.
+/// Devuelve el header del bloque si se agregó a la cadena.
+fn recibir_bloque_compacto(
+    socket: &mut ConexionPeer,
+    cadena: Cadena,
+    bytes: &[u8],
+    txn_memory_server: &Arc<Mutex<TrxServer>>,
+    headers_archivo: &mut File,
+    config: &Config,
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<Vec<BlockHeader>, RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let logger_sender = tupla_senders.0;
+    let compacto = CompactBlock::from_bytes(bytes)?;
     log(
-        Lvl::Info(Action::WALLET),
-        "Recibido mensaje block.",
+        Lvl::Info(Action::LISTENER),
+        "Recibido mensaje cmpctblock.",
         logger_sender,
     );
-    validar_bloque(
-        headers,
+    {
+        let headers_vec = headers.lock()?;
+        if header_reciente(&headers_vec, &compacto.header) {
+            return Ok(vec![]);
+        }
+        let hash_punta = match headers_vec.last() {
+            Some(punta) => hash_bloque(punta),
+            None => return Err(RustifyError::NotFound),
+        };
+        if compacto.header.previous_block_header_hash != hash_punta {
+            log(
+                Lvl::Info(Action::LISTENER),
+                "El bloque compacto no continúa la cadena local. Se piden los headers que faltan.",
+                logger_sender,
+            );
+            pedir_headers_faltantes(socket, &headers_vec, config)?;
+            return Ok(vec![]);
+        }
+        validar_headers(&headers_vec, std::slice::from_ref(&compacto.header))?;
+    }
+
+    let mempool: Vec<Txn> = txn_memory_server
+        .lock()?
+        .iter()
+        .map(|(_, txn, _)| txn.clone())
+        .collect();
+    let parcial = compacto.reconstruir(&mempool)?;
+    let faltantes = parcial.faltantes();
+    log_with_parameters(
+        Lvl::Info(Action::LISTENER),
+        format!(
+            "Bloque compacto reconstruido con {} de {} transacciones.",
+            compacto.cant_txns() - faltantes.len(),
+            compacto.cant_txns()
+        ),
         logger_sender,
+    );
+
+    let block_hash = hash_bloque(&compacto.header);
+    let recibidas = if faltantes.is_empty() {
+        vec![]
+    } else {
+        let getblocktxn = GetBlockTxn {
+            block_hash,
+            indices: faltantes,
+        };
+        escribir_mensaje(socket, &NetworkMessage::GetBlockTxn(getblocktxn))?;
+        let timeout = Duration::from_secs(config.timeout_respuesta_secs);
+        let blocktxn = match esperar_respuesta(socket, "blocktxn", timeout, logger_sender)? {
+            NetworkMessage::BlockTxn(bytes) => BlockTxn::from_bytes(&bytes)?,
+            _ => return Err(RustifyError::MensajeInesperado),
+        };
+        if blocktxn.block_hash != block_hash {
+            return Err(RustifyError::BloqueNoSolicitado);
+        }
+        blocktxn.txns
+    };
+
+    let bloque = parcial.completar(recibidas)?;
+    if !proof_of_inclusion(&bloque) {
+        log(
+            Lvl::Warning(Action::LISTENER),
+            "El bloque compacto no se pudo reconstruir. Se pide el bloque completo.",
+            logger_sender,
+        );
+        let inv_bloque = Inv::new(
+            1,
+            MSG_BLOCK as u32,
+            vec![compacto.header.as_bytes().to_vec()],
+        );
+        recibir_bloque(
+            socket,
+            (headers, indice, utxos),
+            headers_archivo,
+            config,
+            &inv_bloque,
+            tupla_senders,
+        )?;
+        return Ok(vec![compacto.header]);
+    }
+
+    let agregado = validar_bloque(
+        (headers, indice, utxos),
         headers_archivo,
-        blocks_path,
-        bytes_respuesta.clone(),
-        sender_gui,
-        sender_wallet,
+        config,
+        bloque.as_bytes(),
+        tupla_senders,
     )?;
-    Ok(())
+    if !agregado {
+        return Ok(vec![]);
+    }
+    Ok(vec![compacto.header])
+}
+
+/// Indica si el header es alguno de los últimos de la cadena local (por ejemplo, si se anunció dos veces).
+fn header_reciente(headers: &[BlockHeader], header: &BlockHeader) -> bool {
+    headers
+        .iter()
+        .rev()
+        .take(CANT_HEADERS_RECIENTES)
+        .any(|reciente| reciente.as_bytes() == header.as_bytes())
+}
+
+/// Envía un getheaders pidiendo los headers que faltan a partir de la cadena local.
+/// Se usa un localizador de bloques, para que el nodo encuentre el último header en común
+/// aunque la punta local no esté en su cadena.
+/// La respuesta no se espera acá: llega al listener como un mensaje headers más.
+fn pedir_headers_faltantes(
+    socket: &mut ConexionPeer,
+    headers: &[BlockHeader],
+    config: &Config,
+) -> Result<(), RustifyError> {
+    let getheaders = GetHeadersMessage::new(localizador(headers), NULL_HASH.to_vec(), config);
+    escribir_mensaje(socket, &NetworkMessage::GetHeaders(getheaders))
 }
 
 /// Valida el bloque recibido.
-/// Si el bloque cumple la POW y la POI, se agrega a la blockchain local.
+/// Si el header es válido como continuación de la cadena local (encadenamiento, dificultad, timestamps y POW)
+/// y el bloque cumple la POI, se validan sus transacciones contra el set de UTXOs (ver validar_transacciones_bloque).
+/// Si también son válidas, se agrega a la blockchain local y se conecta al set de UTXOs.
+/// Si el bloque no continúa la punta de la cadena, se ignora sin penalizar al nodo (puede ser un bloque repetido
+/// o de otra rama) y devuelve false.
+/// Caso contrario se devuelve error, para desconectar y penalizar al nodo que lo envió.
 /// El bloque se descarga a disco (carpeta blocks), y el header a memoria y disco.
 fn validar_bloque(
-    headers: &mut Arc<Mutex<Vec<BlockHeader>>>,
-    logger_sender: &Sender<String>,
+    cadena: Cadena,
     headers_archivo: &mut File,
-    blocks_path: &String,
+    config: &Config,
     bytes_respuesta: Vec<u8>,
-    sender_gui: &gtk::glib::Sender<GuiEvent>,
-    sender_wallet: &Sender<WalletEvent>,
-) -> Result<(), RustifyError> {
+    tupla_senders: (
+        &Sender<String>,
+        &gtk::glib::Sender<GuiEvent>,
+        &Sender<WalletEvent>,
+    ),
+) -> Result<bool, RustifyError> {
+    let (headers, indice, utxos) = cadena;
+    let (logger_sender, sender_gui, sender_wallet) = tupla_senders;
     let bloque = SerializedBlock::from_bytes(&bytes_respuesta)?;
     let header_bloque = &bloque.block_header;
-    if proof_of_work(header_bloque) && proof_of_inclusion(&bloque) {
+    let mut headers_vec = headers.lock()?;
+    match headers_vec.last() {
+        Some(punta) if header_bloque.previous_block_header_hash == hash_bloque(punta) => {}
+        Some(_) => {
+            log(
+                Lvl::Warning(Action::POWPOI),
+                "El bloque no continúa la cadena local. Se piden los headers que faltan.",
+                logger_sender,
+            );
+            return Ok(false);
+        }
+        None => return Err(RustifyError::NotFound),
+    }
+    if validar_headers(&headers_vec, std::slice::from_ref(header_bloque)).is_err()
+        || !proof_of_inclusion(&bloque)
+    {
         log(
-            Lvl::Info(Action::POWPOI),
-            "El bloque fue aceptado y guardado localmente",
+            Lvl::Warning(Action::POWPOI),
+            "El bloque no fue aceptado",
             logger_sender,
         );
-        let mut headers_vec = headers.lock()?;
-        sender_gui.send(GuiEvent::CargarBloques(
-            vec![header_bloque.clone()],
-            headers_vec.len() as u32,
-        ))?;
-
-        let header_bytes: String = header_bloque
-            .as_bytes()
-            .to_vec()
-            .iter()
-            .map(|b| format!("{:02x}", b) + "")
-            .collect();
-        writeln!(headers_archivo, "{}", header_bytes)?;
-        headers_vec.push(header_bloque.clone());
-
-        guardar_bloque_memoria(bytes_respuesta, blocks_path)?;
-        sender_wallet.send(WalletEvent::RecibirBloque(bloque))?;
-    } else {
-        log(
+        return Err(RustifyError::BloqueInvalido);
+    }
+    let altura = headers_vec.len() as u32;
+    if let Err(e) =
+        validar_transacciones_bloque(&bloque, altura, &*utxos.lock()?, utxos_completas(config))
+    {
+        log_with_parameters(
             Lvl::Warning(Action::POWPOI),
-            "El bloque no fue aceptado",
+            format!(
+                "El bloque fue rechazado: {}",
+                obtener_mensaje_personalizado(e.clone())
+            ),
             logger_sender,
         );
+        return Err(e);
     }
-    Ok(())
+    log(
+        Lvl::Info(Action::POWPOI),
+        "El bloque fue aceptado y guardado localmente",
+        logger_sender,
+    );
+    sender_gui.send(GuiEvent::CargarBloques(
+        vec![header_bloque.clone()],
+        headers_vec.len() as u32,
+    ))?;
+
+    let header_bytes: String = header_bloque
+        .as_bytes()
+        .to_vec()
+        .iter()
+        .map(|b| format!("{:02x}", b) + "")
+        .collect();
+    writeln!(headers_archivo, "{}", header_bytes)?;
+    headers_vec.push(header_bloque.clone());
+    indice.extender(header_bloque);
+
+    guardar_bloque_memoria(bytes_respuesta, &config.blocks_path)?;
+    update_utxo(
+        &mut *utxos.lock()?,
+        logger_sender,
+        &bloque,
+        altura,
+        &config.blocks_path,
+    )?;
+    sender_wallet.send(WalletEvent::RecibirBloque(bloque))?;
+    Ok(true)
 }
 
 // NODE UTILS //
 
+/// Envía el mensaje feefilter, para que el nodo no anuncie transacciones con fee menor a la indicada (en sat/kB).
+pub fn feefilter(
+    socket: &mut ConexionPeer,
+    fee_minima: u64,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::FeeFilter(fee_minima))?;
+    log_with_parameters(
+        Lvl::Info(Action::NETWORK),
+        format!("Enviado mensaje feefilter ({} sat/kB).", fee_minima),
+        logger_sender,
+    );
+    Ok(())
+}
+
 ///Respondo al PING con el mensaje PONG al instante.
-/// Recibe lo necesario del PING para poder crear el cuerpo del mensaje PONG.
+/// Recibe el nonce del PING para poder crear el cuerpo del mensaje PONG.
 pub fn pong(
-    bytes_pong_respuesta: &[u8],
-    socket: &mut TcpStream,
+    nonce: u64,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
-    let pong_message_header = MessageHeader::new("pong".to_string(), bytes_pong_respuesta);
-    let pong_message_header_bytes = pong_message_header.as_bytes();
-
-    write_to_node(socket, &pong_message_header_bytes, bytes_pong_respuesta)?;
+    escribir_mensaje(socket, &NetworkMessage::Pong(nonce))?;
     log(
         Lvl::Info(Action::NETWORK),
         "Enviado mensaje pong.",
@@ -393,44 +1285,170 @@ pub fn pong(
     Ok(())
 }
 
-/// Escribe un mensaje al nodo.
-/// Recibe el socket conectado al nodo, el header del mensaje, y el payload del mensaje.
-pub fn write_to_node(
-    socket: &mut TcpStream,
-    header: &[u8],
-    payload: &[u8],
-) -> Result<(), RustifyError> {
-    let buffer = [header, payload].concat();
-    socket.write_all(&buffer)?;
-    socket.flush()?;
-    Ok(())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_header::leer_headers;
+    use crate::compactsize::CompactSize;
+    use crate::outpoint::OutPoint;
+    use crate::txin::TxIn;
+    use crate::txout::TxOut;
+    use crate::utxo::leer_undo;
 
-/// Lee el mensaje recibido desde el nodo.
-/// Recibe el socket conectado al nodo, y el largo del mensaje a leer.
-/// Devuelve el mensaje recibido como vector.
-pub fn read_from_node(
-    socket: &mut TcpStream,
-    largo_mensaje: usize,
-) -> Result<Vec<u8>, RustifyError> {
-    let mut buffer = vec![0u8; largo_mensaje];
-    socket.read_exact(&mut buffer)?;
-    Ok(buffer)
-}
+    /// Header que continúa al previo, distinguido por el nonce. No cumple la prueba de trabajo,
+    /// pero los headers de una reorganización ya llegan validados a las funciones que se prueban.
+    fn header_siguiente(previo: &BlockHeader, nonce: u32) -> BlockHeader {
+        let mut header = previo.clone();
+        header.previous_block_header_hash = hash_bloque(previo);
+        header.time = previo.time + 600;
+        header.nonce = nonce;
+        header
+    }
 
-/// Envía el mensaje de tipo inv, en base al mensaje de tipo inv pasado por parametro
-/// y el nombre del mensaje especificado
-pub fn send_inv(command: String, socket: &mut TcpStream, inv: &Inv) -> Result<u64, RustifyError> {
-    let cant_inv = &inv.count;
-    let getdata_message_bytes = inv.as_bytes();
+    /// Bloque del header indicado con solamente una coinbase de la altura dada, que cobra el valor indicado.
+    fn bloque_coinbase(header: &BlockHeader, altura: u8, valor: i64) -> SerializedBlock {
+        let coinbase = Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(1),
+            tx_in: vec![TxIn {
+                previous_output: OutPoint {
+                    hash_previous_output_txid: [0; 32],
+                    output_index: 0xffffffff,
+                },
+                script_bytes: CompactSize::new(2),
+                signature_script: vec![0x01, altura],
+                sequence: 0xffffffff,
+            }],
+            tx_out_count: CompactSize::new(1),
+            tx_out: vec![TxOut {
+                value_amount_satoshis: valor,
+                pk_script_bytes: CompactSize::new(25),
+                pk_script: [vec![0x76, 0xa9, 0x14], vec![0; 20], vec![0x88, 0xac]].concat(),
+            }],
+            tx_lock_time: 0,
+        };
+        SerializedBlock {
+            block_header: header.clone(),
+            txn_count: CompactSize::new(1),
+            txns: vec![coinbase],
+        }
+    }
 
-    let getdata_message_header = MessageHeader::new(command, &getdata_message_bytes);
-    let getdata_message_header_bytes = getdata_message_header.as_bytes();
+    /// Test para chequear que si la rama de una reorganización tiene un bloque inválido, se vuelve a la cadena anterior:
+    /// sus headers, su trabajo acumulado en el índice, su set de UTXOs y sus bloques y datos de undo en disco.
+    #[test]
+    fn test_reorganizacion_con_bloque_invalido_vuelve_a_la_cadena_anterior(
+    ) -> Result<(), RustifyError> {
+        let directorio = std::env::temp_dir().join("rustify_test_reorganizacion");
+        let _ = std::fs::remove_dir_all(&directorio);
+        std::fs::create_dir_all(&directorio)?;
+        let config = Config {
+            headers_path: directorio.join("headers.txt").to_string_lossy().to_string(),
+            blocks_path: directorio.join("blocks").to_string_lossy().to_string(),
+            modo_spv: false,
+            filtros_compactos: false,
+            ..Config::new("./node.config").map_err(|_| RustifyError::NotFound)?
+        };
+        let (logger_sender, _logger_receiver) = std::sync::mpsc::channel();
+        let (sender_gui, _gui_receiver) =
+            gtk::glib::MainContext::channel(gtk::glib::source::Priority::DEFAULT);
+        let (sender_wallet, _wallet_receiver) = std::sync::mpsc::channel();
+        let senders = (&logger_sender, &sender_gui, &sender_wallet);
 
-    write_to_node(
-        socket,
-        &getdata_message_header_bytes,
-        &getdata_message_bytes,
-    )?;
-    Ok(cant_inv.value())
+        // Cadena activa: génesis, A1 y A2, con sus bloques conectados al set de UTXOs.
+        let genesis = BlockHeader::from_bytes(&parametros_red().genesis_header)?;
+        let a1 = header_siguiente(&genesis, 1);
+        let a2 = header_siguiente(&a1, 1);
+        let utxos = Arc::new(Mutex::new(SetUtxos::default()));
+        for (altura, header) in [(1, &a1), (2, &a2)] {
+            let bloque = bloque_coinbase(header, altura, 5_000_000_000);
+            guardar_bloque_memoria(bloque.as_bytes(), &config.blocks_path)?;
+            update_utxo(
+                &mut *utxos.lock()?,
+                &logger_sender,
+                &bloque,
+                altura as u32,
+                &config.blocks_path,
+            )?;
+        }
+        let cadena_anterior = vec![genesis.clone(), a1.clone(), a2.clone()];
+        reescribir_headers(&config.headers_path, &cadena_anterior)?;
+        let mut headers = Arc::new(Mutex::new(cadena_anterior.clone()));
+        let mut indice = IndiceBloques::new(&cadena_anterior);
+        let trabajo_anterior = indice.trabajo_punta();
+        let set_anterior = utxos.lock()?.clone();
+
+        // La rama B1, B2, B3 sale del génesis y tiene más trabajo, pero la coinbase de B2 cobra de más.
+        let b1 = header_siguiente(&genesis, 2);
+        let b2 = header_siguiente(&b1, 2);
+        let b3 = header_siguiente(&b2, 2);
+        for header in [&b1, &b2, &b3] {
+            indice.agregar_rama(&cadena_anterior, header)?;
+        }
+        let reorganizacion = indice
+            .reorganizar(&cadena_anterior, &hash_bloque(&b3))
+            .ok_or(RustifyError::NotFound)?;
+        aplicar_reorganizacion(
+            &mut *headers.lock()?,
+            &reorganizacion,
+            &utxos,
+            &config,
+            senders,
+        )?;
+        assert!(utxos.lock()?.utxos.is_empty());
+
+        let mut conectados = 0;
+        let mut resultado = Ok(());
+        for (altura, header, valor) in [(1, &b1, 4_000_000_000), (2, &b2, 10_000_000_000)] {
+            let bloque = bloque_coinbase(header, altura, valor);
+            guardar_bloque_memoria(bloque.as_bytes(), &config.blocks_path)?;
+            resultado = conectar_bloque_descargado(
+                (&mut headers, &mut indice, &utxos),
+                altura as usize,
+                bloque,
+                &config,
+                senders,
+            );
+            if resultado.is_err() {
+                break;
+            }
+            conectados += 1;
+        }
+        assert_eq!(resultado, Err(RustifyError::RecompensaCoinbaseExcedida));
+        assert_eq!(conectados, 1);
+
+        revertir_reorganizacion(
+            (&mut headers, &mut indice, &utxos),
+            &reorganizacion,
+            conectados,
+            &config,
+            senders,
+        )?;
+        let bytes_headers = |cadena: &[BlockHeader]| -> Vec<Vec<u8>> {
+            cadena
+                .iter()
+                .map(|header| header.as_bytes().to_vec())
+                .collect()
+        };
+        assert_eq!(
+            bytes_headers(&headers.lock()?),
+            bytes_headers(&cadena_anterior)
+        );
+        assert_eq!(
+            bytes_headers(&leer_headers(&config.headers_path)?),
+            bytes_headers(&cadena_anterior)
+        );
+        assert_eq!(indice.trabajo_punta(), trabajo_anterior);
+        assert_eq!(*utxos.lock()?, set_anterior);
+        for header in [&a1, &a2] {
+            assert!(leer_bloque_guardado(header, &config.blocks_path)?.is_some());
+            assert!(leer_undo(header, &config.blocks_path)?.is_some());
+        }
+        for header in [&b1, &b2] {
+            assert!(leer_bloque_guardado(header, &config.blocks_path)?.is_none());
+            assert!(leer_undo(header, &config.blocks_path)?.is_none());
+        }
+        std::fs::remove_dir_all(&directorio)?;
+        Ok(())
+    }
 }
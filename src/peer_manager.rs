@@ -0,0 +1,537 @@
+use crate::{
+    addr::getaddr,
+    address_manager::penalizar_direccion,
+    addrv2::DireccionNodo,
+    ban_manager::{direccion_baneada, obtener_ban_manager},
+    config::Config,
+    errors::RustifyError,
+    event_loop::ConexionPeer,
+    logger::{log, log_re_err, log_with_parameters, Action, Lvl},
+    message_handler::handle_message,
+    node::{conectar_excluyendo, handshake},
+};
+use chrono::Utc;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+const INTERVALO_MANTENIMIENTO_SECS: u64 = 30;
+
+/// Estado de cada uno de los peers administrados por el PeerManager.
+/// - Conectado: el peer está sano y libre para recibir pedidos.
+/// - Ocupado: el peer está siendo usado en forma exclusiva (getheaders, listener, etc).
+/// - Caido: el peer falló y será reemplazado por una nueva conexión.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EstadoPeer {
+    Conectado,
+    Ocupado,
+    Caido,
+}
+
+/// Conexión saliente a un nodo remoto, junto con sus estadísticas.
+#[derive(Debug)]
+pub struct Peer {
+    pub id: usize,
+    pub addr: DireccionNodo,
+    pub socket: ConexionPeer,
+    pub estado: EstadoPeer,
+    pub conectado_desde: i64,
+    pub pedidos_realizados: u64,
+    pub fallas: u32,
+}
+
+/// Información de un peer que se expone hacia afuera del PeerManager (logs, interfaz).
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoPeer {
+    pub id: usize,
+    pub addr: String,
+    pub estado: EstadoPeer,
+    pub conectado_desde: i64,
+    pub pedidos_realizados: u64,
+    pub fallas: u32,
+}
+
+/// Administra las conexiones salientes del nodo.
+/// Mantiene la cantidad de peers configurada (cant_peers), reconecta cuando alguno se cae,
+/// y distribuye los pedidos (getheaders, getdata, tx) entre los peers sanos.
+#[derive(Debug, Clone)]
+pub struct PeerManager {
+    peers: Arc<Mutex<Vec<Peer>>>,
+    proximo_id: Arc<AtomicUsize>,
+    config: Config,
+    logger_sender: Sender<String>,
+}
+
+impl PeerManager {
+    /// Constructor del PeerManager. Se conecta y hace el handshake con cant_peers nodos.
+    /// Devuelve error si no se pudo establecer ninguna conexión.
+    pub fn build(
+        config: &Config,
+        logger_sender: &Sender<String>,
+    ) -> Result<PeerManager, RustifyError> {
+        if config.cant_peers == 0 {
+            return Err(RustifyError::CantPeers);
+        }
+        let peer_manager = PeerManager {
+            peers: Arc::new(Mutex::new(vec![])),
+            proximo_id: Arc::new(AtomicUsize::new(0)),
+            config: config.clone(),
+            logger_sender: logger_sender.clone(),
+        };
+        peer_manager.completar_peers()?;
+
+        if peer_manager.cant_peers_sanos()? == 0 {
+            return Err(RustifyError::NoHayConexionesDisponibles);
+        }
+        log_with_parameters(
+            Lvl::Info(Action::PEERS),
+            format!(
+                "PeerManager inicializado con {} peers.",
+                peer_manager.cant_peers_sanos()?
+            ),
+            logger_sender,
+        );
+        Ok(peer_manager)
+    }
+
+    /// Toma un peer sano para uso exclusivo, marcándolo como ocupado.
+    /// Si no hay ninguno libre, intenta conectarse a nuevos peers.
+    /// Devuelve el id del peer y un clon de su conexión.
+    pub fn tomar_peer(&self) -> Result<(usize, ConexionPeer), RustifyError> {
+        if let Some(peer) = self.tomar_peer_libre()? {
+            return Ok(peer);
+        }
+        self.completar_peers()?;
+        self.tomar_peer_libre()?
+            .ok_or(RustifyError::NoHayPeersDisponibles)
+    }
+
+    /// Libera un peer previamente tomado, para que pueda recibir nuevos pedidos.
+    pub fn liberar_peer(&self, id: usize) -> Result<(), RustifyError> {
+        let mut peers = self.peers.lock()?;
+        if let Some(peer) = peers.iter_mut().find(|p| p.id == id) {
+            if peer.estado == EstadoPeer::Ocupado {
+                peer.estado = EstadoPeer::Conectado;
+            }
+            peer.pedidos_realizados += 1;
+        }
+        Ok(())
+    }
+
+    /// Marca a un peer como caído. En el próximo mantenimiento será reemplazado.
+    pub fn marcar_caido(&self, id: usize) -> Result<(), RustifyError> {
+        let mut peers = self.peers.lock()?;
+        if let Some(peer) = peers.iter_mut().find(|p| p.id == id) {
+            peer.estado = EstadoPeer::Caido;
+            peer.fallas += 1;
+            log_with_parameters(
+                Lvl::Warning(Action::PEERS),
+                format!("El peer {} ({}) se marcó como caído.", peer.id, peer.addr),
+                &self.logger_sender,
+            );
+        }
+        Ok(())
+    }
+
+    /// Registra el error ocurrido al usar un peer y lo marca como caído.
+    /// Si el error es una violación del protocolo, además se cierra la conexión en el momento
+    /// y se penaliza su dirección.
+    pub fn reportar_error(&self, id: usize, error: &RustifyError) -> Result<(), RustifyError> {
+        self.marcar_caido(id)?;
+        if !error.es_violacion_protocolo() {
+            return Ok(());
+        }
+        let peers = self.peers.lock()?;
+        if let Some(peer) = peers.iter().find(|p| p.id == id) {
+            peer.socket.cerrar();
+            penalizar_direccion(&peer.addr, error, &self.logger_sender);
+        }
+        Ok(())
+    }
+
+    /// Ejecuta un pedido sobre un peer sano. Si el pedido falla, el peer se marca como caído
+    /// y se reintenta con otro peer, hasta agotar la cantidad de peers configurada.
+    /// Si falla, devuelve el error del último pedido realizado (o el de no poder tomar un peer,
+    /// si no se llegó a realizar ninguno).
+    pub fn ejecutar_en_peer<T, F>(&self, mut pedido: F) -> Result<T, RustifyError>
+    where
+        F: FnMut(&mut ConexionPeer) -> Result<T, RustifyError>,
+    {
+        let mut ultimo_error = None;
+        for _ in 0..=self.config.cant_peers {
+            let (id, mut socket) = match self.tomar_peer() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    log_re_err(Action::PEERS, e.clone(), &self.logger_sender);
+                    ultimo_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            match pedido(&mut socket) {
+                Ok(resultado) => {
+                    self.liberar_peer(id)?;
+                    return Ok(resultado);
+                }
+                Err(e) => {
+                    log_re_err(Action::PEERS, e.clone(), &self.logger_sender);
+                    self.reportar_error(id, &e)?;
+                    ultimo_error = Some(e);
+                }
+            }
+        }
+        Err(ultimo_error.unwrap_or(RustifyError::NoHayPeersDisponibles))
+    }
+
+    /// Envía un mensaje a todos los peers sanos (por ejemplo, el broadcast de una transacción).
+    /// Devuelve la cantidad de peers a los que se les pudo enviar el mensaje.
+    pub fn enviar_a_todos<F>(&self, mut envio: F) -> Result<usize, RustifyError>
+    where
+        F: FnMut(&mut ConexionPeer) -> Result<(), RustifyError>,
+    {
+        let mut peers = self.peers.lock()?;
+        let mut enviados = 0;
+        for peer in peers.iter_mut() {
+            if peer.estado == EstadoPeer::Caido {
+                continue;
+            }
+            let mut socket = peer.socket.clone();
+            match envio(&mut socket) {
+                Ok(()) => {
+                    peer.pedidos_realizados += 1;
+                    enviados += 1;
+                }
+                Err(e) => {
+                    log_re_err(Action::PEERS, e, &self.logger_sender);
+                    peer.estado = EstadoPeer::Caido;
+                    peer.fallas += 1;
+                }
+            }
+        }
+        if enviados == 0 {
+            return Err(RustifyError::NoHayPeersDisponibles);
+        }
+        Ok(enviados)
+    }
+
+    /// Devuelve el estado actual de cada uno de los peers.
+    pub fn estado_peers(&self) -> Result<Vec<InfoPeer>, RustifyError> {
+        let peers = self.peers.lock()?;
+        Ok(peers
+            .iter()
+            .map(|peer| InfoPeer {
+                id: peer.id,
+                addr: peer.addr.to_string(),
+                estado: peer.estado.clone(),
+                conectado_desde: peer.conectado_desde,
+                pedidos_realizados: peer.pedidos_realizados,
+                fallas: peer.fallas,
+            })
+            .collect())
+    }
+
+    /// Devuelve la cantidad de peers que no están caídos.
+    pub fn cant_peers_sanos(&self) -> Result<usize, RustifyError> {
+        let peers = self.peers.lock()?;
+        Ok(peers
+            .iter()
+            .filter(|p| p.estado != EstadoPeer::Caido)
+            .count())
+    }
+
+    /// Inicia el thread de mantenimiento de conexiones.
+    /// Periódicamente responde los pings de los peers libres, descarta los peers caídos
+    /// y se reconecta hasta volver a tener la cantidad de peers configurada.
+    pub fn iniciar_mantenimiento(&self) {
+        let peer_manager = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(INTERVALO_MANTENIMIENTO_SECS));
+            if let Err(e) = peer_manager.mantener_conexiones() {
+                log_re_err(Action::PEERS, e, &peer_manager.logger_sender);
+            }
+        });
+    }
+
+    /// Un ciclo de mantenimiento: desconecta los baneados, drena los peers libres,
+    /// quita los caídos y reconecta.
+    fn mantener_conexiones(&self) -> Result<(), RustifyError> {
+        self.desconectar_baneados()?;
+        self.drenar_peers_libres()?;
+        self.quitar_peers_caidos()?;
+        self.completar_peers()?;
+
+        for info in self.estado_peers()? {
+            log_with_parameters(
+                Lvl::Info(Action::PEERS),
+                format!(
+                    "Peer {} ({}): {:?}, pedidos: {}, fallas: {}.",
+                    info.id, info.addr, info.estado, info.pedidos_realizados, info.fallas
+                ),
+                &self.logger_sender,
+            );
+        }
+        Ok(())
+    }
+
+    /// Handlea los mensajes que los peers libres tengan encolados (respondiendo los pings),
+    /// para que el nodo remoto no nos desconecte por inactividad.
+    fn drenar_peers_libres(&self) -> Result<(), RustifyError> {
+        let mut libres = vec![];
+        {
+            let mut peers = self.peers.lock()?;
+            for peer in peers.iter_mut() {
+                if peer.estado == EstadoPeer::Conectado {
+                    peer.estado = EstadoPeer::Ocupado;
+                    libres.push((peer.id, peer.socket.clone()));
+                }
+            }
+        }
+
+        for (id, mut socket) in libres {
+            match drenar_mensajes(&mut socket, &self.logger_sender) {
+                Ok(()) => self.liberar_peer_sin_pedido(id)?,
+                Err(e) => self.reportar_error(id, &e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Marca como caídos a los peers baneados, por ejemplo con el comando ban mientras el nodo corre.
+    fn desconectar_baneados(&self) -> Result<(), RustifyError> {
+        if let Some(ban_manager) = obtener_ban_manager() {
+            ban_manager.recargar()?;
+        }
+        let mut peers = self.peers.lock()?;
+        for peer in peers.iter_mut().filter(|p| direccion_baneada(&p.addr)) {
+            peer.estado = EstadoPeer::Caido;
+            log_with_parameters(
+                Lvl::Warning(Action::PEERS),
+                format!(
+                    "Se desconecta al peer {} ({}) por estar baneado.",
+                    peer.id, peer.addr
+                ),
+                &self.logger_sender,
+            );
+        }
+        Ok(())
+    }
+
+    /// Libera un peer sin contabilizarlo como pedido realizado.
+    fn liberar_peer_sin_pedido(&self, id: usize) -> Result<(), RustifyError> {
+        let mut peers = self.peers.lock()?;
+        if let Some(peer) = peers.iter_mut().find(|p| p.id == id) {
+            if peer.estado == EstadoPeer::Ocupado {
+                peer.estado = EstadoPeer::Conectado;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cierra y descarta las conexiones de los peers caídos.
+    fn quitar_peers_caidos(&self) -> Result<(), RustifyError> {
+        let mut peers = self.peers.lock()?;
+        for peer in peers.iter().filter(|p| p.estado == EstadoPeer::Caido) {
+            peer.socket.cerrar();
+        }
+        peers.retain(|p| p.estado != EstadoPeer::Caido);
+        Ok(())
+    }
+
+    /// Se conecta a nuevos peers hasta alcanzar la cantidad configurada.
+    fn completar_peers(&self) -> Result<(), RustifyError> {
+        let faltantes = self
+            .config
+            .cant_peers
+            .saturating_sub(self.cant_peers_sanos()?);
+        for _ in 0..faltantes {
+            if let Err(e) = self.conectar_peer() {
+                log_re_err(Action::PEERS, e, &self.logger_sender);
+            }
+        }
+        Ok(())
+    }
+
+    /// Se conecta y hace el handshake con un nuevo peer, distinto a los ya conectados.
+    fn conectar_peer(&self) -> Result<usize, RustifyError> {
+        let excluidas: Vec<DireccionNodo> = self.peers.lock()?.iter().map(|p| p.addr).collect();
+        let mut socket = conectar_excluyendo(&self.config, &self.logger_sender, &excluidas)?;
+        let addr = socket.addr();
+        handshake(&mut socket, &self.config, &self.logger_sender)?;
+        getaddr(&mut socket, &self.logger_sender)?;
+        let id = self.proximo_id.fetch_add(1, Ordering::SeqCst);
+
+        self.peers.lock()?.push(Peer {
+            id,
+            addr,
+            socket,
+            estado: EstadoPeer::Conectado,
+            conectado_desde: Utc::now().timestamp(),
+            pedidos_realizados: 0,
+            fallas: 0,
+        });
+        log_with_parameters(
+            Lvl::Info(Action::PEERS),
+            format!("Nuevo peer {} con IP {}.", id, addr),
+            &self.logger_sender,
+        );
+        Ok(id)
+    }
+
+    /// Toma el peer libre con menos pedidos realizados, si es que existe alguno.
+    fn tomar_peer_libre(&self) -> Result<Option<(usize, ConexionPeer)>, RustifyError> {
+        let mut peers = self.peers.lock()?;
+        let peer = peers
+            .iter_mut()
+            .filter(|p| p.estado == EstadoPeer::Conectado)
+            .min_by_key(|p| p.pedidos_realizados);
+        match peer {
+            Some(peer) => {
+                peer.estado = EstadoPeer::Ocupado;
+                Ok(Some((peer.id, peer.socket.clone())))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Handlea los mensajes que el peer tenga encolados, sin bloquearse esperando nuevos.
+/// Devuelve error si la conexión con el peer se cerró.
+fn drenar_mensajes(
+    socket: &mut ConexionPeer,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    while let Some(mensaje) = socket.try_recibir()? {
+        handle_message(&mensaje, socket, logger_sender, "")?;
+        log(
+            Lvl::Info(Action::PEERS),
+            "Se drenó un mensaje de un peer libre.",
+            logger_sender,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_loop::event_loop;
+    use std::net::{TcpListener, TcpStream};
+
+    fn peer_manager_test(peers: Vec<Peer>, cant_peers: usize) -> PeerManager {
+        let config = Config::new("./node.config").unwrap();
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        let proximo_id = peers.len();
+        PeerManager {
+            peers: Arc::new(Mutex::new(peers)),
+            proximo_id: Arc::new(AtomicUsize::new(proximo_id)),
+            config: Config {
+                cant_peers,
+                ..config
+            },
+            logger_sender,
+        }
+    }
+
+    fn peer_local(id: usize, listener: &TcpListener, pedidos_realizados: u64) -> Peer {
+        let (logger_sender, _receiver) = std::sync::mpsc::channel();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let addr = DireccionNodo::Ip(socket.peer_addr().unwrap());
+        Peer {
+            id,
+            addr,
+            socket: event_loop(&logger_sender)
+                .unwrap()
+                .registrar(socket, addr)
+                .unwrap(),
+            estado: EstadoPeer::Conectado,
+            conectado_desde: 0,
+            pedidos_realizados,
+            fallas: 0,
+        }
+    }
+
+    #[test]
+    fn test_tomar_peer_elige_el_menos_usado() -> Result<(), RustifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peers = vec![peer_local(0, &listener, 5), peer_local(1, &listener, 2)];
+        let peer_manager = peer_manager_test(peers, 2);
+
+        let (id, _socket) = peer_manager.tomar_peer()?;
+        assert_eq!(id, 1);
+
+        let estados = peer_manager.estado_peers()?;
+        assert_eq!(estados[1].estado, EstadoPeer::Ocupado);
+
+        peer_manager.liberar_peer(id)?;
+        let estados = peer_manager.estado_peers()?;
+        assert_eq!(estados[1].estado, EstadoPeer::Conectado);
+        assert_eq!(estados[1].pedidos_realizados, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ejecutar_en_peer_reintenta_en_otro_peer() -> Result<(), RustifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peers = vec![peer_local(0, &listener, 0), peer_local(1, &listener, 1)];
+        let peer_manager = peer_manager_test(peers, 2);
+
+        let mut intentos = 0;
+        let resultado = peer_manager.ejecutar_en_peer(|_socket| {
+            intentos += 1;
+            if intentos == 1 {
+                Err(RustifyError::PipeRoto)
+            } else {
+                Ok(intentos)
+            }
+        })?;
+
+        assert_eq!(resultado, 2);
+        let estados = peer_manager.estado_peers()?;
+        assert_eq!(estados[0].estado, EstadoPeer::Caido);
+        assert_eq!(estados[0].fallas, 1);
+        assert_eq!(estados[1].estado, EstadoPeer::Conectado);
+        assert_eq!(peer_manager.cant_peers_sanos()?, 1);
+        Ok(())
+    }
+
+    /// Test para chequear que si no quedan peers para reintentar, se devuelve el error del último pedido.
+    #[test]
+    fn test_ejecutar_en_peer_devuelve_el_ultimo_error() -> Result<(), RustifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut peer_manager = peer_manager_test(vec![peer_local(0, &listener, 0)], 1);
+        // Sin reintentos de conexión, no se puede reemplazar al peer caído.
+        peer_manager.config.cant_retries = 0;
+        peer_manager.config.address = "127.0.0.1:1".to_string();
+        peer_manager.config.addresses_path = "".to_string();
+
+        let mut intentos = 0;
+        let resultado: Result<(), RustifyError> = peer_manager.ejecutar_en_peer(|_socket| {
+            intentos += 1;
+            Err(RustifyError::PipeRoto)
+        });
+
+        assert_eq!(resultado, Err(RustifyError::PipeRoto));
+        assert_eq!(intentos, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quitar_peers_caidos() -> Result<(), RustifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peers = vec![peer_local(0, &listener, 0), peer_local(1, &listener, 0)];
+        let peer_manager = peer_manager_test(peers, 2);
+
+        peer_manager.marcar_caido(0)?;
+        peer_manager.quitar_peers_caidos()?;
+
+        let estados = peer_manager.estado_peers()?;
+        assert_eq!(estados.len(), 1);
+        assert_eq!(estados[0].id, 1);
+        Ok(())
+    }
+}
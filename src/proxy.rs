@@ -0,0 +1,269 @@
+use crate::addrv2::DireccionNodo;
+use crate::errors::RustifyError;
+use rand::RngCore;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const METODO_SIN_AUTENTICACION: u8 = 0x00;
+const METODO_USUARIO_PASSWORD: u8 = 0x02;
+const METODO_NO_ACEPTADO: u8 = 0xff;
+const VERSION_USUARIO_PASSWORD: u8 = 0x01;
+const COMANDO_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMINIO: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const RESPUESTA_EXITOSA: u8 = 0x00;
+
+/// Credenciales de usuario y contraseña para autenticarse con el proxy (RFC 1929).
+/// Tor utiliza credenciales distintas para aislar cada conexión en un circuito propio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CredencialesProxy {
+    pub usuario: String,
+    pub password: String,
+}
+
+impl CredencialesProxy {
+    /// Genera credenciales aleatorias, para aislar la conexión del resto (stream isolation).
+    pub fn aleatorias() -> CredencialesProxy {
+        let mut rng = rand::thread_rng();
+        let mut bytes = [0u8; 16];
+        rng.fill_bytes(&mut bytes);
+        let usuario = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        rng.fill_bytes(&mut bytes);
+        let password = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        CredencialesProxy { usuario, password }
+    }
+}
+
+/// Se conecta al destino a través del proxy SOCKS5 indicado.
+/// Las direcciones que no son IP (.onion, .i2p) se envían al proxy como nombre de dominio.
+/// Devuelve el TcpStream con la conexión establecida, listo para hablar con el nodo destino.
+pub fn conectar_socks5(
+    proxy: &SocketAddr,
+    destino: &DireccionNodo,
+    credenciales: Option<&CredencialesProxy>,
+    timeout: Duration,
+) -> Result<TcpStream, RustifyError> {
+    let mut socket = TcpStream::connect_timeout(proxy, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+
+    negociar_metodo(&mut socket, credenciales)?;
+    enviar_connect(&mut socket, destino)?;
+    recibir_respuesta_connect(&mut socket)?;
+
+    socket.set_read_timeout(None)?;
+    socket.set_write_timeout(None)?;
+    Ok(socket)
+}
+
+/// Envía el saludo inicial con los métodos de autenticación soportados,
+/// y se autentica con usuario y contraseña si el proxy lo pide.
+fn negociar_metodo(
+    socket: &mut TcpStream,
+    credenciales: Option<&CredencialesProxy>,
+) -> Result<(), RustifyError> {
+    let metodo = match credenciales {
+        Some(_) => METODO_USUARIO_PASSWORD,
+        None => METODO_SIN_AUTENTICACION,
+    };
+    socket.write_all(&[SOCKS5_VERSION, 0x01, metodo])?;
+
+    let mut respuesta = [0u8; 2];
+    socket.read_exact(&mut respuesta)?;
+    if respuesta[0] != SOCKS5_VERSION || respuesta[1] == METODO_NO_ACEPTADO {
+        return Err(RustifyError::ErrorProxy);
+    }
+
+    match (respuesta[1], credenciales) {
+        (METODO_SIN_AUTENTICACION, _) => Ok(()),
+        (METODO_USUARIO_PASSWORD, Some(credenciales)) => autenticar(socket, credenciales),
+        _ => Err(RustifyError::ErrorProxy),
+    }
+}
+
+/// Autenticación con usuario y contraseña (RFC 1929).
+fn autenticar(
+    socket: &mut TcpStream,
+    credenciales: &CredencialesProxy,
+) -> Result<(), RustifyError> {
+    let usuario = credenciales.usuario.as_bytes();
+    let password = credenciales.password.as_bytes();
+    if usuario.len() > 255 || password.len() > 255 {
+        return Err(RustifyError::ErrorProxy);
+    }
+    let mut mensaje = vec![VERSION_USUARIO_PASSWORD, usuario.len() as u8];
+    mensaje.extend_from_slice(usuario);
+    mensaje.push(password.len() as u8);
+    mensaje.extend_from_slice(password);
+    socket.write_all(&mensaje)?;
+
+    let mut respuesta = [0u8; 2];
+    socket.read_exact(&mut respuesta)?;
+    if respuesta[1] != RESPUESTA_EXITOSA {
+        return Err(RustifyError::ErrorProxy);
+    }
+    Ok(())
+}
+
+/// Envía el pedido CONNECT con la dirección del nodo destino.
+fn enviar_connect(socket: &mut TcpStream, destino: &DireccionNodo) -> Result<(), RustifyError> {
+    let mut mensaje = vec![SOCKS5_VERSION, COMANDO_CONNECT, 0x00];
+    match destino {
+        DireccionNodo::Ip(addr) => match addr.ip() {
+            IpAddr::V4(ip) => {
+                mensaje.push(ATYP_IPV4);
+                mensaje.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                mensaje.push(ATYP_IPV6);
+                mensaje.extend_from_slice(&ip.octets());
+            }
+        },
+        DireccionNodo::Cjdns(ip, _) => {
+            mensaje.push(ATYP_IPV6);
+            mensaje.extend_from_slice(&ip.octets());
+        }
+        _ => {
+            let host = destino.host();
+            mensaje.push(ATYP_DOMINIO);
+            mensaje.push(host.len() as u8);
+            mensaje.extend_from_slice(host.as_bytes());
+        }
+    }
+    mensaje.extend_from_slice(&destino.port().to_be_bytes());
+    socket.write_all(&mensaje)?;
+    Ok(())
+}
+
+/// Lee la respuesta al CONNECT. Devuelve error si el proxy no pudo conectarse al destino.
+fn recibir_respuesta_connect(socket: &mut TcpStream) -> Result<(), RustifyError> {
+    let mut respuesta = [0u8; 4];
+    socket.read_exact(&mut respuesta)?;
+    if respuesta[0] != SOCKS5_VERSION || respuesta[1] != RESPUESTA_EXITOSA {
+        return Err(RustifyError::ErrorProxy);
+    }
+    // Se descarta la dirección con la que el proxy se conectó (BND.ADDR y BND.PORT).
+    let largo_direccion = match respuesta[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMINIO => {
+            let mut largo = [0u8; 1];
+            socket.read_exact(&mut largo)?;
+            largo[0] as usize
+        }
+        _ => return Err(RustifyError::ErrorProxy),
+    };
+    let mut direccion = vec![0u8; largo_direccion + 2];
+    socket.read_exact(&mut direccion)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Proxy SOCKS5 mínimo para los tests: valida el pedido y devuelve lo que recibió del cliente.
+    fn proxy_socks5_local(
+        credenciales: Option<CredencialesProxy>,
+    ) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut saludo = [0u8; 3];
+            socket.read_exact(&mut saludo).unwrap();
+            match credenciales {
+                Some(credenciales) => {
+                    socket
+                        .write_all(&[SOCKS5_VERSION, METODO_USUARIO_PASSWORD])
+                        .unwrap();
+                    let largo_auth = 3 + credenciales.usuario.len() + credenciales.password.len();
+                    let mut auth = vec![0u8; largo_auth];
+                    socket.read_exact(&mut auth).unwrap();
+                    socket
+                        .write_all(&[VERSION_USUARIO_PASSWORD, RESPUESTA_EXITOSA])
+                        .unwrap();
+                }
+                None => socket
+                    .write_all(&[SOCKS5_VERSION, METODO_SIN_AUTENTICACION])
+                    .unwrap(),
+            }
+            let mut pedido = [0u8; 5];
+            socket.read_exact(&mut pedido).unwrap();
+            let largo_direccion = match pedido[3] {
+                ATYP_IPV4 => 3,
+                ATYP_IPV6 => 15,
+                _ => pedido[4] as usize,
+            };
+            let mut resto = vec![0u8; largo_direccion + 2];
+            socket.read_exact(&mut resto).unwrap();
+            socket
+                .write_all(&[
+                    SOCKS5_VERSION,
+                    RESPUESTA_EXITOSA,
+                    0,
+                    ATYP_IPV4,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ])
+                .unwrap();
+            [pedido.to_vec(), resto].concat()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_conectar_socks5_ip() -> Result<(), RustifyError> {
+        let (proxy, handle) = proxy_socks5_local(None);
+        let destino: DireccionNodo = "10.0.0.1:18333".parse()?;
+        conectar_socks5(&proxy, &destino, None, Duration::from_secs(5))?;
+
+        let pedido = handle.join().unwrap();
+        assert_eq!(
+            pedido,
+            vec![
+                SOCKS5_VERSION,
+                COMANDO_CONNECT,
+                0,
+                ATYP_IPV4,
+                10,
+                0,
+                0,
+                1,
+                0x47,
+                0x9d
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_conectar_socks5_onion_con_credenciales() -> Result<(), RustifyError> {
+        let credenciales = CredencialesProxy::aleatorias();
+        let (proxy, handle) = proxy_socks5_local(Some(credenciales.clone()));
+        let destino: DireccionNodo =
+            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333".parse()?;
+        conectar_socks5(
+            &proxy,
+            &destino,
+            Some(&credenciales),
+            Duration::from_secs(5),
+        )?;
+
+        let pedido = handle.join().unwrap();
+        let host = destino.host();
+        assert_eq!(pedido[3], ATYP_DOMINIO);
+        assert_eq!(pedido[4] as usize, host.len());
+        assert_eq!(&pedido[5..5 + host.len()], host.as_bytes());
+        Ok(())
+    }
+}
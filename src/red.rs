@@ -0,0 +1,284 @@
+use crate::errors::RustifyError;
+use bitcoin_hashes::{sha256d, Hash};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+static PARAMETROS_RED: OnceLock<ParametrosRed> = OnceLock::new();
+
+/// Redes de Bitcoin soportadas por el nodo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Red {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl FromStr for Red {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Red::Mainnet),
+            "testnet" => Ok(Red::Testnet),
+            "signet" => Ok(Red::Signet),
+            "regtest" => Ok(Red::Regtest),
+            _ => Err(format!("Red desconocida: {}", s)),
+        }
+    }
+}
+
+/// Parámetros de consenso y de red que dependen de la red elegida.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParametrosRed {
+    pub red: Red,
+    /// Start string (magic bytes) de los mensajes P2P.
+    pub magic: [u8; 4],
+    pub puerto_por_defecto: u16,
+    pub dns_seeds: &'static [&'static str],
+    pub genesis_header: [u8; 80],
+    /// Version byte de las direcciones P2PKH.
+    pub prefijo_p2pkh: u8,
+    /// Version byte de las direcciones P2SH.
+    pub prefijo_p2sh: u8,
+    /// Version byte de las claves privadas en formato WIF.
+    pub prefijo_wif: u8,
+    /// Máximo target permitido (dificultad mínima), en formato n_bits.
+    pub pow_limit_bits: u32,
+    /// Si es true, se permiten bloques de dificultad mínima luego de 20 minutos sin bloques (testnet).
+    pub permite_dificultad_minima: bool,
+    /// Si es true, la dificultad nunca se reajusta (regtest).
+    pub sin_reajuste_dificultad: bool,
+    /// Cada cuántos bloques se reduce a la mitad el subsidio de la coinbase.
+    pub intervalo_halving: u32,
+    /// Altura desde la que la coinbase tiene que empezar con la altura del bloque (BIP34).
+    pub altura_bip34: u32,
+    /// Altura desde la que las firmas tienen que estar en DER estricto (BIP66).
+    pub altura_bip66: u32,
+    /// Altura desde la que se ejecuta OP_CHECKLOCKTIMEVERIFY (BIP65).
+    pub altura_bip65: u32,
+    /// Altura desde la que se ejecuta OP_CHECKSEQUENCEVERIFY (BIP112).
+    pub altura_csv: u32,
+    /// Altura desde la que rige segwit (BIP141), y con ella la regla NULLDUMMY (BIP147).
+    pub altura_segwit: u32,
+    /// Pares (altura, hash en hexa) de bloques conocidos de la cadena principal.
+    pub checkpoints: &'static [(usize, &'static str)],
+}
+
+impl ParametrosRed {
+    pub fn new(red: Red) -> ParametrosRed {
+        match red {
+            Red::Mainnet => ParametrosRed {
+                red,
+                magic: [0xf9, 0xbe, 0xb4, 0xd9],
+                puerto_por_defecto: 8333,
+                dns_seeds: &[
+                    "seed.bitcoin.sipa.be",
+                    "dnsseed.bluematt.me",
+                    "seed.bitcoinstats.com",
+                    "seed.bitcoin.jonasschnelli.ch",
+                ],
+                genesis_header: genesis_header(1231006505, 0x1d00ffff, 2083236893),
+                prefijo_p2pkh: 0x00,
+                prefijo_p2sh: 0x05,
+                prefijo_wif: 0x80,
+                pow_limit_bits: 0x1d00ffff,
+                permite_dificultad_minima: false,
+                sin_reajuste_dificultad: false,
+                intervalo_halving: 210000,
+                altura_bip34: 227931,
+                altura_bip66: 363725,
+                altura_bip65: 388381,
+                altura_csv: 419328,
+                altura_segwit: 481824,
+                checkpoints: &[
+                    (
+                        11111,
+                        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+                    ),
+                    (
+                        105000,
+                        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+                    ),
+                    (
+                        210000,
+                        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+                    ),
+                    (
+                        250000,
+                        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+                    ),
+                ],
+            },
+            Red::Testnet => ParametrosRed {
+                red,
+                magic: [0x0b, 0x11, 0x09, 0x07],
+                puerto_por_defecto: 18333,
+                dns_seeds: &[
+                    "seed.testnet.bitcoin.sprovoost.nl",
+                    "testnet-seed.bitcoin.jonasschnelli.ch",
+                    "testnet-seed.bluematt.me",
+                ],
+                genesis_header: genesis_header(1296688602, 0x1d00ffff, 414098458),
+                prefijo_p2pkh: 0x6f,
+                prefijo_p2sh: 0xc4,
+                prefijo_wif: 0xef,
+                pow_limit_bits: 0x1d00ffff,
+                permite_dificultad_minima: true,
+                sin_reajuste_dificultad: false,
+                intervalo_halving: 210000,
+                altura_bip34: 21111,
+                altura_bip66: 330776,
+                altura_bip65: 581885,
+                altura_csv: 770112,
+                altura_segwit: 834624,
+                checkpoints: &[(
+                    546,
+                    "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+                )],
+            },
+            Red::Signet => ParametrosRed {
+                red,
+                magic: [0x0a, 0x03, 0xcf, 0x40],
+                puerto_por_defecto: 38333,
+                dns_seeds: &["seed.signet.bitcoin.sprovoost.nl"],
+                genesis_header: genesis_header(1598918400, 0x1e0377ae, 52613770),
+                prefijo_p2pkh: 0x6f,
+                prefijo_p2sh: 0xc4,
+                prefijo_wif: 0xef,
+                pow_limit_bits: 0x1e0377ae,
+                permite_dificultad_minima: false,
+                sin_reajuste_dificultad: false,
+                intervalo_halving: 210000,
+                altura_bip34: 1,
+                altura_bip66: 1,
+                altura_bip65: 1,
+                altura_csv: 1,
+                altura_segwit: 1,
+                checkpoints: &[],
+            },
+            Red::Regtest => ParametrosRed {
+                red,
+                magic: [0xfa, 0xbf, 0xb5, 0xda],
+                puerto_por_defecto: 18444,
+                dns_seeds: &[],
+                genesis_header: genesis_header(1296688602, 0x207fffff, 2),
+                prefijo_p2pkh: 0x6f,
+                prefijo_p2sh: 0xc4,
+                prefijo_wif: 0xef,
+                pow_limit_bits: 0x207fffff,
+                permite_dificultad_minima: true,
+                sin_reajuste_dificultad: true,
+                intervalo_halving: 150,
+                altura_bip34: 1,
+                altura_bip66: 1,
+                altura_bip65: 1,
+                altura_csv: 1,
+                altura_segwit: 1,
+                checkpoints: &[],
+            },
+        }
+    }
+
+    /// Hash del bloque genesis, en el orden de bytes interno (little endian).
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        sha256d::Hash::hash(&self.genesis_header).to_byte_array()
+    }
+
+    /// Verifica que el hash del header en la altura indicada coincida con el checkpoint, si lo hay.
+    /// El hash se recibe en el orden de bytes interno (little endian).
+    pub fn verificar_checkpoint(&self, altura: usize, hash: &[u8]) -> Result<(), RustifyError> {
+        let checkpoint = self.checkpoints.iter().find(|(h, _)| *h == altura);
+        if let Some((_, hash_esperado)) = checkpoint {
+            let hash_hexa: String = hash.iter().rev().map(|b| format!("{:02x}", b)).collect();
+            if hash_hexa != *hash_esperado {
+                return Err(RustifyError::CheckpointInvalido);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Arma el header del bloque genesis. Todas las redes comparten la transacción coinbase
+/// del genesis (y por ende su merkle root); solo cambian time, n_bits y nonce.
+fn genesis_header(time: u32, n_bits: u32, nonce: u32) -> [u8; 80] {
+    const GENESIS_MERKLE_ROOT: [u8; 32] = [
+        0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f,
+        0x61, 0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e,
+        0x5e, 0x4a,
+    ];
+    let mut header = [0u8; 80];
+    header[0..4].copy_from_slice(&1i32.to_le_bytes());
+    header[36..68].copy_from_slice(&GENESIS_MERKLE_ROOT);
+    header[68..72].copy_from_slice(&time.to_le_bytes());
+    header[72..76].copy_from_slice(&n_bits.to_le_bytes());
+    header[76..80].copy_from_slice(&nonce.to_le_bytes());
+    header
+}
+
+/// Selecciona la red con la que va a operar el nodo. Debe llamarse una sola vez, al iniciar.
+/// Si ya se había seleccionado otra red, se mantiene la anterior y se devuelve error.
+pub fn establecer_red(red: Red) -> Result<(), RustifyError> {
+    let parametros = PARAMETROS_RED.get_or_init(|| ParametrosRed::new(red));
+    if parametros.red != red {
+        return Err(RustifyError::RedYaEstablecida);
+    }
+    Ok(())
+}
+
+/// Devuelve los parámetros de la red seleccionada. Si no se seleccionó ninguna, se usa testnet.
+pub fn parametros_red() -> &'static ParametrosRed {
+    PARAMETROS_RED.get_or_init(|| ParametrosRed::new(Red::Testnet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_hexa(hash: [u8; 32]) -> String {
+        hash.iter().rev().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_genesis_hash_por_red() {
+        assert_eq!(
+            hash_hexa(ParametrosRed::new(Red::Mainnet).genesis_hash()),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            hash_hexa(ParametrosRed::new(Red::Testnet).genesis_hash()),
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+        );
+        assert_eq!(
+            hash_hexa(ParametrosRed::new(Red::Signet).genesis_hash()),
+            "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"
+        );
+        assert_eq!(
+            hash_hexa(ParametrosRed::new(Red::Regtest).genesis_hash()),
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+        );
+    }
+
+    #[test]
+    fn test_verificar_checkpoint() {
+        let parametros = ParametrosRed::new(Red::Testnet);
+        let mut hash = [0u8; 32];
+        assert_eq!(
+            parametros.verificar_checkpoint(546, &hash),
+            Err(RustifyError::CheckpointInvalido)
+        );
+        assert_eq!(parametros.verificar_checkpoint(547, &hash), Ok(()));
+
+        let hash_hexa = "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70";
+        for i in 0..32 {
+            hash[31 - i] = u8::from_str_radix(&hash_hexa[2 * i..2 * i + 2], 16).unwrap();
+        }
+        assert_eq!(parametros.verificar_checkpoint(546, &hash), Ok(()));
+    }
+
+    #[test]
+    fn test_red_from_str() {
+        assert_eq!("regtest".parse::<Red>(), Ok(Red::Regtest));
+        assert!("litecoin".parse::<Red>().is_err());
+    }
+}
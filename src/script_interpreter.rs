@@ -0,0 +1,1771 @@
+use crate::compactsize::CompactSize;
+use crate::errors::RustifyError;
+use crate::red::parametros_red;
+use crate::txn::Txn;
+use crate::txout::TxOut;
+use bitcoin_hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, VerifyOnly};
+use std::sync::OnceLock;
+
+/// Evalúa el redeem script de los inputs que gastan outputs P2SH (BIP16).
+pub const FLAG_P2SH: u32 = 1 << 0;
+/// Las firmas tienen que tener un tipo de sighash definido, y las claves públicas estar en formato SEC.
+pub const FLAG_STRICTENC: u32 = 1 << 1;
+/// Las firmas tienen que estar codificadas en DER estricto (BIP66).
+pub const FLAG_DERSIG: u32 = 1 << 2;
+/// El valor S de las firmas tiene que ser el menor de los dos posibles.
+pub const FLAG_LOW_S: u32 = 1 << 3;
+/// El elemento extra que consume CHECKMULTISIG tiene que estar vacío (BIP147).
+pub const FLAG_NULLDUMMY: u32 = 1 << 4;
+/// El script del input solamente puede tener pushes.
+pub const FLAG_SIGPUSHONLY: u32 = 1 << 5;
+/// Los pushes y los números tienen que usar la codificación más corta posible.
+pub const FLAG_MINIMALDATA: u32 = 1 << 6;
+/// Los NOP reservados para futuras actualizaciones hacen fallar el script.
+pub const FLAG_DISCOURAGE_UPGRADABLE_NOPS: u32 = 1 << 7;
+/// Al terminar la ejecución, en el stack tiene que quedar un único elemento.
+pub const FLAG_CLEANSTACK: u32 = 1 << 8;
+/// Ejecuta OP_CHECKLOCKTIMEVERIFY (BIP65); si no, es un NOP.
+pub const FLAG_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+/// Ejecuta OP_CHECKSEQUENCEVERIFY (BIP112); si no, es un NOP.
+pub const FLAG_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+/// Las firmas que no verifican tienen que estar vacías.
+pub const FLAG_NULLFAIL: u32 = 1 << 11;
+
+/// Flags que exige el nodo para aceptar transacciones en memoria: las de consenso más las de estandaridad.
+pub const FLAGS_ESTANDAR: u32 = FLAG_P2SH
+    | FLAG_STRICTENC
+    | FLAG_DERSIG
+    | FLAG_LOW_S
+    | FLAG_NULLDUMMY
+    | FLAG_SIGPUSHONLY
+    | FLAG_MINIMALDATA
+    | FLAG_DISCOURAGE_UPGRADABLE_NOPS
+    | FLAG_CLEANSTACK
+    | FLAG_CHECKLOCKTIMEVERIFY
+    | FLAG_CHECKSEQUENCEVERIFY
+    | FLAG_NULLFAIL;
+
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+/// Bits del tipo de sighash que indican qué outputs se firman (ALL, NONE o SINGLE).
+const MASCARA_TIPO_SIGHASH: u32 = 0x1f;
+
+/// Tamaño máximo de un script, en bytes.
+const MAX_TAMANIO_SCRIPT: usize = 10_000;
+/// Tamaño máximo de un elemento del stack, en bytes.
+const MAX_TAMANIO_ELEMENTO: usize = 520;
+/// Máxima cantidad de operaciones (sin contar los pushes) de un script.
+const MAX_OPERACIONES: usize = 201;
+/// Máxima cantidad de elementos entre el stack y el alt stack.
+const MAX_ELEMENTOS_STACK: usize = 1000;
+/// Máxima cantidad de claves de un CHECKMULTISIG.
+pub const MAX_CLAVES_MULTISIG: usize = 20;
+/// Tamaño máximo de los números de script, en bytes. CLTV y CSV admiten números de hasta 5 bytes.
+const MAX_TAMANIO_NUMERO: usize = 4;
+const MAX_TAMANIO_NUMERO_LOCKTIME: usize = 5;
+/// Los locktime menores a este valor son alturas de bloque; los mayores, timestamps.
+const LIMITE_LOCKTIME: i64 = 500_000_000;
+/// Bits del sequence de un input que usa CSV (BIP68).
+const SEQUENCE_DESHABILITADO: i64 = 1 << 31;
+const SEQUENCE_TIPO_TIEMPO: i64 = 1 << 22;
+const SEQUENCE_MASCARA: i64 = 0x0000ffff;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_NOP: u8 = 0x61;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_TOALTSTACK: u8 = 0x6b;
+pub const OP_FROMALTSTACK: u8 = 0x6c;
+pub const OP_2DROP: u8 = 0x6d;
+pub const OP_2DUP: u8 = 0x6e;
+pub const OP_3DUP: u8 = 0x6f;
+pub const OP_2OVER: u8 = 0x70;
+pub const OP_2ROT: u8 = 0x71;
+pub const OP_2SWAP: u8 = 0x72;
+pub const OP_IFDUP: u8 = 0x73;
+pub const OP_DEPTH: u8 = 0x74;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_NIP: u8 = 0x77;
+pub const OP_OVER: u8 = 0x78;
+pub const OP_PICK: u8 = 0x79;
+pub const OP_ROLL: u8 = 0x7a;
+pub const OP_ROT: u8 = 0x7b;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_TUCK: u8 = 0x7d;
+pub const OP_CAT: u8 = 0x7e;
+pub const OP_SUBSTR: u8 = 0x7f;
+pub const OP_LEFT: u8 = 0x80;
+pub const OP_RIGHT: u8 = 0x81;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_INVERT: u8 = 0x83;
+pub const OP_AND: u8 = 0x84;
+pub const OP_OR: u8 = 0x85;
+pub const OP_XOR: u8 = 0x86;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_1ADD: u8 = 0x8b;
+pub const OP_1SUB: u8 = 0x8c;
+pub const OP_2MUL: u8 = 0x8d;
+pub const OP_2DIV: u8 = 0x8e;
+pub const OP_NEGATE: u8 = 0x8f;
+pub const OP_ABS: u8 = 0x90;
+pub const OP_NOT: u8 = 0x91;
+pub const OP_0NOTEQUAL: u8 = 0x92;
+pub const OP_ADD: u8 = 0x93;
+pub const OP_SUB: u8 = 0x94;
+pub const OP_MUL: u8 = 0x95;
+pub const OP_DIV: u8 = 0x96;
+pub const OP_MOD: u8 = 0x97;
+pub const OP_LSHIFT: u8 = 0x98;
+pub const OP_RSHIFT: u8 = 0x99;
+pub const OP_BOOLAND: u8 = 0x9a;
+pub const OP_BOOLOR: u8 = 0x9b;
+pub const OP_NUMEQUAL: u8 = 0x9c;
+pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+pub const OP_NUMNOTEQUAL: u8 = 0x9e;
+pub const OP_LESSTHAN: u8 = 0x9f;
+pub const OP_GREATERTHAN: u8 = 0xa0;
+pub const OP_LESSTHANOREQUAL: u8 = 0xa1;
+pub const OP_GREATERTHANOREQUAL: u8 = 0xa2;
+pub const OP_MIN: u8 = 0xa3;
+pub const OP_MAX: u8 = 0xa4;
+pub const OP_WITHIN: u8 = 0xa5;
+pub const OP_RIPEMD160: u8 = 0xa6;
+pub const OP_SHA1: u8 = 0xa7;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_HASH256: u8 = 0xaa;
+pub const OP_CODESEPARATOR: u8 = 0xab;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_NOP1: u8 = 0xb0;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+pub const OP_NOP4: u8 = 0xb3;
+pub const OP_NOP10: u8 = 0xb9;
+
+/// Opcodes deshabilitados: hacen fallar el script aunque estén en una rama que no se ejecuta.
+const OPCODES_DESHABILITADOS: [u8; 15] = [
+    OP_CAT, OP_SUBSTR, OP_LEFT, OP_RIGHT, OP_INVERT, OP_AND, OP_OR, OP_XOR, OP_2MUL, OP_2DIV,
+    OP_MUL, OP_DIV, OP_MOD, OP_LSHIFT, OP_RSHIFT,
+];
+
+static CONTEXTO_SECP: OnceLock<Secp256k1<VerifyOnly>> = OnceLock::new();
+
+/// Flags de consenso vigentes para los bloques de la altura indicada, según las alturas
+/// de activación de la red.
+pub fn flags_consenso(altura: u32) -> u32 {
+    let parametros = parametros_red();
+    let mut flags = FLAG_P2SH;
+    if altura >= parametros.altura_bip66 {
+        flags |= FLAG_DERSIG;
+    }
+    if altura >= parametros.altura_bip65 {
+        flags |= FLAG_CHECKLOCKTIMEVERIFY;
+    }
+    if altura >= parametros.altura_csv {
+        flags |= FLAG_CHECKSEQUENCEVERIFY;
+    }
+    if altura >= parametros.altura_segwit {
+        flags |= FLAG_NULLDUMMY;
+    }
+    flags
+}
+
+/// Verifica que el input de la transacción en la posición indicada pueda gastar el output previo:
+/// ejecuta el script del input y luego el pk_script del output sobre el mismo stack, que tiene que
+/// terminar en verdadero. Si el output es P2SH (y está activo el flag), ejecuta además el redeem script.
+/// Los errores de las reglas de estandaridad se devuelven como ScriptNoEstandar.
+pub fn verify_input(
+    txn: &Txn,
+    index: usize,
+    prevout: &TxOut,
+    flags: u32,
+) -> Result<(), RustifyError> {
+    let signature_script = &txn
+        .tx_in
+        .get(index)
+        .ok_or(RustifyError::ScriptInvalido)?
+        .signature_script;
+    if flags & FLAG_SIGPUSHONLY != 0 && !es_solo_pushes(signature_script) {
+        return Err(RustifyError::ScriptNoEstandar);
+    }
+
+    let mut interprete = Interprete {
+        txn,
+        index,
+        flags,
+        stack: vec![],
+        firma_fallida: false,
+    };
+    interprete.ejecutar(signature_script)?;
+    let stack_input = interprete.stack.clone();
+    interprete.ejecutar(&prevout.pk_script)?;
+    interprete.chequear_resultado()?;
+
+    if flags & FLAG_P2SH != 0 && es_p2sh(&prevout.pk_script) {
+        if !es_solo_pushes(signature_script) {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        interprete.stack = stack_input;
+        let redeem_script = sacar(&mut interprete.stack)?;
+        interprete.ejecutar(&redeem_script)?;
+        interprete.chequear_resultado()?;
+    }
+
+    if flags & FLAG_CLEANSTACK != 0 && interprete.stack.len() != 1 {
+        return Err(RustifyError::ScriptNoEstandar);
+    }
+    Ok(())
+}
+
+/// Estado de la verificación de un input.
+struct Interprete<'a> {
+    txn: &'a Txn,
+    index: usize,
+    flags: u32,
+    stack: Vec<Vec<u8>>,
+    /// Si alguna firma no vacía no verificó, para informar FirmaInvalida si el script termina en falso.
+    firma_fallida: bool,
+}
+
+impl Interprete<'_> {
+    /// Ejecuta el script sobre el stack actual. El alt stack y los condicionales no se comparten entre scripts.
+    fn ejecutar(&mut self, script: &[u8]) -> Result<(), RustifyError> {
+        if script.len() > MAX_TAMANIO_SCRIPT {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let mut alt_stack: Vec<Vec<u8>> = vec![];
+        let mut condiciones: Vec<bool> = vec![];
+        let mut cantidad_operaciones = 0;
+        let mut inicio_script_code = 0;
+        let mut index = 0;
+        while index < script.len() {
+            let (opcode, datos, siguiente) =
+                leer_operacion(script, index).ok_or(RustifyError::ScriptInvalido)?;
+            index = siguiente;
+            let ejecuta = !condiciones.contains(&false);
+
+            if datos.len() > MAX_TAMANIO_ELEMENTO {
+                return Err(RustifyError::ScriptInvalido);
+            }
+            if opcode > OP_16 {
+                cantidad_operaciones += 1;
+                if cantidad_operaciones > MAX_OPERACIONES {
+                    return Err(RustifyError::ScriptInvalido);
+                }
+            }
+            if OPCODES_DESHABILITADOS.contains(&opcode) {
+                return Err(RustifyError::ScriptInvalido);
+            }
+
+            if ejecuta && opcode <= OP_PUSHDATA4 {
+                if self.flags & FLAG_MINIMALDATA != 0 && !es_push_minimo(opcode, datos) {
+                    return Err(RustifyError::ScriptNoEstandar);
+                }
+                self.stack.push(datos.to_vec());
+            } else if ejecuta || (OP_IF..=OP_ENDIF).contains(&opcode) {
+                match opcode {
+                    OP_1NEGATE | OP_1..=OP_16 => self
+                        .stack
+                        .push(codificar_numero(opcode as i64 - (OP_1 as i64 - 1))),
+                    OP_NOP => {}
+                    OP_NOP1 | OP_NOP4..=OP_NOP10 => self.nop_reservado()?,
+                    OP_CHECKLOCKTIMEVERIFY => self.check_locktime()?,
+                    OP_CHECKSEQUENCEVERIFY => self.check_sequence()?,
+                    OP_IF | OP_NOTIF => {
+                        let mut condicion = false;
+                        if ejecuta {
+                            condicion =
+                                es_verdadero(&sacar(&mut self.stack)?) != (opcode == OP_NOTIF);
+                        }
+                        condiciones.push(condicion);
+                    }
+                    OP_ELSE => {
+                        let condicion =
+                            condiciones.last_mut().ok_or(RustifyError::ScriptInvalido)?;
+                        *condicion = !*condicion;
+                    }
+                    OP_ENDIF => {
+                        condiciones.pop().ok_or(RustifyError::ScriptInvalido)?;
+                    }
+                    OP_VERIFY => verificar(
+                        es_verdadero(&sacar(&mut self.stack)?),
+                        RustifyError::ScriptInvalido,
+                    )?,
+                    OP_TOALTSTACK => alt_stack.push(sacar(&mut self.stack)?),
+                    OP_FROMALTSTACK => self.stack.push(sacar(&mut alt_stack)?),
+                    OP_2DROP..=OP_DUP | OP_NIP | OP_OVER | OP_ROT..=OP_TUCK | OP_SIZE => {
+                        operacion_stack(opcode, &mut self.stack)?
+                    }
+                    OP_PICK | OP_ROLL => {
+                        let posicion =
+                            leer_numero(&sacar(&mut self.stack)?, self.flags, MAX_TAMANIO_NUMERO)?;
+                        if posicion < 0 || posicion as usize >= self.stack.len() {
+                            return Err(RustifyError::ScriptInvalido);
+                        }
+                        let posicion = self.stack.len() - 1 - posicion as usize;
+                        let elemento = match opcode {
+                            OP_ROLL => self.stack.remove(posicion),
+                            _ => self.stack[posicion].clone(),
+                        };
+                        self.stack.push(elemento);
+                    }
+                    OP_EQUAL | OP_EQUALVERIFY => {
+                        let iguales = sacar(&mut self.stack)? == sacar(&mut self.stack)?;
+                        if opcode == OP_EQUALVERIFY {
+                            verificar(iguales, RustifyError::ScriptInvalido)?;
+                        } else {
+                            self.stack.push(codificar_numero(iguales as i64));
+                        }
+                    }
+                    OP_1ADD..=OP_WITHIN => operacion_numerica(opcode, &mut self.stack, self.flags)?,
+                    OP_RIPEMD160..=OP_HASH256 => {
+                        let elemento = sacar(&mut self.stack)?;
+                        self.stack.push(hashear(opcode, &elemento));
+                    }
+                    OP_CODESEPARATOR => inicio_script_code = index,
+                    OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                        self.checksig(opcode, &script[inicio_script_code..])?
+                    }
+                    OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => self.checkmultisig(
+                        opcode,
+                        &script[inicio_script_code..],
+                        &mut cantidad_operaciones,
+                    )?,
+                    // OP_RETURN, OP_RESERVED, OP_VER, OP_VERIF, OP_VERNOTIF y los opcodes sin definir.
+                    _ => return Err(RustifyError::ScriptInvalido),
+                }
+            }
+
+            if self.stack.len() + alt_stack.len() > MAX_ELEMENTOS_STACK {
+                return Err(RustifyError::ScriptInvalido);
+            }
+        }
+        if !condiciones.is_empty() {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        Ok(())
+    }
+
+    /// Chequea que la ejecución haya terminado con un elemento verdadero en el tope del stack.
+    fn chequear_resultado(&self) -> Result<(), RustifyError> {
+        match self.stack.last() {
+            Some(tope) if es_verdadero(tope) => Ok(()),
+            _ if self.firma_fallida => Err(RustifyError::FirmaInvalida),
+            _ => Err(RustifyError::ScriptInvalido),
+        }
+    }
+
+    fn nop_reservado(&self) -> Result<(), RustifyError> {
+        if self.flags & FLAG_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+            return Err(RustifyError::ScriptNoEstandar);
+        }
+        Ok(())
+    }
+
+    /// OP_CHECKLOCKTIMEVERIFY (BIP65): el locktime de la transacción tiene que ser del mismo tipo (altura o
+    /// timestamp) y al menos el del tope del stack, y el input no puede tener el sequence final.
+    fn check_locktime(&self) -> Result<(), RustifyError> {
+        if self.flags & FLAG_CHECKLOCKTIMEVERIFY == 0 {
+            return self.nop_reservado();
+        }
+        let locktime = leer_numero(
+            desde_tope(&self.stack, 0)?,
+            self.flags,
+            MAX_TAMANIO_NUMERO_LOCKTIME,
+        )?;
+        if locktime < 0 {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let locktime_txn = self.txn.tx_lock_time as i64;
+        let mismo_tipo = (locktime < LIMITE_LOCKTIME) == (locktime_txn < LIMITE_LOCKTIME);
+        if !mismo_tipo
+            || locktime > locktime_txn
+            || self.txn.tx_in[self.index].sequence == 0xffffffff
+        {
+            return Err(RustifyError::LockTimeNoCumplido);
+        }
+        Ok(())
+    }
+
+    /// OP_CHECKSEQUENCEVERIFY (BIP112): el sequence del input tiene que representar un locktime relativo
+    /// del mismo tipo (bloques o tiempo) y al menos el del tope del stack.
+    fn check_sequence(&self) -> Result<(), RustifyError> {
+        if self.flags & FLAG_CHECKSEQUENCEVERIFY == 0 {
+            return self.nop_reservado();
+        }
+        let sequence = leer_numero(
+            desde_tope(&self.stack, 0)?,
+            self.flags,
+            MAX_TAMANIO_NUMERO_LOCKTIME,
+        )?;
+        if sequence < 0 {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        if sequence & SEQUENCE_DESHABILITADO != 0 {
+            return Ok(());
+        }
+        let sequence_input = self.txn.tx_in[self.index].sequence as i64;
+        // Como en Bitcoin Core, la versión se compara sin signo.
+        if (self.txn.version as u32) < 2 || sequence_input & SEQUENCE_DESHABILITADO != 0 {
+            return Err(RustifyError::LockTimeNoCumplido);
+        }
+        let mascara = SEQUENCE_TIPO_TIEMPO | SEQUENCE_MASCARA;
+        let (sequence, sequence_input) = (sequence & mascara, sequence_input & mascara);
+        let mismo_tipo =
+            (sequence < SEQUENCE_TIPO_TIEMPO) == (sequence_input < SEQUENCE_TIPO_TIEMPO);
+        if !mismo_tipo || sequence > sequence_input {
+            return Err(RustifyError::LockTimeNoCumplido);
+        }
+        Ok(())
+    }
+
+    /// OP_CHECKSIG: verifica la firma contra la clave pública, firmando el script desde el último OP_CODESEPARATOR.
+    fn checksig(&mut self, opcode: u8, script_code: &[u8]) -> Result<(), RustifyError> {
+        let clave = sacar(&mut self.stack)?;
+        let firma = sacar(&mut self.stack)?;
+        let script_code = quitar_push(script_code, &firma);
+        let valida = self.verificar_firma(&firma, &clave, &script_code)?;
+        if !valida && !firma.is_empty() {
+            if self.flags & FLAG_NULLFAIL != 0 {
+                return Err(RustifyError::FirmaInvalida);
+            }
+            self.firma_fallida = true;
+        }
+        if opcode == OP_CHECKSIGVERIFY {
+            return verificar(valida, RustifyError::FirmaInvalida);
+        }
+        self.stack.push(codificar_numero(valida as i64));
+        Ok(())
+    }
+
+    /// OP_CHECKMULTISIG: con el stack `<dummy> <firmas> <m> <claves> <n>`, verifica que las m firmas
+    /// correspondan a claves distintas, en el mismo orden en que están las claves.
+    fn checkmultisig(
+        &mut self,
+        opcode: u8,
+        script_code: &[u8],
+        cantidad_operaciones: &mut usize,
+    ) -> Result<(), RustifyError> {
+        let cantidad_claves =
+            leer_numero(desde_tope(&self.stack, 0)?, self.flags, MAX_TAMANIO_NUMERO)?;
+        if !(0..=MAX_CLAVES_MULTISIG as i64).contains(&cantidad_claves) {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let cantidad_claves = cantidad_claves as usize;
+        *cantidad_operaciones += cantidad_claves;
+        if *cantidad_operaciones > MAX_OPERACIONES {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let cantidad_firmas = leer_numero(
+            desde_tope(&self.stack, cantidad_claves + 1)?,
+            self.flags,
+            MAX_TAMANIO_NUMERO,
+        )?;
+        if !(0..=cantidad_claves as i64).contains(&cantidad_firmas) {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let cantidad_firmas = cantidad_firmas as usize;
+        let total = cantidad_claves + cantidad_firmas + 3;
+        if self.stack.len() < total {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        let elementos = self.stack.split_off(self.stack.len() - total);
+        let dummy = &elementos[0];
+        let firmas = &elementos[1..=cantidad_firmas];
+        let claves = &elementos[cantidad_firmas + 2..total - 1];
+
+        let mut script_code = script_code.to_vec();
+        for firma in firmas {
+            script_code = quitar_push(&script_code, firma);
+        }
+        // Se recorren desde el tope del stack: la última firma contra la última clave.
+        let (mut firmas_restantes, mut claves_restantes) = (cantidad_firmas, cantidad_claves);
+        let mut valida = true;
+        while valida && firmas_restantes > 0 {
+            let firma = &firmas[firmas_restantes - 1];
+            let clave = &claves[claves_restantes - 1];
+            if self.verificar_firma(firma, clave, &script_code)? {
+                firmas_restantes -= 1;
+            }
+            claves_restantes -= 1;
+            valida = firmas_restantes <= claves_restantes;
+        }
+
+        if !valida && firmas.iter().any(|firma| !firma.is_empty()) {
+            if self.flags & FLAG_NULLFAIL != 0 {
+                return Err(RustifyError::FirmaInvalida);
+            }
+            self.firma_fallida = true;
+        }
+        // NULLDUMMY es de consenso desde segwit (ver flags_consenso): no es solamente una regla de estandaridad.
+        if self.flags & FLAG_NULLDUMMY != 0 && !dummy.is_empty() {
+            return Err(RustifyError::ScriptInvalido);
+        }
+        if opcode == OP_CHECKMULTISIGVERIFY {
+            return verificar(valida, RustifyError::FirmaInvalida);
+        }
+        self.stack.push(codificar_numero(valida as i64));
+        Ok(())
+    }
+
+    /// Chequea la codificación de la firma y de la clave según los flags, y verifica la firma ECDSA
+    /// sobre el sighash del input. Una firma vacía no verifica.
+    fn verificar_firma(
+        &self,
+        firma: &[u8],
+        clave: &[u8],
+        script_code: &[u8],
+    ) -> Result<bool, RustifyError> {
+        chequear_codificacion_firma(firma, self.flags)?;
+        if self.flags & FLAG_STRICTENC != 0 && !es_clave_sec(clave) {
+            return Err(RustifyError::ScriptNoEstandar);
+        }
+        let Some((hash_type, der)) = firma.split_last() else {
+            return Ok(false);
+        };
+        let hash = firma_hash_legacy(self.txn, self.index, script_code, *hash_type as u32);
+        Ok(verificar_ecdsa(der, clave, &hash))
+    }
+}
+
+/// Hash que firma el input en la posición indicada (sighash legacy): la transacción con el script_code
+/// en el input firmado y los demás scripts vacíos, modificada según el tipo de sighash, seguida del tipo.
+/// Con SIGHASH_SINGLE y sin un output en la misma posición que el input, el hash es 1.
+/// Como en Bitcoin Core, el tipo se toma de los 5 bits bajos, y cualquier otro valor firma como SIGHASH_ALL.
+pub fn firma_hash_legacy(txn: &Txn, index: usize, script_code: &[u8], hash_type: u32) -> [u8; 32] {
+    let tipo_base = hash_type & MASCARA_TIPO_SIGHASH;
+    let mut uno = [0; 32];
+    uno[0] = 1;
+    if index >= txn.tx_in.len() || (tipo_base == SIGHASH_SINGLE && index >= txn.tx_out.len()) {
+        return uno;
+    }
+
+    let script_code = filtrar_operaciones(script_code, |operacion| operacion == [OP_CODESEPARATOR]);
+    let mut copia = txn.clone();
+    for (i, input) in copia.tx_in.iter_mut().enumerate() {
+        input.signature_script = if i == index {
+            script_code.clone()
+        } else {
+            vec![]
+        };
+        input.script_bytes = CompactSize::new(input.signature_script.len() as u64);
+        if i != index && (tipo_base == SIGHASH_NONE || tipo_base == SIGHASH_SINGLE) {
+            input.sequence = 0;
+        }
+    }
+    if hash_type & SIGHASH_ANYONECANPAY != 0 {
+        copia.tx_in = vec![copia.tx_in.swap_remove(index)];
+    }
+    match tipo_base {
+        SIGHASH_NONE => copia.tx_out.clear(),
+        SIGHASH_SINGLE => {
+            copia.tx_out.truncate(index + 1);
+            for output in &mut copia.tx_out[..index] {
+                *output = TxOut {
+                    value_amount_satoshis: -1,
+                    pk_script_bytes: CompactSize::new(0),
+                    pk_script: vec![],
+                };
+            }
+        }
+        _ => {}
+    }
+    copia.tx_in_count = CompactSize::new(copia.tx_in.len() as u64);
+    copia.tx_out_count = CompactSize::new(copia.tx_out.len() as u64);
+
+    let mut bytes = copia.as_bytes();
+    bytes.extend_from_slice(&hash_type.to_le_bytes());
+    sha256d::Hash::hash(&bytes).to_byte_array()
+}
+
+/// Verifica la firma DER (aceptando codificaciones laxas y S alto) contra la clave y el hash.
+fn verificar_ecdsa(der: &[u8], clave: &[u8], hash: &[u8; 32]) -> bool {
+    let (Ok(mut firma), Ok(clave), Ok(mensaje)) = (
+        Signature::from_der_lax(der),
+        PublicKey::from_slice(clave),
+        Message::from_slice(hash),
+    ) else {
+        return false;
+    };
+    firma.normalize_s();
+    CONTEXTO_SECP
+        .get_or_init(Secp256k1::verification_only)
+        .verify_ecdsa(&mensaje, &firma, &clave)
+        .is_ok()
+}
+
+/// Chequea la codificación de una firma no vacía: DER estricto (con DERSIG, LOW_S o STRICTENC),
+/// S bajo (con LOW_S) y tipo de sighash definido (con STRICTENC).
+fn chequear_codificacion_firma(firma: &[u8], flags: u32) -> Result<(), RustifyError> {
+    if firma.is_empty() {
+        return Ok(());
+    }
+    if flags & (FLAG_DERSIG | FLAG_LOW_S | FLAG_STRICTENC) != 0 && !es_der_estricto(firma) {
+        return Err(match flags & FLAG_DERSIG {
+            0 => RustifyError::ScriptNoEstandar,
+            _ => RustifyError::FirmaInvalida,
+        });
+    }
+    if flags & FLAG_LOW_S != 0 && !es_s_bajo(&firma[..firma.len() - 1]) {
+        return Err(RustifyError::ScriptNoEstandar);
+    }
+    let tipo_base = firma[firma.len() - 1] as u32 & !SIGHASH_ANYONECANPAY;
+    if flags & FLAG_STRICTENC != 0 && !(SIGHASH_ALL..=SIGHASH_SINGLE).contains(&tipo_base) {
+        return Err(RustifyError::ScriptNoEstandar);
+    }
+    Ok(())
+}
+
+/// Indica si la firma (con el byte de sighash al final) está codificada en DER estricto (BIP66).
+fn es_der_estricto(firma: &[u8]) -> bool {
+    if firma.len() < 9
+        || firma.len() > 73
+        || firma[0] != 0x30
+        || firma[1] as usize != firma.len() - 3
+    {
+        return false;
+    }
+    let largo_r = firma[3] as usize;
+    if 5 + largo_r >= firma.len() {
+        return false;
+    }
+    let largo_s = firma[5 + largo_r] as usize;
+    if largo_r + largo_s + 7 != firma.len() {
+        return false;
+    }
+    let entero_valido = |inicio: usize, largo: usize| {
+        firma[inicio - 2] == 0x02
+            && largo > 0
+            && firma[inicio] & 0x80 == 0
+            && !(largo > 1 && firma[inicio] == 0x00 && firma[inicio + 1] & 0x80 == 0)
+    };
+    entero_valido(4, largo_r) && entero_valido(largo_r + 6, largo_s)
+}
+
+fn es_s_bajo(der: &[u8]) -> bool {
+    match Signature::from_der_lax(der) {
+        Ok(firma) => {
+            let mut normalizada = firma;
+            normalizada.normalize_s();
+            normalizada == firma
+        }
+        Err(_) => false,
+    }
+}
+
+/// Indica si la clave pública está en formato SEC, comprimido o sin comprimir.
+fn es_clave_sec(clave: &[u8]) -> bool {
+    match clave.first() {
+        Some(0x02 | 0x03) => clave.len() == 33,
+        Some(0x04) => clave.len() == 65,
+        _ => false,
+    }
+}
+
+/// Operaciones que manipulan el stack sin interpretar sus elementos.
+fn operacion_stack(opcode: u8, stack: &mut Vec<Vec<u8>>) -> Result<(), RustifyError> {
+    let requeridos = match opcode {
+        OP_DEPTH => 0,
+        OP_IFDUP | OP_DROP | OP_DUP | OP_SIZE => 1,
+        OP_3DUP | OP_ROT => 3,
+        OP_2OVER | OP_2SWAP => 4,
+        OP_2ROT => 6,
+        _ => 2,
+    };
+    let largo = stack.len();
+    if largo < requeridos {
+        return Err(RustifyError::ScriptInvalido);
+    }
+    match opcode {
+        OP_2DROP => stack.truncate(largo - 2),
+        OP_2DUP => stack.extend_from_within(largo - 2..),
+        OP_3DUP => stack.extend_from_within(largo - 3..),
+        OP_2OVER => stack.extend_from_within(largo - 4..largo - 2),
+        OP_2ROT => stack[largo - 6..].rotate_left(2),
+        OP_2SWAP => stack[largo - 4..].rotate_left(2),
+        OP_IFDUP => {
+            if es_verdadero(&stack[largo - 1]) {
+                stack.extend_from_within(largo - 1..);
+            }
+        }
+        OP_DEPTH => stack.push(codificar_numero(largo as i64)),
+        OP_DROP => stack.truncate(largo - 1),
+        OP_DUP => stack.extend_from_within(largo - 1..),
+        OP_NIP => {
+            stack.remove(largo - 2);
+        }
+        OP_OVER => stack.extend_from_within(largo - 2..largo - 1),
+        OP_ROT => stack[largo - 3..].rotate_left(1),
+        OP_SWAP => stack.swap(largo - 2, largo - 1),
+        OP_TUCK => stack.insert(largo - 2, stack[largo - 1].clone()),
+        OP_SIZE => stack.push(codificar_numero(stack[largo - 1].len() as i64)),
+        _ => return Err(RustifyError::ScriptInvalido),
+    }
+    Ok(())
+}
+
+/// Operaciones aritméticas y de comparación sobre números de script de hasta 4 bytes.
+fn operacion_numerica(
+    opcode: u8,
+    stack: &mut Vec<Vec<u8>>,
+    flags: u32,
+) -> Result<(), RustifyError> {
+    let cantidad = match opcode {
+        OP_1ADD..=OP_0NOTEQUAL => 1,
+        OP_WITHIN => 3,
+        _ => 2,
+    };
+    if stack.len() < cantidad {
+        return Err(RustifyError::ScriptInvalido);
+    }
+    let mut numeros = vec![];
+    for elemento in stack.split_off(stack.len() - cantidad) {
+        numeros.push(leer_numero(&elemento, flags, MAX_TAMANIO_NUMERO)?);
+    }
+    let resultado = match (opcode, numeros.as_slice()) {
+        (OP_1ADD, [a]) => a + 1,
+        (OP_1SUB, [a]) => a - 1,
+        (OP_NEGATE, [a]) => -a,
+        (OP_ABS, [a]) => a.abs(),
+        (OP_NOT, [a]) => (*a == 0) as i64,
+        (OP_0NOTEQUAL, [a]) => (*a != 0) as i64,
+        (OP_ADD, [a, b]) => a + b,
+        (OP_SUB, [a, b]) => a - b,
+        (OP_BOOLAND, [a, b]) => (*a != 0 && *b != 0) as i64,
+        (OP_BOOLOR, [a, b]) => (*a != 0 || *b != 0) as i64,
+        (OP_NUMEQUAL | OP_NUMEQUALVERIFY, [a, b]) => (a == b) as i64,
+        (OP_NUMNOTEQUAL, [a, b]) => (a != b) as i64,
+        (OP_LESSTHAN, [a, b]) => (a < b) as i64,
+        (OP_GREATERTHAN, [a, b]) => (a > b) as i64,
+        (OP_LESSTHANOREQUAL, [a, b]) => (a <= b) as i64,
+        (OP_GREATERTHANOREQUAL, [a, b]) => (a >= b) as i64,
+        (OP_MIN, [a, b]) => *a.min(b),
+        (OP_MAX, [a, b]) => *a.max(b),
+        (OP_WITHIN, [x, minimo, maximo]) => (minimo <= x && x < maximo) as i64,
+        _ => return Err(RustifyError::ScriptInvalido),
+    };
+    if opcode == OP_NUMEQUALVERIFY {
+        return verificar(resultado != 0, RustifyError::ScriptInvalido);
+    }
+    stack.push(codificar_numero(resultado));
+    Ok(())
+}
+
+fn hashear(opcode: u8, datos: &[u8]) -> Vec<u8> {
+    match opcode {
+        OP_RIPEMD160 => ripemd160::Hash::hash(datos).to_byte_array().to_vec(),
+        OP_SHA1 => sha1::Hash::hash(datos).to_byte_array().to_vec(),
+        OP_SHA256 => sha256::Hash::hash(datos).to_byte_array().to_vec(),
+        OP_HASH160 => hash160::Hash::hash(datos).to_byte_array().to_vec(),
+        _ => sha256d::Hash::hash(datos).to_byte_array().to_vec(),
+    }
+}
+
+fn verificar(condicion: bool, error: RustifyError) -> Result<(), RustifyError> {
+    match condicion {
+        true => Ok(()),
+        false => Err(error),
+    }
+}
+
+fn sacar(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, RustifyError> {
+    stack.pop().ok_or(RustifyError::ScriptInvalido)
+}
+
+/// Elemento del stack en la posición indicada, contando desde el tope (0 es el tope).
+fn desde_tope(stack: &[Vec<u8>], posicion: usize) -> Result<&Vec<u8>, RustifyError> {
+    stack
+        .len()
+        .checked_sub(posicion + 1)
+        .map(|index| &stack[index])
+        .ok_or(RustifyError::ScriptInvalido)
+}
+
+/// Un elemento es falso si está vacío o si todos sus bytes son cero (incluyendo el cero negativo, 0x80 al final).
+fn es_verdadero(elemento: &[u8]) -> bool {
+    match elemento.split_last() {
+        Some((ultimo, resto)) => resto.iter().any(|byte| *byte != 0) || (*ultimo & 0x7f) != 0,
+        None => false,
+    }
+}
+
+/// Interpreta un número de script: little endian con el bit más alto del último byte como signo.
+/// Con MINIMALDATA, rechaza los números que no usan la codificación más corta.
+fn leer_numero(bytes: &[u8], flags: u32, largo_maximo: usize) -> Result<i64, RustifyError> {
+    if bytes.len() > largo_maximo {
+        return Err(RustifyError::ScriptInvalido);
+    }
+    if let Some(ultimo) = bytes.last() {
+        let no_minimo =
+            ultimo & 0x7f == 0 && (bytes.len() == 1 || bytes[bytes.len() - 2] & 0x80 == 0);
+        if flags & FLAG_MINIMALDATA != 0 && no_minimo {
+            return Err(RustifyError::ScriptNoEstandar);
+        }
+    }
+    let mut numero: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        numero |= (*byte as i64) << (8 * i);
+    }
+    if bytes.last().is_some_and(|ultimo| ultimo & 0x80 != 0) {
+        numero &= !(0x80 << (8 * (bytes.len() - 1)));
+        numero = -numero;
+    }
+    Ok(numero)
+}
+
+/// Codifica un número de script con la menor cantidad de bytes posible.
+pub fn codificar_numero(numero: i64) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut absoluto = numero.unsigned_abs();
+    while absoluto > 0 {
+        bytes.push((absoluto & 0xff) as u8);
+        absoluto >>= 8;
+    }
+    if let Some(ultimo) = bytes.last_mut() {
+        if *ultimo & 0x80 == 0 {
+            if numero < 0 {
+                *ultimo |= 0x80;
+            }
+        } else {
+            bytes.push(if numero < 0 { 0x80 } else { 0 });
+        }
+    }
+    bytes
+}
+
+/// Codifica el push de los datos con el opcode más corto posible.
+pub fn codificar_push(datos: &[u8]) -> Vec<u8> {
+    let mut push = match datos.len() {
+        largo if largo < OP_PUSHDATA1 as usize => vec![largo as u8],
+        largo if largo <= 0xff => vec![OP_PUSHDATA1, largo as u8],
+        largo if largo <= 0xffff => {
+            [vec![OP_PUSHDATA2], (largo as u16).to_le_bytes().to_vec()].concat()
+        }
+        largo => [vec![OP_PUSHDATA4], (largo as u32).to_le_bytes().to_vec()].concat(),
+    };
+    push.extend_from_slice(datos);
+    push
+}
+
+/// Indica si el push usa la codificación más corta: OP_0 para vacío, OP_1 a OP_16 y OP_1NEGATE para
+/// esos números, y el opcode de push de menor tamaño para el resto.
+fn es_push_minimo(opcode: u8, datos: &[u8]) -> bool {
+    match datos {
+        [] => opcode == OP_0,
+        [numero @ 1..=16] => opcode == OP_1 + numero - 1,
+        [0x81] => opcode == OP_1NEGATE,
+        _ => codificar_push(datos)[0] == opcode,
+    }
+}
+
+/// Lee la operación que empieza en index: devuelve su opcode, los datos que pushea (vacíos si no es un push)
+/// y el índice de la siguiente operación. Devuelve None si no hay operación o si el script termina en medio de un push.
+fn leer_operacion(script: &[u8], index: usize) -> Option<(u8, &[u8], usize)> {
+    let opcode = *script.get(index)?;
+    let index = index + 1;
+    let (largo, bytes_largo) = match opcode {
+        OP_PUSHDATA1 => (*script.get(index)? as usize, 1),
+        OP_PUSHDATA2 => {
+            let largo = script.get(index..index + 2)?;
+            (u16::from_le_bytes([largo[0], largo[1]]) as usize, 2)
+        }
+        OP_PUSHDATA4 => {
+            let largo = script.get(index..index + 4)?;
+            (
+                u32::from_le_bytes([largo[0], largo[1], largo[2], largo[3]]) as usize,
+                4,
+            )
+        }
+        largo if largo < OP_PUSHDATA1 => (largo as usize, 0),
+        _ => (0, 0),
+    };
+    let inicio = index + bytes_largo;
+    let datos = script.get(inicio..inicio.checked_add(largo)?)?;
+    Some((opcode, datos, inicio + largo))
+}
+
+/// Separa el script en sus operaciones: cada una con su opcode y los datos que pushea (vacíos si no es un push).
+/// Si el script termina en medio de un push, se descarta el resto.
+pub fn operaciones_script(script: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut operaciones = vec![];
+    let mut index = 0;
+    while let Some((opcode, datos, siguiente)) = leer_operacion(script, index) {
+        operaciones.push((opcode, datos));
+        index = siguiente;
+    }
+    operaciones
+}
+
+/// Copia del script sin las operaciones (en sus bytes serializados) que cumplen la condición.
+/// Si el script termina en medio de un push, esos bytes se mantienen.
+fn filtrar_operaciones(script: &[u8], quitar: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let mut filtrado = vec![];
+    let mut index = 0;
+    while let Some((_, _, siguiente)) = leer_operacion(script, index) {
+        if !quitar(&script[index..siguiente]) {
+            filtrado.extend_from_slice(&script[index..siguiente]);
+        }
+        index = siguiente;
+    }
+    filtrado.extend_from_slice(&script[index..]);
+    filtrado
+}
+
+/// Quita del script los pushes de la firma (FindAndDelete), ya que una firma no puede firmarse a sí misma.
+fn quitar_push(script: &[u8], firma: &[u8]) -> Vec<u8> {
+    if firma.is_empty() {
+        return script.to_vec();
+    }
+    let push = codificar_push(firma);
+    filtrar_operaciones(script, |operacion| operacion == push)
+}
+
+/// Indica si el script tiene solamente pushes (los opcodes hasta OP_16 se consideran pushes).
+fn es_solo_pushes(script: &[u8]) -> bool {
+    let mut index = 0;
+    while index < script.len() {
+        match leer_operacion(script, index) {
+            Some((opcode, _, siguiente)) if opcode <= OP_16 => index = siguiente,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Indica si el pk_script es P2SH: OP_HASH160 <20 bytes> OP_EQUAL.
+pub fn es_p2sh(pk_script: &[u8]) -> bool {
+    pk_script.len() == 23
+        && pk_script[0] == OP_HASH160
+        && pk_script[1] == 20
+        && pk_script[22] == OP_EQUAL
+}
+
+/// Indica si el script es un programa witness (BIP141): la versión (OP_0 u OP_1 a OP_16)
+/// seguida de un único push de 2 a 40 bytes.
+pub fn es_programa_witness(script: &[u8]) -> bool {
+    (4..=42).contains(&script.len())
+        && (script[0] == OP_0 || (OP_1..=OP_16).contains(&script[0]))
+        && script[1] as usize + 2 == script.len()
+}
+
+/// Indica si el input gasta un output witness, directamente o con un redeem script P2SH que es un
+/// programa witness. Sus firmas están en el witness de la transacción, que el nodo no recibe.
+pub fn gasta_witness(signature_script: &[u8], prevout: &TxOut) -> bool {
+    if es_programa_witness(&prevout.pk_script) {
+        return true;
+    }
+    es_p2sh(&prevout.pk_script)
+        && es_solo_pushes(signature_script)
+        && matches!(operaciones_script(signature_script).last(),
+            Some((_, redeem_script)) if es_programa_witness(redeem_script))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::outpoint::OutPoint;
+    use crate::txin::TxIn;
+    use crate::wallet_txn::firmar;
+    use secp256k1::SecretKey;
+
+    /// Transacción con un input que gasta el output 0 de una transacción ficticia, con el script indicado.
+    fn txn_de_prueba(signature_script: Vec<u8>, pk_script: Vec<u8>) -> Txn {
+        Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(1),
+            tx_in: vec![TxIn::new(&("00".repeat(32), 0), signature_script)],
+            tx_out_count: CompactSize::new(1),
+            tx_out: vec![output(pk_script)],
+            tx_lock_time: 0,
+        }
+    }
+
+    fn output(pk_script: Vec<u8>) -> TxOut {
+        TxOut {
+            value_amount_satoshis: 10_000,
+            pk_script_bytes: CompactSize::new(pk_script.len() as u64),
+            pk_script,
+        }
+    }
+
+    /// Ejecuta el script como pk_script de un output gastado por un input sin script.
+    fn ejecutar_script(pk_script: Vec<u8>, flags: u32) -> Result<(), RustifyError> {
+        let txn = txn_de_prueba(vec![], vec![OP_1]);
+        verify_input(&txn, 0, &output(pk_script), flags)
+    }
+
+    fn clave(numero: u8) -> SecretKey {
+        SecretKey::from_slice(&[numero; 32]).unwrap()
+    }
+
+    /// Firma el input 0 de la transacción con SIGHASH_ALL, devolviendo la firma DER con el byte de sighash.
+    fn firmar_input(txn: &Txn, script_code: &[u8], clave: &SecretKey) -> Vec<u8> {
+        firmar_con_tipo(txn, 0, script_code, clave, SIGHASH_ALL)
+    }
+
+    /// Firma el input indicado de la transacción con el tipo de sighash dado.
+    fn firmar_con_tipo(
+        txn: &Txn,
+        index: usize,
+        script_code: &[u8],
+        clave: &SecretKey,
+        hash_type: u32,
+    ) -> Vec<u8> {
+        let hash = firma_hash_legacy(txn, index, script_code, hash_type);
+        let mensaje = Message::from_slice(&hash).unwrap();
+        let mut firma = Secp256k1::new()
+            .sign_ecdsa(&mensaje, clave)
+            .serialize_der()
+            .to_vec();
+        firma.push(hash_type as u8);
+        firma
+    }
+
+    /// Nombres de los opcodes desde OP_NOP (0x61), como los escriben los tests de Bitcoin Core.
+    const NOMBRES_OPCODES: [&str; 89] = [
+        "NOP",
+        "VER",
+        "IF",
+        "NOTIF",
+        "VERIF",
+        "VERNOTIF",
+        "ELSE",
+        "ENDIF",
+        "VERIFY",
+        "RETURN",
+        "TOALTSTACK",
+        "FROMALTSTACK",
+        "2DROP",
+        "2DUP",
+        "3DUP",
+        "2OVER",
+        "2ROT",
+        "2SWAP",
+        "IFDUP",
+        "DEPTH",
+        "DROP",
+        "DUP",
+        "NIP",
+        "OVER",
+        "PICK",
+        "ROLL",
+        "ROT",
+        "SWAP",
+        "TUCK",
+        "CAT",
+        "SUBSTR",
+        "LEFT",
+        "RIGHT",
+        "SIZE",
+        "INVERT",
+        "AND",
+        "OR",
+        "XOR",
+        "EQUAL",
+        "EQUALVERIFY",
+        "RESERVED1",
+        "RESERVED2",
+        "1ADD",
+        "1SUB",
+        "2MUL",
+        "2DIV",
+        "NEGATE",
+        "ABS",
+        "NOT",
+        "0NOTEQUAL",
+        "ADD",
+        "SUB",
+        "MUL",
+        "DIV",
+        "MOD",
+        "LSHIFT",
+        "RSHIFT",
+        "BOOLAND",
+        "BOOLOR",
+        "NUMEQUAL",
+        "NUMEQUALVERIFY",
+        "NUMNOTEQUAL",
+        "LESSTHAN",
+        "GREATERTHAN",
+        "LESSTHANOREQUAL",
+        "GREATERTHANOREQUAL",
+        "MIN",
+        "MAX",
+        "WITHIN",
+        "RIPEMD160",
+        "SHA1",
+        "SHA256",
+        "HASH160",
+        "HASH256",
+        "CODESEPARATOR",
+        "CHECKSIG",
+        "CHECKSIGVERIFY",
+        "CHECKMULTISIG",
+        "CHECKMULTISIGVERIFY",
+        "NOP1",
+        "CHECKLOCKTIMEVERIFY",
+        "CHECKSEQUENCEVERIFY",
+        "NOP4",
+        "NOP5",
+        "NOP6",
+        "NOP7",
+        "NOP8",
+        "NOP9",
+        "NOP10",
+    ];
+
+    fn bytes_desde_hexa(hexa: &str) -> Vec<u8> {
+        (0..hexa.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hexa[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Traduce un script en la notación de los tests de Bitcoin Core (script_tests.json): números en decimal,
+    /// bytes en hexa con el prefijo 0x (que se copian tal cual), strings entre comillas simples y opcodes por nombre.
+    fn script_core(asm: &str) -> Vec<u8> {
+        let mut script = vec![];
+        for palabra in asm.split_whitespace() {
+            let nombre = palabra.trim_start_matches("OP_");
+            if let Ok(numero) = palabra.parse::<i64>() {
+                match numero {
+                    -1 => script.push(OP_1NEGATE),
+                    1..=16 => script.push(OP_1 + numero as u8 - 1),
+                    _ => script.extend(codificar_push(&codificar_numero(numero))),
+                }
+            } else if let Some(hexa) = palabra.strip_prefix("0x") {
+                script.extend(bytes_desde_hexa(hexa));
+            } else if let Some(texto) = palabra
+                .strip_prefix('\'')
+                .and_then(|p| p.strip_suffix('\''))
+            {
+                script.extend(codificar_push(texto.as_bytes()));
+            } else if let Some(posicion) = NOMBRES_OPCODES.iter().position(|n| *n == nombre) {
+                script.push(OP_NOP + posicion as u8);
+            } else {
+                script.push(match nombre {
+                    "RESERVED" => 0x50,
+                    "NOP2" => OP_CHECKLOCKTIMEVERIFY,
+                    "NOP3" => OP_CHECKSEQUENCEVERIFY,
+                    otro => panic!("opcode desconocido: {otro}"),
+                });
+            }
+        }
+        script
+    }
+
+    /// Flags en la notación de los tests de Bitcoin Core, separados por comas.
+    fn flags_core(nombres: &str) -> u32 {
+        nombres
+            .split(',')
+            .filter(|nombre| !nombre.is_empty() && *nombre != "NONE")
+            .map(|nombre| match nombre {
+                "P2SH" => FLAG_P2SH,
+                "STRICTENC" => FLAG_STRICTENC,
+                "DERSIG" => FLAG_DERSIG,
+                "LOW_S" => FLAG_LOW_S,
+                "NULLDUMMY" => FLAG_NULLDUMMY,
+                "SIGPUSHONLY" => FLAG_SIGPUSHONLY,
+                "MINIMALDATA" => FLAG_MINIMALDATA,
+                "DISCOURAGE_UPGRADABLE_NOPS" => FLAG_DISCOURAGE_UPGRADABLE_NOPS,
+                "CLEANSTACK" => FLAG_CLEANSTACK,
+                "CHECKLOCKTIMEVERIFY" => FLAG_CHECKLOCKTIMEVERIFY,
+                "CHECKSEQUENCEVERIFY" => FLAG_CHECKSEQUENCEVERIFY,
+                "NULLFAIL" => FLAG_NULLFAIL,
+                otro => panic!("flag desconocido: {otro}"),
+            })
+            .fold(0, |flags, flag| flags | flag)
+    }
+
+    /// Transacciones con las que Bitcoin Core ejecuta sus tests de scripts: una que crea un output de valor 0
+    /// con el pk_script, desde un input coinbase con el script `0 0`, y otra que lo gasta con el script del input.
+    /// Devuelve la segunda junto con el output que gasta.
+    fn transacciones_core(signature_script: Vec<u8>, pk_script: Vec<u8>) -> (Txn, TxOut) {
+        let previo = TxOut {
+            value_amount_satoshis: 0,
+            pk_script_bytes: CompactSize::new(pk_script.len() as u64),
+            pk_script,
+        };
+        let credito = Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(1),
+            tx_in: vec![TxIn {
+                previous_output: OutPoint {
+                    hash_previous_output_txid: [0; 32],
+                    output_index: 0xffffffff,
+                },
+                script_bytes: CompactSize::new(2),
+                signature_script: vec![OP_0, OP_0],
+                sequence: 0xffffffff,
+            }],
+            tx_out_count: CompactSize::new(1),
+            tx_out: vec![previo.clone()],
+            tx_lock_time: 0,
+        };
+        let gasto = Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(1),
+            tx_in: vec![TxIn {
+                previous_output: OutPoint {
+                    hash_previous_output_txid: sha256d::Hash::hash(&credito.as_bytes())
+                        .to_byte_array(),
+                    output_index: 0,
+                },
+                script_bytes: CompactSize::new(signature_script.len() as u64),
+                signature_script,
+                sequence: 0xffffffff,
+            }],
+            tx_out_count: CompactSize::new(1),
+            tx_out: vec![TxOut {
+                value_amount_satoshis: 0,
+                pk_script_bytes: CompactSize::new(0),
+                pk_script: vec![],
+            }],
+            tx_lock_time: 0,
+        };
+        (gasto, previo)
+    }
+
+    /// Ejecuta casos en el formato de script_tests.json: script del input, pk_script, flags, resultado esperado
+    /// y comentario. Solamente se distingue entre OK y cualquier error.
+    fn ejecutar_casos_core(casos: &[[&str; 5]]) {
+        for [script_sig, pk_script, flags, esperado, comentario] in casos {
+            let (txn, previo) = transacciones_core(script_core(script_sig), script_core(pk_script));
+            let resultado = verify_input(&txn, 0, &previo, flags_core(flags));
+            assert_eq!(
+                resultado.is_ok(),
+                *esperado == "OK",
+                "{comentario}: {resultado:?}"
+            );
+        }
+    }
+
+    /// Test para chequear que una transacción P2PKH firmada por la wallet verifica, y que deja de
+    /// verificar si se modifica luego de firmada.
+    #[test]
+    fn test_verificar_p2pkh_firmado_por_la_wallet() -> Result<(), RustifyError> {
+        let emisor = Account::new_str(
+            "mremfsNt32NAqPodczJQcY9sfKbcFk33ge",
+            "cRQuMXoGdBQm6iKmJ1fyT6qqCkK9AtAadFeoxqN4QYWsA8wN3eyy",
+        );
+        let previo = output(emisor.obtain_pk_script());
+        let txn = txn_de_prueba(emisor.obtain_pk_script(), emisor.obtain_pk_script());
+        let mut firmada = firmar(txn, &emisor)?;
+        verify_input(&firmada, 0, &previo, FLAGS_ESTANDAR)?;
+        verify_input(&firmada, 0, &previo, flags_consenso(u32::MAX))?;
+
+        firmada.tx_out[0].value_amount_satoshis += 1;
+        assert_eq!(
+            verify_input(&firmada, 0, &previo, FLAGS_ESTANDAR),
+            Err(RustifyError::FirmaInvalida)
+        );
+        assert_eq!(
+            verify_input(&firmada, 0, &previo, FLAG_P2SH),
+            Err(RustifyError::FirmaInvalida)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear las operaciones aritméticas, de stack, de hash y los condicionales, y que los
+    /// opcodes deshabilitados fallan aunque estén en una rama que no se ejecuta.
+    #[test]
+    fn test_opcodes() {
+        let sha256_abc = sha256::Hash::hash(b"abc").to_byte_array();
+        let validos = [
+            vec![0x52, 0x53, OP_ADD, 0x55, OP_EQUAL],
+            vec![
+                0x55,
+                0x53,
+                OP_SUB,
+                OP_1NEGATE,
+                OP_ABS,
+                OP_ADD,
+                0x53,
+                OP_NUMEQUAL,
+            ],
+            vec![
+                OP_1,
+                0x52,
+                0x53,
+                OP_ROT,
+                OP_1,
+                OP_EQUALVERIFY,
+                0x53,
+                OP_EQUALVERIFY,
+                0x52,
+                OP_EQUAL,
+            ],
+            vec![
+                0x53,
+                OP_1,
+                0x55,
+                OP_WITHIN,
+                OP_1,
+                0x52,
+                OP_2DUP,
+                OP_MAX,
+                0x52,
+                OP_EQUALVERIFY,
+                OP_MIN,
+                OP_1,
+                OP_EQUALVERIFY,
+            ],
+            vec![
+                0x52,
+                0x53,
+                0x54,
+                0x52,
+                OP_PICK,
+                0x52,
+                OP_EQUALVERIFY,
+                OP_DEPTH,
+                0x53,
+                OP_EQUALVERIFY,
+                OP_2DROP,
+            ],
+            vec![OP_0, OP_IF, OP_0, OP_ELSE, OP_1, OP_ENDIF],
+            vec![OP_0, OP_IF, OP_RETURN, OP_ENDIF, OP_1],
+            vec![
+                OP_1,
+                OP_NOTIF,
+                OP_0,
+                OP_ELSE,
+                OP_1,
+                OP_TOALTSTACK,
+                OP_FROMALTSTACK,
+                OP_ENDIF,
+            ],
+            [
+                vec![3],
+                b"abc".to_vec(),
+                vec![OP_SHA256, 32],
+                sha256_abc.to_vec(),
+                vec![OP_EQUAL],
+            ]
+            .concat(),
+        ];
+        for script in validos {
+            assert_eq!(ejecutar_script(script, FLAGS_ESTANDAR), Ok(()));
+        }
+
+        let invalidos = [
+            vec![0x52, 0x53, OP_ADD, 0x56, OP_EQUAL],
+            vec![OP_0, OP_IF, OP_CAT, OP_ENDIF, OP_1],
+            vec![OP_0, OP_IF, 0x65, OP_ENDIF, OP_1],
+            vec![OP_1, OP_IF, OP_1],
+            vec![OP_1, OP_RETURN],
+            vec![OP_1, OP_DROP, OP_DROP, OP_1],
+            vec![0x05, 1, 2, 3, 4, 5, OP_1ADD],
+            vec![OP_PUSHDATA1, 2, 1],
+        ];
+        for script in invalidos {
+            assert_eq!(
+                ejecutar_script(script, FLAGS_ESTANDAR),
+                Err(RustifyError::ScriptInvalido)
+            );
+        }
+
+        // Un push no mínimo y un NOP reservado solamente fallan con las flags de estandaridad.
+        for script in [vec![0x01, 0x05, OP_DROP, OP_1], vec![OP_NOP1, OP_1]] {
+            assert_eq!(
+                ejecutar_script(script.clone(), FLAGS_ESTANDAR),
+                Err(RustifyError::ScriptNoEstandar)
+            );
+            assert_eq!(ejecutar_script(script, FLAG_P2SH), Ok(()));
+        }
+    }
+
+    /// Test para chequear la codificación de los números de script.
+    #[test]
+    fn test_numeros_de_script() -> Result<(), RustifyError> {
+        for (numero, bytes) in [
+            (0, vec![]),
+            (-1, vec![0x81]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x00]),
+            (-128, vec![0x80, 0x80]),
+            (255, vec![0xff, 0x00]),
+            (-65535, vec![0xff, 0xff, 0x80]),
+        ] {
+            assert_eq!(codificar_numero(numero), bytes);
+            assert_eq!(leer_numero(&bytes, FLAG_MINIMALDATA, 4)?, numero);
+        }
+        assert_eq!(leer_numero(&[0x01, 0x00], 0, 4)?, 1);
+        assert_eq!(
+            leer_numero(&[0x01, 0x00], FLAG_MINIMALDATA, 4),
+            Err(RustifyError::ScriptNoEstandar)
+        );
+        assert_eq!(
+            leer_numero(&[1, 2, 3, 4, 5], 0, 4),
+            Err(RustifyError::ScriptInvalido)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear un multisig 2 de 3 dentro de un P2SH: las firmas tienen que estar en el orden
+    /// de las claves, y el elemento extra tiene que estar vacío para ser estándar.
+    #[test]
+    fn test_p2sh_multisig() -> Result<(), RustifyError> {
+        let secp = Secp256k1::new();
+        let claves: Vec<SecretKey> = (1..=3).map(clave).collect();
+        let mut redeem_script = vec![0x52];
+        for clave in &claves {
+            redeem_script.extend(codificar_push(&clave.public_key(&secp).serialize()));
+        }
+        redeem_script.extend_from_slice(&[0x53, OP_CHECKMULTISIG]);
+        let pk_script = [
+            vec![OP_HASH160, 20],
+            hash160::Hash::hash(&redeem_script).to_byte_array().to_vec(),
+            vec![OP_EQUAL],
+        ]
+        .concat();
+        let previo = output(pk_script);
+
+        let txn = txn_de_prueba(vec![], vec![OP_1]);
+        let firma_1 = firmar_input(&txn, &redeem_script, &claves[0]);
+        let firma_3 = firmar_input(&txn, &redeem_script, &claves[2]);
+        let con_script = |dummy: u8, firmas: [&Vec<u8>; 2]| {
+            let mut script = vec![dummy];
+            for firma in firmas {
+                script.extend(codificar_push(firma));
+            }
+            script.extend(codificar_push(&redeem_script));
+            let mut txn = txn.clone();
+            txn.tx_in[0].script_bytes = CompactSize::new(script.len() as u64);
+            txn.tx_in[0].signature_script = script;
+            txn
+        };
+
+        verify_input(
+            &con_script(OP_0, [&firma_1, &firma_3]),
+            0,
+            &previo,
+            FLAGS_ESTANDAR,
+        )?;
+        assert_eq!(
+            verify_input(
+                &con_script(OP_0, [&firma_3, &firma_1]),
+                0,
+                &previo,
+                FLAGS_ESTANDAR
+            ),
+            Err(RustifyError::FirmaInvalida)
+        );
+        let con_dummy = con_script(OP_1, [&firma_1, &firma_3]);
+        assert_eq!(
+            verify_input(&con_dummy, 0, &previo, FLAGS_ESTANDAR),
+            Err(RustifyError::ScriptInvalido)
+        );
+        // Antes de segwit el elemento extra puede tener cualquier valor; desde segwit tiene que estar vacío (BIP147).
+        let altura_segwit = parametros_red().altura_segwit;
+        verify_input(&con_dummy, 0, &previo, flags_consenso(altura_segwit - 1))?;
+        assert_eq!(
+            verify_input(&con_dummy, 0, &previo, flags_consenso(altura_segwit)),
+            Err(RustifyError::ScriptInvalido)
+        );
+
+        // Sin P2SH, alcanza con que el script del input tenga el redeem script.
+        let mut sin_firmas = con_script(OP_0, [&firma_1, &firma_1]);
+        sin_firmas.tx_in[0].signature_script = codificar_push(&redeem_script);
+        verify_input(&sin_firmas, 0, &previo, 0)?;
+        assert_eq!(
+            verify_input(&sin_firmas, 0, &previo, FLAG_P2SH),
+            Err(RustifyError::ScriptInvalido)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear OP_CHECKLOCKTIMEVERIFY y OP_CHECKSEQUENCEVERIFY contra el locktime y el
+    /// sequence del input, y que sin sus flags se comportan como NOP.
+    #[test]
+    fn test_cltv_y_csv() -> Result<(), RustifyError> {
+        let cltv = output(
+            [
+                codificar_push(&codificar_numero(100)),
+                vec![OP_CHECKLOCKTIMEVERIFY, OP_DROP, OP_1],
+            ]
+            .concat(),
+        );
+        let mut txn = txn_de_prueba(vec![], vec![OP_1]);
+        txn.tx_lock_time = 100;
+        txn.tx_in[0].sequence = 0xfffffffe;
+        verify_input(&txn, 0, &cltv, FLAGS_ESTANDAR)?;
+        txn.tx_lock_time = 99;
+        assert_eq!(
+            verify_input(&txn, 0, &cltv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        txn.tx_lock_time = LIMITE_LOCKTIME as u32;
+        assert_eq!(
+            verify_input(&txn, 0, &cltv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        txn.tx_lock_time = 100;
+        txn.tx_in[0].sequence = 0xffffffff;
+        assert_eq!(
+            verify_input(&txn, 0, &cltv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        verify_input(&txn, 0, &cltv, FLAG_P2SH)?;
+
+        let csv = output(vec![0x5a, OP_CHECKSEQUENCEVERIFY, OP_DROP, OP_1]);
+        txn.tx_in[0].sequence = 10;
+        assert_eq!(
+            verify_input(&txn, 0, &csv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        txn.version = 2;
+        verify_input(&txn, 0, &csv, FLAGS_ESTANDAR)?;
+        // Como en Bitcoin Core, la versión se compara sin signo: una negativa es mayor a 2.
+        txn.version = -1;
+        verify_input(&txn, 0, &csv, FLAGS_ESTANDAR)?;
+        txn.tx_in[0].sequence = 9;
+        assert_eq!(
+            verify_input(&txn, 0, &csv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        txn.tx_in[0].sequence = 10 | SEQUENCE_TIPO_TIEMPO as u32;
+        assert_eq!(
+            verify_input(&txn, 0, &csv, FLAGS_ESTANDAR),
+            Err(RustifyError::LockTimeNoCumplido)
+        );
+        Ok(())
+    }
+
+    /// Test con casos de script_tests.json de Bitcoin Core, ejecutados sobre las mismas transacciones
+    /// que usa Core: firmas P2PK, P2PKH, P2SH y ANYONECANPAY de sus claves de prueba, y reglas de opcodes.
+    #[test]
+    fn test_vectores_script_tests_core() {
+        ejecutar_casos_core(&[
+            ["", "DEPTH 0 EQUAL", "P2SH,STRICTENC", "OK", "Test the test: we should have an empty stack after scriptSig evaluation"],
+            ["1 2", "2 EQUALVERIFY 1 EQUAL", "P2SH,STRICTENC", "OK", "Similarly whitespace around and between symbols"],
+            ["0x51", "0x5f ADD 0x60 EQUAL", "P2SH,STRICTENC", "OK", "0x51 through 0x60 push 1 through 16 onto stack"],
+            ["0", "IF 0x50 ENDIF 1", "P2SH,STRICTENC", "OK", "0x50 is reserved (ok if not executed)"],
+            ["0x50", "1", "P2SH,STRICTENC", "BAD_OPCODE", "opcode 0x50 is reserved"],
+            ["0", "IF VER ELSE 1 ENDIF", "P2SH,STRICTENC", "OK", "VER non-functional (ok if not executed)"],
+            ["0", "IF RESERVED RESERVED1 RESERVED2 ELSE 1 ENDIF", "P2SH,STRICTENC", "OK", "RESERVED ok in un-executed IF"],
+            ["0", "IF VERIF ELSE 1 ENDIF", "P2SH,STRICTENC", "BAD_OPCODE", "VERIF illegal everywhere"],
+            ["0", "IF VERNOTIF ELSE 1 ENDIF", "P2SH,STRICTENC", "BAD_OPCODE", "VERNOTIF illegal everywhere"],
+            ["0", "IF 0 ELSE 1 ELSE 0 ENDIF", "P2SH,STRICTENC", "OK", "Multiple ELSE's are valid and executed inverts on each ELSE encountered"],
+            ["'a' 'b'", "CAT", "P2SH,STRICTENC", "DISABLED_OPCODE", "CAT disabled"],
+            ["0", "IF 2MUL ELSE 1 ENDIF", "P2SH,STRICTENC", "DISABLED_OPCODE", "2MUL disabled even in an unexecuted branch"],
+            ["", "0 0 0 CHECKMULTISIG VERIFY DEPTH 0 EQUAL", "P2SH,STRICTENC", "OK", "CHECKMULTISIG is allowed to have zero keys and/or sigs"],
+            ["1", "NOP1 CHECKLOCKTIMEVERIFY CHECKSEQUENCEVERIFY NOP4 NOP5 NOP6 NOP7 NOP8 NOP9 NOP10 1 EQUAL", "P2SH,STRICTENC", "OK", ""],
+            ["1", "NOP10", "DISCOURAGE_UPGRADABLE_NOPS", "DISCOURAGE_UPGRADABLE_NOPS", "Discouraged NOP10 in scriptPubKey"],
+            [
+                "0x47 0x304402200a5c6163f07b8d3b013c4d1d6dba25e780b39658d79ba37af7057a3b7f15ffa102201fd9b4eaa9943f734928b99a83592c2e7bf342ea2680f6a2bb705167966b742001",
+                "0x41 0x0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8 CHECKSIG",
+                "", "OK", "P2PK",
+            ],
+            [
+                "0x47 0x304402206e05a6fe23c59196ffe176c9ddc31e73a9885638f9d1328d47c0c703863b8876022076feb53811aa5b04e0e79f938eb19906cc5e67548bc555a8e8b8b0fc603d840c01 0x21 0x038282263212c609d9ea2a6e3e172de238d8c39cabd5ac1ca10646e23fd5f51508",
+                "DUP HASH160 0x14 0x1018853670f9f3b0582c5b9ee8ce93764ac32b93 EQUALVERIFY CHECKSIG",
+                "", "OK", "P2PKH",
+            ],
+            [
+                "0x47 0x304402204710a85181663b32d25c70ec2bbd14adff5ddfff6cb50d09e155ef5f541fc86c0220056b0cc949be9386ecc5f6c2ac0493269031dbb185781db90171b54ac127790281",
+                "0x41 0x048282263212c609d9ea2a6e3e172de238d8c39cabd5ac1ca10646e23fd5f5150811f8a8098557dfe45e8256e830b60ace62d613ac2f7b17bed31b6eaff6e26caf CHECKSIG",
+                "", "OK", "P2PK anyonecanpay",
+            ],
+            [
+                "0x47 0x3044022003fef42ed6c7be8917441218f525a60e2431be978e28b7aca4d7a532cc413ae8022067a1f82c74e8d69291b90d148778405c6257bbcfc2353cc38a3e1f22bf44254601 0x23 0x210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac",
+                "HASH160 0x14 0x23b0ad3477f2178bc0b3eed26e4e6316f4e83aa1 EQUAL",
+                "P2SH", "OK", "P2SH(P2PK)",
+            ],
+        ]);
+    }
+
+    /// Test con casos de sighash.json de Bitcoin Core: transacción, script, input, tipo de sighash (con bits
+    /// que no son de ningún tipo, y con ANYONECANPAY) y el hash esperado, en el orden en que se muestra.
+    #[test]
+    fn test_vectores_sighash_core() -> Result<(), RustifyError> {
+        let casos: [(&str, &str, usize, i32, &str); 5] = [
+            (
+                "907c2bc503ade11cc3b04eb2918b6f547b0630ab569273824748c87ea14b0696526c66ba740200000004ab65ababfd1f9bdd4ef073c7afc4ae00da8a66f429c917a0081ad1e1dabce28d373eab81d8628de802000000096aab5253ab52000052ad042b5f25efb33beec9f3364e8a9139e8439d9d7e26529c3c30b6c3fd89f8684cfd68ea0200000009ab53526500636a52ab599ac2fe02a526ed040000000008535300516352515164370e010000000003006300ab2ec229",
+                "",
+                2,
+                1864164639,
+                "31af167a6cf3f9d5f6875caa4d31704ceb0eba078d132b78dab52c3b8997317e",
+            ),
+            (
+                "6e7e9d4b04ce17afa1e8546b627bb8d89a6a7fefd9d892ec8a192d79c2ceafc01694a6a7e7030000000953ac6a51006353636a33bced1544f797f08ceed02f108da22cd24c9e7809a446c61eb3895914508ac91f07053a01000000055163ab516affffffff11dc54eee8f9e4ff0bcf6b1a1a35b1cd10d63389571375501af7444073bcec3c02000000046aab53514a821f0ce3956e235f71e4c69d91abe1e93fb703bd33039ac567249ed339bf0ba0883ef300000000090063ab65000065ac654bec3cc504bcf499020000000005ab6a52abac64eb060100000000076a6a5351650053bbbc130100000000056a6aab53abd6e1380100000000026a51c4e509b8",
+                "acab655151",
+                0,
+                479279909,
+                "2a3d95b09237b72034b23f2d2bb29fa32a58ab5c6aa72f6aafdfa178ab1dd01c",
+            ),
+            (
+                "73107cbd025c22ebc8c3e0a47b2a760739216a528de8d4dab5d45cbeb3051cebae73b01ca10200000007ab6353656a636affffffffe26816dffc670841e6a6c8c61c586da401df1261a330a6c6b3dd9f9a0789bc9e000000000800ac6552ac6aac51ffffffff0174a8f0010000000004ac52515100000000",
+                "5163ac63635151ac",
+                1,
+                1190874345,
+                "06e328de263a87b09beabe222a21627a6ea5c7f560030da31610c4611f4a46bc",
+            ),
+            (
+                "50818f4c01b464538b1e7e7f5ae4ed96ad23c68c830e78da9a845bc19b5c3b0b20bb82e5e9030000000763526a63655352ffffffff023b3f9c040000000008630051516a6a5163a83caf01000000000553ab65510000000000",
+                "6aac",
+                0,
+                946795545,
+                "746306f322de2b4b58ffe7faae83f6a72433c22f88062cdde881d4dd8a5a4e2d",
+            ),
+            (
+                "cf781855040a755f5ba85eef93837236b34a5d3daeb2dbbdcf58bb811828d806ed05754ab8010000000351ac53ffffffffda1e264727cf55c67f06ebcc56dfe7fa12ac2a994fecd0180ce09ee15c480f7d00000000096351516a51acac00ab53dd49ff9f334befd6d6f87f1a832cddfd826a90b78fd8cf19a52cb8287788af94e939d6020000000700525251ac526310d54a7e8900ed633f0f6f0841145aae7ee0cbbb1e2a0cae724ee4558dbabfdc58ba6855010000000552536a53abfd1b101102c51f910500000000096300656a525252656a300bee010000000009ac52005263635151abe19235c9",
+                "53005365",
+                2,
+                1422854188,
+                "d5981bd4467817c1330da72ddb8760d6c2556cd809264b2d85e6d274609fc3a3",
+            ),
+        ];
+        for (txn, script, index, hash_type, esperado) in casos {
+            let (txn, _) = Txn::from_bytes(bytes_desde_hexa(txn), 0)?;
+            let mut hash =
+                firma_hash_legacy(&txn, index, &bytes_desde_hexa(script), hash_type as u32);
+            hash.reverse();
+            assert_eq!(hash.to_vec(), bytes_desde_hexa(esperado));
+        }
+        Ok(())
+    }
+
+    /// Test para chequear FindAndDelete y OP_CODESEPARATOR sobre las transacciones de los tests de Core.
+    /// Con CHECKSIG en el script del input, el script firmado es ese script sin el push de la firma, que
+    /// solamente se quita si está codificado igual. Cada OP_CODESEPARATOR ejecutado recorta el script firmado,
+    /// y los que quedan en él no se firman.
+    #[test]
+    fn test_find_and_delete_y_codeseparator() -> Result<(), RustifyError> {
+        let secp = Secp256k1::new();
+        let clave_publica = codificar_push(&clave(1).public_key(&secp).serialize());
+        let (txn, _) = transacciones_core(vec![], vec![]);
+
+        let script_firmado = [clave_publica.clone(), vec![OP_CHECKSIG]].concat();
+        let firma = firmar_input(&txn, &script_firmado, &clave(1));
+        let con_firma = |push_firma: Vec<u8>| {
+            let script_sig = [push_firma, script_firmado.clone()].concat();
+            let (txn, previo) = transacciones_core(script_sig, vec![]);
+            verify_input(&txn, 0, &previo, 0)
+        };
+        con_firma(codificar_push(&firma))?;
+        let push_no_minimo = [vec![OP_PUSHDATA1, firma.len() as u8], firma.clone()].concat();
+        assert_eq!(con_firma(push_no_minimo), Err(RustifyError::FirmaInvalida));
+
+        // <clave> CHECKSIGVERIFY CODESEPARATOR <clave> CHECKSIG: la primera firma cubre el script sin el
+        // CODESEPARATOR, y la segunda solamente lo que sigue a él.
+        let pk_script = [
+            clave_publica.clone(),
+            vec![OP_CHECKSIGVERIFY, OP_CODESEPARATOR],
+            clave_publica.clone(),
+            vec![OP_CHECKSIG],
+        ]
+        .concat();
+        let (txn, _) = transacciones_core(vec![], pk_script.clone());
+        let firma_1 = firmar_input(
+            &txn,
+            &[
+                clave_publica.clone(),
+                vec![OP_CHECKSIGVERIFY],
+                clave_publica.clone(),
+                vec![OP_CHECKSIG],
+            ]
+            .concat(),
+            &clave(1),
+        );
+        let firma_2 = firmar_input(&txn, &script_firmado, &clave(1));
+        let con_firmas = |firmas: [&Vec<u8>; 2]| {
+            let script_sig = [codificar_push(firmas[0]), codificar_push(firmas[1])].concat();
+            let (txn, previo) = transacciones_core(script_sig, pk_script.clone());
+            verify_input(&txn, 0, &previo, FLAG_P2SH)
+        };
+        con_firmas([&firma_2, &firma_1])?;
+        assert_eq!(
+            con_firmas([&firma_1, &firma_1]),
+            Err(RustifyError::FirmaInvalida)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear el bug de SIGHASH_SINGLE: si el input no tiene un output en su misma posición,
+    /// se firma el hash 1, y la firma sirve para cualquier transacción. El tipo se toma de los 5 bits bajos.
+    #[test]
+    fn test_bug_sighash_single() -> Result<(), RustifyError> {
+        let secp = Secp256k1::new();
+        let pk_script = [
+            codificar_push(&clave(1).public_key(&secp).serialize()),
+            vec![OP_CHECKSIG],
+        ]
+        .concat();
+        let (mut txn, previo) = transacciones_core(vec![], pk_script.clone());
+        txn.tx_in.push(txn.tx_in[0].clone());
+        txn.tx_in_count = CompactSize::new(2);
+
+        let mut uno = [0; 32];
+        uno[0] = 1;
+        for hash_type in [SIGHASH_SINGLE, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY, 0x43] {
+            assert_eq!(firma_hash_legacy(&txn, 1, &pk_script, hash_type), uno);
+        }
+        assert_ne!(firma_hash_legacy(&txn, 0, &pk_script, SIGHASH_SINGLE), uno);
+
+        let firma = firmar_con_tipo(&txn, 1, &pk_script, &clave(1), SIGHASH_SINGLE);
+        let mut otra = txn.clone();
+        otra.tx_out[0].value_amount_satoshis = 1_000;
+        otra.tx_lock_time = 500;
+        for mut txn in [txn, otra] {
+            txn.tx_in[1].signature_script = codificar_push(&firma);
+            txn.tx_in[1].script_bytes = CompactSize::new(firma.len() as u64 + 1);
+            verify_input(&txn, 1, &previo, FLAGS_ESTANDAR)?;
+        }
+        Ok(())
+    }
+
+    /// Test para chequear NULLDUMMY (BIP147) sobre un multisig 1 de 1: el elemento extra que consume
+    /// CHECKMULTISIG tiene que estar vacío con el flag, que es de consenso desde la altura de segwit.
+    #[test]
+    fn test_nulldummy() -> Result<(), RustifyError> {
+        let secp = Secp256k1::new();
+        let pk_script = [
+            vec![OP_1],
+            codificar_push(&clave(1).public_key(&secp).serialize()),
+            vec![OP_1, OP_CHECKMULTISIG],
+        ]
+        .concat();
+        let (txn, _) = transacciones_core(vec![], pk_script.clone());
+        let firma = codificar_push(&firmar_input(&txn, &pk_script, &clave(1)));
+        let con_dummy = |dummy: u8| {
+            transacciones_core([vec![dummy], firma.clone()].concat(), pk_script.clone())
+        };
+
+        let altura_segwit = parametros_red().altura_segwit;
+        let (txn, previo) = con_dummy(OP_0);
+        verify_input(&txn, 0, &previo, FLAG_NULLDUMMY)?;
+        verify_input(&txn, 0, &previo, flags_consenso(altura_segwit))?;
+        let (txn, previo) = con_dummy(OP_1);
+        verify_input(&txn, 0, &previo, 0)?;
+        verify_input(&txn, 0, &previo, flags_consenso(altura_segwit - 1))?;
+        assert_eq!(
+            verify_input(&txn, 0, &previo, FLAG_NULLDUMMY),
+            Err(RustifyError::ScriptInvalido)
+        );
+        assert_eq!(
+            verify_input(&txn, 0, &previo, flags_consenso(altura_segwit)),
+            Err(RustifyError::ScriptInvalido)
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use crate::errors::RustifyError;
 use crate::txn::Txn;
-use crate::{block_header::BlockHeader, compactsize::CompactSize};
+use crate::{
+    block_header::BlockHeader,
+    compactsize::{leer_bytes, CompactSize},
+};
 use bitcoin_hashes::{sha256d, Hash};
 
 #[derive(Debug, Clone)]
@@ -14,11 +17,11 @@ impl SerializedBlock {
     /// del mensaje de tipo "Block"
     pub fn from_bytes(block_bytes: &[u8]) -> Result<SerializedBlock, RustifyError> {
         let mut index = 0;
-        let block_header = BlockHeader::from_bytes(&block_bytes[index..index + 80])?;
+        let block_header = BlockHeader::from_bytes(leer_bytes(block_bytes, index, 80)?)?;
         index += 80;
-        let (txn_count, csize_bytes) = CompactSize::parse_from_byte_array(&block_bytes[80..90]);
-
-        index += csize_bytes;
+        let (cant_txns, siguiente) = CompactSize::leer(block_bytes, index)?;
+        let txn_count = CompactSize::new(cant_txns);
+        index = siguiente;
 
         let mut txns: Vec<Txn> = vec![];
         let mut transaction: Txn;
@@ -40,6 +43,16 @@ impl SerializedBlock {
         }
     }
 
+    /// Serializa el bloque como en el payload del mensaje block.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.block_header.as_bytes().to_vec();
+        bytes.extend(self.txn_count.as_bytes());
+        for txn in &self.txns {
+            bytes.extend(txn.as_bytes());
+        }
+        bytes
+    }
+
     /// Obtiene el nombre del archivo utilizando el hash del bloque
     pub fn obtain_name_for_blockfile(bytes_block: &[u8]) -> String {
         sha256d::Hash::hash(&bytes_block[0..80]).to_string()
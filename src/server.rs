@@ -1,28 +1,59 @@
 use bitcoin_hashes::{sha256d, Hash};
 
 use crate::{
+    ban_manager::{direccion_baneada, penalizar_nodo},
     block_header::BlockHeader,
+    compact_filter::IndiceFiltros,
     config::Config,
-    errors::RustifyError,
+    errors::{obtener_mensaje_personalizado, RustifyError},
+    event_loop::{event_loop, ConexionPeer},
     inv::Inv,
     logger::{log, log_with_parameters, Action, Lvl},
-    message_header::{MessageHeader, MESSAGE_HEADER_SIZE},
-    node::read_from_node,
-    server_messages::{recibir_getdata, recibir_getheaders, recibir_handshake},
+    network_message::NetworkMessage,
+    node::pong,
+    server_messages::{
+        recibir_getblocktxn, recibir_getcfcheckpt, recibir_getcfheaders, recibir_getcfilters,
+        recibir_getdata, recibir_getheaders, recibir_verack, recibir_version, FuentesFiltros,
+    },
     server_notification::envio_notificaciones_cliente,
     txn::Txn,
 };
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::{mpsc::Sender, Arc, Mutex},
+    collections::{hash_map::Entry, HashMap},
+    net::TcpListener,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
     thread,
 };
-type TrxServer = Vec<(String, Txn)>;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
+
+/// Etapa en la que se encuentra cada cliente conectado al servidor.
+#[derive(Debug, Clone, PartialEq)]
+enum EtapaCliente {
+    EsperandoVersion,
+    EsperandoVerack,
+    Conectado,
+}
+
+/// Datos compartidos que necesita el servidor para contestar las peticiones de los clientes.
+struct EstadoServidor {
+    config: Config,
+    logger_sender: Sender<String>,
+    headers: Arc<Mutex<Vec<BlockHeader>>>,
+    headers_hash_height: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+    txn_memory_client: Arc<Mutex<TrxServer>>,
+    client_connections: Arc<Mutex<HashMap<String, ConexionPeer>>>,
+    /// Fee mínima (feefilter, BIP133) que pidió cada cliente, por IP.
+    filtros_fee: Arc<Mutex<HashMap<String, u64>>>,
+    /// Filtros compactos (BIP158) de los bloques guardados, que se sirven con getcfilters, getcfheaders y getcfcheckpt.
+    indice_filtros: Arc<Mutex<IndiceFiltros>>,
+}
 
 /// Inicia la instancia del servidor donde el nodo recibirá conexiones entrantes de otros nodos.
-/// Crea un TcpListener que queda a la espera de nuevas conexiones.
-/// Cada nueva conexion se handlea en handlear_cliente().
+/// Las conexiones se aceptan y se leen en el event loop del nodo; un único thread
+/// atiende los mensajes de todos los clientes a medida que llegan, con handlear_cliente().
 pub fn iniciar_server(
     config: &Config,
     logger_sender: &Sender<String>,
@@ -37,150 +68,262 @@ pub fn iniciar_server(
     );
     let config_clone = config.clone();
     let logger_sender_listener = logger_sender.clone();
-    let client_conections: Arc<Mutex<HashMap<String, TcpStream>>> =
+    let client_conections: Arc<Mutex<HashMap<String, ConexionPeer>>> =
         Arc::new(Mutex::new(HashMap::new()));
     let client_connections_notif = Arc::clone(&client_conections);
+    let filtros_fee: Arc<Mutex<HashMap<String, u64>>> = Arc::new(Mutex::new(HashMap::new()));
+    let filtros_fee_notif = Arc::clone(&filtros_fee);
+    let txn_memory_notif = Arc::clone(&txn_memory_client);
+    let indice_filtros = Arc::new(Mutex::new(IndiceFiltros::default()));
+    let indice_filtros_notif = Arc::clone(&indice_filtros);
+    let headers_notif = Arc::clone(&headers);
+    let config_notif = config.clone();
 
     thread::spawn(move || -> Result<(), RustifyError> {
-        let headers_hash_height = Arc::new(Mutex::new(obtener_hash_height_headers(&headers)?));
-        let listener = TcpListener::bind(&config_clone.server_address)?;
+        let estado = EstadoServidor {
+            headers_hash_height: Arc::new(Mutex::new(obtener_hash_height_headers(&headers)?)),
+            config: config_clone,
+            logger_sender: logger_sender_listener,
+            headers,
+            txn_memory_client,
+            client_connections: client_conections,
+            filtros_fee,
+            indice_filtros,
+        };
+        let cant_filtros = estado
+            .indice_filtros
+            .lock()?
+            .actualizar(&estado.headers, &estado.config)?;
+        log_with_parameters(
+            Lvl::Info(Action::SERVER),
+            format!(
+                "Se indexaron los filtros compactos de {} bloques.",
+                cant_filtros
+            ),
+            &estado.logger_sender,
+        );
+        let listener = match TcpListener::bind(&estado.config.server_address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log(
+                    Lvl::Error(Action::SERVER),
+                    "No se pudo iniciar el servidor.",
+                    &estado.logger_sender,
+                );
+                return Err(e.into());
+            }
+        };
+        let (avisos_sender, avisos) = mpsc::channel();
+        let event_loop = event_loop(&estado.logger_sender)?;
+        match estado.config.transporte_v2 {
+            true => event_loop.escuchar_v2(listener, avisos_sender)?,
+            false => event_loop.escuchar(listener, avisos_sender)?,
+        }
         log(
             Lvl::Info(Action::SERVER),
             "Servidor iniciado",
-            &logger_sender_listener,
+            &estado.logger_sender,
         );
-        loop {
-            match listener.accept() {
-                Ok((socket, addr)) => {
-                    agregar_cliente_en_vector_conexiones(&client_conections, &socket, &addr)?;
-                    conectar_cliente(
-                        socket,
-                        addr,
-                        &config_clone,
-                        &logger_sender_listener,
-                        headers.clone(),
-                        headers_hash_height.clone(),
-                        txn_memory_client.clone(),
-                    )
-                }
-                Err(e) => {
-                    log(
-                        Lvl::Error(Action::SERVER),
-                        "No se pudo conectar al cliente.",
-                        &logger_sender_listener,
-                    );
-                    return Err(e.into());
-                }
-            }
+
+        let mut clientes: HashMap<usize, (ConexionPeer, EtapaCliente)> = HashMap::new();
+        for conexion in avisos {
+            handlear_cliente(conexion, &mut clientes, &estado)?;
         }
+        Ok(())
     });
 
     let logger_sender_notif = Sender::clone(logger_sender);
     thread::spawn(move || -> Result<(), RustifyError> {
-        envio_notificaciones_cliente(client_connections_notif, logger_sender_notif, recv_notif)?;
+        envio_notificaciones_cliente(
+            (client_connections_notif, filtros_fee_notif),
+            txn_memory_notif,
+            (headers_notif, indice_filtros_notif, config_notif),
+            logger_sender_notif,
+            recv_notif,
+        )?;
         Ok(())
     });
 }
 
-/// Establece la conexión con el cliente realizando un handshake.
-/// Se crea un thread por cada nuevo cliente.
-fn conectar_cliente(
-    socket: TcpStream,
-    addr: SocketAddr,
-    config: &Config,
-    logger_sender: &Sender<String>,
-    headers: Arc<Mutex<Vec<BlockHeader>>>,
-    headers_hash_height: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
-    txn_memory_client: Arc<Mutex<TrxServer>>,
-) {
-    let config_clone = config.clone();
-    let logger_sender_clone = logger_sender.clone();
-    thread::spawn(move || -> Result<(), RustifyError> {
-        let mut socket_clone = socket.try_clone()?;
+/// Handlea el aviso del event loop sobre un cliente: una conexión nueva, mensajes nuevos o una desconexión.
+/// Se contestan todos los mensajes que el cliente tenga encolados.
+fn handlear_cliente(
+    conexion: ConexionPeer,
+    clientes: &mut HashMap<usize, (ConexionPeer, EtapaCliente)>,
+    estado: &EstadoServidor,
+) -> Result<(), RustifyError> {
+    let id = conexion.id();
+    if let Entry::Vacant(cliente_nuevo) = clientes.entry(id) {
+        if conexion.cerrada() {
+            return Ok(());
+        }
+        if direccion_baneada(&conexion.addr()) {
+            log_with_parameters(
+                Lvl::Warning(Action::SERVER),
+                format!(
+                    "Se rechazó la conexión del cliente baneado {}.",
+                    conexion.addr()
+                ),
+                &estado.logger_sender,
+            );
+            conexion.cerrar();
+            return Ok(());
+        }
         log_with_parameters(
             Lvl::Info(Action::SERVER),
-            format!("Nuevo cliente con IP {}.", &addr.to_string()),
-            &logger_sender_clone,
+            format!("Nuevo cliente con IP {}.", conexion.addr()),
+            &estado.logger_sender,
         );
-        match recibir_handshake(&mut socket_clone, &config_clone, &logger_sender_clone) {
-            Ok(()) => {}
-            Err(e) => {
-                log(
-                    Lvl::Error(Action::SERVER),
-                    "No se pudo realizar el handshake con el cliente.",
-                    &logger_sender_clone,
-                );
-                return Err(e);
+        cliente_nuevo.insert((conexion, EtapaCliente::EsperandoVersion));
+    }
+
+    let Some((socket, etapa)) = clientes.get_mut(&id) else {
+        return Ok(());
+    };
+    let ip_cliente = socket.addr().to_string();
+    let resultado = loop {
+        match socket.try_recibir() {
+            Ok(Some(mensaje)) => {
+                if let Err(e) = handlear_peticion_cliente(socket, etapa, mensaje, estado) {
+                    break Err(e);
+                }
             }
-        };
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
 
-        // Mandar ping y si no lo contesta en X tiempo, dropear la conexión.
-        handlear_peticiones_cliente(
-            &mut socket_clone,
-            &logger_sender_clone,
-            headers,
-            headers_hash_height,
-            &addr.to_string(),
-            txn_memory_client,
-            &config_clone,
-        )?;
-        Ok(())
-    });
+    if let Err(e) = resultado {
+        if e.es_violacion_protocolo() {
+            log_with_parameters(
+                Lvl::Warning(Action::SERVER),
+                format!(
+                    "El cliente {} violó el protocolo ({}). Se lo desconecta.",
+                    ip_cliente,
+                    obtener_mensaje_personalizado(e.clone())
+                ),
+                &estado.logger_sender,
+            );
+            penalizar_nodo(&socket.addr(), &e, &estado.logger_sender);
+        } else {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!("Se desconectó el cliente {}.", ip_cliente),
+                &estado.logger_sender,
+            );
+        }
+        socket.cerrar();
+        clientes.remove(&id);
+        estado.client_connections.lock()?.remove(&ip_cliente);
+        estado.filtros_fee.lock()?.remove(&ip_cliente);
+    }
+    Ok(())
 }
 
-/// Recibe las peticiones del cliente y las maneja acordemente.
-fn handlear_peticiones_cliente(
-    socket: &mut TcpStream,
-    logger_sender: &Sender<String>,
-    headers: Arc<Mutex<Vec<BlockHeader>>>,
-    headers_hash_height: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
-    ip_cliente: &String,
-    txn_memory_client: Arc<Mutex<TrxServer>>,
-    config: &Config,
+/// Contesta un mensaje del cliente de acuerdo a la etapa en la que se encuentra:
+/// primero el handshake (version y verack), y luego los pedidos de headers y datos.
+fn handlear_peticion_cliente(
+    socket: &mut ConexionPeer,
+    etapa: &mut EtapaCliente,
+    mensaje: NetworkMessage,
+    estado: &EstadoServidor,
 ) -> Result<(), RustifyError> {
-    while let Ok((comando, message_header)) = leer_peticion_cliente(socket) {
-        match &comando as &str {
-            "getheaders\0\0" => {
-                log_with_parameters(
-                    Lvl::Info(Action::SERVER),
-                    format!("Recibido mensaje {} de cliente {}.", comando, ip_cliente),
-                    logger_sender,
-                );
-                recibir_getheaders(
-                    socket,
-                    logger_sender,
-                    message_header,
-                    &headers,
-                    &headers_hash_height,
-                )?;
-            }
-            "getdata\0\0\0\0\0" => {
-                recibir_getdata(
-                    &txn_memory_client,
-                    socket,
-                    message_header,
-                    ip_cliente,
-                    logger_sender,
-                    config,
-                )?;
-            }
-            _ => log_with_parameters(
+    let logger_sender = &estado.logger_sender;
+    let ip_cliente = socket.addr().to_string();
+    match (etapa.clone(), mensaje) {
+        (EtapaCliente::EsperandoVersion, NetworkMessage::Version(_)) => {
+            recibir_version(socket, &estado.config, logger_sender)?;
+            *etapa = EtapaCliente::EsperandoVerack;
+        }
+        (EtapaCliente::EsperandoVerack, NetworkMessage::Verack) => {
+            recibir_verack(socket, logger_sender)?;
+            *etapa = EtapaCliente::Conectado;
+            agregar_cliente_en_vector_conexiones(&estado.client_connections, socket)?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::GetHeaders(getheaders)) => {
+            log_with_parameters(
                 Lvl::Info(Action::SERVER),
-                format!("Mensaje {} ignorado.", comando),
+                format!("Recibido mensaje getheaders de cliente {}.", ip_cliente),
                 logger_sender,
-            ),
+            );
+            recibir_getheaders(
+                socket,
+                logger_sender,
+                getheaders,
+                &estado.headers,
+                &estado.headers_hash_height,
+            )?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::GetData(getdata)) => {
+            recibir_getdata(
+                &estado.txn_memory_client,
+                socket,
+                getdata,
+                &ip_cliente,
+                logger_sender,
+                &estado.config,
+            )?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::GetBlockTxn(getblocktxn)) => {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!("Recibido mensaje getblocktxn de cliente {}.", ip_cliente),
+                logger_sender,
+            );
+            recibir_getblocktxn(socket, getblocktxn, logger_sender, &estado.config)?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::GetCFilters(pedido)) => {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!("Recibido mensaje getcfilters de cliente {}.", ip_cliente),
+                logger_sender,
+            );
+            recibir_getcfilters(socket, pedido, fuentes_filtros(estado), logger_sender)?;
         }
+        (EtapaCliente::Conectado, NetworkMessage::GetCFHeaders(pedido)) => {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!("Recibido mensaje getcfheaders de cliente {}.", ip_cliente),
+                logger_sender,
+            );
+            recibir_getcfheaders(socket, pedido, fuentes_filtros(estado), logger_sender)?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::GetCFCheckpt(getcfcheckpt)) => {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!("Recibido mensaje getcfcheckpt de cliente {}.", ip_cliente),
+                logger_sender,
+            );
+            recibir_getcfcheckpt(socket, getcfcheckpt, fuentes_filtros(estado), logger_sender)?;
+        }
+        (EtapaCliente::Conectado, NetworkMessage::FeeFilter(fee)) => {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!(
+                    "El cliente {} pidió no recibir transacciones con fee menor a {} sat/kB.",
+                    ip_cliente, fee
+                ),
+                logger_sender,
+            );
+            estado.filtros_fee.lock()?.insert(ip_cliente, fee);
+        }
+        (_, NetworkMessage::Ping(nonce)) => pong(nonce, socket, logger_sender)?,
+        (_, otro) => log_with_parameters(
+            Lvl::Info(Action::SERVER),
+            format!("Mensaje {} ignorado.", otro.comando()),
+            logger_sender,
+        ),
     }
-
     Ok(())
 }
 
-/// Lee la petición del cliente y devuelve el header y nombre del mensaje recibido.
-fn leer_peticion_cliente(socket: &mut TcpStream) -> Result<(String, MessageHeader), RustifyError> {
-    let bytes_header_respuesta = read_from_node(socket, MESSAGE_HEADER_SIZE)?;
-    let message_header_respuesta = MessageHeader::from_bytes(&bytes_header_respuesta)?;
-    let comando = String::from_utf8((message_header_respuesta.command_name).to_vec())?;
-    Ok((comando, message_header_respuesta))
+/// Datos del servidor con los que se responden los pedidos de filtros compactos.
+fn fuentes_filtros(estado: &EstadoServidor) -> FuentesFiltros<'_> {
+    (
+        &estado.headers,
+        &estado.headers_hash_height,
+        &estado.indice_filtros,
+    )
 }
 
 /// Genera un HashMap que tiene como clave al hash del BlockHeader y como valor a la height de ese BlockHeader.
@@ -203,16 +346,15 @@ fn obtener_hash_height_headers(
 /// Agrega en el vector de clientes conectados a uno nuevo, siempre y cuando no se encontrara
 /// ya en el vector
 fn agregar_cliente_en_vector_conexiones(
-    client_connections: &Arc<Mutex<HashMap<String, TcpStream>>>,
-    socket: &TcpStream,
-    addr: &SocketAddr,
+    client_connections: &Arc<Mutex<HashMap<String, ConexionPeer>>>,
+    socket: &ConexionPeer,
 ) -> Result<(), RustifyError> {
     let mut vector_clientes = client_connections.lock()?;
 
-    match vector_clientes.get(&addr.to_string()) {
+    match vector_clientes.get(&socket.addr().to_string()) {
         Some(_) => {}
         None => {
-            vector_clientes.insert(addr.to_string(), socket.try_clone()?);
+            vector_clientes.insert(socket.addr().to_string(), socket.clone());
         }
     };
     Ok(())
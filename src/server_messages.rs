@@ -1,95 +1,87 @@
 use crate::{
     block_header::BlockHeader,
-    compactsize::CompactSize,
+    compact_block::{sendcmpct, BlockTxn, CompactBlock, GetBlockTxn, MSG_CMPCT_BLOCK},
+    compact_filter::{
+        CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, IndiceFiltros, PedidoFiltros, FILTRO_BASICO,
+        INTERVALO_CHECKPOINTS, MAX_CFHEADERS_POR_MENSAJE, MAX_CFILTERS_POR_PEDIDO,
+    },
     config::Config,
     errors::RustifyError,
+    event_loop::ConexionPeer,
     getheaders::GetHeadersMessage,
     inv::Inv,
     logger::{log, log_re_err, log_with_parameters, Action, Lvl},
-    message_handler::handle_specific_message,
-    message_header::MessageHeader,
-    node::{read_from_node, send_inv, write_to_node},
+    network_message::{escribir_mensaje, NetworkMessage},
     serialized_block::SerializedBlock,
     server_notification::find_txn_in_memory,
     txn::Txn,
-    version::VersionMessage,
+    utxo::utxos_completas,
+    version::{VersionMessage, NODE_COMPACT_FILTERS},
     wallet_txn::broadcast_txn,
 };
 use bitcoin_hashes::{sha256d, Hash};
+use rand::RngCore;
 use std::{
     collections::HashMap,
     fs::File,
     io::Read,
-    net::TcpStream,
     sync::{mpsc::Sender, Arc, Mutex},
 };
-type TrxServer = Vec<(String, Txn)>;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
+/// Headers de la cadena, sus alturas por hash e índice de filtros compactos.
+pub type FuentesFiltros<'a> = (
+    &'a Arc<Mutex<Vec<BlockHeader>>>,
+    &'a Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+    &'a Arc<Mutex<IndiceFiltros>>,
+);
 
 const MAX_HEADERS_POR_MENSAJE: usize = 2000;
 const MSG_TX: usize = 1;
 const MSG_BLOCK: usize = 2;
+const MSG_CMPCT_BLOCK_TIPO: usize = MSG_CMPCT_BLOCK as usize;
 const LIM_MINIMO_INVENTARIO: usize = 5;
 
-/// Recibe los mensajes version y verack, y los contesta.
-/// En caso afirmativo, el handshake queda establecido.
-pub fn recibir_handshake(
-    socket: &mut TcpStream,
+/// Contesta el mensaje version recibido del cliente con su propio mensaje version.
+/// El servidor anuncia que sirve filtros compactos de bloques (BIP157) solamente si puede armarlos,
+/// es decir si descarga los bloques completos desde el génesis (ver IndiceFiltros).
+pub fn recibir_version(
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
-    recibir_version(socket, config, logger_sender)?;
-    recibir_verack(socket, logger_sender)?;
+    let mut version = VersionMessage::new(socket.peer_addr(), socket.local_addr(), config);
+    if utxos_completas(config) {
+        version.services |= NODE_COMPACT_FILTERS;
+    }
+    escribir_mensaje(socket, &NetworkMessage::Version(version))?;
     log(
         Lvl::Info(Action::SERVER),
-        "Se realizó el handshake con el nodo. Conexión establecida",
+        "Enviado mensaje version",
         logger_sender,
     );
+
     Ok(())
 }
 
-/// Recibe el mensaje version, y contesta con su propio mensaje version.
-fn recibir_version(
-    socket: &mut TcpStream,
-    config: &Config,
+/// Contesta el mensaje verack recibido del cliente con su propio mensaje verack.
+/// En ese momento el handshake queda establecido, y se le indica al cliente con sendcmpct
+/// que puede pedir bloques compactos (BIP152). Los bloques nuevos se le siguen anunciando con inv.
+pub fn recibir_verack(
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
-    let version_recibido_bytes =
-        handle_specific_message(socket, "version\0\0\0\0\0".to_string(), logger_sender)?;
-    let _version_recibido = VersionMessage::from_bytes(&version_recibido_bytes)?;
-
-    let version = VersionMessage::new(socket.peer_addr()?, socket.local_addr()?, config);
-    let version_bytes = version.as_bytes(&config.user_agent_rustify);
-
-    let version_header = MessageHeader::new("version".to_string(), &version_bytes);
-    let version_header_bytes = version_header.as_bytes();
-
-    write_to_node(socket, &version_header_bytes, &version_bytes)?;
+    escribir_mensaje(socket, &NetworkMessage::Verack)?;
     log(
         Lvl::Info(Action::SERVER),
-        "Enviado mensaje version",
+        "Enviado mensaje verack.",
         logger_sender,
     );
-
-    Ok(())
-}
-
-/// Recibe el mensaje verack, y contesta con su propio mensaje verack.
-fn recibir_verack(
-    socket: &mut TcpStream,
-    logger_sender: &Sender<String>,
-) -> Result<(), RustifyError> {
-    let _verack_header_recibido_bytes =
-        handle_specific_message(socket, "verack\0\0\0\0\0\0".to_string(), logger_sender)?;
-
-    let verack_header = MessageHeader::new("verack".to_string(), &[]);
-    let verack_header_bytes = verack_header.as_bytes();
-
-    write_to_node(socket, &verack_header_bytes, &[])?;
     log(
         Lvl::Info(Action::SERVER),
-        "Enviado mensaje verack.",
+        "Se realizó el handshake con el nodo. Conexión establecida",
         logger_sender,
     );
+    sendcmpct(socket, false, logger_sender)?;
     Ok(())
 }
 
@@ -97,21 +89,16 @@ fn recibir_verack(
 /// Se envian todos los headers subsiguientes al starting, con un maximo de 2000 headers por mensaje.
 /// En caso de que no se haya encontrado ningún starting hash, se envía el mensaje headers vacío (con count 0).
 pub fn recibir_getheaders(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
-    message_header: MessageHeader,
+    getheaders_recibido: GetHeadersMessage,
     headers: &Arc<Mutex<Vec<BlockHeader>>>,
     headers_hash_height: &Arc<Mutex<HashMap<Vec<u8>, usize>>>,
 ) -> Result<(), RustifyError> {
-    let getheaders_recibido_bytes = read_from_node(socket, message_header.payload_size as usize)?;
-    let getheaders_recibido = GetHeadersMessage::from_bytes(&getheaders_recibido_bytes)?;
-
-    actualizar_headers_hash_height(headers_hash_height, headers)?;
-
-    let mut headers_cliente_bytes = vec![];
+    let mut headers_cliente = vec![];
     let headers_vec = headers.lock()?;
-    let headers_hash_height_map = headers_hash_height.lock()?;
-    let mut header_count = 0_u64;
+    let mut headers_hash_height_map = headers_hash_height.lock()?;
+    actualizar_headers_hash_height(&mut headers_hash_height_map, &headers_vec);
     for starting_hash in getheaders_recibido.starting_hashes {
         match headers_hash_height_map.get(&starting_hash) {
             Some(height) => {
@@ -123,12 +110,12 @@ pub fn recibir_getheaders(
                     && header_index < max_headers_index
                     && header_hash != getheaders_recibido.stopping_hash
                 {
-                    let header_bytes = headers_vec[header_index].as_bytes();
-                    header_hash = sha256d::Hash::hash(&header_bytes).to_byte_array().to_vec();
-                    headers_cliente_bytes.append(&mut header_bytes.to_vec());
-                    headers_cliente_bytes.append(&mut vec![0x00]); // Transaction count
+                    let header = &headers_vec[header_index];
+                    header_hash = sha256d::Hash::hash(&header.as_bytes())
+                        .to_byte_array()
+                        .to_vec();
+                    headers_cliente.push(header.clone());
                     header_index += 1;
-                    header_count += 1;
                 }
                 break;
             }
@@ -136,26 +123,18 @@ pub fn recibir_getheaders(
         }
     }
 
-    enviar_headers(socket, logger_sender, headers_cliente_bytes, header_count)?;
+    enviar_headers(socket, logger_sender, headers_cliente)?;
 
     Ok(())
 }
 
 /// Envía por el socket los headers pedidos al nodo cliente, incluyendo la cantidad.
 fn enviar_headers(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
-    mut headers_cliente_bytes: Vec<u8>,
-    header_count: u64,
+    headers_cliente: Vec<BlockHeader>,
 ) -> Result<(), RustifyError> {
-    let mut header_count_bytes = CompactSize::new(header_count).as_bytes();
-    header_count_bytes.append(&mut headers_cliente_bytes);
-
-    let headers_message_bytes = header_count_bytes;
-    let headers_header = MessageHeader::new("headers".to_string(), &headers_message_bytes);
-    let headers_header_bytes = headers_header.as_bytes();
-
-    write_to_node(socket, &headers_header_bytes, &headers_message_bytes)?;
+    escribir_mensaje(socket, &NetworkMessage::Headers(headers_cliente))?;
     log(
         Lvl::Info(Action::SERVER),
         "Enviado mensaje headers.",
@@ -164,42 +143,48 @@ fn enviar_headers(
     Ok(())
 }
 
-/// Actualiza el HashMap de headers, en el caso de que hayan llegado nuevos headers por block broadcasting.
+/// Actualiza el HashMap de headers con la cadena activa, en el caso de que hayan llegado nuevos headers
+/// por block broadcasting o de que la cadena se haya reorganizado.
+/// Se busca, desde la punta indexada hacia atrás, la última altura cuyo header sigue en la cadena: los hashes
+/// de las alturas posteriores son de la rama abandonada y se quitan, y se indexan los de la cadena activa.
 fn actualizar_headers_hash_height(
-    headers_hash_height: &Arc<Mutex<HashMap<Vec<u8>, usize>>>,
-    headers: &Arc<Mutex<Vec<BlockHeader>>>,
-) -> Result<(), RustifyError> {
-    let headers_vec = headers.lock()?;
-    let mut headers_hash_height_map = headers_hash_height.lock()?;
-    let len_hash = headers_hash_height_map.len();
-
-    if headers_vec.len() > len_hash {
-        let slice_headers = &headers_vec[len_hash - 1..];
-        for (index, header) in slice_headers.iter().enumerate() {
-            let header_hash = sha256d::Hash::hash(&header.as_bytes())
-                .to_byte_array()
-                .to_vec();
-            headers_hash_height_map.insert(header_hash, len_hash - 1 + index);
+    headers_hash_height_map: &mut HashMap<Vec<u8>, usize>,
+    headers_vec: &[BlockHeader],
+) {
+    let mut altura_comun = headers_vec.len().min(headers_hash_height_map.len());
+    while altura_comun > 0 {
+        let header_hash = hash_header(&headers_vec[altura_comun - 1]);
+        if headers_hash_height_map.get(&header_hash) == Some(&(altura_comun - 1)) {
+            break;
         }
+        altura_comun -= 1;
     }
+    if headers_hash_height_map.len() > altura_comun {
+        headers_hash_height_map.retain(|_, height| *height < altura_comun);
+    }
+    for (height, header) in headers_vec.iter().enumerate().skip(altura_comun) {
+        headers_hash_height_map.insert(hash_header(header), height);
+    }
+}
 
-    Ok(())
+fn hash_header(header: &BlockHeader) -> Vec<u8> {
+    sha256d::Hash::hash(&header.as_bytes())
+        .to_byte_array()
+        .to_vec()
 }
 
 /// Handlea los mensaje getdata recibidos por el cliente y los separa en
 /// funcion de si son pedidos de bloques o pedidos de transacciones.
 pub fn recibir_getdata(
     txn_memory_client: &Arc<Mutex<TrxServer>>,
-    socket: &mut TcpStream,
-    message_header: MessageHeader,
+    socket: &mut ConexionPeer,
+    getdata: Inv,
     ip_cliente: &String,
     logger_sender: &Sender<String>,
     config: &Config,
 ) -> Result<(), RustifyError> {
-    let getdata_bytes = read_from_node(socket, message_header.payload_size as usize)?;
-    let getdata = Inv::from_bytes(&getdata_bytes)?;
     for inventory in getdata.inventories {
-        let tipo = inventory[0] as usize;
+        let tipo = *inventory.first().ok_or(RustifyError::MensajeMalformado)? as usize;
         match tipo {
             MSG_BLOCK => {
                 log_with_parameters(
@@ -216,6 +201,24 @@ pub fn recibir_getdata(
                     Err(e) => log_re_err(Action::SERVER, e, logger_sender),
                 };
             }
+            MSG_CMPCT_BLOCK_TIPO => {
+                log_with_parameters(
+                    Lvl::Info(Action::SERVER),
+                    format!(
+                        "Recibido pedido de bloque compacto del cliente {}.",
+                        ip_cliente
+                    ),
+                    logger_sender,
+                );
+                match respond_getdata_cmpctblock(inventory, socket, logger_sender, config) {
+                    Ok(_) => log(
+                        Lvl::Info(Action::SERVER),
+                        "Se respondió exitosamente el pedido de bloque compacto del cliente.",
+                        logger_sender,
+                    ),
+                    Err(e) => log_re_err(Action::SERVER, e, logger_sender),
+                };
+            }
             MSG_TX => {
                 log_with_parameters(
                     Lvl::Info(Action::SERVER),
@@ -245,7 +248,7 @@ pub fn recibir_getdata(
 /// se lo enviara al cliente, caso contrario, se enviara un notfound
 fn respond_getdata_block(
     inventory: Vec<u8>,
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
     config: &Config,
 ) -> Result<(), RustifyError> {
@@ -253,8 +256,255 @@ fn respond_getdata_block(
     if inventory.len() < LIM_MINIMO_INVENTARIO {
         return Err(RustifyError::NoSeEncontroBloquePedidoPorCliente);
     }
-    let possible_block = inventory[4..].to_vec();
-    let filename = SerializedBlock::obtain_blockname_from_blockhash(possible_block);
+    let buffer = match leer_bloque_en_disco(inventory[4..].to_vec(), config)? {
+        Some(buffer) => buffer,
+        None => {
+            send_not_found(socket, inventory, logger_sender);
+            return Err(RustifyError::NoSeEncontroBloquePedidoPorCliente);
+        }
+    };
+
+    send_block(socket, buffer)?;
+
+    Ok(())
+}
+
+/// Responde al pedido de bloque compacto del cliente (BIP152), armándolo a partir del bloque en disco.
+/// Solamente se envía completa la coinbase: el resto de las transacciones las reconstruye el cliente
+/// o las pide con getblocktxn. Si el bloque no esta en disco, se enviara un notfound
+fn respond_getdata_cmpctblock(
+    inventory: Vec<u8>,
+    socket: &mut ConexionPeer,
+    logger_sender: &Sender<String>,
+    config: &Config,
+) -> Result<(), RustifyError> {
+    if inventory.len() < LIM_MINIMO_INVENTARIO {
+        return Err(RustifyError::NoSeEncontroBloquePedidoPorCliente);
+    }
+    let buffer = match leer_bloque_en_disco(inventory[4..].to_vec(), config)? {
+        Some(buffer) => buffer,
+        None => {
+            send_not_found(socket, inventory, logger_sender);
+            return Err(RustifyError::NoSeEncontroBloquePedidoPorCliente);
+        }
+    };
+    let bloque = SerializedBlock::from_bytes(&buffer)?;
+    let compacto = CompactBlock::desde_bloque(&bloque, rand::thread_rng().next_u64());
+    escribir_mensaje(socket, &NetworkMessage::CmpctBlock(compacto.as_bytes()))
+}
+
+/// Responde al mensaje getblocktxn con las transacciones pedidas del bloque, en un mensaje blocktxn.
+/// Si el bloque no esta en disco, se enviara un notfound. Si se piden posiciones que el bloque
+/// no tiene, se devuelve error para desconectar al cliente.
+pub fn recibir_getblocktxn(
+    socket: &mut ConexionPeer,
+    getblocktxn: GetBlockTxn,
+    logger_sender: &Sender<String>,
+    config: &Config,
+) -> Result<(), RustifyError> {
+    let buffer = match leer_bloque_en_disco(getblocktxn.block_hash.to_vec(), config)? {
+        Some(buffer) => buffer,
+        None => {
+            let mut inventory = (MSG_BLOCK as u32).to_le_bytes().to_vec();
+            inventory.extend_from_slice(&getblocktxn.block_hash);
+            send_not_found(socket, inventory, logger_sender);
+            return Ok(());
+        }
+    };
+    let bloque = SerializedBlock::from_bytes(&buffer)?;
+    let mut txns = vec![];
+    for indice in getblocktxn.indices {
+        match bloque.txns.get(indice) {
+            Some(txn) => txns.push(txn.clone()),
+            None => return Err(RustifyError::MensajeMalformado),
+        }
+    }
+    let blocktxn = BlockTxn {
+        block_hash: getblocktxn.block_hash,
+        txns,
+    };
+    escribir_mensaje(socket, &NetworkMessage::BlockTxn(blocktxn.as_bytes()))?;
+    log(
+        Lvl::Info(Action::SERVER),
+        "Enviado mensaje blocktxn.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Responde al mensaje getcfilters con un mensaje cfilter por cada bloque del rango pedido.
+pub fn recibir_getcfilters(
+    socket: &mut ConexionPeer,
+    pedido: PedidoFiltros,
+    fuentes: FuentesFiltros,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    let (_, _, indice_filtros) = fuentes;
+    let indice = indice_filtros.lock()?;
+    let (desde, hasta) = match rango_filtros(&pedido, MAX_CFILTERS_POR_PEDIDO, fuentes, &indice)? {
+        Some(rango) => rango,
+        None => return pedido_filtros_ignorado("getcfilters", logger_sender),
+    };
+    for altura in desde..=hasta {
+        let cfilter = CFilter {
+            tipo_filtro: FILTRO_BASICO,
+            block_hash: indice.hash_bloque(altura).ok_or(RustifyError::NotFound)?,
+            filtro: indice
+                .filtro(altura)
+                .ok_or(RustifyError::NotFound)?
+                .to_vec(),
+        };
+        escribir_mensaje(socket, &NetworkMessage::CFilter(cfilter))?;
+    }
+    log_with_parameters(
+        Lvl::Info(Action::SERVER),
+        format!("Enviados {} mensajes cfilter.", hasta - desde + 1),
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Responde al mensaje getcfheaders con los hashes de los filtros del rango pedido y el header
+/// del filtro anterior al rango, en un mensaje cfheaders.
+pub fn recibir_getcfheaders(
+    socket: &mut ConexionPeer,
+    pedido: PedidoFiltros,
+    fuentes: FuentesFiltros,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    let (_, _, indice_filtros) = fuentes;
+    let indice = indice_filtros.lock()?;
+    let (desde, hasta) = match rango_filtros(&pedido, MAX_CFHEADERS_POR_MENSAJE, fuentes, &indice)?
+    {
+        Some(rango) => rango,
+        None => return pedido_filtros_ignorado("getcfheaders", logger_sender),
+    };
+    let mut hashes_filtros = vec![];
+    for altura in desde..=hasta {
+        let filtro = indice.filtro(altura).ok_or(RustifyError::NotFound)?;
+        hashes_filtros.push(sha256d::Hash::hash(filtro).to_byte_array());
+    }
+    let cfheaders = CFHeaders {
+        tipo_filtro: FILTRO_BASICO,
+        hash_final: pedido.hash_final,
+        header_filtro_previo: indice.header_previo(desde).ok_or(RustifyError::NotFound)?,
+        hashes_filtros,
+    };
+    escribir_mensaje(socket, &NetworkMessage::CFHeaders(cfheaders))?;
+    log(
+        Lvl::Info(Action::SERVER),
+        "Enviado mensaje cfheaders.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Responde al mensaje getcfcheckpt con los headers de los filtros cada INTERVALO_CHECKPOINTS bloques,
+/// hasta el bloque pedido, en un mensaje cfcheckpt.
+pub fn recibir_getcfcheckpt(
+    socket: &mut ConexionPeer,
+    getcfcheckpt: GetCFCheckpt,
+    fuentes: FuentesFiltros,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    if getcfcheckpt.tipo_filtro != FILTRO_BASICO {
+        return Err(RustifyError::MensajeMalformado);
+    }
+    let altura_final = match altura_de_hash(&getcfcheckpt.hash_final, fuentes)? {
+        Some(altura) => altura,
+        None => return pedido_filtros_ignorado("getcfcheckpt", logger_sender),
+    };
+    // Si el bloque pedido es anterior al primer checkpoint, la respuesta no tiene headers.
+    let mut headers_filtros = vec![];
+    if altura_final >= INTERVALO_CHECKPOINTS {
+        let pedido = PedidoFiltros {
+            tipo_filtro: getcfcheckpt.tipo_filtro,
+            altura_inicial: INTERVALO_CHECKPOINTS as u32,
+            hash_final: getcfcheckpt.hash_final,
+        };
+        let (_, _, indice_filtros) = fuentes;
+        let indice = indice_filtros.lock()?;
+        let (desde, hasta) = match rango_filtros(&pedido, usize::MAX, fuentes, &indice)? {
+            Some(rango) => rango,
+            None => return pedido_filtros_ignorado("getcfcheckpt", logger_sender),
+        };
+        for altura in (desde..=hasta).step_by(INTERVALO_CHECKPOINTS) {
+            headers_filtros.push(indice.header_filtro(altura).ok_or(RustifyError::NotFound)?);
+        }
+    }
+    let cfcheckpt = CFCheckpt {
+        tipo_filtro: FILTRO_BASICO,
+        hash_final: getcfcheckpt.hash_final,
+        headers_filtros,
+    };
+    escribir_mensaje(socket, &NetworkMessage::CFCheckpt(cfcheckpt))?;
+    log(
+        Lvl::Info(Action::SERVER),
+        "Enviado mensaje cfcheckpt.",
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Valida el rango de un pedido de filtros y devuelve las alturas inicial y final (inclusive).
+/// Un tipo de filtro desconocido o un rango invertido o mayor al máximo es un pedido malformado.
+/// Si el hash final no es de la cadena o los filtros del rango no están indexados para ese bloque
+/// (por ejemplo, porque el índice todavía no se actualizó después de una reorganización), devuelve None.
+fn rango_filtros(
+    pedido: &PedidoFiltros,
+    max_bloques: usize,
+    fuentes: FuentesFiltros,
+    indice: &IndiceFiltros,
+) -> Result<Option<(usize, usize)>, RustifyError> {
+    if pedido.tipo_filtro != FILTRO_BASICO {
+        return Err(RustifyError::MensajeMalformado);
+    }
+    let hasta = match altura_de_hash(&pedido.hash_final, fuentes)? {
+        Some(altura) => altura,
+        None => return Ok(None),
+    };
+    let desde = pedido.altura_inicial as usize;
+    if desde > hasta || hasta - desde >= max_bloques {
+        return Err(RustifyError::MensajeMalformado);
+    }
+    let indexado =
+        indice.cubre(desde, hasta) && indice.hash_bloque(hasta) == Some(pedido.hash_final);
+    Ok(indexado.then_some((desde, hasta)))
+}
+
+/// Devuelve la altura del bloque con el hash indicado, si es de la cadena local.
+fn altura_de_hash(
+    block_hash: &[u8; 32],
+    (headers, headers_hash_height, _): FuentesFiltros,
+) -> Result<Option<usize>, RustifyError> {
+    let headers_vec = headers.lock()?;
+    let mut headers_hash_height_map = headers_hash_height.lock()?;
+    actualizar_headers_hash_height(&mut headers_hash_height_map, &headers_vec);
+    Ok(headers_hash_height_map.get(block_hash.as_slice()).copied())
+}
+
+fn pedido_filtros_ignorado(
+    comando: &str,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    log_with_parameters(
+        Lvl::Info(Action::SERVER),
+        format!(
+            "Mensaje {} ignorado: los filtros pedidos no están indexados.",
+            comando
+        ),
+        logger_sender,
+    );
+    Ok(())
+}
+
+/// Lee del disco el bloque con el hash indicado (en el orden del inventario).
+/// Devuelve None si el bloque no esta en disco.
+fn leer_bloque_en_disco(
+    block_hash: Vec<u8>,
+    config: &Config,
+) -> Result<Option<Vec<u8>>, RustifyError> {
+    let filename = SerializedBlock::obtain_blockname_from_blockhash(block_hash);
     let path = format!("{}/{}.txt", config.blocks_path, filename);
 
     let mut archivo_bloque = match File::options()
@@ -264,21 +514,12 @@ fn respond_getdata_block(
         .open(path)
     {
         Ok(block) => block,
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                send_not_found(socket, inventory, logger_sender);
-                return Err(RustifyError::NoSeEncontroBloquePedidoPorCliente);
-            } else {
-                return Ok(());
-            }
-        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
     };
     let mut buffer = Vec::<u8>::new();
     archivo_bloque.read_to_end(&mut buffer)?;
-
-    send_block(socket, buffer)?;
-
-    Ok(())
+    Ok(Some(buffer))
 }
 
 /// Responde al pedido de transaccion del cliente. Si la transaccion esta guardada en
@@ -286,7 +527,7 @@ fn respond_getdata_block(
 /// se la enviara al cliente, caso contrario, se enviara un notfound
 fn respond_getdata_txn(
     inventory: Vec<u8>,
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     logger_sender: &Sender<String>,
     txn_memory_client: &Arc<Mutex<TrxServer>>,
 ) -> Result<(), RustifyError> {
@@ -319,9 +560,9 @@ fn respond_getdata_txn(
 /// Envia al nodo cliente el mensaje de tipo Not Found
 /// En esta solución se propone el envio de un unico elemento
 /// en el inventario de este mensaje
-fn send_not_found(socket: &mut TcpStream, inventory: Vec<u8>, logger_sender: &Sender<String>) {
+fn send_not_found(socket: &mut ConexionPeer, inventory: Vec<u8>, logger_sender: &Sender<String>) {
     let inv = Inv::new(1, MSG_BLOCK as u32, vec![inventory]);
-    if send_inv("notfound".to_owned(), socket, &inv).is_ok() {
+    if escribir_mensaje(socket, &NetworkMessage::NotFound(inv)).is_ok() {
         log(
             Lvl::Info(Action::SERVER),
             "Se envió al cliente el mensaje notfound",
@@ -331,9 +572,51 @@ fn send_not_found(socket: &mut TcpStream, inventory: Vec<u8>, logger_sender: &Se
 }
 
 /// Envia al nodo cliente un bloque previamente solicitado
-fn send_block(socket: &mut TcpStream, block_message_bytes: Vec<u8>) -> Result<(), RustifyError> {
-    let block_message_header = MessageHeader::new("block".to_owned(), &block_message_bytes);
-    let block_message_header_bytes = block_message_header.as_bytes();
-    write_to_node(socket, &block_message_header_bytes, &block_message_bytes)?;
-    Ok(())
+fn send_block(socket: &mut ConexionPeer, block_message_bytes: Vec<u8>) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::Block(block_message_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cadena_test(desde: &BlockHeader, cant: usize, nonce: u32) -> Vec<BlockHeader> {
+        let mut cadena = vec![];
+        let mut previo = desde.clone();
+        for _ in 0..cant {
+            let mut header = previo.clone();
+            header.previous_block_header_hash =
+                sha256d::Hash::hash(&previo.as_bytes()).to_byte_array();
+            header.nonce = nonce;
+            cadena.push(header.clone());
+            previo = header;
+        }
+        cadena
+    }
+
+    /// Después de una reorganización de la misma longitud, los hashes de la rama abandonada
+    /// se quitan del mapa y se indexan los de la nueva rama en sus alturas.
+    #[test]
+    fn test_actualizar_headers_hash_height_despues_de_reorganizacion() -> Result<(), RustifyError> {
+        let genesis = BlockHeader::from_bytes(&crate::red::parametros_red().genesis_header)?;
+        let comun = [vec![genesis.clone()], cadena_test(&genesis, 2, 1)].concat();
+        let vieja = [comun.clone(), cadena_test(&comun[2], 2, 2)].concat();
+        let nueva = [comun.clone(), cadena_test(&comun[2], 2, 3)].concat();
+
+        let mut mapa = HashMap::new();
+        actualizar_headers_hash_height(&mut mapa, &vieja);
+        assert_eq!(mapa.len(), vieja.len());
+
+        actualizar_headers_hash_height(&mut mapa, &nueva);
+        assert_eq!(mapa.len(), nueva.len());
+        for (height, header) in nueva.iter().enumerate() {
+            assert_eq!(mapa.get(&hash_header(header)), Some(&height));
+        }
+        assert_eq!(mapa.get(&hash_header(&vieja[3])), None);
+
+        // Si la cadena se acorta, se quitan las alturas que ya no están.
+        actualizar_headers_hash_height(&mut mapa, &comun);
+        assert_eq!(mapa.len(), comun.len());
+        Ok(())
+    }
 }
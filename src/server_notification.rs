@@ -1,36 +1,64 @@
 use std::{
     collections::HashMap,
-    net::TcpStream,
     sync::{mpsc::Sender, Arc, Mutex},
 };
 
 use crate::{
+    block_header::BlockHeader,
+    compact_filter::IndiceFiltros,
+    compactsize::CompactSize,
     config::Config,
     errors::RustifyError,
+    event_loop::ConexionPeer,
     inv::Inv,
-    logger::{log, log_with_parameters, Action, Lvl},
-    node::send_inv,
+    logger::{log, log_re_err, log_with_parameters, Action, Lvl},
+    network_message::{escribir_mensaje, NetworkMessage},
+    tx_validation::sumar_monto,
     txn::Txn,
+    utxo::SetUtxos,
 };
 
-type TrxServer = Vec<(String, Txn)>;
+type TrxServer = Vec<(String, Txn, Option<u64>)>;
+type ConexionesClientes = (
+    Arc<Mutex<HashMap<String, ConexionPeer>>>,
+    Arc<Mutex<HashMap<String, u64>>>,
+);
+type FiltrosCadena = (
+    Arc<Mutex<Vec<BlockHeader>>>,
+    Arc<Mutex<IndiceFiltros>>,
+    Config,
+);
+
+const MSG_TX: u32 = 1;
+const MSG_BLOCK: u32 = 2;
 
 /// Se genera un nuevo proceso (uno para todos los clientes) para transmitir
 /// Invs que se encuentren en el channel de notificaciones, recibiendo desde el listener.
 /// De ocurrir algun error (entre los que se incluye tambien que se haya caido la conexion del cliente)
-/// el mismo sera retirado del vector de conexiones, y ya no se le notificara mas nada
+/// el mismo sera retirado del vector de conexiones, y ya no se le notificara mas nada.
+/// A cada cliente solamente se le anuncian las transacciones con fee mayor o igual a la de su feefilter (BIP133).
+/// Cuando se anuncian bloques, que el listener ya conectó a la cadena, se actualiza el índice de filtros compactos.
 pub fn envio_notificaciones_cliente(
-    client_connections: Arc<Mutex<HashMap<String, TcpStream>>>,
+    conexiones: ConexionesClientes,
+    txn_memory_server: Arc<Mutex<TrxServer>>,
+    filtros_cadena: FiltrosCadena,
     logger_sender: Sender<String>,
     recv_notif: std::sync::mpsc::Receiver<Inv>,
 ) -> Result<(), RustifyError> {
+    let (client_connections, filtros_fee) = conexiones;
     for inv in recv_notif {
+        let filtros = filtros_fee.lock()?.clone();
         let mut conexiones_cliente = client_connections.lock()?;
 
         let mut clientes_caidos = vec![];
 
         for (addr, socket) in conexiones_cliente.iter_mut() {
-            match send_inv("inv".to_owned(), socket, &inv) {
+            let fee_cliente = filtros.get(addr).copied().unwrap_or(0);
+            let inv_cliente = filtrar_por_fee(&inv, fee_cliente, &txn_memory_server)?;
+            if inv_cliente.inventories.is_empty() {
+                continue;
+            }
+            match escribir_mensaje(socket, &NetworkMessage::Inv(inv_cliente)) {
                 Ok(_) => {
                     log(
                         Lvl::Info(Action::SERVER),
@@ -50,30 +78,169 @@ pub fn envio_notificaciones_cliente(
             }
         }
 
-        for addr in clientes_caidos {
-            conexiones_cliente.remove(&addr);
+        for addr in &clientes_caidos {
+            conexiones_cliente.remove(addr);
+        }
+        drop(conexiones_cliente);
+        let mut filtros = filtros_fee.lock()?;
+        for addr in &clientes_caidos {
+            filtros.remove(addr);
+        }
+        drop(filtros);
+
+        if inv
+            .inventories
+            .iter()
+            .any(|inventory| inventory.get(0..4) == Some(&MSG_BLOCK.to_le_bytes()[..]))
+        {
+            if let Err(e) = actualizar_indice_filtros(&filtros_cadena, &logger_sender) {
+                log_re_err(Action::SERVER, e, &logger_sender);
+            }
         }
     }
 
     Ok(())
 }
 
+/// Agrega al índice de filtros compactos los bloques conectados a la cadena.
+fn actualizar_indice_filtros(
+    (headers, indice_filtros, config): &FiltrosCadena,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    let cant_filtros = indice_filtros.lock()?.actualizar(headers, config)?;
+    if cant_filtros > 0 {
+        log_with_parameters(
+            Lvl::Info(Action::SERVER),
+            format!(
+                "Se indexaron los filtros compactos de {} bloques.",
+                cant_filtros
+            ),
+            logger_sender,
+        );
+    }
+    Ok(())
+}
+
+/// Devuelve el inv sin las transacciones cuya fee es menor a la indicada.
+/// Las transacciones cuya fee no se pudo calcular al guardarlas (ver fee_rate) se anuncian igual, ya que podrían
+/// pagar más que la fee pedida. Las que no están en memoria no se anuncian, ya que el cliente no podría pedirlas.
+fn filtrar_por_fee(
+    inv: &Inv,
+    fee_minima: u64,
+    txn_memory_server: &Arc<Mutex<TrxServer>>,
+) -> Result<Inv, RustifyError> {
+    if fee_minima == 0 {
+        return Ok(inv.clone());
+    }
+    let txn_memory = txn_memory_server.lock()?;
+    let inventories: Vec<Vec<u8>> = inv
+        .inventories
+        .iter()
+        .filter(|inventory| {
+            if inventory.len() < 4 || inventory[0..4] != MSG_TX.to_le_bytes() {
+                return true;
+            }
+            let txid = Txn::obtain_txid_from_inventory(inventory[4..].to_vec());
+            match txn_memory.iter().find(|(id, _, _)| *id == txid) {
+                Some((_, _, Some(fee))) => *fee >= fee_minima,
+                Some((_, _, None)) => true,
+                None => false,
+            }
+        })
+        .cloned()
+        .collect();
+    Ok(Inv {
+        count: CompactSize::new(inventories.len() as u64),
+        inventories,
+    })
+}
+
+/// Fee de la transacción en satoshis por kilobyte.
+/// Cada output que gasta se busca en el set de UTXOs y, si no está, entre las transacciones en memoria.
+/// Devuelve None si alguno no se encuentra: el set no tiene los outputs de los bloques anteriores
+/// a la fecha de inicio de la descarga, ni los de los bloques que no se descargan (modos SPV y de filtros).
+/// Como la transacción todavía no se validó, también devuelve None si algún monto o alguna suma no está
+/// entre 0 y el máximo de satoshis.
+pub fn fee_rate(set: &SetUtxos, txn_memory: &TrxServer, transaccion: &Txn) -> Option<u64> {
+    let mut total_inputs: i64 = 0;
+    for tx_in in &transaccion.tx_in {
+        let outpoint = tx_in.obtain_tx_id_of_previous_output();
+        let previa = set.utxos.get(&outpoint).or_else(|| {
+            txn_memory
+                .iter()
+                .find(|(txid, _, _)| *txid == outpoint.0)
+                .map(|(_, previa, _)| previa)
+        })?;
+        let output = previa.tx_out.get(outpoint.1 as usize)?;
+        total_inputs = sumar_monto(total_inputs, output.value_amount_satoshis).ok()?;
+    }
+    let mut total_outputs: i64 = 0;
+    for output in &transaccion.tx_out {
+        total_outputs = sumar_monto(total_outputs, output.value_amount_satoshis).ok()?;
+    }
+    let fee = u64::try_from(total_inputs.checked_sub(total_outputs)?).ok()?;
+    fee.checked_mul(1000)?
+        .checked_div(transaccion.as_bytes().len() as u64)
+}
+
+/// Fee mínima, en satoshis por kilobyte, que tiene que pagar una transacción para guardarse en memoria.
+/// Es la fee mínima configurada, salvo que la memoria esté llena: en ese caso es la menor fee
+/// conocida entre las transacciones en memoria, para no reemplazarlas por otras que paguen menos.
+pub fn fee_minima_mempool(
+    txn_memory_server: &Arc<Mutex<TrxServer>>,
+    config: &Config,
+) -> Result<u64, RustifyError> {
+    let txn_memory = txn_memory_server.lock()?;
+    Ok(fee_minima(&txn_memory, config))
+}
+
+/// Se usan las fees calculadas al guardar cada transacción en memoria, sin volver a buscar sus outputs.
+fn fee_minima(txn_memory: &TrxServer, config: &Config) -> u64 {
+    if txn_memory.len() < config.cant_max_txn_memoria {
+        return config.fee_minima_relay;
+    }
+    txn_memory
+        .iter()
+        .filter_map(|(_, _, fee)| *fee)
+        .min()
+        .map_or(config.fee_minima_relay, |fee| {
+            fee.max(config.fee_minima_relay)
+        })
+}
+
 /// Añade una transaccion en memoria, para que luego el servidor pueda
-/// enviarla, en caso de ser solicitada
+/// enviarla, en caso de ser solicitada. Se guarda junto con su fee, si se conoce.
+/// Si se conoce su fee y es menor a la fee mínima de la memoria, no se guarda.
 pub fn add_txn_in_memory(
-    txn_memory_server: &mut Arc<Mutex<TrxServer>>,
+    (txn_memory_server, utxos): (&mut Arc<Mutex<TrxServer>>, &Arc<Mutex<SetUtxos>>),
     transaccion: &Txn,
     txid_str: &String,
     config: &Config,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
+    let set = utxos.lock()?;
     let mut txn_memory = txn_memory_server.lock()?;
 
+    let fee = fee_rate(&set, &txn_memory, transaccion);
+    if let Some(fee) = fee {
+        if fee < fee_minima(&txn_memory, config) {
+            log_with_parameters(
+                Lvl::Info(Action::SERVER),
+                format!(
+                    "No se guarda en memoria la transaccion {}: su fee ({} sat/kB) es menor a la mínima",
+                    txid_str, fee
+                ),
+                logger_sender,
+            );
+            return Ok(());
+        }
+    }
+
     if txn_memory.len() == config.cant_max_txn_memoria {
         txn_memory.remove(0);
     }
 
-    txn_memory.push((txid_str.to_string(), transaccion.clone()));
+    txn_memory.push((txid_str.to_string(), transaccion.clone(), fee));
     log(
         Lvl::Info(Action::SERVER),
         "Se guarda transaccion en memoria",
@@ -103,3 +270,101 @@ pub fn find_txn_in_memory(
     }
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{txin::TxIn, txout::TxOut};
+
+    fn txn_test(inputs: Vec<(String, u32)>, valor: i64) -> Txn {
+        let tx_in: Vec<TxIn> = inputs
+            .iter()
+            .map(|trxkey| TxIn::new(trxkey, vec![0x51]))
+            .collect();
+        let tx_out = vec![TxOut {
+            value_amount_satoshis: valor,
+            pk_script_bytes: CompactSize::new(1),
+            pk_script: vec![0x51],
+        }];
+        Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(tx_in.len() as u64),
+            tx_in,
+            tx_out_count: CompactSize::new(1),
+            tx_out,
+            tx_lock_time: 0,
+        }
+    }
+
+    /// La fee se calcula con los outputs gastados que están en el set de UTXOs o en memoria.
+    #[test]
+    fn test_fee_rate_con_set_de_utxos_y_memoria() {
+        let previa = txn_test(vec![("11".repeat(32), 0)], 10_000);
+        let txid_previa = Txn::obtain_tx_id(previa.as_bytes());
+        let memoria = vec![(txid_previa.clone(), previa.clone(), None)];
+
+        let hija = txn_test(vec![(txid_previa.clone(), 0)], 9_000);
+        let tamanio = hija.as_bytes().len() as u64;
+        assert_eq!(
+            fee_rate(&SetUtxos::default(), &memoria, &hija),
+            Some(1_000 * 1000 / tamanio)
+        );
+
+        let mut set = SetUtxos::default();
+        set.utxos.insert((txid_previa.clone(), 0), previa);
+        assert_eq!(fee_rate(&set, &vec![], &hija), Some(1_000 * 1000 / tamanio));
+
+        // Si gasta un output que no está en ninguno de los dos, no se puede calcular la fee.
+        let desconocida = txn_test(vec![("22".repeat(32), 0)], 9_000);
+        assert_eq!(fee_rate(&set, &memoria, &desconocida), None);
+
+        // Los montos fuera de rango no se suman: la fee queda desconocida, sin overflow.
+        let negativa = txn_test(vec![(txid_previa.clone(), 0)], -1);
+        assert_eq!(fee_rate(&set, &memoria, &negativa), None);
+        let mut enorme = txn_test(vec![(txid_previa, 0)], i64::MAX);
+        assert_eq!(fee_rate(&set, &memoria, &enorme), None);
+        enorme.tx_out.push(enorme.tx_out[0].clone());
+        enorme.tx_out[0].value_amount_satoshis = 1;
+        assert_eq!(fee_rate(&set, &memoria, &enorme), None);
+    }
+
+    /// Se anuncian las transacciones con fee suficiente y las de fee desconocida,
+    /// pero no las de fee menor a la del feefilter ni las que no están en memoria.
+    #[test]
+    fn test_filtrar_por_fee_anuncia_las_de_fee_desconocida() -> Result<(), RustifyError> {
+        let previa = txn_test(vec![("11".repeat(32), 0)], 10_000);
+        let txid_previa = Txn::obtain_tx_id(previa.as_bytes());
+        let mut set = SetUtxos::default();
+        set.utxos.insert((txid_previa.clone(), 0), previa);
+        let memoria_previa = vec![];
+
+        let barata = txn_test(vec![(txid_previa.clone(), 0)], 9_990);
+        let cara = txn_test(vec![(txid_previa, 0)], 5_000);
+        let desconocida = txn_test(vec![("22".repeat(32), 0)], 9_000);
+        let fuera_de_memoria = txn_test(vec![("33".repeat(32), 0)], 9_000);
+        let memoria: TrxServer = [&barata, &cara, &desconocida]
+            .iter()
+            .map(|txn| {
+                let fee = fee_rate(&set, &memoria_previa, txn);
+                (Txn::obtain_tx_id(txn.as_bytes()), (*txn).clone(), fee)
+            })
+            .collect();
+
+        let inv = Inv::new(
+            4,
+            MSG_TX,
+            vec![
+                barata.as_bytes(),
+                cara.as_bytes(),
+                desconocida.as_bytes(),
+                fuera_de_memoria.as_bytes(),
+            ],
+        );
+        let filtrado = filtrar_por_fee(&inv, 1_000, &Arc::new(Mutex::new(memoria)))?;
+        assert_eq!(
+            filtrado.inventories,
+            vec![inv.inventories[1].clone(), inv.inventories[2].clone()]
+        );
+        Ok(())
+    }
+}
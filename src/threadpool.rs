@@ -1,42 +1,65 @@
-use crate::block::block_download;
+use crate::address_manager::{obtener_address_manager, penalizar_direccion};
+use crate::addrv2::DireccionNodo;
+use crate::block::{pedir_bloques, recibir_bloque_pedido};
 use crate::block_header::BlockHeader;
 use crate::config::Config;
-use crate::errors::RustifyError;
-use crate::logger::{log, log_with_parameters, Action, Lvl};
-use crate::node::{conectar, handshake};
-use std::sync::mpsc::Sender;
+use crate::errors::{obtener_mensaje_personalizado, RustifyError};
+use crate::event_loop::ConexionPeer;
+use crate::logger::{log, log_re_err, log_with_parameters, Action, Lvl};
+use crate::node::{conectar_excluyendo, handshake};
+use crate::serialized_block::SerializedBlock;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
     thread,
+    time::Duration,
 };
 
+/// Cantidad de nodos distintos a los que se pide un bloque antes de abandonar la descarga.
+const MAX_INTENTOS_POR_BLOQUE: usize = 5;
+/// Cantidad de conexiones fallidas seguidas tras las cuales se deja de reponer workers.
+const MAX_FALLAS_CONEXION_WORKER: usize = 5;
+
 /// Estructura que contiene los workers (threads) para paralelizar la descarga de bloques.
-/// También tiene un channel para poder enviarle los headers a los threads para descargar los bloques asociados.
+/// También tiene la cola compartida de la que los threads toman los headers de los bloques a descargar.
+/// Cuando un worker se cae, sus bloques en curso vuelven a la cola y se lo reemplaza por
+/// un worker conectado a otro nodo.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<BlockHeader>,
+    workers: HashMap<usize, Worker>,
+    cola: Arc<ColaDescargas>,
+    avisos: Receiver<usize>,
+    avisos_sender: Sender<usize>,
+    direcciones_descartadas: Vec<DireccionNodo>,
+    proximo_id: usize,
+    config: Config,
+    logger_sender: Sender<String>,
 }
 
 impl ThreadPool {
     /// Constructor de la ThreadPool, indicando cantidad de threads y los archivos de config y log.
+    /// Si algún worker no se puede conectar se sigue con los demás; falla solamente si no se conectó ninguno.
     pub fn build(config: &Config, log_sender: &Sender<String>) -> Result<ThreadPool, RustifyError> {
         if config.cant_threads == 0 {
             return Err(RustifyError::CantThreads);
         }
 
-        let (sender, receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
+        let (avisos_sender, avisos) = mpsc::channel();
+        let mut threadpool = ThreadPool {
+            workers: HashMap::with_capacity(config.cant_threads),
+            cola: Arc::new(ColaDescargas::new(config.ventana_descarga)),
+            avisos,
+            avisos_sender,
+            direcciones_descartadas: vec![],
+            proximo_id: 0,
+            config: config.clone(),
+            logger_sender: log_sender.clone(),
+        };
+        threadpool.reponer_workers();
 
-        let mut workers = Vec::with_capacity(config.cant_threads);
-
-        for id in 0..config.cant_threads {
-            workers.push(Worker::build(
-                id,
-                Arc::clone(&receiver),
-                config,
-                log_sender,
-            )?);
+        if threadpool.workers.is_empty() {
+            return Err(RustifyError::NoHayPeersDisponibles);
         }
 
         log(
@@ -45,39 +68,62 @@ impl ThreadPool {
             log_sender,
         );
 
-        Ok(ThreadPool { workers, sender })
+        Ok(threadpool)
     }
 
     /// Descarga paralelizada de bloques. Recibe el vector de headers para descargar los bloques.
-    /// Cada thread recibe por el channel un header para descargar el bloque asociado.
+    /// Cada thread toma de la cola un header para descargar el bloque asociado.
+    /// Termina cuando todos los bloques pedidos están en disco; si no se pudo descargar alguno, devuelve error.
     pub fn download_blocks(
-        self,
+        mut self,
         headers: Vec<BlockHeader>,
         logger_sender: &Sender<String>,
     ) -> Result<(), RustifyError> {
-        for header in headers {
-            self.sender.send(header)?;
-        }
+        self.cola.cargar(headers.clone())?;
 
         self.wait_for_threads(logger_sender)?;
 
+        let faltantes = headers
+            .iter()
+            .filter(|header| !bloque_en_disco(header, &self.config.blocks_path))
+            .count();
+        if faltantes > 0 {
+            log_with_parameters(
+                Lvl::Error(Action::THREADPOOL),
+                format!("Quedaron {} bloques sin descargar.", faltantes),
+                logger_sender,
+            );
+            return Err(RustifyError::DescargaDeBloquesIncompleta);
+        }
         Ok(())
     }
 
-    /// Apaga los threads al finalizar la descarga de bloques.
-    /// Desconecta al sender para que los threads salgan del loop y finalicen su ejecución.
-    /// Finalmente hace el join de los threads, para cada worker.
-    fn wait_for_threads(self, logger_sender: &Sender<String>) -> Result<(), RustifyError> {
-        drop(self.sender);
-        for worker in self.workers {
+    /// Espera a que los threads terminen la descarga de bloques, haciendo el join de cada worker que termina.
+    /// Los threads salen del loop cuando ya no quedan bloques por descargar ni pedidos en curso.
+    /// Si un worker termina por un error mientras todavía quedan bloques, se lo reemplaza.
+    fn wait_for_threads(&mut self, logger_sender: &Sender<String>) -> Result<(), RustifyError> {
+        while !self.workers.is_empty() {
+            let id = self
+                .avisos
+                .recv()
+                .map_err(|_| RustifyError::DescargaDeBloquesIncompleta)?;
+            let worker = match self.workers.remove(&id) {
+                Some(worker) => worker,
+                None => continue,
+            };
             match worker.thread.join() {
-                Ok(_) => {}
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    log_re_err(Action::THREADPOOL, e, logger_sender);
+                    self.direcciones_descartadas.push(worker.addr);
+                }
                 Err(_) => {
                     log_with_parameters(
                         Lvl::Error(Action::THREADPOOL),
                         format!("Falla en el worker {}.", worker.id),
                         logger_sender,
                     );
+                    self.direcciones_descartadas.push(worker.addr);
                 }
             }
             log_with_parameters(
@@ -85,77 +131,499 @@ impl ThreadPool {
                 format!("Apagando worker {}.", worker.id),
                 logger_sender,
             );
+            if !self.cola.terminada()? {
+                self.reponer_workers();
+            }
+        }
+        Ok(())
+    }
+
+    /// Conecta nuevos workers hasta llegar a cant_threads, evitando los nodos en uso y los que ya fallaron.
+    /// Deja de intentar luego de MAX_FALLAS_CONEXION_WORKER fallas seguidas.
+    fn reponer_workers(&mut self) {
+        let mut fallas = 0;
+        while self.workers.len() < self.config.cant_threads && fallas < MAX_FALLAS_CONEXION_WORKER {
+            let mut excluidas: Vec<DireccionNodo> = self.workers.values().map(|w| w.addr).collect();
+            excluidas.extend(&self.direcciones_descartadas);
+
+            let id = self.proximo_id;
+            self.proximo_id += 1;
+            let resultado = conectar_excluyendo(&self.config, &self.logger_sender, &excluidas)
+                .and_then(|socket| {
+                    let addr = socket.addr();
+                    Worker::build(
+                        id,
+                        socket,
+                        Arc::clone(&self.cola),
+                        self.avisos_sender.clone(),
+                        &self.config,
+                        &self.logger_sender,
+                    )
+                    .inspect_err(|_| self.direcciones_descartadas.push(addr))
+                });
+            match resultado {
+                Ok(worker) => {
+                    fallas = 0;
+                    self.workers.insert(id, worker);
+                }
+                Err(e) => {
+                    fallas += 1;
+                    log_with_parameters(
+                        Lvl::Warning(Action::THREADPOOL),
+                        format!(
+                            "No se pudo conectar el worker {} ({}).",
+                            id,
+                            obtener_mensaje_personalizado(e)
+                        ),
+                        &self.logger_sender,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Indica si el bloque del header ya está guardado en la carpeta de bloques.
+fn bloque_en_disco(header: &BlockHeader, blocks_path: &str) -> bool {
+    let nombre = SerializedBlock::obtain_name_for_blockfile(&header.as_bytes());
+    Path::new(&format!("{}/{}.txt", blocks_path, nombre)).exists()
+}
+
+/// Cola de headers cuyos bloques quedan por descargar, compartida por los workers.
+/// Lleva los bloques pedidos a cada nodo que todavía no llegaron, para devolverlos a la cola
+/// si el worker de ese nodo se cae, y para que ningún worker termine mientras eso pueda pasar.
+/// Los bloques pueden llegar en cualquier orden, pero se conectan a la cadena en orden: solamente
+/// se piden bloques dentro de una ventana a partir del primero que todavía no se pudo conectar.
+struct ColaDescargas {
+    estado: Mutex<EstadoDescargas>,
+    cambios: Condvar,
+}
+
+/// Estado interno de la cola de descargas. Los bloques se identifican por su posición en la descarga.
+pub struct EstadoDescargas {
+    pendientes: VecDeque<(usize, BlockHeader)>,
+    en_curso: HashMap<DireccionNodo, Vec<(usize, BlockHeader)>>,
+    recibidos: HashSet<usize>,
+    conectados: usize,
+    intentos: HashMap<usize, usize>,
+    ventana: usize,
+    cargada: bool,
+    abandonada: bool,
+}
+
+impl EstadoDescargas {
+    fn hay_en_curso(&self) -> bool {
+        self.en_curso.values().any(|headers| !headers.is_empty())
+    }
+
+    fn terminada(&self) -> bool {
+        self.abandonada || (self.cargada && self.pendientes.is_empty() && !self.hay_en_curso())
+    }
+}
+
+impl ColaDescargas {
+    fn new(ventana: usize) -> ColaDescargas {
+        ColaDescargas {
+            estado: Mutex::new(EstadoDescargas {
+                pendientes: VecDeque::new(),
+                en_curso: HashMap::new(),
+                recibidos: HashSet::new(),
+                conectados: 0,
+                intentos: HashMap::new(),
+                ventana: ventana.max(1),
+                cargada: false,
+                abandonada: false,
+            }),
+            cambios: Condvar::new(),
         }
+    }
+
+    /// Carga los headers de todos los bloques a descargar, en el orden de la cadena.
+    fn cargar(&self, headers: Vec<BlockHeader>) -> Result<(), RustifyError> {
+        let mut estado = self.estado.lock()?;
+        estado.pendientes.extend(headers.into_iter().enumerate());
+        estado.cargada = true;
+        self.cambios.notify_all();
         Ok(())
     }
+
+    /// Toma hasta max headers a descargar desde el nodo indicado, de los que entran en la ventana.
+    /// Si esperar es true, bloquea hasta que haya al menos uno; si no, puede devolver un vector vacío.
+    /// Devuelve None cuando ya no quedan bloques por descargar ni pedidos en curso, o si se abandonó la descarga.
+    fn tomar(
+        &self,
+        addr: &DireccionNodo,
+        max: usize,
+        esperar: bool,
+    ) -> Result<Option<Vec<BlockHeader>>, RustifyError> {
+        let mut estado = self.estado.lock()?;
+        loop {
+            if estado.terminada() {
+                return Ok(None);
+            }
+            let limite = estado.conectados + estado.ventana;
+            let mut tomados = vec![];
+            while tomados.len() < max {
+                match estado.pendientes.front() {
+                    Some((posicion, _)) if *posicion < limite => {}
+                    _ => break,
+                }
+                if let Some(pendiente) = estado.pendientes.pop_front() {
+                    tomados.push(pendiente.1.clone());
+                    estado.en_curso.entry(*addr).or_default().push(pendiente);
+                }
+            }
+            if !tomados.is_empty() || !esperar {
+                return Ok(Some(tomados));
+            }
+            estado = self.cambios.wait(estado)?;
+        }
+    }
+
+    /// Marca como descargado un bloque pedido al nodo indicado, y conecta en orden todos los bloques
+    /// que ya llegaron a continuación del último conectado. Devuelve la cantidad de bloques conectados.
+    fn completar(&self, addr: &DireccionNodo, header: &BlockHeader) -> Result<usize, RustifyError> {
+        let mut estado = self.estado.lock()?;
+        let mut posicion_recibida = None;
+        if let Some(headers) = estado.en_curso.get_mut(addr) {
+            if let Some(i) = headers
+                .iter()
+                .position(|(_, en_curso)| en_curso.as_bytes() == header.as_bytes())
+            {
+                posicion_recibida = Some(headers.remove(i).0);
+            }
+        }
+        if let Some(posicion) = posicion_recibida {
+            estado.recibidos.insert(posicion);
+        }
+        loop {
+            let proximo = estado.conectados;
+            if !estado.recibidos.remove(&proximo) {
+                break;
+            }
+            estado.conectados += 1;
+        }
+        self.cambios.notify_all();
+        Ok(estado.conectados)
+    }
+
+    /// Devuelve a la cola los bloques pedidos al nodo indicado, para que los pida otro worker.
+    /// Se reinsertan según su posición, para no demorar la descarga de los bloques más viejos.
+    /// Si un bloque ya falló en MAX_INTENTOS_POR_BLOQUE nodos, se abandona la descarga.
+    /// Devuelve la cantidad de bloques reasignados.
+    fn liberar(&self, addr: &DireccionNodo) -> Result<usize, RustifyError> {
+        let mut estado = self.estado.lock()?;
+        let headers = estado.en_curso.remove(addr).unwrap_or_default();
+        let mut reasignados = 0;
+        for (posicion, header) in headers {
+            let intentos = estado.intentos.entry(posicion).or_insert(0);
+            *intentos += 1;
+            if *intentos >= MAX_INTENTOS_POR_BLOQUE {
+                estado.abandonada = true;
+            }
+            let indice = estado.pendientes.partition_point(|(p, _)| *p < posicion);
+            estado.pendientes.insert(indice, (posicion, header));
+            reasignados += 1;
+        }
+        self.cambios.notify_all();
+        Ok(reasignados)
+    }
+
+    /// Indica si ya no quedan bloques por descargar ni pedidos en curso, o si se abandonó la descarga.
+    fn terminada(&self) -> Result<bool, RustifyError> {
+        Ok(self.estado.lock()?.terminada())
+    }
 }
 
-/// Estructura que contiene un thread y un ID que lo identifica.
-/// Cada worker va a descargar de a un bloque.
+/// Estructura que contiene un thread y un ID que lo identifica, junto a la dirección del nodo al que se conectó.
+/// Cada worker mantiene hasta cant_blocks_por_inv bloques pedidos a su nodo.
 struct Worker {
     id: usize,
+    addr: DireccionNodo,
     thread: thread::JoinHandle<Result<(), RustifyError>>,
 }
 
 impl Worker {
     /// Constructor de los workers.
-    /// Cada uno se conecta a un nodo y hace un handshake para descargar los bloques.
-    /// Una vez que spawnean un thread se quedan esperando a que les lleguen headers por el channel para descargar los bloques.
+    /// Cada uno hace un handshake con el nodo al que se conectó para descargar los bloques.
+    /// Una vez que spawnean un thread toman headers de la cola para descargar los bloques.
+    /// Si el nodo falla o no envía los bloques a tiempo, el worker se desconecta y sus pedidos vuelven a la cola.
     fn build(
         id: usize,
-        receiver: Arc<Mutex<mpsc::Receiver<BlockHeader>>>,
+        mut socket: ConexionPeer,
+        cola: Arc<ColaDescargas>,
+        avisos: Sender<usize>,
         config: &Config,
         logger_sender: &Sender<String>,
     ) -> Result<Worker, RustifyError> {
-        let mut socket = conectar(config, logger_sender)?;
+        let addr = socket.addr();
         handshake(&mut socket, config, logger_sender)?;
 
         let block_path = config.blocks_path.clone();
-        let cant_block_for_inv = config.cant_blocks_por_inv;
+        let max_en_curso = (config.cant_blocks_por_inv as usize).max(1);
+        let timeout = Duration::from_secs(config.timeout_respuesta_secs);
         let logger_sender_clone = logger_sender.clone();
 
         log_with_parameters(
             Lvl::Info(Action::THREADPOOL),
-            format!("Worker {:?} conectado y listo para descargar bloques.", id),
+            format!(
+                "Worker {:?} conectado a {} y listo para descargar bloques.",
+                id, addr
+            ),
             &logger_sender_clone,
         );
 
         let thread = thread::spawn(move || -> Result<(), RustifyError> {
-            loop {
-                let mensaje = receiver.lock()?.recv();
-                match mensaje {
-                    Ok(header) => {
-                        let header_bytes: String = header
-                            .as_bytes()
-                            .iter()
-                            .map(|b| format!("{:02x}", b) + "")
-                            .collect();
-                        log_with_parameters(
-                            Lvl::Info(Action::THREADPOOL),
-                            format!("Worker {:?} descargando el header {}", id, header_bytes),
-                            &logger_sender_clone,
-                        );
-                        block_download(
-                            &mut socket,
-                            header,
-                            block_path.to_string(),
-                            cant_block_for_inv,
-                            &logger_sender_clone,
-                        )?;
-                    }
-                    Err(_) => {
-                        log_with_parameters(
-                            Lvl::Info(Action::THREADPOOL),
-                            format!("Worker {:?} desconectado; apagando.", id),
-                            &logger_sender_clone,
-                        );
-                        break;
-                    }
-                }
+            let _fin = FinWorker {
+                id,
+                addr,
+                cola: Arc::clone(&cola),
+                avisos,
+                logger_sender: logger_sender_clone.clone(),
+            };
+            if let Err(e) = descargar_bloques(
+                id,
+                &mut socket,
+                &cola,
+                &block_path,
+                max_en_curso,
+                timeout,
+                &logger_sender_clone,
+            ) {
+                reportar_falla_descarga(id, &addr, &e, &logger_sender_clone);
+                return Err(e);
             }
+            log_with_parameters(
+                Lvl::Info(Action::THREADPOOL),
+                format!("Worker {:?} sin bloques por descargar; apagando.", id),
+                &logger_sender_clone,
+            );
             Ok(())
         });
 
-        Ok(Worker { id, thread })
+        Ok(Worker { id, addr, thread })
+    }
+}
+
+/// Loop de descarga de un worker. Mantiene hasta max_en_curso bloques pedidos al nodo: cada vez que
+/// llega un bloque, pide en un mismo getdata los que entren en la ventana para volver a completar el máximo.
+fn descargar_bloques(
+    id: usize,
+    socket: &mut ConexionPeer,
+    cola: &ColaDescargas,
+    block_path: &String,
+    max_en_curso: usize,
+    timeout: Duration,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    let addr = socket.addr();
+    let mut en_curso: Vec<BlockHeader> = vec![];
+    loop {
+        if en_curso.len() < max_en_curso {
+            let nuevos =
+                match cola.tomar(&addr, max_en_curso - en_curso.len(), en_curso.is_empty())? {
+                    Some(nuevos) => nuevos,
+                    None => return Ok(()),
+                };
+            if !nuevos.is_empty() {
+                log_with_parameters(
+                    Lvl::Info(Action::THREADPOOL),
+                    format!("Worker {:?} pidiendo {} bloques", id, nuevos.len()),
+                    logger_sender,
+                );
+                pedir_bloques(socket, &nuevos)?;
+                en_curso.extend(nuevos);
+            }
+        }
+
+        let header = recibir_bloque_pedido(socket, &en_curso, block_path, timeout, logger_sender)?
+            .block_header;
+        en_curso.retain(|pedido| pedido.as_bytes() != header.as_bytes());
+        let conectados = cola.completar(&addr, &header)?;
+        log_with_parameters(
+            Lvl::Info(Action::THREADPOOL),
+            format!(
+                "Worker {:?} recibió un bloque; {} bloques conectados en orden.",
+                id, conectados
+            ),
+            logger_sender,
+        );
+    }
+}
+
+/// Se crea al iniciar el thread de un worker y se dropea al terminar, de cualquier forma que termine.
+/// Devuelve a la cola los bloques que el worker tenía en curso y avisa a la ThreadPool que el worker terminó.
+struct FinWorker {
+    id: usize,
+    addr: DireccionNodo,
+    cola: Arc<ColaDescargas>,
+    avisos: Sender<usize>,
+    logger_sender: Sender<String>,
+}
+
+impl Drop for FinWorker {
+    fn drop(&mut self) {
+        match self.cola.liberar(&self.addr) {
+            Ok(0) => {}
+            Ok(reasignados) => log_with_parameters(
+                Lvl::Info(Action::THREADPOOL),
+                format!(
+                    "Worker {:?}: se reasignan {} bloques pedidos a {}.",
+                    self.id, reasignados, self.addr
+                ),
+                &self.logger_sender,
+            ),
+            Err(e) => log_re_err(Action::THREADPOOL, e, &self.logger_sender),
+        }
+        let _ = self.avisos.send(self.id);
+    }
+}
+
+/// Registra la falla del nodo del que se estaba descargando un bloque.
+/// Si el nodo no respondió a tiempo se considera trabado (stall) y se registra la falla en el
+/// AddressManager; si violó el protocolo, además se lo penaliza.
+fn reportar_falla_descarga(
+    id: usize,
+    addr: &DireccionNodo,
+    error: &RustifyError,
+    logger_sender: &Sender<String>,
+) {
+    if error.es_violacion_protocolo() {
+        penalizar_direccion(addr, error, logger_sender);
+        return;
+    }
+    log_with_parameters(
+        Lvl::Warning(Action::THREADPOOL),
+        format!(
+            "Worker {:?}: falló la descarga desde el nodo {} ({}). Se reasigna el bloque a otro worker.",
+            id,
+            addr,
+            obtener_mensaje_personalizado(error.clone())
+        ),
+        logger_sender,
+    );
+    if *error == RustifyError::TimeoutRespuesta {
+        if let Some(address_manager) = obtener_address_manager() {
+            if let Err(e) = address_manager.registrar_falla(addr) {
+                log_re_err(Action::THREADPOOL, e, logger_sender);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_test(nonce: u8) -> Result<BlockHeader, RustifyError> {
+        let mut bytes = [0u8; 80];
+        bytes[76] = nonce;
+        BlockHeader::from_bytes(&bytes)
+    }
+
+    fn headers_test(cantidad: u8) -> Result<Vec<BlockHeader>, RustifyError> {
+        (0..cantidad).map(header_test).collect()
+    }
+
+    fn addr_test(puerto: u16) -> DireccionNodo {
+        DireccionNodo::Ip(std::net::SocketAddr::from(([127, 0, 0, 1], puerto)))
+    }
+
+    fn nonces(headers: &[BlockHeader]) -> Vec<u32> {
+        headers.iter().map(|header| header.nonce).collect()
+    }
+
+    #[test]
+    fn test_bloque_reasignado_lo_toma_otro_worker() -> Result<(), RustifyError> {
+        let cola = Arc::new(ColaDescargas::new(1024));
+        cola.cargar(headers_test(1)?)?;
+
+        let tomados = cola
+            .tomar(&addr_test(1), 1, true)?
+            .ok_or(RustifyError::NotFound)?;
+        assert_eq!(nonces(&tomados), vec![0]);
+
+        // Un segundo worker no termina mientras el bloque sigue en curso.
+        let cola_otro_worker = Arc::clone(&cola);
+        let otro_worker = thread::spawn(move || -> Result<Option<Vec<u32>>, RustifyError> {
+            Ok(cola_otro_worker
+                .tomar(&addr_test(2), 1, true)?
+                .map(|headers| nonces(&headers)))
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!otro_worker.is_finished());
+
+        // El nodo del primer worker no respondió: el bloque pasa al segundo.
+        assert_eq!(cola.liberar(&addr_test(1))?, 1);
+        let reasignado = otro_worker
+            .join()
+            .map_err(|_| RustifyError::MutexPoisonError)??;
+        assert_eq!(reasignado, Some(vec![0]));
+        assert!(!cola.terminada()?);
+
+        assert_eq!(cola.completar(&addr_test(2), &tomados[0])?, 1);
+        assert!(cola.terminada()?);
+        assert!(cola.tomar(&addr_test(2), 1, true)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ventana_avanza_al_conectar_en_orden() -> Result<(), RustifyError> {
+        let cola = ColaDescargas::new(2);
+        cola.cargar(headers_test(4)?)?;
+
+        let tomados = cola
+            .tomar(&addr_test(1), 16, true)?
+            .ok_or(RustifyError::NotFound)?;
+        assert_eq!(nonces(&tomados), vec![0, 1]);
+        let sin_lugar = cola.tomar(&addr_test(2), 16, false)?.map(|h| nonces(&h));
+        assert_eq!(sin_lugar, Some(vec![]));
+
+        // El segundo bloque llega antes que el primero: se acepta, pero la ventana no avanza.
+        assert_eq!(cola.completar(&addr_test(1), &tomados[1])?, 0);
+        let sin_lugar = cola.tomar(&addr_test(2), 16, false)?.map(|h| nonces(&h));
+        assert_eq!(sin_lugar, Some(vec![]));
+
+        // Al llegar el primero se conectan los dos y se liberan los siguientes.
+        assert_eq!(cola.completar(&addr_test(1), &tomados[0])?, 2);
+        let siguientes = cola.tomar(&addr_test(2), 16, false)?.map(|h| nonces(&h));
+        assert_eq!(siguientes, Some(vec![2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloques_reasignados_quedan_en_su_posicion() -> Result<(), RustifyError> {
+        let cola = ColaDescargas::new(1024);
+        cola.cargar(headers_test(4)?)?;
+
+        let primeros = cola
+            .tomar(&addr_test(1), 2, true)?
+            .ok_or(RustifyError::NotFound)?;
+        let tercero = cola
+            .tomar(&addr_test(2), 1, true)?
+            .ok_or(RustifyError::NotFound)?;
+        cola.completar(&addr_test(1), &primeros[0])?;
+        cola.liberar(&addr_test(1))?;
+        cola.liberar(&addr_test(2))?;
+
+        let restantes = cola.tomar(&addr_test(3), 16, true)?.map(|h| nonces(&h));
+        assert_eq!(nonces(&tercero), vec![2]);
+        assert_eq!(restantes, Some(vec![1, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloque_que_falla_en_varios_nodos_abandona_la_descarga() -> Result<(), RustifyError> {
+        let cola = ColaDescargas::new(1024);
+        cola.cargar(headers_test(2)?)?;
+
+        for puerto in 0..MAX_INTENTOS_POR_BLOQUE as u16 {
+            cola.tomar(&addr_test(puerto), 1, true)?
+                .ok_or(RustifyError::NotFound)?;
+            cola.liberar(&addr_test(puerto))?;
+        }
+        assert!(cola.terminada()?);
+        assert!(cola.tomar(&addr_test(100), 1, true)?.is_none());
+        Ok(())
     }
 }
@@ -0,0 +1,599 @@
+use crate::errors::RustifyError;
+use crate::red::parametros_red;
+use crate::script_interpreter::{
+    es_p2sh, flags_consenso, gasta_witness, operaciones_script, verify_input, FLAGS_ESTANDAR,
+    MAX_CLAVES_MULTISIG, OP_0, OP_1, OP_16, OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY, OP_CHECKSIG,
+    OP_CHECKSIGVERIFY,
+};
+use crate::serialized_block::SerializedBlock;
+use crate::txn::Txn;
+use crate::txout::TxOut;
+use crate::utxo::SetUtxos;
+use std::collections::{HashMap, HashSet};
+
+type TrxKey = (String, u32);
+
+/// Peso máximo de un bloque (BIP141).
+const MAX_PESO_BLOQUE: usize = 4_000_000;
+/// Relación entre el peso y el tamaño de los datos sin witness. Los bloques se reciben sin witness,
+/// así que su peso es el tamaño serializado multiplicado por este factor.
+const FACTOR_ESCALA_WITNESS: usize = 4;
+/// Máximo costo en operaciones de firma de un bloque (BIP141).
+const MAX_COSTO_SIGOPS_BLOQUE: usize = 80_000;
+/// Cantidad de bloques que tienen que pasar para poder gastar los outputs de una coinbase.
+const MADUREZ_COINBASE: u32 = 100;
+/// Subsidio de la coinbase antes del primer halving, en satoshis.
+const SUBSIDIO_INICIAL: i64 = 50 * 100_000_000;
+/// Máxima cantidad de satoshis que pueden existir.
+const MAX_SATOSHIS: i64 = 21_000_000 * 100_000_000;
+/// Output que gasta un input, con los datos necesarios para validar el gasto.
+struct OutputPrevio<'a> {
+    output: &'a TxOut,
+    /// Altura del bloque en el que se creó el output.
+    altura: u32,
+    es_coinbase: bool,
+}
+
+/// Valida las transacciones de un bloque en el contexto de la cadena: que la primera (y solamente ella) sea
+/// una coinbase con la altura del bloque (BIP34), el peso y las operaciones de firma del bloque, que cada input
+/// gaste un output existente y no gastado por otro input del bloque, la madurez de los outputs de coinbase
+/// gastados, que ninguna transacción gaste más de lo que recibe y que la coinbase no cobre más que el subsidio
+/// más las fees. Los scripts de los inputs cuyo output se conoce se ejecutan con las flags de consenso
+/// de la altura del bloque.
+/// Con un set de UTXOs incompleto (ver utxos_completas), los inputs que no se encuentran se aceptan:
+/// la transacción y la coinbase no se pueden validar contra sus montos, ya que no se conocen todas las fees.
+/// Devuelve el error con el motivo del rechazo.
+pub fn validar_transacciones_bloque(
+    bloque: &SerializedBlock,
+    altura: u32,
+    set: &SetUtxos,
+    utxos_completas: bool,
+) -> Result<(), RustifyError> {
+    validar_coinbase(bloque, altura)?;
+    if bloque.as_bytes().len() * FACTOR_ESCALA_WITNESS > MAX_PESO_BLOQUE {
+        return Err(RustifyError::BloqueExcedePeso);
+    }
+
+    let mut creadas: HashMap<String, &Txn> = HashMap::new();
+    let mut gastados: HashSet<TrxKey> = HashSet::new();
+    let mut fees = Some(0);
+    let mut costo_sigops = 0;
+    let flags = flags_consenso(altura);
+    for (i, txn) in bloque.txns.iter().enumerate() {
+        let valor_outputs = sumar_outputs(txn)?;
+        costo_sigops += contar_sigops_legacy(txn) * FACTOR_ESCALA_WITNESS;
+        if i > 0 {
+            let mut valor_inputs = Some(0);
+            for (index, input) in txn.tx_in.iter().enumerate() {
+                let outpoint = input.obtain_tx_id_of_previous_output();
+                let previo = buscar_output(&outpoint, &creadas, set, altura)?;
+                if !gastados.insert(outpoint) {
+                    return Err(RustifyError::DobleGasto);
+                }
+                match previo {
+                    Some(previo) => {
+                        if previo.es_coinbase
+                            && altura.saturating_sub(previo.altura) < MADUREZ_COINBASE
+                        {
+                            return Err(RustifyError::CoinbaseInmadura);
+                        }
+                        verify_input(txn, index, previo.output, flags)?;
+                        if es_p2sh(&previo.output.pk_script) {
+                            costo_sigops +=
+                                contar_sigops_p2sh(&input.signature_script) * FACTOR_ESCALA_WITNESS;
+                        }
+                        let valor = sumar_monto(0, previo.output.value_amount_satoshis)?;
+                        valor_inputs = match valor_inputs {
+                            Some(total) => Some(sumar_monto(total, valor)?),
+                            None => None,
+                        };
+                    }
+                    None if utxos_completas => return Err(RustifyError::InputInexistente),
+                    None => valor_inputs = None,
+                }
+            }
+            match valor_inputs {
+                Some(valor_inputs) if valor_inputs < valor_outputs => {
+                    return Err(RustifyError::MontosInvalidos)
+                }
+                Some(valor_inputs) => {
+                    fees = match fees {
+                        Some(fees) => Some(sumar_monto(fees, valor_inputs - valor_outputs)?),
+                        None => None,
+                    }
+                }
+                None => fees = None,
+            }
+        }
+        creadas.insert(Txn::obtain_tx_id(txn.as_bytes()), txn);
+    }
+
+    if costo_sigops > MAX_COSTO_SIGOPS_BLOQUE {
+        return Err(RustifyError::BloqueExcedeSigops);
+    }
+    if let Some(fees) = fees {
+        if sumar_outputs(&bloque.txns[0])? > subsidio(altura) + fees {
+            return Err(RustifyError::RecompensaCoinbaseExcedida);
+        }
+    }
+    Ok(())
+}
+
+/// Verifica los scripts de una transacción recibida para la memoria, con las flags de estandaridad.
+/// El output que gasta cada input se busca en el set de UTXOs o entre las transacciones en memoria;
+/// los inputs cuyo output no se conoce, o que gastan outputs witness, no se pueden verificar y se aceptan.
+pub fn verificar_scripts_txn(
+    txn: &Txn,
+    set: &SetUtxos,
+    memoria: &[(String, Txn, Option<u64>)],
+) -> Result<(), RustifyError> {
+    for (index, input) in txn.tx_in.iter().enumerate() {
+        let outpoint = input.obtain_tx_id_of_previous_output();
+        let previa = set.utxos.get(&outpoint).or_else(|| {
+            memoria
+                .iter()
+                .find(|(txid, _, _)| *txid == outpoint.0)
+                .map(|(_, previa, _)| previa)
+        });
+        let output = previa.and_then(|previa| previa.tx_out.get(outpoint.1 as usize));
+        if let Some(output) =
+            output.filter(|output| !gasta_witness(&input.signature_script, output))
+        {
+            verify_input(txn, index, output, FLAGS_ESTANDAR)?;
+        }
+    }
+    Ok(())
+}
+
+/// Chequea que la primera transacción del bloque sea la única coinbase, y que desde la altura
+/// de activación de BIP34 su script empiece con la altura del bloque.
+fn validar_coinbase(bloque: &SerializedBlock, altura: u32) -> Result<(), RustifyError> {
+    match bloque.txns.first() {
+        Some(coinbase) if coinbase.es_coinbase() => {}
+        _ => return Err(RustifyError::CoinbaseInvalida),
+    }
+    if bloque.txns[1..].iter().any(|txn| txn.es_coinbase()) {
+        return Err(RustifyError::CoinbaseInvalida);
+    }
+    if altura >= parametros_red().altura_bip34
+        && !bloque.txns[0].tx_in[0]
+            .signature_script
+            .starts_with(&script_altura(altura))
+    {
+        return Err(RustifyError::AlturaCoinbaseInvalida);
+    }
+    Ok(())
+}
+
+/// Busca el output que gasta un input: primero entre las transacciones anteriores del mismo bloque,
+/// y luego en el set de UTXOs. Devuelve None si no se encuentra, y error si la transacción es conocida
+/// pero no tiene ese output. Si el output es de una coinbase del set cuya altura no se conoce,
+/// no se puede chequear su madurez, así que también devuelve None.
+fn buscar_output<'a>(
+    outpoint: &TrxKey,
+    creadas: &HashMap<String, &'a Txn>,
+    set: &'a SetUtxos,
+    altura: u32,
+) -> Result<Option<OutputPrevio<'a>>, RustifyError> {
+    let (txn, altura_previo) = match (creadas.get(&outpoint.0), set.utxos.get(outpoint)) {
+        (Some(txn), _) => (*txn, altura),
+        (None, Some(txn)) => match set.alturas.get(outpoint) {
+            Some(altura_previo) => (txn, *altura_previo),
+            None if txn.es_coinbase() => return Ok(None),
+            None => (txn, 0),
+        },
+        (None, None) => return Ok(None),
+    };
+    let output = txn
+        .tx_out
+        .get(outpoint.1 as usize)
+        .ok_or(RustifyError::InputInexistente)?;
+    Ok(Some(OutputPrevio {
+        output,
+        altura: altura_previo,
+        es_coinbase: txn.es_coinbase(),
+    }))
+}
+
+/// Suma los montos de los outputs de la transacción, chequeando que cada uno y el total estén entre 0 y el máximo de satoshis.
+fn sumar_outputs(txn: &Txn) -> Result<i64, RustifyError> {
+    let mut total: i64 = 0;
+    for output in &txn.tx_out {
+        total = sumar_monto(total, output.value_amount_satoshis)?;
+    }
+    Ok(total)
+}
+
+/// Suma un monto a un total, chequeando que el monto y el resultado estén entre 0 y el máximo de satoshis.
+pub fn sumar_monto(total: i64, valor: i64) -> Result<i64, RustifyError> {
+    if !(0..=MAX_SATOSHIS).contains(&valor) {
+        return Err(RustifyError::MontosInvalidos);
+    }
+    match total.checked_add(valor) {
+        Some(total) if total <= MAX_SATOSHIS => Ok(total),
+        _ => Err(RustifyError::MontosInvalidos),
+    }
+}
+
+/// Subsidio de la coinbase en la altura indicada: se reduce a la mitad cada intervalo de halving.
+pub fn subsidio(altura: u32) -> i64 {
+    let halvings = altura / parametros_red().intervalo_halving;
+    if halvings >= 64 {
+        return 0;
+    }
+    SUBSIDIO_INICIAL >> halvings
+}
+
+/// Comienzo del script de la coinbase con la altura del bloque (BIP34): la altura como número de script,
+/// con el push mínimo (OP_0, OP_1 a OP_16, o sus bytes little endian con un bit de signo).
+fn script_altura(altura: u32) -> Vec<u8> {
+    match altura {
+        0 => vec![OP_0],
+        1..=16 => vec![OP_1 + altura as u8 - 1],
+        _ => {
+            let mut bytes = altura.to_le_bytes().to_vec();
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            if bytes.last().is_some_and(|byte| byte & 0x80 != 0) {
+                bytes.push(0);
+            }
+            [vec![bytes.len() as u8], bytes].concat()
+        }
+    }
+}
+
+/// Cuenta las operaciones de firma de un script. Si es preciso, un CHECKMULTISIG precedido por OP_1 a OP_16
+/// cuenta esa cantidad de claves; si no, cuenta el máximo de claves de un multisig.
+fn contar_sigops(script: &[u8], preciso: bool) -> usize {
+    let mut sigops = 0;
+    let mut opcode_anterior = None;
+    for (opcode, _) in operaciones_script(script) {
+        match opcode {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => sigops += 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                sigops += match opcode_anterior {
+                    Some(op_n @ OP_1..=OP_16) if preciso => (op_n - OP_1 + 1) as usize,
+                    _ => MAX_CLAVES_MULTISIG,
+                }
+            }
+            _ => {}
+        }
+        opcode_anterior = Some(opcode);
+    }
+    sigops
+}
+
+/// Operaciones de firma de los scripts de los inputs y outputs de la transacción, contadas sin precisión.
+fn contar_sigops_legacy(txn: &Txn) -> usize {
+    let inputs: usize = txn
+        .tx_in
+        .iter()
+        .map(|input| contar_sigops(&input.signature_script, false))
+        .sum();
+    let outputs: usize = txn
+        .tx_out
+        .iter()
+        .map(|output| contar_sigops(&output.pk_script, false))
+        .sum();
+    inputs + outputs
+}
+
+/// Operaciones de firma del redeem script de un input que gasta un output P2SH (BIP16): el último push
+/// del script del input, contadas con precisión. Si el script del input no es solamente de pushes, no cuenta ninguna.
+fn contar_sigops_p2sh(signature_script: &[u8]) -> usize {
+    let operaciones = operaciones_script(signature_script);
+    if operaciones.iter().any(|(opcode, _)| *opcode > OP_16) {
+        return 0;
+    }
+    match operaciones.last() {
+        Some((_, redeem_script)) => contar_sigops(redeem_script, true),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_header::BlockHeader;
+    use crate::compactsize::CompactSize;
+    use crate::outpoint::OutPoint;
+    use crate::script_interpreter::{OP_EQUAL, OP_HASH160, OP_PUSHDATA1};
+    use crate::txin::TxIn;
+    use bitcoin_hashes::{hash160, Hash};
+
+    const ALTURA: u32 = 300_000;
+
+    fn txn_de_prueba(inputs: Vec<TxIn>, valores: &[i64]) -> Txn {
+        let tx_out: Vec<TxOut> = valores
+            .iter()
+            .map(|valor| TxOut {
+                value_amount_satoshis: *valor,
+                pk_script_bytes: CompactSize::new(1),
+                pk_script: vec![OP_1],
+            })
+            .collect();
+        Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(inputs.len() as u64),
+            tx_in: inputs,
+            tx_out_count: CompactSize::new(tx_out.len() as u64),
+            tx_out,
+            tx_lock_time: 0,
+        }
+    }
+
+    fn coinbase(altura: u32, valor: i64) -> Txn {
+        let script = script_altura(altura);
+        let input = TxIn {
+            previous_output: OutPoint {
+                hash_previous_output_txid: [0; 32],
+                output_index: 0xffffffff,
+            },
+            script_bytes: CompactSize::new(script.len() as u64),
+            signature_script: script,
+            sequence: 0xffffffff,
+        };
+        txn_de_prueba(vec![input], &[valor])
+    }
+
+    fn bloque_de_prueba(txns: Vec<Txn>) -> Result<SerializedBlock, RustifyError> {
+        Ok(SerializedBlock {
+            block_header: BlockHeader::from_bytes(&parametros_red().genesis_header)?,
+            txn_count: CompactSize::new(txns.len() as u64),
+            txns,
+        })
+    }
+
+    fn gastar(txn: &Txn, indice: u32) -> TxIn {
+        TxIn::new(&(Txn::obtain_tx_id(txn.as_bytes()), indice), vec![])
+    }
+
+    /// Set de UTXOs con los outputs de la transacción, creados en la altura indicada.
+    fn set_con(txn: &Txn, altura: u32) -> SetUtxos {
+        let mut set = SetUtxos::default();
+        for indice in 0..txn.tx_out.len() as u32 {
+            let clave = (Txn::obtain_tx_id(txn.as_bytes()), indice);
+            set.alturas.insert(clave.clone(), altura);
+            set.utxos.insert(clave, txn.clone());
+        }
+        set
+    }
+
+    /// Test para chequear que se acepta un bloque cuya coinbase cobra exactamente el subsidio más las fees,
+    /// incluyendo una transacción que gasta un output de otra del mismo bloque.
+    #[test]
+    fn test_bloque_valido() -> Result<(), RustifyError> {
+        let previa = txn_de_prueba(vec![gastar(&coinbase(1, 0), 0)], &[10_000]);
+        let set = set_con(&previa, ALTURA - 1);
+        let gasto = txn_de_prueba(vec![gastar(&previa, 0)], &[9_000]);
+        let gasto_en_bloque = txn_de_prueba(vec![gastar(&gasto, 0)], &[8_500]);
+        let bloque = bloque_de_prueba(vec![
+            coinbase(ALTURA, subsidio(ALTURA) + 1_500),
+            gasto,
+            gasto_en_bloque,
+        ])?;
+        validar_transacciones_bloque(&bloque, ALTURA, &set, true)
+    }
+
+    /// Test para chequear los distintos motivos de rechazo de un bloque.
+    #[test]
+    fn test_motivos_de_rechazo() -> Result<(), RustifyError> {
+        let previa = txn_de_prueba(vec![gastar(&coinbase(1, 0), 0)], &[10_000]);
+        let set = set_con(&previa, ALTURA - 1);
+        let gasto = txn_de_prueba(vec![gastar(&previa, 0)], &[9_000]);
+        let validar = |txns: Vec<Txn>| -> Result<(), RustifyError> {
+            validar_transacciones_bloque(&bloque_de_prueba(txns)?, ALTURA, &set, true)
+        };
+
+        let otro_gasto = txn_de_prueba(vec![gastar(&previa, 0)], &[8_000]);
+        assert_eq!(
+            validar(vec![coinbase(ALTURA, 0), gasto.clone(), otro_gasto]),
+            Err(RustifyError::DobleGasto)
+        );
+        assert_eq!(
+            validar(vec![
+                coinbase(ALTURA, subsidio(ALTURA) + 1_001),
+                gasto.clone()
+            ]),
+            Err(RustifyError::RecompensaCoinbaseExcedida)
+        );
+        let gasta_de_mas = txn_de_prueba(vec![gastar(&previa, 0)], &[10_001]);
+        assert_eq!(
+            validar(vec![coinbase(ALTURA, 0), gasta_de_mas]),
+            Err(RustifyError::MontosInvalidos)
+        );
+        assert_eq!(
+            validar(vec![coinbase(ALTURA - 1, 0), gasto.clone()]),
+            Err(RustifyError::AlturaCoinbaseInvalida)
+        );
+        assert_eq!(
+            validar(vec![gasto.clone()]),
+            Err(RustifyError::CoinbaseInvalida)
+        );
+        let inexistente = txn_de_prueba(vec![gastar(&previa, 1)], &[1]);
+        assert_eq!(
+            validar(vec![coinbase(ALTURA, 0), inexistente]),
+            Err(RustifyError::InputInexistente)
+        );
+
+        // Un output cuyo pk_script termina en falso no se puede gastar.
+        let mut bloqueada = previa.clone();
+        bloqueada.tx_out[0].pk_script = vec![OP_0];
+        let set = set_con(&bloqueada, ALTURA - 1);
+        let gasto = txn_de_prueba(vec![gastar(&bloqueada, 0)], &[9_000]);
+        assert_eq!(
+            validar_transacciones_bloque(
+                &bloque_de_prueba(vec![coinbase(ALTURA, 0), gasto])?,
+                ALTURA,
+                &set,
+                true
+            ),
+            Err(RustifyError::ScriptInvalido)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear que un output de coinbase se puede gastar recién 100 bloques después de creado.
+    #[test]
+    fn test_madurez_coinbase() -> Result<(), RustifyError> {
+        let coinbase_previa = coinbase(ALTURA - 99, subsidio(ALTURA));
+        let gasto = txn_de_prueba(vec![gastar(&coinbase_previa, 0)], &[1_000]);
+        let bloque = bloque_de_prueba(vec![coinbase(ALTURA, 0), gasto])?;
+
+        let set = set_con(&coinbase_previa, ALTURA - 99);
+        assert_eq!(
+            validar_transacciones_bloque(&bloque, ALTURA, &set, true),
+            Err(RustifyError::CoinbaseInmadura)
+        );
+        let set = set_con(&coinbase_previa, ALTURA - 100);
+        validar_transacciones_bloque(&bloque, ALTURA, &set, true)?;
+
+        // Si no se conoce la altura de la coinbase gastada, el output se trata como no encontrado.
+        let mut set = set_con(&coinbase_previa, ALTURA - 99);
+        set.alturas.clear();
+        assert_eq!(
+            validar_transacciones_bloque(&bloque, ALTURA, &set, true),
+            Err(RustifyError::InputInexistente)
+        );
+        validar_transacciones_bloque(&bloque, ALTURA, &set, false)
+    }
+
+    /// Test para chequear que los montos de los outputs gastados, y su suma, tienen que estar
+    /// entre 0 y el máximo de satoshis, aunque otros inputs de la transacción sean desconocidos.
+    #[test]
+    fn test_montos_de_inputs_fuera_de_rango() -> Result<(), RustifyError> {
+        let previa = txn_de_prueba(
+            vec![gastar(&coinbase(1, 0), 0)],
+            &[MAX_SATOSHIS, MAX_SATOSHIS, -1],
+        );
+        let set = set_con(&previa, ALTURA - 1);
+        let validar = |inputs: Vec<TxIn>| -> Result<(), RustifyError> {
+            let gasto = txn_de_prueba(inputs, &[1_000]);
+            let bloque = bloque_de_prueba(vec![coinbase(ALTURA, 0), gasto])?;
+            validar_transacciones_bloque(&bloque, ALTURA, &set, false)
+        };
+
+        validar(vec![gastar(&previa, 0)])?;
+        assert_eq!(
+            validar(vec![gastar(&previa, 0), gastar(&previa, 1)]),
+            Err(RustifyError::MontosInvalidos)
+        );
+        let desconocida = txn_de_prueba(vec![gastar(&coinbase(2, 0), 0)], &[1]);
+        assert_eq!(
+            validar(vec![gastar(&desconocida, 0), gastar(&previa, 2)]),
+            Err(RustifyError::MontosInvalidos)
+        );
+        Ok(())
+    }
+
+    /// Test para chequear que con un set de UTXOs incompleto se aceptan los inputs desconocidos,
+    /// sin validar la recompensa de la coinbase.
+    #[test]
+    fn test_inputs_desconocidos_con_set_incompleto() -> Result<(), RustifyError> {
+        let desconocida = txn_de_prueba(vec![gastar(&coinbase(1, 0), 0)], &[10_000]);
+        let gasto = txn_de_prueba(vec![gastar(&desconocida, 0)], &[9_000]);
+        let bloque = bloque_de_prueba(vec![coinbase(ALTURA, subsidio(ALTURA) + 5_000), gasto])?;
+        let set = SetUtxos::default();
+        assert_eq!(
+            validar_transacciones_bloque(&bloque, ALTURA, &set, true),
+            Err(RustifyError::InputInexistente)
+        );
+        validar_transacciones_bloque(&bloque, ALTURA, &set, false)
+    }
+
+    /// Test para chequear que una transacción para la memoria se verifica contra los outputs conocidos,
+    /// en el set de UTXOs o en la memoria, y que los inputs desconocidos se aceptan.
+    #[test]
+    fn test_verificar_scripts_txn() {
+        let mut bloqueada = txn_de_prueba(vec![gastar(&coinbase(1, 0), 0)], &[10_000]);
+        bloqueada.tx_out[0].pk_script = vec![OP_0];
+        let gasto = txn_de_prueba(vec![gastar(&bloqueada, 0)], &[9_000]);
+        let set = SetUtxos::default();
+        assert_eq!(verificar_scripts_txn(&gasto, &set, &[]), Ok(()));
+
+        let memoria = vec![(
+            Txn::obtain_tx_id(bloqueada.as_bytes()),
+            bloqueada.clone(),
+            None,
+        )];
+        assert_eq!(
+            verificar_scripts_txn(&gasto, &set, &memoria),
+            Err(RustifyError::ScriptInvalido)
+        );
+        assert_eq!(
+            verificar_scripts_txn(&gasto, &set_con(&bloqueada, ALTURA), &[]),
+            Err(RustifyError::ScriptInvalido)
+        );
+    }
+
+    /// Test para chequear que se aceptan sin verificar los inputs que gastan outputs P2WPKH, nativos
+    /// o envueltos en P2SH, cuyas firmas están en el witness.
+    #[test]
+    fn test_verificar_scripts_txn_witness() -> Result<(), RustifyError> {
+        let mut programa = vec![OP_0, 20];
+        programa.extend([7; 20]);
+        let mut p2sh = vec![OP_HASH160, 20];
+        p2sh.extend(hash160::Hash::hash(&programa).to_byte_array());
+        p2sh.push(OP_EQUAL);
+
+        let mut previa = txn_de_prueba(vec![gastar(&coinbase(1, 0), 0)], &[10_000, 10_000]);
+        previa.tx_out[0].pk_script = programa.clone();
+        previa.tx_out[1].pk_script = p2sh;
+        let set = set_con(&previa, ALTURA);
+
+        let p2wpkh = txn_de_prueba(vec![gastar(&previa, 0)], &[9_000]);
+        assert_eq!(
+            verify_input(&p2wpkh, 0, &previa.tx_out[0], FLAGS_ESTANDAR),
+            Err(RustifyError::ScriptNoEstandar)
+        );
+        assert_eq!(verificar_scripts_txn(&p2wpkh, &set, &[]), Ok(()));
+
+        let mut input = gastar(&previa, 1);
+        input.signature_script = vec![programa.len() as u8];
+        input.signature_script.extend(&programa);
+        let p2sh_p2wpkh = txn_de_prueba(vec![input], &[9_000]);
+        assert_eq!(
+            verify_input(&p2sh_p2wpkh, 0, &previa.tx_out[1], FLAGS_ESTANDAR),
+            Err(RustifyError::ScriptNoEstandar)
+        );
+        assert_eq!(verificar_scripts_txn(&p2sh_p2wpkh, &set, &[]), Ok(()));
+        Ok(())
+    }
+
+    /// Test para chequear el subsidio en los halvings y la codificación de la altura en la coinbase.
+    #[test]
+    fn test_subsidio_y_script_altura() {
+        let intervalo = parametros_red().intervalo_halving;
+        assert_eq!(subsidio(0), 5_000_000_000);
+        assert_eq!(subsidio(intervalo - 1), 5_000_000_000);
+        assert_eq!(subsidio(intervalo), 2_500_000_000);
+        assert_eq!(subsidio(intervalo * 64), 0);
+
+        assert_eq!(script_altura(0), vec![OP_0]);
+        assert_eq!(script_altura(16), vec![OP_16]);
+        assert_eq!(script_altura(128), vec![0x02, 0x80, 0x00]);
+        assert_eq!(script_altura(2434337), vec![0x03, 0x21, 0x25, 0x25]);
+    }
+
+    /// Test para chequear el conteo de operaciones de firma, con y sin precisión y en un redeem script P2SH.
+    #[test]
+    fn test_contar_sigops() {
+        // 2 de 3 multisig: OP_2 <3 claves> OP_3 OP_CHECKMULTISIG
+        let mut multisig = vec![0x52];
+        for _ in 0..3 {
+            multisig.push(33);
+            multisig.extend_from_slice(&[2; 33]);
+        }
+        multisig.extend_from_slice(&[0x53, OP_CHECKMULTISIG]);
+        assert_eq!(contar_sigops(&multisig, false), MAX_CLAVES_MULTISIG);
+        assert_eq!(contar_sigops(&multisig, true), 3);
+
+        // Script del input: OP_0 <firma> OP_PUSHDATA1 <redeem script>
+        let mut signature_script = vec![OP_0, 2, 0x30, 0x01, OP_PUSHDATA1, multisig.len() as u8];
+        signature_script.extend_from_slice(&multisig);
+        assert_eq!(contar_sigops_p2sh(&signature_script), 3);
+        // Los datos pusheados no cuentan como operaciones.
+        assert_eq!(contar_sigops(&signature_script, false), 0);
+    }
+}
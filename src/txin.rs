@@ -1,4 +1,8 @@
-use crate::{compactsize::CompactSize, errors::RustifyError, outpoint::OutPoint};
+use crate::{
+    compactsize::{leer_bytes, CompactSize},
+    errors::RustifyError,
+    outpoint::OutPoint,
+};
 
 type TrxKey = (String, u32);
 
@@ -26,20 +30,20 @@ impl TxIn {
         mut index: usize,
     ) -> Result<(TxIn, usize), RustifyError> {
         let previous_output =
-            OutPoint::from_bytes(raw_transaction_bytes[index..index + 36].to_vec());
+            OutPoint::from_bytes(leer_bytes(&raw_transaction_bytes, index, 36)?.to_vec());
 
         index += 36;
 
-        let (script_bytes, csize_index) =
-            CompactSize::parse_from_byte_array(&raw_transaction_bytes[index..index + 10]);
-        index += csize_index;
-        let updated_index_sig_script = script_bytes.value() as usize;
+        let (largo_script, siguiente) = CompactSize::leer(&raw_transaction_bytes, index)?;
+        let script_bytes = CompactSize::new(largo_script);
+        index = siguiente;
         let signature_script: Vec<u8> =
-            raw_transaction_bytes[index..index + updated_index_sig_script].to_vec();
+            leer_bytes(&raw_transaction_bytes, index, largo_script as usize)?.to_vec();
 
-        index += updated_index_sig_script;
+        index += signature_script.len();
 
-        let sequence = u32::from_le_bytes(raw_transaction_bytes[index..index + 4].try_into()?);
+        let sequence =
+            u32::from_le_bytes(leer_bytes(&raw_transaction_bytes, index, 4)?.try_into()?);
 
         index += 4;
 
@@ -3,8 +3,12 @@ use std::{collections::HashMap, vec};
 use bitcoin_hashes::{sha256d, Hash};
 
 use crate::{
-    account::Account, compactsize::CompactSize, errors::RustifyError, locktime::LockTime,
-    txin::TxIn, txout::TxOut,
+    account::Account,
+    compactsize::{leer_bytes, CompactSize},
+    errors::RustifyError,
+    locktime::LockTime,
+    txin::TxIn,
+    txout::TxOut,
 };
 
 type TrxKey = (String, u32);
@@ -58,11 +62,11 @@ impl Txn {
         raw_transaction_bytes: Vec<u8>,
         mut index: usize,
     ) -> Result<(Txn, usize), RustifyError> {
-        let version = i32::from_le_bytes(raw_transaction_bytes[index..index + 4].try_into()?);
+        let version = i32::from_le_bytes(leer_bytes(&raw_transaction_bytes, index, 4)?.try_into()?);
         index += 4;
-        let (tx_in_count, csize_index) =
-            CompactSize::parse_from_byte_array(&raw_transaction_bytes[index..index + 10]);
-        index += csize_index;
+        let (cant_inputs, siguiente) = CompactSize::leer(&raw_transaction_bytes, index)?;
+        let tx_in_count = CompactSize::new(cant_inputs);
+        index = siguiente;
         let mut tx_in: Vec<TxIn> = vec![];
         let mut transaction_input: TxIn;
 
@@ -71,10 +75,9 @@ impl Txn {
             tx_in.push(transaction_input);
         }
 
-        let (tx_out_count, csize_index) =
-            CompactSize::parse_from_byte_array(&raw_transaction_bytes[index..index + 10]);
-
-        index += csize_index;
+        let (cant_outputs, siguiente) = CompactSize::leer(&raw_transaction_bytes, index)?;
+        let tx_out_count = CompactSize::new(cant_outputs);
+        index = siguiente;
 
         let mut tx_out: Vec<TxOut> = vec![];
         let mut transaction_ouput: TxOut;
@@ -84,7 +87,8 @@ impl Txn {
             tx_out.push(transaction_ouput);
         }
 
-        let tx_lock_time = LockTime::from_bytes(raw_transaction_bytes[index..index + 4].to_vec());
+        let tx_lock_time =
+            LockTime::from_bytes(leer_bytes(&raw_transaction_bytes, index, 4)?.to_vec());
         index += 4;
 
         Ok((
@@ -125,6 +129,14 @@ impl Txn {
         bytes_transaction
     }
 
+    /// Indica si es una transacción coinbase: tiene un único input, que no gasta ningún output
+    /// (txid nulo e índice 0xffffffff).
+    pub fn es_coinbase(&self) -> bool {
+        self.tx_in.len() == 1
+            && self.tx_in[0].previous_output.hash_previous_output_txid == [0; 32]
+            && self.tx_in[0].previous_output.output_index == 0xffffffff
+    }
+
     /// Obtiene el TXID de la transaccion, en tipo String,
     /// desde un inventario (mensajes Inv)
     pub fn obtain_txid_from_inventory(mut inventory: Vec<u8>) -> String {
@@ -145,6 +157,8 @@ impl Txn {
 mod tests {
 
     use super::Txn;
+    use crate::errors::RustifyError;
+
     #[test]
     fn test_obtain_txid() {
         let raw_txn = "020000000181ebdb2d1140794034dff51b184c9e0ffd51bc9644be5cdd750d0173888c30ff0100000000fdffffff0217751000000000001976a914a7165cba93aeec181da155e04680d3bf84f960cb88aca219719d000000001976a914bdd785fe75fb2ead304f5e66adf05af8b9fcc1a388ac5a3f2500";
@@ -160,6 +174,27 @@ mod tests {
         )
     }
 
+    /// Test para chequear que una transacción truncada se rechaza con error en lugar de hacer panic,
+    /// aunque su último script sea más corto que un compactsize de 9 bytes.
+    #[test]
+    fn test_txn_truncada() -> Result<(), RustifyError> {
+        let raw_txn = "0100000001000000000000000000000000000000000000000000000000000000000000000000000000026a51ffffffff010000000000000000015100000000";
+        let bytes = (0..raw_txn.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&raw_txn[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+        let (txn, largo) = Txn::from_bytes(bytes.clone(), 0)?;
+        assert_eq!(largo, bytes.len());
+        assert_eq!(txn.as_bytes(), bytes);
+        for corte in 0..bytes.len() {
+            assert_eq!(
+                Txn::from_bytes(bytes[..corte].to_vec(), 0).map(|_| ()),
+                Err(RustifyError::MensajeMalformado)
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_obtain_txid_from_inventory() {
         let vect: Vec<u8> = vec![
@@ -1,4 +1,8 @@
-use crate::{account::Account, compactsize::CompactSize, errors::RustifyError};
+use crate::{
+    account::Account,
+    compactsize::{leer_bytes, CompactSize},
+    errors::RustifyError,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TxOut {
@@ -23,17 +27,16 @@ impl TxOut {
         mut index: usize,
     ) -> Result<(TxOut, usize), RustifyError> {
         let value_amount_satoshis =
-            i64::from_le_bytes(raw_transaction_bytes[index..index + 8].try_into()?);
+            i64::from_le_bytes(leer_bytes(&raw_transaction_bytes, index, 8)?.try_into()?);
         index += 8;
 
-        let (pk_script_bytes, csize_index) =
-            CompactSize::parse_from_byte_array(&raw_transaction_bytes[index..index + 10]);
-        index += csize_index;
+        let (largo_script, siguiente) = CompactSize::leer(&raw_transaction_bytes, index)?;
+        let pk_script_bytes = CompactSize::new(largo_script);
+        index = siguiente;
 
-        let updated_index_pk_script = pk_script_bytes.value() as usize;
         let pk_script: Vec<u8> =
-            raw_transaction_bytes[index..index + updated_index_pk_script].to_vec();
-        index += updated_index_pk_script;
+            leer_bytes(&raw_transaction_bytes, index, largo_script as usize)?.to_vec();
+        index += pk_script.len();
 
         Ok((
             TxOut {
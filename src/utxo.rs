@@ -1,7 +1,10 @@
 use crate::{
+    block_header::{leer_headers, BlockHeader},
+    compactsize::CompactSize,
     config::Config,
     errors::RustifyError,
-    logger::{log, log_with_parameters, Action, Lvl},
+    logger::{log, log_re_err, log_with_parameters, Action, Lvl},
+    red::parametros_red,
     serialized_block::SerializedBlock,
     txn::Txn,
 };
@@ -9,6 +12,7 @@ use std::{
     collections::HashMap,
     fs::{self, DirEntry, File, ReadDir},
     io::Read,
+    path::Path,
     sync::mpsc::Sender,
 };
 
@@ -16,6 +20,95 @@ use std::{
 type TrxKey = (String, u32);
 type TrxHashMap<T> = HashMap<TrxKey, T>;
 
+/// Extensión de los archivos de bloques en el directorio blocks.
+const EXTENSION_BLOQUE: &str = "txt";
+/// Extensión de los archivos de undo, que se guardan junto al archivo de cada bloque.
+const EXTENSION_UNDO: &str = "undo";
+
+/// Set de UTXOs, junto con la altura del bloque en el que se creó cada una.
+/// Lo comparten el nodo, que conecta y desconecta los bloques, y la wallet, que lo consulta.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SetUtxos {
+    pub utxos: TrxHashMap<Txn>,
+    pub alturas: TrxHashMap<u32>,
+}
+
+/// Output gastado por un bloque, con los datos necesarios para volver a agregarlo
+/// al set de UTXOs si el bloque se desconecta.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputGastado {
+    pub outpoint: TrxKey,
+    /// Altura del bloque en el que se creó el output (0 si no se conoce).
+    pub altura: u32,
+    pub valor: i64,
+    pub pk_script: Vec<u8>,
+}
+
+/// Datos de undo de un bloque: los outputs que gastó, en el orden en que los gastó.
+/// Los inputs que no gastan ninguna UTXO conocida (por el corte de fecha) no se registran.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UndoBloque {
+    pub gastados: Vec<OutputGastado>,
+}
+
+impl UndoBloque {
+    /// Serializa los datos de undo: la cantidad de outputs, y por cada uno su txid (en hexa), índice,
+    /// altura, valor y pk_script, con los largos variables como compactsize.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = CompactSize::new(self.gastados.len() as u64).as_bytes();
+        for gastado in &self.gastados {
+            let (txid, indice) = &gastado.outpoint;
+            bytes.append(&mut CompactSize::new(txid.len() as u64).as_bytes());
+            bytes.extend_from_slice(txid.as_bytes());
+            bytes.extend_from_slice(&indice.to_le_bytes());
+            bytes.extend_from_slice(&gastado.altura.to_le_bytes());
+            bytes.extend_from_slice(&gastado.valor.to_le_bytes());
+            bytes.append(&mut CompactSize::new(gastado.pk_script.len() as u64).as_bytes());
+            bytes.extend_from_slice(&gastado.pk_script);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<UndoBloque, RustifyError> {
+        let (cantidad, mut index) = CompactSize::leer(bytes, 0)?;
+        let mut gastados = vec![];
+        for _ in 0..cantidad {
+            let txid;
+            (txid, index) = leer_con_largo(bytes, index)?;
+            let txid = String::from_utf8(txid)?;
+            let campos = bytes
+                .get(index..index + 16)
+                .ok_or(RustifyError::ErrorAlParsearBloque)?;
+            let indice = u32::from_le_bytes(campos[0..4].try_into()?);
+            let altura = u32::from_le_bytes(campos[4..8].try_into()?);
+            let valor = i64::from_le_bytes(campos[8..16].try_into()?);
+            index += 16;
+            let pk_script;
+            (pk_script, index) = leer_con_largo(bytes, index)?;
+            gastados.push(OutputGastado {
+                outpoint: (txid, indice),
+                altura,
+                valor,
+                pk_script,
+            });
+        }
+        Ok(UndoBloque { gastados })
+    }
+}
+
+/// Lee un campo precedido por su largo como compactsize. Devuelve el campo y el índice siguiente.
+fn leer_con_largo(bytes: &[u8], index: usize) -> Result<(Vec<u8>, usize), RustifyError> {
+    let (largo, index) = CompactSize::leer(bytes, index)?;
+    let fin = usize::try_from(largo)
+        .ok()
+        .and_then(|largo| index.checked_add(largo))
+        .ok_or(RustifyError::ErrorAlParsearBloque)?;
+    let campo = bytes
+        .get(index..fin)
+        .ok_or(RustifyError::ErrorAlParsearBloque)?;
+    Ok((campo.to_vec(), fin))
+}
+
 /// Obtiene listado de UTXOs realizando los siguientes pasos:
 /// 1) Lee los bloques descargados (se considera precondición que la descarga de bloques fue exitosa)
 /// y los parsea en las estructuras propias de su constitución
@@ -25,10 +118,12 @@ type TrxHashMap<T> = HashMap<TrxKey, T>;
 ///
 /// Extra: Nosotros consideramos esta cuenta como valida
 /// OUTPUTS_TOTAL - (INPUTS_TOTAL - INPUTS_SIN_MATCH) = UTXO
+///
+/// Obtiene también la altura del bloque en el que se creó cada UTXO, según su posición en la cadena de headers.
 pub fn obtain_utxo(
     config: &Config,
     logger_sender: &Sender<String>,
-) -> Result<TrxHashMap<Txn>, RustifyError> {
+) -> Result<SetUtxos, RustifyError> {
     let now = std::time::Instant::now();
     log(
         Lvl::Info(Action::UTXO),
@@ -40,8 +135,10 @@ pub fn obtain_utxo(
     let iter_bloques_input = fs::read_dir(&config.blocks_path)?;
     let iter_bloques_utxos = fs::read_dir(&config.blocks_path)?;
 
-    let inputs = obtain_inputs(iter_bloques_input)?;
-    let utxos = obtain_utxos_from(inputs, iter_bloques_utxos, logger_sender)?;
+    let alturas_bloques = alturas_bloques(&config.headers_path);
+    let inputs = obtain_inputs(iter_bloques_input, &alturas_bloques)?;
+    let (utxos, alturas) =
+        obtain_utxos_from(inputs, iter_bloques_utxos, &alturas_bloques, logger_sender)?;
 
     log_with_parameters(
         Lvl::Info(Action::UTXO),
@@ -57,22 +154,61 @@ pub fn obtain_utxo(
         logger_sender,
     );
 
-    Ok(utxos)
+    Ok(SetUtxos { utxos, alturas })
+}
+
+/// Indica si el set de UTXOs incluye todos los outputs no gastados de la cadena: solamente cuando se descargaron
+/// los bloques completos desde el génesis. Si no, los inputs que no se encuentran en el set pueden gastar
+/// outputs anteriores a la fecha inicial, o de bloques que no se descargaron (modos SPV y de filtros compactos).
+pub fn utxos_completas(config: &Config) -> bool {
+    let tiempo_genesis = BlockHeader::from_bytes(&parametros_red().genesis_header)
+        .map(|genesis| genesis.time)
+        .unwrap_or(u32::MAX);
+    !config.modo_spv
+        && !config.filtros_compactos
+        && config.timestamp_bloque_inicial <= tiempo_genesis
+}
+
+/// Altura de cada bloque de la cadena de headers guardada, por su hash.
+/// Si no se puede leer el archivo de headers, se devuelve vacío y las alturas quedan sin conocer.
+fn alturas_bloques(headers_path: &str) -> HashMap<String, u32> {
+    leer_headers(headers_path)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(altura, header)| {
+            (
+                SerializedBlock::obtain_blockhash(header.as_bytes()),
+                altura as u32,
+            )
+        })
+        .collect()
 }
 
 /// Realiza el procedimiento de obtencion de outputs y matcheo con los inputs
-/// para asi obtener finalmente las UTXO
+/// para asi obtener finalmente las UTXO, junto con la altura del bloque de cada una
 fn obtain_utxos_from(
     mut inputs: TrxHashMap<()>,
     dir_blocks: ReadDir,
+    alturas_bloques: &HashMap<String, u32>,
     logger_sender: &Sender<String>,
-) -> Result<TrxHashMap<Txn>, RustifyError> {
+) -> Result<(TrxHashMap<Txn>, TrxHashMap<u32>), RustifyError> {
     let mut buffer: Vec<u8>;
     let mut utxos: TrxHashMap<Txn> = HashMap::new();
+    let mut alturas: TrxHashMap<u32> = HashMap::new();
 
-    for entry in dir_blocks {
+    for entry in dir_blocks.filter(es_archivo_bloque) {
         buffer = obtener_buffer(entry?)?;
         let block = obtener_block_de_buffer(buffer)?;
+        if !en_cadena(&block, alturas_bloques) {
+            continue;
+        }
+        let altura = alturas_bloques
+            .get(&SerializedBlock::obtain_blockhash(
+                block.block_header.as_bytes(),
+            ))
+            .copied()
+            .unwrap_or_default();
 
         for tx_index in 0..block.txns.len() {
             let txid = Txn::obtain_tx_id(block.txns[tx_index].as_bytes());
@@ -83,6 +219,7 @@ fn obtain_utxos_from(
                         inputs.remove(&key);
                     }
                     None => {
+                        alturas.insert(key.clone(), altura);
                         utxos.insert(key, block.txns[tx_index].clone());
                     }
                 }
@@ -91,17 +228,23 @@ fn obtain_utxos_from(
     }
     log_with_parameters(Lvl::Warning(Action::UTXO), format!("Hay {} inputs que no se pudieron matchear con ningun output (por el corte de la fecha que realizamos).", inputs.len()), logger_sender);
 
-    Ok(utxos)
+    Ok((utxos, alturas))
 }
 
-/// Obtiene los inputs de todos los bloques guardados en disco
-fn obtain_inputs(dir_blocks: ReadDir) -> Result<TrxHashMap<()>, RustifyError> {
+/// Obtiene los inputs de todos los bloques de la cadena guardados en disco
+fn obtain_inputs(
+    dir_blocks: ReadDir,
+    alturas_bloques: &HashMap<String, u32>,
+) -> Result<TrxHashMap<()>, RustifyError> {
     let mut buffer: Vec<u8>;
     let mut inputs: TrxHashMap<()> = HashMap::new();
 
-    for entry in dir_blocks {
+    for entry in dir_blocks.filter(es_archivo_bloque) {
         buffer = obtener_buffer(entry?)?;
         let block = obtener_block_de_buffer(buffer)?;
+        if !en_cadena(&block, alturas_bloques) {
+            continue;
+        }
 
         for tx_index in 0..block.txns.len() {
             for input_index in 0..block.txns[tx_index].tx_in.len() {
@@ -115,6 +258,27 @@ fn obtain_inputs(dir_blocks: ReadDir) -> Result<TrxHashMap<()>, RustifyError> {
     Ok(inputs)
 }
 
+/// Indica si el bloque guardado pertenece a la cadena de headers. Durante una reorganización quedan en disco
+/// los bloques desconectados hasta que se conecta la nueva rama, y no tienen que formar parte del set.
+/// Si no se conocen las alturas (no se pudo leer el archivo de headers), se consideran todos los bloques.
+fn en_cadena(bloque: &SerializedBlock, alturas_bloques: &HashMap<String, u32>) -> bool {
+    alturas_bloques.is_empty()
+        || alturas_bloques.contains_key(&SerializedBlock::obtain_blockhash(
+            bloque.block_header.as_bytes(),
+        ))
+}
+
+/// Indica si la entrada del directorio blocks es el archivo de un bloque (y no, por ejemplo, un archivo de undo).
+/// Los errores se dejan pasar, para que los reporte quien lee la entrada.
+pub fn es_archivo_bloque(entry: &std::io::Result<DirEntry>) -> bool {
+    match entry {
+        Ok(entry) => {
+            entry.path().extension().and_then(|ext| ext.to_str()) == Some(EXTENSION_BLOQUE)
+        }
+        Err(_) => true,
+    }
+}
+
 fn obtener_block_de_buffer(buffer: Vec<u8>) -> Result<SerializedBlock, RustifyError> {
     let block = SerializedBlock::from_bytes(&buffer)?;
     Ok(block)
@@ -133,60 +297,31 @@ fn obtener_buffer(entry: DirEntry) -> Result<Vec<u8>, RustifyError> {
     Ok(buffer)
 }
 
-/// Actualiza la lista de utxos dado un nuevo bloque recibido
-/// durante la ejecución de la wallet
+/// Actualiza el set de UTXOs dado un nuevo bloque recibido
+/// durante la ejecución del nodo, que queda en la altura indicada de la cadena.
+/// Los outputs que gasta el bloque se guardan como datos de undo junto al archivo del bloque,
+/// para poder desconectarlo luego con disconnect_block.
 pub fn update_utxo(
-    mut utxos: TrxHashMap<Txn>,
+    set: &mut SetUtxos,
     logger_sender: &Sender<String>,
     new_block: &SerializedBlock,
-) -> Result<TrxHashMap<Txn>, RustifyError> {
+    altura: u32,
+    blocks_path: &str,
+) -> Result<(), RustifyError> {
     let now = std::time::Instant::now();
-    let mut inputs_s_matchear = 0;
-    let mut spent_utxo: TrxHashMap<()> = HashMap::new();
-    let mut cant_outputs = 0;
-
-    //agregado de nuevos outputs y creacion de inputs
-    let mut inputs = HashMap::new();
-    for tx_index in 0..new_block.txns.len() {
-        let txid = Txn::obtain_tx_id(new_block.txns[tx_index].as_bytes());
-
-        for input_index in 0..new_block.txns[tx_index].tx_in.len() {
-            let previous_output =
-                new_block.txns[tx_index].tx_in[input_index].obtain_tx_id_of_previous_output();
-            inputs.insert(previous_output, ());
-        }
+    let cant_outputs: usize = new_block.txns.iter().map(|txn| txn.tx_out.len()).sum();
+    let cant_inputs: usize = new_block.txns.iter().map(|txn| txn.tx_in.len()).sum();
 
-        //Los outputs ahora son nuevos UTXOs
-        for output_index in 0..new_block.txns[tx_index].tx_out.len() {
-            let key = (txid.clone(), output_index as u32);
-            utxos.insert(key, new_block.txns[tx_index].clone());
-            cant_outputs += 1;
-        }
-    }
-
-    //Se matchean las utxo gastadas
-    for input in inputs.keys() {
-        match utxos.get(input) {
-            Some(_k) => {
-                //Una UTXO se transforma en SPENT
-                spent_utxo.insert(input.clone(), ());
-            }
-            None => {
-                inputs_s_matchear += 1;
-            }
-        };
-    }
-    //Se eliminan Spent utxos
-    for key in spent_utxo.keys() {
-        utxos.remove(key);
-    }
+    let undo = connect_block(set, new_block, altura);
+    guardar_undo(&undo, &new_block.block_header, blocks_path)?;
+    let inputs_s_matchear = cant_inputs - undo.gastados.len();
 
     log_with_parameters(
         Lvl::Info(Action::UTXO),
         format!(
             "INFO: {} nuevas UTXO y {} UTXOs se gastaron",
             cant_outputs,
-            spent_utxo.len()
+            undo.gastados.len()
         ),
         logger_sender,
     );
@@ -211,5 +346,304 @@ pub fn update_utxo(
         );
     }
 
-    Ok(utxos)
+    Ok(())
+}
+
+/// Desconecta del set de UTXOs los bloques indicados, que tienen que estar ordenados desde la punta de la cadena,
+/// usando sus datos de undo. Las transacciones que crearon los outputs a restaurar se buscan entre los bloques
+/// desconectados y en los bloques guardados de la cadena que queda activa, indicada por sus headers.
+/// Si a algún bloque le faltan los datos de undo o alguna de esas transacciones, el set se recalcula con
+/// los bloques que quedan en disco (los desconectados ya se tienen que haber borrado).
+pub fn desconectar_bloques(
+    set: &mut SetUtxos,
+    bloques: &[SerializedBlock],
+    headers: &[BlockHeader],
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    for bloque in bloques {
+        let desconectado = leer_undo(&bloque.block_header, &config.blocks_path).and_then(|undo| {
+            let undo = undo.ok_or(RustifyError::NotFound)?;
+            let previas = buscar_txns_previas(&undo, bloques, headers, &config.blocks_path)?;
+            disconnect_block(set, bloque, &undo, &previas)
+        });
+        if let Err(e) = desconectado {
+            log_re_err(Action::UTXO, e, logger_sender);
+            *set = obtain_utxo(config, logger_sender)?;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Busca las transacciones que crearon los outputs gastados según los datos de undo, por txid:
+/// entre los bloques desconectados, y si no, en el bloque guardado de la altura en la que se creó cada output.
+fn buscar_txns_previas(
+    undo: &UndoBloque,
+    desconectados: &[SerializedBlock],
+    headers: &[BlockHeader],
+    blocks_path: &str,
+) -> Result<HashMap<String, Txn>, RustifyError> {
+    let mut previas: HashMap<String, Txn> = desconectados
+        .iter()
+        .flat_map(|bloque| &bloque.txns)
+        .map(|txn| (Txn::obtain_tx_id(txn.as_bytes()), txn.clone()))
+        .collect();
+    for gastado in &undo.gastados {
+        if previas.contains_key(&gastado.outpoint.0) {
+            continue;
+        }
+        let header = headers
+            .get(gastado.altura as usize)
+            .ok_or(RustifyError::NotFound)?;
+        let ruta = format!(
+            "{}/{}.{}",
+            blocks_path,
+            SerializedBlock::obtain_blockhash(header.as_bytes()),
+            EXTENSION_BLOQUE
+        );
+        let bloque = SerializedBlock::from_bytes(&fs::read(ruta)?)?;
+        for txn in bloque.txns {
+            previas.insert(Txn::obtain_tx_id(txn.as_bytes()), txn);
+        }
+    }
+    Ok(previas)
+}
+
+/// Conecta el bloque, que está en la altura indicada de la cadena, al set de UTXOs: procesando las transacciones
+/// en orden, cada input gasta la UTXO correspondiente (si se conoce) y cada output pasa a ser una UTXO nueva,
+/// con la altura del bloque. Devuelve los datos de undo con los outputs gastados.
+pub fn connect_block(
+    set: &mut SetUtxos,
+    bloque: &SerializedBlock,
+    altura_bloque: u32,
+) -> UndoBloque {
+    let mut undo = UndoBloque::default();
+    for txn in &bloque.txns {
+        for input in &txn.tx_in {
+            let outpoint = input.obtain_tx_id_of_previous_output();
+            if let Some(txn_previa) = set.utxos.remove(&outpoint) {
+                let output = &txn_previa.tx_out[outpoint.1 as usize];
+                undo.gastados.push(OutputGastado {
+                    altura: set.alturas.remove(&outpoint).unwrap_or_default(),
+                    valor: output.value_amount_satoshis,
+                    pk_script: output.pk_script.clone(),
+                    outpoint,
+                });
+            }
+        }
+        let txid = Txn::obtain_tx_id(txn.as_bytes());
+        for output_index in 0..txn.tx_out.len() {
+            let key = (txid.clone(), output_index as u32);
+            set.alturas.insert(key.clone(), altura_bloque);
+            set.utxos.insert(key, txn.clone());
+        }
+    }
+    undo
+}
+
+/// Desconecta el bloque del set de UTXOs, dejándolo como estaba antes de conectarlo:
+/// recorriendo las transacciones de la última a la primera, se quitan sus outputs
+/// y se restauran los outputs que gastaron, a partir de los datos de undo del bloque.
+/// Como el set guarda la transacción que creó cada UTXO, se la toma del mismo bloque o de las previas
+/// (por txid). Si falta alguna, o su output no coincide con el de los datos de undo, se devuelve
+/// NotFound sin modificar el set.
+pub fn disconnect_block(
+    set: &mut SetUtxos,
+    bloque: &SerializedBlock,
+    undo: &UndoBloque,
+    previas: &HashMap<String, Txn>,
+) -> Result<(), RustifyError> {
+    let del_bloque: HashMap<String, &Txn> = bloque
+        .txns
+        .iter()
+        .map(|txn| (Txn::obtain_tx_id(txn.as_bytes()), txn))
+        .collect();
+    let mut gastados: HashMap<&TrxKey, (u32, &Txn)> = HashMap::new();
+    for gastado in &undo.gastados {
+        let (txid, indice) = &gastado.outpoint;
+        let txn = del_bloque
+            .get(txid)
+            .copied()
+            .or_else(|| previas.get(txid))
+            .ok_or(RustifyError::NotFound)?;
+        match txn.tx_out.get(*indice as usize) {
+            Some(output)
+                if output.value_amount_satoshis == gastado.valor
+                    && output.pk_script == gastado.pk_script => {}
+            _ => return Err(RustifyError::NotFound),
+        }
+        gastados.insert(&gastado.outpoint, (gastado.altura, txn));
+    }
+    for txn in bloque.txns.iter().rev() {
+        let txid = Txn::obtain_tx_id(txn.as_bytes());
+        for output_index in 0..txn.tx_out.len() {
+            let key = (txid.clone(), output_index as u32);
+            set.utxos.remove(&key);
+            set.alturas.remove(&key);
+        }
+        for input in &txn.tx_in {
+            let outpoint = input.obtain_tx_id_of_previous_output();
+            if let Some((altura, txn_previa)) = gastados.get(&outpoint) {
+                set.alturas.insert(outpoint.clone(), *altura);
+                set.utxos.insert(outpoint, (*txn_previa).clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ruta del archivo de undo del bloque: la del archivo del bloque, con otra extensión.
+fn ruta_undo(header: &BlockHeader, blocks_path: &str) -> String {
+    format!(
+        "{}/{}.{}",
+        blocks_path,
+        SerializedBlock::obtain_blockhash(header.as_bytes()),
+        EXTENSION_UNDO
+    )
+}
+
+/// Guarda los datos de undo del bloque junto al archivo del bloque.
+pub fn guardar_undo(
+    undo: &UndoBloque,
+    header: &BlockHeader,
+    blocks_path: &str,
+) -> Result<(), RustifyError> {
+    fs::create_dir_all(blocks_path)?;
+    fs::write(ruta_undo(header, blocks_path), undo.as_bytes())?;
+    Ok(())
+}
+
+/// Lee los datos de undo del bloque, para desconectarlo. No los borra: se borran junto con el bloque
+/// (ver borrar_undo), una vez que la reorganización que lo desconectó terminó de conectar la nueva rama.
+/// Devuelve None si el bloque no tiene datos de undo (por ejemplo, si se descargó en la descarga inicial).
+pub fn leer_undo(
+    header: &BlockHeader,
+    blocks_path: &str,
+) -> Result<Option<UndoBloque>, RustifyError> {
+    let ruta = ruta_undo(header, blocks_path);
+    if !Path::new(&ruta).exists() {
+        return Ok(None);
+    }
+    Ok(Some(UndoBloque::from_bytes(&fs::read(&ruta)?)?))
+}
+
+/// Borra los datos de undo del bloque, si los tiene.
+pub fn borrar_undo(header: &BlockHeader, blocks_path: &str) -> Result<(), RustifyError> {
+    let ruta = ruta_undo(header, blocks_path);
+    if Path::new(&ruta).exists() {
+        fs::remove_file(&ruta)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{outpoint::OutPoint, txin::TxIn, txout::TxOut};
+
+    fn txn_de_prueba(inputs: Vec<TxIn>, valores: &[i64]) -> Txn {
+        let tx_out: Vec<TxOut> = valores
+            .iter()
+            .map(|valor| TxOut {
+                value_amount_satoshis: *valor,
+                pk_script_bytes: CompactSize::new(25),
+                pk_script: [vec![0x76, 0xa9, 0x14], vec![0; 20], vec![0x88, 0xac]].concat(),
+            })
+            .collect();
+        Txn {
+            version: 1,
+            tx_in_count: CompactSize::new(inputs.len() as u64),
+            tx_in: inputs,
+            tx_out_count: CompactSize::new(tx_out.len() as u64),
+            tx_out,
+            tx_lock_time: 0,
+        }
+    }
+
+    /// Bloque con una coinbase de la altura indicada y las transacciones dadas.
+    fn bloque_de_prueba(altura: u8, txns: Vec<Txn>) -> Result<SerializedBlock, RustifyError> {
+        let coinbase = TxIn {
+            previous_output: OutPoint {
+                hash_previous_output_txid: [0; 32],
+                output_index: 0xffffffff,
+            },
+            script_bytes: CompactSize::new(2),
+            signature_script: vec![0x01, altura],
+            sequence: 0xffffffff,
+        };
+        let mut todas = vec![txn_de_prueba(vec![coinbase], &[5_000_000_000])];
+        todas.extend(txns);
+        Ok(SerializedBlock {
+            block_header: BlockHeader::from_bytes(&parametros_red().genesis_header)?,
+            txn_count: CompactSize::new(todas.len() as u64),
+            txns: todas,
+        })
+    }
+
+    fn clave(txn: &Txn, indice: u32) -> TrxKey {
+        (Txn::obtain_tx_id(txn.as_bytes()), indice)
+    }
+
+    /// Test para chequear que al desconectar un bloque el set de UTXOs y sus alturas
+    /// vuelven a quedar como antes de conectarlo, incluso con un gasto dentro del mismo bloque.
+    #[test]
+    fn test_connect_disconnect_block() -> Result<(), RustifyError> {
+        let previa = txn_de_prueba(
+            vec![TxIn::new(&("11".repeat(32), 0), vec![])],
+            &[1000, 2000],
+        );
+        let mut set = SetUtxos::default();
+        for indice in 0..2 {
+            set.utxos.insert(clave(&previa, indice), previa.clone());
+            set.alturas.insert(clave(&previa, indice), 7);
+        }
+        let set_antes = set.clone();
+
+        let gasto = txn_de_prueba(vec![TxIn::new(&clave(&previa, 1), vec![])], &[1500]);
+        let gasto_en_bloque = txn_de_prueba(vec![TxIn::new(&clave(&gasto, 0), vec![])], &[1400]);
+        let bloque = bloque_de_prueba(9, vec![gasto.clone(), gasto_en_bloque.clone()])?;
+
+        // La altura es la que indica quien conecta el bloque, no la de su coinbase.
+        let undo = connect_block(&mut set, &bloque, 12);
+        assert_eq!(undo.gastados.len(), 2);
+        assert_eq!(undo.gastados[0].altura, 7);
+        assert_eq!(undo.gastados[0].valor, 2000);
+        assert!(!set.utxos.contains_key(&clave(&previa, 1)));
+        assert!(!set.utxos.contains_key(&clave(&gasto, 0)));
+        assert_eq!(set.alturas.get(&clave(&gasto_en_bloque, 0)), Some(&12));
+
+        assert_eq!(
+            disconnect_block(&mut set, &bloque, &undo, &HashMap::new()),
+            Err(RustifyError::NotFound)
+        );
+        let previas = HashMap::from([(clave(&previa, 0).0, previa)]);
+        disconnect_block(&mut set, &bloque, &undo, &previas)?;
+        assert_eq!(set, set_antes);
+        Ok(())
+    }
+
+    /// Test para chequear que los datos de undo se serializan y se vuelven a leer iguales.
+    #[test]
+    fn test_undo_bloque_as_bytes_from_bytes() -> Result<(), RustifyError> {
+        let txn = txn_de_prueba(
+            vec![TxIn::new(&("11".repeat(32), 0), vec![])],
+            &[1000, 2000],
+        );
+        let undo = UndoBloque {
+            gastados: vec![OutputGastado {
+                outpoint: clave(&txn, 1),
+                altura: 2434337,
+                valor: 2000,
+                pk_script: vec![0x51],
+            }],
+        };
+        assert_eq!(UndoBloque::from_bytes(&undo.as_bytes())?, undo);
+        assert!(UndoBloque::from_bytes(&undo.as_bytes()[..20]).is_err());
+        assert_eq!(
+            leer_con_largo(&[0xff; 12], 0),
+            Err(RustifyError::ErrorAlParsearBloque)
+        );
+        Ok(())
+    }
 }
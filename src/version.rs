@@ -1,13 +1,22 @@
+use crate::compactsize::leer_bytes;
+use crate::config::Config;
 use crate::errors::RustifyError;
+use crate::event_loop::ConexionPeer;
 use crate::logger::{log, Action, Lvl};
-use crate::message_handler::handle_specific_message;
-use crate::node::write_to_node;
-use crate::{config::Config, message_header::MessageHeader};
+use crate::message_handler::esperar_respuesta;
+use crate::network_message::{escribir_mensaje, NetworkMessage};
 use chrono::Utc;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 
 const VERSION_SIZE: usize = 110;
+/// Servicio que anuncian los nodos que aceptan filtros bloom (BIP111).
+const NODE_BLOOM: u64 = 1 << 2;
+/// Servicio que anuncian los nodos que sirven filtros compactos de bloques (BIP157).
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+/// Servicio que anuncian los nodos que soportan el transporte cifrado v2 (BIP324).
+const NODE_P2P_V2: u64 = 1 << 11;
 
 #[derive(Debug)]
 pub struct VersionMessage {
@@ -29,21 +38,26 @@ pub struct VersionMessage {
 
 impl VersionMessage {
     pub fn new(receiver: SocketAddr, sender: SocketAddr, config: &Config) -> VersionMessage {
+        let services = match config.transporte_v2 {
+            true => config.node_network_limited | NODE_P2P_V2,
+            false => config.node_network_limited,
+        };
         VersionMessage {
             version: config.version,
-            services: config.node_network_limited,
+            services,
             timestamp: Utc::now().timestamp(),
             receiver_services: config.node_network,
             receiver_address: VersionMessage::procesar_ip(receiver.ip()),
             receiver_port: receiver.port(),
-            sender_services: config.node_network_limited,
+            sender_services: services,
             sender_address: VersionMessage::procesar_ip(sender.ip()),
             sender_port: sender.port(),
             nonce: 0x00,
             user_agent_bytes: config.user_agent_rustify.len() as u8,
             user_agent: config.user_agent_rustify.as_bytes().to_vec(),
             start_height: 0x00,
-            relay: 0x01,
+            // En modo SPV no se reciben transacciones hasta cargar el filtro bloom.
+            relay: if config.modo_spv { 0x00 } else { 0x01 },
         }
     }
 
@@ -60,70 +74,56 @@ impl VersionMessage {
         }
     }
 
-    pub fn as_bytes(&self, config_user_agent_rustify: &str) -> [u8; VERSION_SIZE] {
-        let mut result: [u8; VERSION_SIZE] = [0; VERSION_SIZE];
-        let mut index = 0;
-        result[index..index + 4].copy_from_slice(&self.version.to_le_bytes());
-        index += 4;
-        result[index..index + 8].copy_from_slice(&self.services.to_le_bytes());
-        index += 8;
-        result[index..index + 8].copy_from_slice(&self.timestamp.to_le_bytes());
-        index += 8;
-        result[index..index + 8].copy_from_slice(&self.receiver_services.to_le_bytes());
-        index += 8;
-        result[index..index + 16].copy_from_slice(&self.receiver_address);
-        index += 16;
-        result[index..index + 2].copy_from_slice(&self.receiver_port.to_be_bytes());
-        index += 2;
-        result[index..index + 8].copy_from_slice(&self.sender_services.to_le_bytes());
-        index += 8;
-        result[index..index + 16].copy_from_slice(&self.sender_address);
-        index += 16;
-        result[index..index + 2].copy_from_slice(&self.sender_port.to_be_bytes());
-        index += 2;
-        result[index..index + 8].copy_from_slice(&self.nonce.to_le_bytes());
-        index += 8;
-        result[index] = self.user_agent_bytes;
-        index += 1;
-        result[index..index + config_user_agent_rustify.len()].copy_from_slice(&self.user_agent);
-        index += config_user_agent_rustify.len();
-        result[index..index + 4].copy_from_slice(&self.start_height.to_le_bytes());
-        index += 4;
-        result[index..index + 1].copy_from_slice(&self.relay.to_le_bytes());
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(VERSION_SIZE);
+        result.extend_from_slice(&self.version.to_le_bytes());
+        result.extend_from_slice(&self.services.to_le_bytes());
+        result.extend_from_slice(&self.timestamp.to_le_bytes());
+        result.extend_from_slice(&self.receiver_services.to_le_bytes());
+        result.extend_from_slice(&self.receiver_address);
+        result.extend_from_slice(&self.receiver_port.to_be_bytes());
+        result.extend_from_slice(&self.sender_services.to_le_bytes());
+        result.extend_from_slice(&self.sender_address);
+        result.extend_from_slice(&self.sender_port.to_be_bytes());
+        result.extend_from_slice(&self.nonce.to_le_bytes());
+        result.push(self.user_agent_bytes);
+        result.extend_from_slice(&self.user_agent);
+        result.extend_from_slice(&self.start_height.to_le_bytes());
+        result.push(self.relay);
         result
     }
 
+    /// Parsea el mensaje version recibido, verificando que los bytes alcancen para cada campo.
+    /// El campo relay es opcional (BIP37): si no está, se asume que el peer quiere recibir transacciones.
     pub fn from_bytes(bytes: &[u8]) -> Result<VersionMessage, RustifyError> {
         let mut index = 0;
-        let version = i32::from_le_bytes(bytes[index..index + 4].try_into()?);
+        let version = i32::from_le_bytes(leer_bytes(bytes, index, 4)?.try_into()?);
         index += 4;
-        let services = u64::from_le_bytes(bytes[index..index + 8].try_into()?);
+        let services = u64::from_le_bytes(leer_bytes(bytes, index, 8)?.try_into()?);
         index += 8;
-        let timestamp = i64::from_le_bytes(bytes[index..index + 8].try_into()?);
+        let timestamp = i64::from_le_bytes(leer_bytes(bytes, index, 8)?.try_into()?);
         index += 8;
-        let receiver_services = u64::from_le_bytes(bytes[index..index + 8].try_into()?);
+        let receiver_services = u64::from_le_bytes(leer_bytes(bytes, index, 8)?.try_into()?);
         index += 8;
-        let mut receiver_address = [0u8; 16];
-        receiver_address.copy_from_slice(&bytes[index..index + 16]);
+        let receiver_address: [u8; 16] = leer_bytes(bytes, index, 16)?.try_into()?;
         index += 16;
-        let receiver_port = u16::from_be_bytes(bytes[index..index + 2].try_into()?);
+        let receiver_port = u16::from_be_bytes(leer_bytes(bytes, index, 2)?.try_into()?);
         index += 2;
-        let sender_services = u64::from_le_bytes(bytes[index..index + 8].try_into()?);
+        let sender_services = u64::from_le_bytes(leer_bytes(bytes, index, 8)?.try_into()?);
         index += 8;
-        let mut sender_address = [0u8; 16];
-        sender_address.copy_from_slice(&bytes[index..index + 16]);
+        let sender_address: [u8; 16] = leer_bytes(bytes, index, 16)?.try_into()?;
         index += 16;
-        let sender_port = u16::from_be_bytes(bytes[index..index + 2].try_into()?);
+        let sender_port = u16::from_be_bytes(leer_bytes(bytes, index, 2)?.try_into()?);
         index += 2;
-        let nonce = u64::from_le_bytes(bytes[index..index + 8].try_into()?);
+        let nonce = u64::from_le_bytes(leer_bytes(bytes, index, 8)?.try_into()?);
         index += 8;
-        let user_agent_bytes = bytes[index];
+        let user_agent_bytes = leer_bytes(bytes, index, 1)?[0];
         index += 1;
-        let user_agent = bytes[index..index + (user_agent_bytes as usize)].to_vec();
+        let user_agent = leer_bytes(bytes, index, user_agent_bytes as usize)?.to_vec();
         index += user_agent_bytes as usize;
-        let start_height = i32::from_le_bytes(bytes[index..index + 4].try_into()?);
+        let start_height = i32::from_le_bytes(leer_bytes(bytes, index, 4)?.try_into()?);
         index += 4;
-        let relay = bytes[index];
+        let relay = bytes.get(index).copied().unwrap_or(1);
         Ok(VersionMessage {
             version,
             services,
@@ -145,45 +145,55 @@ impl VersionMessage {
 
 /// Envío y recepción de mensajes version para el handshake del nodo.
 pub fn version(
-    socket: &mut TcpStream,
+    socket: &mut ConexionPeer,
     config: &Config,
     logger_sender: &Sender<String>,
 ) -> Result<(), RustifyError> {
-    let version_message = VersionMessage::new(socket.peer_addr()?, socket.local_addr()?, config);
-    let version_message_bytes = version_message.as_bytes(&config.user_agent_rustify);
-
-    let version_message_header = MessageHeader::new("version".to_string(), &version_message_bytes);
-    let version_message_header_bytes = version_message_header.as_bytes();
-
-    write_to_node(
-        socket,
-        &version_message_header_bytes,
-        &version_message_bytes,
-    )?;
+    let version_message = VersionMessage::new(socket.peer_addr(), socket.local_addr(), config);
+    escribir_mensaje(socket, &NetworkMessage::Version(version_message))?;
     log(
         Lvl::Info(Action::CONNECT),
         "Enviado mensaje version.",
         logger_sender,
     );
 
-    handle_specific_message(socket, "version\0\0\0\0\0".to_string(), logger_sender)?;
+    let respuesta = esperar_respuesta(
+        socket,
+        "version",
+        Duration::from_secs(config.timeout_respuesta_secs),
+        logger_sender,
+    )?;
+    if let NetworkMessage::Version(version_peer) = respuesta {
+        if config.modo_spv && version_peer.services & NODE_BLOOM == 0 {
+            return Err(RustifyError::NodoSinFiltrosBloom);
+        }
+        if config.filtros_compactos && version_peer.services & NODE_COMPACT_FILTERS == 0 {
+            return Err(RustifyError::NodoSinFiltrosCompactos);
+        }
+    }
 
     Ok(())
 }
 
 /// Envío y recepción de mensajes verack para el handshake del nodo.
-pub fn verack(socket: &mut TcpStream, logger_sender: &Sender<String>) -> Result<(), RustifyError> {
-    let verack_message_header = MessageHeader::new("verack".to_string(), &[]);
-    let verack_message_header_bytes = verack_message_header.as_bytes();
-
-    write_to_node(socket, &verack_message_header_bytes, &[])?;
+pub fn verack(
+    socket: &mut ConexionPeer,
+    config: &Config,
+    logger_sender: &Sender<String>,
+) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::Verack)?;
     log(
         Lvl::Info(Action::CONNECT),
         "Enviado mensaje verack.",
         logger_sender,
     );
 
-    handle_specific_message(socket, "verack\0\0\0\0\0\0".to_string(), logger_sender)?;
+    esperar_respuesta(
+        socket,
+        "verack",
+        Duration::from_secs(config.timeout_respuesta_secs),
+        logger_sender,
+    )?;
 
     Ok(())
 }
@@ -211,7 +221,7 @@ mod tests {
             relay: 0,
         };
 
-        let result = message.as_bytes(&"/asdasdasdasdasdasdasda/");
+        let result = message.as_bytes();
         let expected: [u8; VERSION_SIZE] = [
             0x00, 0x00, 0x00, 0x20, // version
             0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // services
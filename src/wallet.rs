@@ -2,6 +2,7 @@ use crate::{
     account::Account,
     errors::RustifyError,
     logger::{log, log_err, log_with_parameters, Action, Lvl},
+    peer_manager::PeerManager,
     txn::Txn,
     txn_info::{TxnInfo, TxnType},
     wallet_txn::{broadcast_txn, generar_txn},
@@ -11,7 +12,6 @@ use std::{
     collections::HashMap,
     fs::{self, OpenOptions},
     io::{self, BufRead, BufReader, Write},
-    net::TcpStream,
     path::Path,
     sync::mpsc::Sender,
 };
@@ -44,10 +44,10 @@ impl Wallet {
         self.accounts.insert(alias, account_to_add);
     }
 
-    /// Genera la transacción y la broadcastea
+    /// Genera la transacción y la broadcastea a todos los peers conectados
     pub fn send_txn(
         &self,
-        socket: &mut TcpStream,
+        peer_manager: &PeerManager,
         logger_sender: &Sender<String>,
         emisor: &String,
         receptor: String,
@@ -55,6 +55,7 @@ impl Wallet {
         fee: f64,
     ) -> Result<Txn, RustifyError> {
         let receptor_account = Account::new(receptor, "".to_owned());
+        receptor_account.decode_bitcoin_adress()?;
 
         let transaction = generar_txn(
             logger_sender,
@@ -65,13 +66,14 @@ impl Wallet {
         )?;
         let txid = Txn::obtain_tx_id(transaction.as_bytes());
 
-        broadcast_txn(&transaction, socket)?;
+        let cant_peers =
+            peer_manager.enviar_a_todos(|socket| broadcast_txn(&transaction, socket))?;
 
         log_with_parameters(
             Lvl::Info(Action::WALLET),
             format!(
-                "Se ha broadcasteado exitosamente la transacción: {:?}",
-                txid
+                "Se ha broadcasteado exitosamente la transacción: {:?} a {} peers",
+                txid, cant_peers
             ),
             logger_sender,
         );
@@ -105,6 +107,19 @@ impl Wallet {
         Ok(())
     }
 
+    /// Carga las wallets guardadas sin sus UTXOs, por ejemplo para conocer sus direcciones
+    /// antes de descargar los bloques. Si no hay wallets guardadas, devuelve una wallet vacía.
+    pub fn cargar_sin_utxos(
+        path: String,
+        logger_sender: &Sender<String>,
+    ) -> Result<Wallet, RustifyError> {
+        let mut wallets = Wallet::new(path);
+        match wallets.load(logger_sender, &HashMap::new()) {
+            Ok(()) | Err(RustifyError::NoHayWalletsGuardadas) => Ok(wallets),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Guarda en disco la informacion de las wallets cargadas
     pub fn save(&self, logger_sender: &Sender<String>) -> Result<(), RustifyError> {
         let path = Path::new(&self.path);
@@ -1,11 +1,12 @@
 use std::{
     collections::HashMap,
-    net::TcpStream,
     str::FromStr,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
-use bitcoin_hashes::{sha256d, Hash};
 use secp256k1::SecretKey;
 
 use crate::{
@@ -14,11 +15,12 @@ use crate::{
     errors::{obtener_mensaje_personalizado, RustifyError},
     gui_events::GuiEvent,
     logger::{log, log_re_err, log_with_parameters, Action, Lvl},
+    peer_manager::PeerManager,
     script::Script,
     serialized_block::SerializedBlock,
     txn::Txn,
     txn_info::{TxnInfo, TxnType},
-    utxo::update_utxo,
+    utxo::SetUtxos,
     wallet::Wallet,
 };
 
@@ -30,6 +32,7 @@ pub enum WalletEvent {
     CargarWallet(String),
     RealizarTransferencia(String, f64, String, String, f64),
     RecibirBloque(SerializedBlock),
+    DesconectarBloques(Vec<SerializedBlock>),
     RecibirTxn(Txn, String),
     Cerrar,
 }
@@ -38,11 +41,13 @@ type TrxKey = (String, u32);
 type TrxHashMap<T> = HashMap<TrxKey, T>;
 
 /// Inicia la wallet y espera que le lleguen acciones por el receiver desde la interfaz gráfica.
+/// El set de UTXOs lo mantiene el nodo, que conecta y desconecta los bloques antes de enviarlos:
+/// la wallet solamente lo consulta.
 pub fn iniciar_wallet(
-    socket: &mut TcpStream,
+    peer_manager: &PeerManager,
     config: &Config,
     logger_sender: &Sender<String>,
-    mut utxos: TrxHashMap<Txn>,
+    utxos: Arc<Mutex<SetUtxos>>,
     recv_node: Receiver<WalletEvent>,
     sender_gui: gtk::glib::Sender<GuiEvent>,
 ) {
@@ -53,25 +58,33 @@ pub fn iniciar_wallet(
     );
     let mut wallets = Wallet::new(config.wallets_path.clone());
 
-    (utxos, wallets) =
-        cargar_wallets_inicio(wallets, &logger_sender.clone(), utxos, sender_gui.clone());
+    if let Some(set) = tomar_utxos(&utxos, logger_sender) {
+        wallets = cargar_wallets_inicio(wallets, logger_sender, &set.utxos, sender_gui.clone());
+    }
 
     loop {
         let evento = recv_node.recv();
 
         match evento {
             Ok(WalletEvent::AgregarWallet(private_key, public_key, alias)) => {
+                let Some(set) = tomar_utxos(&utxos, logger_sender) else {
+                    continue;
+                };
                 wallets = evento_agregar_wallet(
                     wallets,
                     logger_sender,
-                    &utxos,
+                    &set.utxos,
                     private_key,
                     public_key,
                     alias,
                 );
             }
             Ok(WalletEvent::CargarWallet(alias)) => {
-                wallets = evento_cargar_wallet(logger_sender, wallets, alias, &sender_gui, &utxos);
+                let Some(set) = tomar_utxos(&utxos, logger_sender) else {
+                    continue;
+                };
+                wallets =
+                    evento_cargar_wallet(logger_sender, wallets, alias, &sender_gui, &set.utxos);
             }
             Ok(WalletEvent::RealizarTransferencia(alias, amount, label, address, fee)) => {
                 let tupla_txn_data = (amount, label, address, fee);
@@ -79,7 +92,7 @@ pub fn iniciar_wallet(
                     logger_sender,
                     &sender_gui,
                     wallets,
-                    socket,
+                    peer_manager,
                     alias,
                     tupla_txn_data,
                 );
@@ -110,13 +123,14 @@ pub fn iniciar_wallet(
                     logger_sender,
                 );
 
-                utxos = update_utxo(utxos, logger_sender, &bloque).unwrap_or_default();
-
+                let Some(set) = tomar_utxos(&utxos, logger_sender) else {
+                    continue;
+                };
                 wallets = match evento_recibir_bloque(
                     wallets.clone(),
                     logger_sender,
                     &sender_gui,
-                    &utxos,
+                    &set.utxos,
                     bloque,
                 ) {
                     Ok(w) => w,
@@ -130,6 +144,36 @@ pub fn iniciar_wallet(
                     }
                 };
             }
+            Ok(WalletEvent::DesconectarBloques(bloques)) => {
+                log_with_parameters(
+                    Lvl::Warning(Action::WALLET),
+                    format!(
+                        "Se desconectaron {} bloques por una reorganización de la cadena.",
+                        bloques.len()
+                    ),
+                    logger_sender,
+                );
+                let Some(set) = tomar_utxos(&utxos, logger_sender) else {
+                    continue;
+                };
+                wallets = match evento_desconectar_bloques(
+                    wallets.clone(),
+                    logger_sender,
+                    &sender_gui,
+                    &set.utxos,
+                    &bloques,
+                ) {
+                    Ok(w) => w,
+                    Err(_) => {
+                        log(
+                            Lvl::Warning(Action::WALLET),
+                            "Se obtuvo un error al desconectar bloques.",
+                            logger_sender,
+                        );
+                        continue;
+                    }
+                };
+            }
             Ok(WalletEvent::Cerrar) | Err(_) => {
                 break;
             }
@@ -137,14 +181,28 @@ pub fn iniciar_wallet(
     }
 }
 
+/// Toma el set de UTXOs compartido con el nodo. Si no se puede, lo informa en el log.
+fn tomar_utxos<'a>(
+    utxos: &'a Arc<Mutex<SetUtxos>>,
+    logger_sender: &Sender<String>,
+) -> Option<MutexGuard<'a, SetUtxos>> {
+    match utxos.lock() {
+        Ok(set) => Some(set),
+        Err(e) => {
+            log_re_err(Action::WALLET, e.into(), logger_sender);
+            None
+        }
+    }
+}
+
 /// Carga en memoria las wallets existentes en los archivos de datos locales
 pub fn cargar_wallets_inicio(
     mut wallets: Wallet,
     logger_sender: &Sender<String>,
-    utxos: TrxHashMap<Txn>,
+    utxos: &TrxHashMap<Txn>,
     sender_gui: gtk::glib::Sender<GuiEvent>,
-) -> (TrxHashMap<Txn>, Wallet) {
-    match wallets.load(logger_sender, &utxos) {
+) -> Wallet {
+    match wallets.load(logger_sender, utxos) {
         Ok(_) => {
             let mut aliases = vec![];
             for alias in wallets.accounts.keys() {
@@ -162,7 +220,7 @@ pub fn cargar_wallets_inicio(
             );
         }
     };
-    (utxos, wallets)
+    wallets
 }
 
 /// Agrega al HashMap de Wallets, la nueva wallet recibida a traves del evento.
@@ -238,7 +296,7 @@ pub fn evento_realizar_trx(
     logger_sender: &Sender<String>,
     sender_gui: &gtk::glib::Sender<GuiEvent>,
     mut wallets: Wallet,
-    socket: &mut TcpStream,
+    peer_manager: &PeerManager,
     alias: String,
     tupla_txn_data: (f64, String, String, f64),
 ) -> Wallet {
@@ -253,7 +311,14 @@ pub fn evento_realizar_trx(
             ),
             logger_sender,
         );
-        match wallets.send_txn(socket, logger_sender, &alias, address.clone(), amount, fee) {
+        match wallets.send_txn(
+            peer_manager,
+            logger_sender,
+            &alias,
+            address.clone(),
+            amount,
+            fee,
+        ) {
             Ok(transaction) => {
                 if let Some(val) = wallets.accounts.get_mut(&alias) {
                     val.sending_txn.push(TxnInfo::new(
@@ -402,32 +467,39 @@ pub fn evento_recibir_bloque(
     Ok(wallets)
 }
 
-/// Verifica si las claves publicas y privadas ingresadas son validas
-pub fn claves_validas(private_key: &str, public_key: &str) -> bool {
-    match decode_public_key(public_key.to_owned()) {
-        (true, _) => {}
-        (false, _) => return false,
-    };
-    SecretKey::from_str(&Account::new_str(public_key, private_key).obtain_hex_privatekey()).is_ok()
+/// Revierte en todas las wallets las confirmaciones de los bloques desconectados por una reorganización,
+/// y actualiza sus balances con el listado de UTXOs ya recalculado.
+pub fn evento_desconectar_bloques(
+    mut wallets: Wallet,
+    logger_sender: &Sender<String>,
+    sender_gui: &gtk::glib::Sender<GuiEvent>,
+    utxos: &TrxHashMap<Txn>,
+    bloques: &[SerializedBlock],
+) -> Result<Wallet, RustifyError> {
+    for (_, wallet) in wallets.accounts.iter_mut() {
+        for bloque in bloques {
+            wallet.revertir_bloque(bloque);
+        }
+        wallet.obtain_account_balance(utxos);
+        wallet.update_pending_balance();
+        sender_gui
+            .send(GuiEvent::ActualizarWallet(wallet.clone()))
+            .unwrap_or(());
+    }
+    wallets.save(logger_sender)?;
+    Ok(wallets)
 }
 
-/// Verifica si la Bitcoin Adress ingresada es una pubkey valida
-fn decode_public_key(public_key: String) -> (bool, Vec<u8>) {
-    let b58 = match bs58::decode(public_key).into_vec() {
-        Ok(b) => b,
-        Err(_) => return (false, vec![]),
-    };
-    if b58.len() > 5 {
-        let b58_checksum = &b58[b58.len() - 4..b58.len()];
-        let b58_hashversion = &b58[0..b58.len() - 4];
-        if b58_checksum != &sha256d::Hash::hash(b58_hashversion)[0..4] {
-            (false, vec![])
-        } else {
-            (true, b58_hashversion[1..].to_vec())
-        }
-    } else {
-        (false, vec![])
+/// Verifica si las claves publicas y privadas ingresadas son validas
+/// para la red seleccionada. La dirección de la wallet tiene que ser P2PKH.
+pub fn claves_validas(private_key: &str, public_key: &str) -> bool {
+    let cuenta = Account::new_str(public_key, private_key);
+    if cuenta.decode_bitcoin_adress().is_err() || cuenta.es_direccion_p2sh() {
+        return false;
     }
+    cuenta
+        .obtain_hex_privatekey()
+        .is_ok_and(|hex| SecretKey::from_str(&hex).is_ok())
 }
 
 #[cfg(test)]
@@ -2,16 +2,16 @@ use crate::{
     account::{amount_of_satoshis, Account},
     compactsize::CompactSize,
     errors::RustifyError,
+    event_loop::ConexionPeer,
     logger::{log, Action, Lvl},
-    message_header::MessageHeader,
-    node::write_to_node,
+    network_message::{escribir_mensaje, NetworkMessage},
     script::Script,
     txn::Txn,
     txout::TxOut,
 };
 use bitcoin_hashes::{sha256d, Hash};
 use secp256k1::{Message, Secp256k1, SecretKey};
-use std::{collections::HashMap, net::TcpStream, str::FromStr, sync::mpsc::Sender};
+use std::{collections::HashMap, str::FromStr, sync::mpsc::Sender};
 
 // Tipo de dato de Hashmap de transacción
 type TrxKey = (String, u32);
@@ -51,13 +51,8 @@ pub fn generar_txn(
 
 /// Envía la transacción en un mensaje de tipo "tx"
 ///  a traves del nodo bitcoin  
-pub fn broadcast_txn(transaction: &Txn, socket: &mut TcpStream) -> Result<(), RustifyError> {
-    let tx_message_bytes = transaction.as_bytes();
-    let tx_message_header = MessageHeader::new("tx".to_string(), &tx_message_bytes);
-    let tx_message_header_bytes = tx_message_header.as_bytes();
-    write_to_node(socket, &tx_message_header_bytes, &tx_message_bytes)?;
-
-    Ok(())
+pub fn broadcast_txn(transaction: &Txn, socket: &mut ConexionPeer) -> Result<(), RustifyError> {
+    escribir_mensaje(socket, &NetworkMessage::Tx(transaction.as_bytes()))
 }
 
 /// Determina las utxo que se van a utilizar para gastar (inputs), el vuelto hacia el emisor (outputs)
@@ -126,7 +121,7 @@ fn obtain_sec_der(z: [u8; 32], firmante: &Account) -> Result<(Vec<u8>, Vec<u8>),
         Err(_) => return Err(RustifyError::ErrorParseoTxn),
     };
 
-    let private_key = match SecretKey::from_str(&firmante.obtain_hex_privatekey()) {
+    let private_key = match SecretKey::from_str(&firmante.obtain_hex_privatekey()?) {
         Ok(k) => k,
         Err(_) => return Err(RustifyError::ErrorConversionSecretKey),
     };